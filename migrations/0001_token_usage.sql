@@ -0,0 +1,9 @@
+-- Daily validation counts per tenant and function_id, written by src/stats.rs
+CREATE TABLE IF NOT EXISTS token_usage (
+    day TEXT NOT NULL,
+    tenant TEXT NOT NULL,
+    function_id TEXT NOT NULL,
+    allowed INTEGER NOT NULL DEFAULT 0,
+    denied INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (day, tenant, function_id)
+);
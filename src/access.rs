@@ -0,0 +1,395 @@
+use std::cell::RefCell;
+
+use async_trait::async_trait;
+use base64::prelude::*;
+use js_sys::Date;
+use rsa::{pkcs1v15, signature::Verifier, BigUint, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use worker::{console_error, Env, Error, Fetch, Headers, Url};
+
+use crate::errors::Rejection;
+use crate::pipeline::{Flow, Stage, ValidationContext};
+use crate::reason::Reason;
+
+const JWKS_CACHE_TTL_MS: f64 = 10.0 * 60.0 * 1000.0;
+// Unknown key ids come from the client, so they may force a refetch at most this often
+const MIN_FORCED_REFRESH_MS: f64 = 60.0 * 1000.0;
+// The copy housekeeping keeps in `TOKEN_KEYS`; short, so a key Access withdrew does not linger
+const JWKS_KV_TTL_SECONDS: u64 = 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessJwtMode {
+    // Require both a valid Access JWT and a valid oait token
+    Additional,
+    // Accept a valid Access JWT in place of the oait token
+    Replace,
+}
+
+#[derive(Clone, Debug)]
+pub struct AccessSettings {
+    pub mode: AccessJwtMode,
+    pub team_domain: String,
+    pub audiences: Vec<String>,
+}
+
+impl AccessSettings {
+    fn issuer(&self) -> String {
+        format!("https://{}", self.team_domain)
+    }
+
+    fn certs_url(&self) -> String {
+        format!("https://{}/cdn-cgi/access/certs", self.team_domain)
+    }
+}
+
+// Enabled with `ACCESS_JWT_MODE=additional|replace` plus `ACCESS_TEAM_DOMAIN` and `ACCESS_AUD`
+pub fn access_settings(env: &Env) -> Option<AccessSettings> {
+    let mode = match env.var("ACCESS_JWT_MODE").ok()?.to_string().as_str() {
+        "additional" => AccessJwtMode::Additional,
+        "replace" => AccessJwtMode::Replace,
+        "off" | "" => return None,
+        other => {
+            console_error!(
+                "Unknown ACCESS_JWT_MODE {}, Access JWT check disabled",
+                other
+            );
+            return None;
+        }
+    };
+
+    let team_domain = env.var("ACCESS_TEAM_DOMAIN").ok()?.to_string();
+    let audiences: Vec<String> = env
+        .var("ACCESS_AUD")
+        .ok()?
+        .to_string()
+        .split(',')
+        .map(|aud| aud.trim().to_string())
+        .filter(|aud| !aud.is_empty())
+        .collect();
+    if team_domain.is_empty() || audiences.is_empty() {
+        console_error!("ACCESS_JWT_MODE set without ACCESS_TEAM_DOMAIN/ACCESS_AUD");
+        return None;
+    }
+
+    Some(AccessSettings {
+        mode,
+        team_domain: team_domain.trim_start_matches("https://").to_string(),
+        audiences,
+    })
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Jwk {
+    kid: String,
+    kty: String,
+    n: String,
+    e: String,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct AccessClaims {
+    iss: String,
+    aud: Audience,
+    exp: f64,
+    nbf: Option<f64>,
+}
+
+struct CachedJwks {
+    team_domain: String,
+    fetched_at: f64,
+    // When an unknown key id last forced a refetch
+    forced_at: Option<f64>,
+    keys: Vec<Jwk>,
+}
+
+impl CachedJwks {
+    // Whether these keys answer the lookup without going back to Access
+    fn serves(&self, team_domain: &str, now: f64, force_refresh: bool) -> bool {
+        let recently_forced = self
+            .forced_at
+            .is_some_and(|forced_at| now - forced_at < MIN_FORCED_REFRESH_MS);
+        self.team_domain == team_domain
+            && now - self.fetched_at < JWKS_CACHE_TTL_MS
+            && (!force_refresh || recently_forced)
+    }
+}
+
+thread_local! {
+    static JWKS_CACHE: RefCell<Option<CachedJwks>> = const { RefCell::new(None) };
+}
+
+// Validates `CF-Access-JWT-Assertion`; the error describes why the assertion was rejected
+pub async fn verify_access_jwt(
+    env: &Env,
+    settings: &AccessSettings,
+    headers: &Headers,
+) -> std::result::Result<(), String> {
+    let assertion = headers
+        .get("CF-Access-JWT-Assertion")
+        .ok()
+        .flatten()
+        .ok_or("missing CF-Access-JWT-Assertion header")?;
+
+    let mut segments = assertion.split('.');
+    let (Some(header_b64), Some(claims_b64), Some(signature_b64), None) = (
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+    ) else {
+        return Err("malformed JWT".into());
+    };
+
+    let header: JwtHeader = decode_segment(header_b64)?;
+    if header.alg != "RS256" {
+        return Err(format!("unsupported JWT alg {}", header.alg));
+    }
+
+    let jwk = match find_key(env, settings, &header.kid, false).await? {
+        Some(jwk) => jwk,
+        // Unknown kid usually means Access rotated its keys since we cached them
+        None => find_key(env, settings, &header.kid, true)
+            .await?
+            .ok_or_else(|| format!("unknown JWT kid {}", header.kid))?,
+    };
+
+    let signature = BASE64_URL_SAFE_NO_PAD
+        .decode(signature_b64)
+        .map_err(|_| "invalid JWT signature encoding")?;
+    verify_rs256(
+        &jwk,
+        format!("{}.{}", header_b64, claims_b64).as_bytes(),
+        &signature,
+    )?;
+
+    let claims: AccessClaims = decode_segment(claims_b64)?;
+    let now = Date::now() / 1000.0;
+    if claims.iss != settings.issuer() {
+        return Err(format!("unexpected JWT issuer {}", claims.iss));
+    }
+    if claims.exp <= now {
+        return Err("JWT expired".into());
+    }
+    if claims.nbf.is_some_and(|nbf| nbf > now) {
+        return Err("JWT not yet valid".into());
+    }
+    let audience_matches = match &claims.aud {
+        Audience::One(aud) => settings.audiences.contains(aud),
+        Audience::Many(auds) => auds.iter().any(|aud| settings.audiences.contains(aud)),
+    };
+    if !audience_matches {
+        return Err("JWT audience mismatch".into());
+    }
+
+    Ok(())
+}
+
+fn decode_segment<T: serde::de::DeserializeOwned>(segment: &str) -> std::result::Result<T, String> {
+    let bytes = BASE64_URL_SAFE_NO_PAD
+        .decode(segment)
+        .map_err(|_| "invalid JWT segment encoding")?;
+    serde_json::from_slice(&bytes).map_err(|e| format!("invalid JWT segment: {}", e))
+}
+
+fn verify_rs256(jwk: &Jwk, message: &[u8], signature: &[u8]) -> std::result::Result<(), String> {
+    if jwk.kty != "RSA" {
+        return Err(format!("unsupported JWK type {}", jwk.kty));
+    }
+
+    let component = |value: &str| {
+        BASE64_URL_SAFE_NO_PAD
+            .decode(value)
+            .map(|bytes| BigUint::from_bytes_be(&bytes))
+            .map_err(|_| "invalid JWK encoding".to_string())
+    };
+    let public_key = RsaPublicKey::new(component(&jwk.n)?, component(&jwk.e)?)
+        .map_err(|e| format!("invalid JWK: {}", e))?;
+    let signature =
+        pkcs1v15::Signature::try_from(signature).map_err(|_| "invalid JWT signature")?;
+
+    pkcs1v15::VerifyingKey::<Sha256>::new(public_key)
+        .verify(message, &signature)
+        .map_err(|_| "JWT signature mismatch".to_string())
+}
+
+async fn find_key(
+    env: &Env,
+    settings: &AccessSettings,
+    kid: &str,
+    force_refresh: bool,
+) -> std::result::Result<Option<Jwk>, String> {
+    let now = Date::now();
+    let (cached, stale) = JWKS_CACHE.with(|cache| {
+        let cache = cache.borrow();
+        let same_team = cache
+            .as_ref()
+            .filter(|cached| cached.team_domain == settings.team_domain);
+        (
+            same_team
+                .filter(|cached| cached.serves(&settings.team_domain, now, force_refresh))
+                .map(|cached| cached.keys.clone()),
+            same_team.map(|cached| cached.keys.clone()),
+        )
+    });
+
+    let keys = match cached {
+        Some(keys) => keys,
+        None => {
+            // A cold isolate starts from the copy housekeeping stored, if there is one
+            let stored = if force_refresh {
+                None
+            } else {
+                stored_jwks(env, settings).await
+            };
+            let fetched = match stored {
+                Some(keys) => Ok(keys),
+                None => fetch_jwks(settings).await,
+            };
+            match (fetched, stale) {
+                (Ok(keys), _) => {
+                    cache_jwks(settings, now, force_refresh.then_some(now), &keys);
+                    keys
+                }
+                // Keys that are merely old beat no keys while Access is unreachable
+                (Err(e), Some(keys)) => {
+                    console_error!("Failed to fetch Access JWKS, keeping cached keys: {}", e);
+                    if force_refresh {
+                        JWKS_CACHE.with(|cache| {
+                            if let Some(cached) = cache.borrow_mut().as_mut() {
+                                cached.forced_at = Some(now);
+                            }
+                        });
+                    }
+                    keys
+                }
+                (Err(e), None) => return Err(format!("failed to fetch Access JWKS: {}", e)),
+            }
+        }
+    };
+
+    Ok(keys.into_iter().find(|jwk| jwk.kid == kid))
+}
+
+fn cache_jwks(settings: &AccessSettings, now: f64, forced_at: Option<f64>, keys: &[Jwk]) {
+    JWKS_CACHE.with(|cache| {
+        *cache.borrow_mut() = Some(CachedJwks {
+            team_domain: settings.team_domain.clone(),
+            fetched_at: now,
+            forced_at,
+            keys: keys.to_vec(),
+        });
+    });
+}
+
+fn jwks_key(settings: &AccessSettings) -> String {
+    format!("access-jwks:{}", settings.team_domain)
+}
+
+async fn stored_jwks(env: &Env, settings: &AccessSettings) -> Option<Vec<Jwk>> {
+    let kv = env.kv("TOKEN_KEYS").ok()?;
+    match kv.get(&jwks_key(settings)).json::<Vec<Jwk>>().await {
+        Ok(keys) => keys.filter(|keys| !keys.is_empty()),
+        Err(e) => {
+            console_error!("Failed to read Access JWKS from KV: {}", e);
+            None
+        }
+    }
+}
+
+// Fetched by the scheduled housekeeping run, so requests rarely wait on the certs endpoint.
+// Returns how many keys Access published.
+pub async fn refresh_jwks(env: &Env, settings: &AccessSettings) -> worker::Result<usize> {
+    let keys = fetch_jwks(settings).await?;
+    if let Ok(kv) = env.kv("TOKEN_KEYS") {
+        kv.put(&jwks_key(settings), serde_json::to_string(&keys)?)?
+            .expiration_ttl(JWKS_KV_TTL_SECONDS)
+            .execute()
+            .await?;
+    }
+    cache_jwks(settings, Date::now(), None, &keys);
+    Ok(keys.len())
+}
+
+async fn fetch_jwks(settings: &AccessSettings) -> worker::Result<Vec<Jwk>> {
+    let url = Url::parse(&settings.certs_url())?;
+    let mut response = Fetch::Url(url).send().await?;
+    let status = response.status_code();
+    if !(200..300).contains(&status) {
+        return Err(Error::RustError(format!(
+            "certs endpoint answered {}",
+            status
+        )));
+    }
+    let keys = response.json::<Jwks>().await?.keys;
+    if keys.is_empty() {
+        return Err(Error::RustError(
+            "certs endpoint returned no keys".to_string(),
+        ));
+    }
+    Ok(keys)
+}
+
+pub struct AccessJwtCheck;
+
+#[async_trait(?Send)]
+impl<'a> Stage<ValidationContext<'a>> for AccessJwtCheck {
+    async fn run(&self, cx: &mut ValidationContext<'a>) -> worker::Result<Flow> {
+        let Some(settings) = cx.access_settings else {
+            return Ok(Flow::Continue);
+        };
+        if let Err(reason) = verify_access_jwt(cx.env, settings, cx.req.headers()).await {
+            console_error!("Access JWT rejected: {}", reason);
+            return Ok(Flow::Reject(Rejection::new(
+                403,
+                Reason::AccessJwtInvalid,
+                "Invalid Access token",
+            )));
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_key_ids_force_a_refetch_at_most_once_a_minute() {
+        let mut cached = CachedJwks {
+            team_domain: "myteam.cloudflareaccess.com".to_string(),
+            fetched_at: 0.0,
+            forced_at: None,
+            keys: Vec::new(),
+        };
+        let serves = |cached: &CachedJwks, now, force| {
+            cached.serves("myteam.cloudflareaccess.com", now, force)
+        };
+        assert!(serves(&cached, 1_000.0, false));
+        assert!(!serves(&cached, 1_000.0, true));
+        assert!(!serves(&cached, JWKS_CACHE_TTL_MS, false));
+        assert!(!cached.serves("other.cloudflareaccess.com", 1_000.0, false));
+
+        cached.forced_at = Some(1_000.0);
+        assert!(serves(&cached, 30_000.0, true));
+        assert!(!serves(&cached, 61_000.0, true));
+    }
+}
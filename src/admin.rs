@@ -0,0 +1,260 @@
+use serde::Deserialize;
+use worker::*;
+
+use crate::audit::{self, hashed_ip};
+use crate::audit_chain;
+use crate::events::{AdminActionEvent, Event};
+use crate::oait;
+use crate::response_cache;
+use crate::revocation::{self, RevocationScope};
+use crate::rules;
+use crate::stats;
+use crate::token::{constant_time_compare, issue_hmac_token, TokenLifetime};
+
+const ADMIN_PATH_PREFIX: &str = "/admin/";
+pub const MAX_SIGNED_URL_TTL_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+// Admin routes are only intercepted once an `ADMIN_TOKEN` secret exists; otherwise they reach the origin
+pub fn is_admin_request(url: &Url, env: &Env) -> bool {
+    url.path().starts_with(ADMIN_PATH_PREFIX) && env.secret("ADMIN_TOKEN").is_ok()
+}
+
+#[derive(Deserialize)]
+struct RevocationRequest {
+    scope: RevocationScope,
+    value: String,
+    reason: Option<String>,
+    ttl_seconds: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct PurgeRequest {
+    url: String,
+}
+
+// A link to pre-sign: bound to `client_ip` when given, otherwise usable from any IP
+#[derive(Deserialize)]
+struct SignUrlRequest {
+    url: String,
+    ttl_seconds: u64,
+    client_ip: Option<String>,
+    kid: Option<String>,
+    #[serde(default)]
+    audiences: Vec<String>,
+    #[serde(default)]
+    forms_token: String,
+    #[serde(default)]
+    access_token: String,
+}
+
+pub async fn handle(mut req: Request, env: &Env, ctx: &Context) -> Result<Response> {
+    if !is_authorized(&req, env)? {
+        return Response::error("Unauthorized", 401);
+    }
+    let client_ip = crate::client_ip::client_ip(env, req.headers());
+    let record = |action: &str, target: String| {
+        audit::record_event(
+            ctx,
+            env,
+            Event::AdminAction(AdminActionEvent {
+                schema_version: AdminActionEvent::SCHEMA_VERSION,
+                timestamp: Date::now().as_millis() as f64,
+                action: action.to_string(),
+                target,
+                hashed_ip: hashed_ip(env, &client_ip),
+            }),
+        )
+    };
+
+    let path = req.path();
+    match (req.method(), path.as_str()) {
+        (Method::Post, "/admin/revocations") => {
+            let Ok(body) = req.json::<RevocationRequest>().await else {
+                return Response::error("Invalid revocation request", 400);
+            };
+            if body.value.is_empty() {
+                return Response::error("Revocation value is required", 400);
+            }
+            revocation::revoke(
+                &env.kv("REVOCATIONS")?,
+                &crate::config::config(env),
+                body.scope,
+                &body.value,
+                body.reason,
+                body.ttl_seconds,
+            )
+            .await?;
+            console_log!("admin: revoked scope={}", body.scope.name());
+            record("revoke", revocation_target(env, body.scope, &body.value));
+            Ok(Response::empty()?.with_status(204))
+        }
+        (Method::Delete, "/admin/revocations") => {
+            let Ok(body) = req.json::<RevocationRequest>().await else {
+                return Response::error("Invalid revocation request", 400);
+            };
+            revocation::unrevoke(
+                &env.kv("REVOCATIONS")?,
+                &crate::config::config(env),
+                body.scope,
+                &body.value,
+            )
+            .await?;
+            console_log!("admin: unrevoked scope={}", body.scope.name());
+            record("unrevoke", revocation_target(env, body.scope, &body.value));
+            Ok(Response::empty()?.with_status(204))
+        }
+        (Method::Post, "/admin/cache/purge") => {
+            let Ok(body) = req.json::<PurgeRequest>().await else {
+                return Response::error("Invalid purge request", 400);
+            };
+            let Ok(url) = Url::parse(&body.url) else {
+                return Response::error("Invalid purge URL", 400);
+            };
+            let purged = response_cache::purge(&url).await?;
+            console_log!("admin: purge url={} purged={}", body.url, purged);
+            record("purge_cache", body.url);
+            Response::from_json(&serde_json::json!({ "purged": purged }))
+        }
+        (Method::Post, "/admin/sign-url") => {
+            let Ok(body) = req.json::<SignUrlRequest>().await else {
+                return Response::error("Invalid sign-url request", 400);
+            };
+            if !(1..=MAX_SIGNED_URL_TTL_SECONDS).contains(&body.ttl_seconds) {
+                return Response::error("ttl_seconds must be between 1 and 2592000", 400);
+            }
+            let Ok(url) = Url::parse(&body.url) else {
+                return Response::error("Invalid URL", 400);
+            };
+            // Links for a tenant with its own secret are signed with that secret
+            let rules = rules::current(env).await;
+            let tenant = crate::tenant::tenant_config(
+                env,
+                &rules,
+                url.host_str().unwrap_or_default(),
+                url.path(),
+            );
+            let Some(default_secret) = crate::tenant_secrets::resolve(
+                env,
+                &crate::lookup_budget::LookupBudget::new(env),
+                tenant.secret.as_ref(),
+                crate::hmac_secret(env),
+            )
+            .await
+            else {
+                return Response::error("Tenant secret unavailable", 503);
+            };
+            let Some((signed, expires_at)) = sign_url(env, &default_secret, url, &body) else {
+                return Response::error("Unknown key id", 400);
+            };
+            console_log!(
+                "admin: signed url host={} ttl_seconds={} ip_bound={}",
+                signed.host_str().unwrap_or_default(),
+                body.ttl_seconds,
+                body.client_ip.is_some()
+            );
+            record(
+                "sign_url",
+                signed.host_str().unwrap_or_default().to_string(),
+            );
+            Response::from_json(&serde_json::json!({
+                "url": signed.to_string(),
+                "expires_at": expires_at,
+            }))
+        }
+        (Method::Get, "/admin/stats") => {
+            let url = req.url()?;
+            let days = match url.query_pairs().find(|(k, _)| k == "days") {
+                Some((_, days)) => match days.parse::<u32>() {
+                    Ok(days) if (1..=stats::MAX_STATS_DAYS).contains(&days) => days,
+                    _ => return Response::error("days must be between 1 and 90", 400),
+                },
+                None => stats::DEFAULT_STATS_DAYS,
+            };
+            let Ok(db) = env.d1("STATS_DB") else {
+                return Response::error("Usage stats are not configured", 404);
+            };
+            Response::from_json(&stats::summary(&db, days).await?)
+        }
+        (Method::Get, "/admin/housekeeping") => crate::housekeeping::last_heartbeat(env).await,
+        (Method::Get, "/admin/audit/verify") => audit_chain::verify(env, &req.url()?).await,
+        (Method::Get, "/admin/rules") => rules::get(env).await,
+        (Method::Put, "/admin/rules") => rules::put(req, env, ctx).await,
+        _ => Response::error("Not found", 404),
+    }
+}
+
+// Key ids are logged as is; a revoked token is never repeated and an IP only in hashed form
+fn revocation_target(env: &Env, scope: RevocationScope, value: &str) -> String {
+    match scope {
+        RevocationScope::Token => scope.name().to_string(),
+        RevocationScope::Ip => format!("{}:{}", scope.name(), hashed_ip(env, value)),
+        RevocationScope::Kid => format!("{}:{}", scope.name(), value),
+    }
+}
+
+// Mints the cloudflare token the way refreshes do, with the link's expiry signed in, and appends
+// the full token set in the configured format
+fn sign_url(
+    env: &Env,
+    default_secret: &str,
+    mut url: Url,
+    body: &SignUrlRequest,
+) -> Option<(Url, f64)> {
+    let kid = body.kid.as_deref();
+    let secret = crate::hmac_secret_for(env, default_secret, kid)?;
+    let algorithm = crate::hmac_algorithm(env);
+    let now = (Date::now().as_millis() / 1000) as f64;
+    let expires_at = now + body.ttl_seconds as f64;
+    let audiences: Vec<&str> = body.audiences.iter().map(String::as_str).collect();
+    let cloudflare_token = issue_hmac_token(
+        algorithm,
+        kid,
+        &audiences,
+        body.client_ip.as_deref(),
+        &secret,
+        crate::timestamp_unit(env).in_unit_of(now, now),
+        TokenLifetime {
+            expires_at: Some(expires_at),
+            session_start: None,
+        },
+        crate::hmac_truncation(env, kid, algorithm),
+    );
+
+    let mut query = url.query_pairs_mut();
+    if oait::token_format(env).accepts_oait() {
+        let delimiter = oait::delimiter(env);
+        let mut oait = format!(
+            "{}{}{}",
+            body.forms_token,
+            delimiter,
+            urlencoding::encode(&cloudflare_token)
+        );
+        if !body.access_token.is_empty() {
+            oait = format!("{}{}{}", oait, delimiter, body.access_token);
+        }
+        query.append_pair("oait", &oait);
+    } else {
+        if !body.forms_token.is_empty() {
+            query.append_pair(oait::FORMS_TOKEN_PARAM, &body.forms_token);
+        }
+        query.append_pair(oait::CF_TOKEN_PARAM, &cloudflare_token);
+        if !body.access_token.is_empty() {
+            query.append_pair(oait::ACCESS_TOKEN_PARAM, &body.access_token);
+        }
+    }
+    drop(query);
+    Some((url, expires_at))
+}
+
+pub fn is_authorized(req: &Request, env: &Env) -> Result<bool> {
+    let expected = env.secret("ADMIN_TOKEN")?.to_string();
+    let provided = req
+        .headers()
+        .get("Authorization")?
+        .and_then(|value| value.strip_prefix("Bearer ").map(str::to_string))
+        .unwrap_or_default();
+    Ok(constant_time_compare(
+        expected.as_bytes(),
+        provided.as_bytes(),
+    ))
+}
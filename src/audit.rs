@@ -0,0 +1,133 @@
+use std::cell::RefCell;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use worker::*;
+
+use crate::events::{AuditEvent, AuditOutcome, Event};
+use crate::reason::Reason;
+
+// Queues accept at most 100 messages per `sendBatch` call
+const MAX_BATCH_SIZE: usize = 100;
+
+thread_local! {
+    static PENDING: RefCell<Vec<Event>> = const { RefCell::new(Vec::new()) };
+}
+
+pub struct Decision<'a> {
+    pub host: &'a str,
+    pub path: &'a str,
+    pub tenant: &'a str,
+    pub function_id: &'a str,
+    pub client_ip: &'a str,
+    pub outcome: AuditOutcome,
+    pub enforced: bool,
+    pub status: u16,
+    pub reason: &'a str,
+    pub code: Reason,
+}
+
+// Queues the decision for the `AUDIT_QUEUE` producer binding, if bound. Events are buffered per
+// isolate and flushed in the background, so concurrent requests share one `sendBatch` call.
+// With `AUDIT_CHAIN_DO` bound the same record is also appended to the hash-chained log in R2.
+pub fn record(ctx: &Context, env: &Env, decision: &Decision) {
+    let queue = env.queue("AUDIT_QUEUE").ok();
+    let chained = crate::audit_chain::is_enabled(env);
+    if queue.is_none() && !chained {
+        return;
+    }
+
+    let event = AuditEvent {
+        schema_version: AuditEvent::SCHEMA_VERSION,
+        timestamp: Date::now().as_millis() as f64,
+        host: decision.host.to_string(),
+        path: decision.path.to_string(),
+        hashed_ip: hashed_ip(env, decision.client_ip),
+        outcome: decision.outcome,
+        enforced: decision.enforced,
+        status: decision.status,
+        reason: decision.reason.to_string(),
+        code: decision.code.code().to_string(),
+        matched_rule: crate::routing::route_table(env)
+            .resolve(decision.host, decision.path)
+            .map(str::to_string),
+    };
+
+    if chained {
+        crate::audit_chain::append(ctx, env, event.clone());
+    }
+    if let Some(queue) = queue {
+        PENDING.with(|pending| pending.borrow_mut().push(Event::AuditDecision(event)));
+        ctx.wait_until(flush(queue));
+    }
+}
+
+// Admin actions and configuration changes share the decisions' queue and batching
+pub fn record_event(ctx: &Context, env: &Env, event: Event) {
+    let Ok(queue) = env.queue("AUDIT_QUEUE") else {
+        return;
+    };
+    PENDING.with(|pending| pending.borrow_mut().push(event));
+    ctx.wait_until(flush(queue));
+}
+
+// Housekeeping publishes events left behind when a request's background flush was cut short
+pub async fn flush_pending(env: &Env) {
+    if let Ok(queue) = env.queue("AUDIT_QUEUE") {
+        flush(queue).await;
+    }
+}
+
+// Whichever request's background task runs first takes everything buffered so far
+async fn flush(queue: Queue) {
+    let batch = PENDING.with(|pending| std::mem::take(&mut *pending.borrow_mut()));
+    for chunk in batch.chunks(MAX_BATCH_SIZE) {
+        if let Err(e) = queue.send_batch(chunk.to_vec()).await {
+            console_error!("Failed to publish {} audit events: {}", chunk.len(), e);
+        }
+    }
+}
+
+// Keyed with `AUDIT_IP_SALT`, so the same client hashes the same wherever it is reported. Empty
+// when no key can be had, see `ip_hash_key`.
+pub fn hashed_ip(env: &Env, client_ip: &str) -> String {
+    let secret = |name| env.secret(name).ok().map(|v| v.to_string());
+    match ip_hash_key(secret("AUDIT_IP_SALT"), secret("HMAC_SECRET")) {
+        Some(key) => hash_ip(&key, client_ip),
+        None => String::new(),
+    }
+}
+
+// Without `AUDIT_IP_SALT` the key is derived from `HMAC_SECRET` for this use alone, so hashes
+// published to third parties are never keyed with the token secret itself. With neither, the
+// only key left is the public default secret, which would let anyone reverse the hashes.
+fn ip_hash_key(salt: Option<String>, hmac_secret: Option<String>) -> Option<String> {
+    salt.or_else(|| hmac_secret.map(|secret| hash_ip(&secret, "audit-ip")))
+}
+
+fn hash_ip(salt: &str, client_ip: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(salt.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(client_ip.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_hashes_are_never_keyed_with_the_token_secret() {
+        let salted = ip_hash_key(Some("salt".to_string()), Some("secret".to_string()));
+        assert_eq!(salted.as_deref(), Some("salt"));
+
+        let derived = ip_hash_key(None, Some("secret".to_string())).unwrap();
+        assert_ne!(derived, "secret");
+        assert_ne!(
+            hash_ip(&derived, "192.0.2.1"),
+            hash_ip("secret", "192.0.2.1")
+        );
+
+        assert_eq!(ip_hash_key(None, None), None);
+    }
+}
@@ -0,0 +1,435 @@
+use std::cell::{Cell, RefCell};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::*;
+
+use crate::events::AuditEvent;
+
+const CHAIN_OBJECT_NAME: &str = "global";
+const BATCH_KEY_PREFIX: &str = "audit-chain/";
+const HEAD_KEY: &str = "head";
+// Each verified batch costs one R2 read, and subrequests per invocation are limited
+const VERIFY_PAGE_SIZE: u32 = 100;
+
+// One R2 object per batch. `previous_hash` is the SHA-256 of the previous batch's stored bytes, so
+// editing, removing or reordering any batch breaks every link after it.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChainBatch {
+    pub sequence: u64,
+    pub previous_hash: String,
+    pub created_at: f64,
+    pub records: Vec<AuditEvent>,
+}
+
+// The last batch written; the first batch links to `GENESIS_HASH`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChainHead {
+    pub sequence: u64,
+    pub hash: String,
+}
+
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+impl ChainHead {
+    fn genesis() -> Self {
+        Self {
+            sequence: 0,
+            hash: GENESIS_HASH.to_string(),
+        }
+    }
+}
+
+fn hash(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+// Zero-padded so R2 lists batches in chain order
+fn batch_key(sequence: u64) -> String {
+    format!("{}{:020}", BATCH_KEY_PREFIX, sequence)
+}
+
+// The bytes to store for the batch after `head`, and the head they make
+fn seal(head: &ChainHead, records: &[AuditEvent], created_at: f64) -> Result<(Vec<u8>, ChainHead)> {
+    let batch = ChainBatch {
+        sequence: head.sequence + 1,
+        previous_hash: head.hash.clone(),
+        created_at,
+        records: records.to_vec(),
+    };
+    let bytes = serde_json::to_vec(&batch)?;
+    let next = ChainHead {
+        sequence: batch.sequence,
+        hash: hash(&bytes),
+    };
+    Ok((bytes, next))
+}
+
+// Walks batches in order, from the genesis or from where an earlier page stopped
+#[derive(Debug)]
+struct Verifier {
+    next_sequence: u64,
+    previous_hash: String,
+    batches: u64,
+    records: u64,
+}
+
+impl Verifier {
+    fn check(&mut self, key: &str, bytes: &[u8]) -> std::result::Result<(), String> {
+        let batch: ChainBatch = serde_json::from_slice(bytes)
+            .map_err(|e| format!("{}: unreadable batch: {}", key, e))?;
+        if batch.sequence != self.next_sequence || key != batch_key(batch.sequence) {
+            return Err(format!(
+                "{}: expected batch {}, found batch {}",
+                key, self.next_sequence, batch.sequence
+            ));
+        }
+        if batch.previous_hash != self.previous_hash {
+            return Err(format!(
+                "{}: previous_hash does not match batch {}",
+                key,
+                batch.sequence - 1
+            ));
+        }
+        self.previous_hash = hash(bytes);
+        self.next_sequence += 1;
+        self.batches += 1;
+        self.records += batch.records.len() as u64;
+        Ok(())
+    }
+}
+
+pub fn is_enabled(env: &Env) -> bool {
+    env.durable_object("AUDIT_CHAIN_DO").is_ok()
+}
+
+thread_local! {
+    static PENDING: RefCell<Vec<AuditEvent>> = const { RefCell::new(Vec::new()) };
+}
+
+// Buffered and flushed in the background like the queue events, so concurrent requests share
+// one batch
+pub fn append(ctx: &Context, env: &Env, event: AuditEvent) {
+    let Ok(namespace) = env.durable_object("AUDIT_CHAIN_DO") else {
+        return;
+    };
+    PENDING.with(|pending| pending.borrow_mut().push(event));
+    ctx.wait_until(flush(namespace));
+}
+
+async fn flush(namespace: ObjectNamespace) {
+    let records = PENDING.with(|pending| std::mem::take(&mut *pending.borrow_mut()));
+    if records.is_empty() {
+        return;
+    }
+    if let Err(e) = send(&namespace, &records).await {
+        console_error!("Failed to append {} audit records: {}", records.len(), e);
+    }
+}
+
+async fn send(namespace: &ObjectNamespace, records: &[AuditEvent]) -> Result<()> {
+    let stub = namespace.id_from_name(CHAIN_OBJECT_NAME)?.get_stub()?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_body(Some(serde_json::to_string(records)?.into()));
+    let response = stub
+        .fetch_with_request(Request::new_with_init("https://audit-chain/append", &init)?)
+        .await?;
+    if response.status_code() >= 400 {
+        return Err(Error::RustError(format!(
+            "audit chain answered {}",
+            response.status_code()
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct VerifyNext {
+    cursor: String,
+    sequence: u64,
+    previous_hash: String,
+}
+
+#[derive(Serialize)]
+struct VerifyReport {
+    status: &'static str,
+    batches_checked: u64,
+    records_checked: u64,
+    // Hash of the last batch checked
+    hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    // Pass these back as query parameters to check the next page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<VerifyNext>,
+}
+
+// `GET /admin/audit/verify` checks up to `VERIFY_PAGE_SIZE` batches per call, starting from the
+// genesis or from the `cursor`, `sequence` and `previous_hash` a previous call returned. On the
+// last page the final hash must also match the head the object holds, so batches cut off the
+// end of the chain are caught too.
+pub async fn verify(env: &Env, url: &Url) -> Result<Response> {
+    let Ok(bucket) = env.bucket("AUDIT_BUCKET") else {
+        return Response::error("The audit chain is not configured", 404);
+    };
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    let mut verifier = Verifier {
+        next_sequence: param("sequence").and_then(|s| s.parse().ok()).unwrap_or(1),
+        previous_hash: param("previous_hash").unwrap_or_else(|| GENESIS_HASH.to_string()),
+        batches: 0,
+        records: 0,
+    };
+
+    let mut list = bucket
+        .list()
+        .prefix(BATCH_KEY_PREFIX)
+        .limit(VERIFY_PAGE_SIZE);
+    if let Some(cursor) = param("cursor") {
+        list = list.cursor(cursor);
+    }
+    let page = list.execute().await?;
+
+    let mut error = None;
+    for object in page.objects() {
+        let key = object.key();
+        let bytes = match bucket.get(&key).execute().await? {
+            Some(object) => match object.body() {
+                Some(body) => body.bytes().await?,
+                None => Vec::new(),
+            },
+            None => {
+                error = Some(format!("{}: removed while verifying", key));
+                break;
+            }
+        };
+        if let Err(e) = verifier.check(&key, &bytes) {
+            error = Some(e);
+            break;
+        }
+    }
+
+    let next = match (&error, page.truncated(), page.cursor()) {
+        (None, true, Some(cursor)) => Some(VerifyNext {
+            cursor,
+            sequence: verifier.next_sequence,
+            previous_hash: verifier.previous_hash.clone(),
+        }),
+        _ => None,
+    };
+    if error.is_none() && next.is_none() {
+        let head = stored_head(env).await?;
+        if head.hash != verifier.previous_hash {
+            error = Some(format!(
+                "chain ends at batch {} but the head is batch {}",
+                verifier.next_sequence - 1,
+                head.sequence
+            ));
+        }
+    }
+
+    console_log!(
+        "admin: audit chain verified batches={} ok={}",
+        verifier.batches,
+        error.is_none()
+    );
+    let status = if error.is_none() { 200 } else { 409 };
+    Ok(Response::from_json(&VerifyReport {
+        status: if error.is_none() { "ok" } else { "broken" },
+        batches_checked: verifier.batches,
+        records_checked: verifier.records,
+        hash: verifier.previous_hash,
+        error,
+        next,
+    })?
+    .with_status(status))
+}
+
+async fn stored_head(env: &Env) -> Result<ChainHead> {
+    let stub = env
+        .durable_object("AUDIT_CHAIN_DO")?
+        .id_from_name(CHAIN_OBJECT_NAME)?
+        .get_stub()?;
+    stub.fetch_with_str("https://audit-chain/head")
+        .await?
+        .json()
+        .await
+}
+
+// The single writer of the chain. Records arriving while a batch is being written wait in
+// `pending` and go out together in the next batch, so batches are written strictly one after
+// another even though R2 calls let other requests in.
+#[durable_object]
+pub struct AuditChainObject {
+    state: State,
+    env: Env,
+    head: RefCell<Option<ChainHead>>,
+    pending: RefCell<Vec<AuditEvent>>,
+    writing: Cell<bool>,
+}
+
+impl AuditChainObject {
+    async fn head(&self, bucket: &Bucket) -> Result<ChainHead> {
+        if let Some(head) = self.head.borrow().clone() {
+            return Ok(head);
+        }
+        let head = match self.state.storage().get::<ChainHead>(HEAD_KEY).await {
+            Ok(head) => head,
+            // Starting over on top of existing batches would fork the chain
+            Err(_) if bucket.head(batch_key(1)).await?.is_some() => {
+                return Err(Error::RustError(
+                    "audit chain head is missing but batches exist".to_string(),
+                ))
+            }
+            Err(_) => ChainHead::genesis(),
+        };
+        *self.head.borrow_mut() = Some(head.clone());
+        Ok(head)
+    }
+
+    async fn write(&self, records: &[AuditEvent]) -> Result<()> {
+        let bucket = self.env.bucket("AUDIT_BUCKET")?;
+        let head = self.head(&bucket).await?;
+        let (bytes, next) = seal(&head, records, Date::now().as_millis() as f64)?;
+        bucket
+            .put(batch_key(next.sequence), bytes)
+            .execute()
+            .await?;
+        // R2 holds the batch now, so the chain moves on even if persisting the head fails
+        *self.head.borrow_mut() = Some(next.clone());
+        self.state.storage().put(HEAD_KEY, &next).await
+    }
+
+    async fn drain(&self) -> Result<()> {
+        loop {
+            let records = std::mem::take(&mut *self.pending.borrow_mut());
+            if records.is_empty() {
+                return Ok(());
+            }
+            if let Err(e) = self.write(&records).await {
+                // Kept for the next append; lost only if the object is evicted first
+                self.pending.borrow_mut().splice(0..0, records);
+                return Err(e);
+            }
+        }
+    }
+}
+
+impl DurableObject for AuditChainObject {
+    fn new(state: State, env: Env) -> Self {
+        Self {
+            state,
+            env,
+            head: RefCell::new(None),
+            pending: RefCell::new(Vec::new()),
+            writing: Cell::new(false),
+        }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        match req.path().as_str() {
+            "/append" => {
+                let records: Vec<AuditEvent> = req.json().await?;
+                self.pending.borrow_mut().extend(records);
+                if self.writing.replace(true) {
+                    return Ok(Response::empty()?.with_status(202));
+                }
+                let drained = self.drain().await;
+                self.writing.set(false);
+                drained?;
+                Ok(Response::empty()?.with_status(204))
+            }
+            "/head" => {
+                let bucket = self.env.bucket("AUDIT_BUCKET")?;
+                Response::from_json(&self.head(&bucket).await?)
+            }
+            _ => Response::error("Not found", 404),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::AuditOutcome;
+
+    fn record(path: &str) -> AuditEvent {
+        AuditEvent {
+            schema_version: AuditEvent::SCHEMA_VERSION,
+            timestamp: 1693123456000.0,
+            host: "login.example.com".to_string(),
+            path: path.to_string(),
+            hashed_ip: "ab".repeat(32),
+            outcome: AuditOutcome::Allow,
+            enforced: true,
+            status: 200,
+            reason: "validated".to_string(),
+            code: "hmac_v1".to_string(),
+            matched_rule: None,
+        }
+    }
+
+    fn chain(batches: usize) -> Vec<(String, Vec<u8>)> {
+        let mut head = ChainHead::genesis();
+        (0..batches)
+            .map(|i| {
+                let (bytes, next) = seal(&head, &[record(&format!("/{}", i))], 0.0).unwrap();
+                head = next;
+                (batch_key(head.sequence), bytes)
+            })
+            .collect()
+    }
+
+    fn verify(batches: &[(String, Vec<u8>)]) -> std::result::Result<Verifier, String> {
+        let mut verifier = Verifier {
+            next_sequence: 1,
+            previous_hash: GENESIS_HASH.to_string(),
+            batches: 0,
+            records: 0,
+        };
+        for (key, bytes) in batches {
+            verifier.check(key, bytes)?;
+        }
+        Ok(verifier)
+    }
+
+    #[test]
+    fn verifies_an_intact_chain() {
+        let batches = chain(3);
+        let verifier = verify(&batches).unwrap();
+        assert_eq!((verifier.batches, verifier.records), (3, 3));
+        assert_eq!(verifier.previous_hash, hash(&batches[2].1));
+    }
+
+    #[test]
+    fn detects_edited_removed_and_reordered_batches() {
+        let mut edited = chain(3);
+        let tampered = String::from_utf8(edited[1].1.clone())
+            .unwrap()
+            .replace("\"/1\"", "\"/x\"");
+        edited[1].1 = tampered.into_bytes();
+        let error = verify(&edited).unwrap_err();
+        assert!(
+            error.contains("previous_hash does not match batch 2"),
+            "{}",
+            error
+        );
+
+        let mut removed = chain(3);
+        removed.remove(1);
+        let error = verify(&removed).unwrap_err();
+        assert!(
+            error.contains("expected batch 2, found batch 3"),
+            "{}",
+            error
+        );
+
+        let mut reordered = chain(3);
+        reordered.swap(0, 1);
+        assert!(verify(&reordered).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use async_trait::async_trait;
+use futures::StreamExt;
+use worker::*;
+
+use crate::errors::Rejection;
+use crate::pipeline::{Flow, RequestContext, Stage};
+use crate::reason::Reason;
+
+// Every protected body is buffered, so even exempt paths need a ceiling well inside the isolate's
+// 128 MB of memory
+const DEFAULT_MAX_EXEMPT_BODY_BYTES: u32 = 32 * 1024 * 1024;
+
+// The cap on a body buffered for the upstream request, and the variable that set it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BodyLimit {
+    pub max_bytes: usize,
+    pub var: &'static str,
+}
+
+// `MAX_BODY_BYTES` caps the bodies buffered for the upstream request. Paths starting with one of
+// the comma-separated `MAX_BODY_EXEMPT_PATHS` prefixes, and every path when `MAX_BODY_BYTES` is
+// unset, are capped by the larger `MAX_EXEMPT_BODY_BYTES` instead.
+pub fn max_body_bytes(env: &Env, path: &str) -> BodyLimit {
+    let max_bytes = env
+        .var("MAX_BODY_BYTES")
+        .ok()
+        .and_then(|v| v.to_string().parse().ok());
+    let exempt = env
+        .var("MAX_BODY_EXEMPT_PATHS")
+        .map(|v| v.to_string())
+        .unwrap_or_default();
+    let exempt_max_bytes =
+        crate::var_or(env, "MAX_EXEMPT_BODY_BYTES", DEFAULT_MAX_EXEMPT_BODY_BYTES) as usize;
+    body_limit(max_bytes, exempt_max_bytes, is_exempt(&exempt, path))
+}
+
+fn body_limit(max_bytes: Option<usize>, exempt_max_bytes: usize, exempt: bool) -> BodyLimit {
+    match max_bytes {
+        Some(max_bytes) if !exempt => BodyLimit {
+            max_bytes,
+            var: "MAX_BODY_BYTES",
+        },
+        _ => BodyLimit {
+            max_bytes: exempt_max_bytes,
+            var: "MAX_EXEMPT_BODY_BYTES",
+        },
+    }
+}
+
+fn is_exempt(exempt_paths: &str, path: &str) -> bool {
+    exempt_paths
+        .split(',')
+        .map(str::trim)
+        .filter(|prefix| !prefix.is_empty())
+        .any(|prefix| path.starts_with(prefix))
+}
+
+fn declared_len(headers: &Headers) -> Option<usize> {
+    headers
+        .get("Content-Length")
+        .ok()
+        .flatten()
+        .and_then(|len| len.parse().ok())
+}
+
+// Reads the body from a clone of the request. `None` means it is over the limit: either the
+// declared length says so up front, or the count passes it while streaming, since a chunked
+// body declares nothing.
+pub async fn read(req: &Request, max_bytes: usize) -> Result<Option<Vec<u8>>> {
+    let mut body_req = req.clone()?;
+    if declared_len(req.headers()).is_some_and(|len| len > max_bytes) {
+        return Ok(None);
+    }
+    if body_req.inner().body().is_none() {
+        return Ok(Some(Vec::new()));
+    }
+
+    let mut stream = body_req.stream()?;
+    let mut body = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > max_bytes {
+            return Ok(None);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Some(body))
+}
+
+// The body is buffered for the upstream request, so oversized ones are refused up front. `HEAD`
+// requests have no body to forward, so nothing is read for them.
+pub struct BodyLimitStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<RequestContext<'a>> for BodyLimitStage {
+    async fn run(&self, cx: &mut RequestContext<'a>) -> Result<Flow> {
+        if cx.req.method() == Method::Head {
+            return Ok(Flow::Continue);
+        }
+        let limit = max_body_bytes(cx.env, cx.url.path());
+        match read(cx.req, limit.max_bytes).await? {
+            Some(body) => {
+                cx.body = body;
+                Ok(Flow::Continue)
+            }
+            None => {
+                console_error!("Request body exceeds {}", limit.var);
+                Ok(Flow::Reject(Rejection::new(
+                    413,
+                    Reason::BodyTooLarge,
+                    "Request body too large",
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exempts_configured_prefixes() {
+        let exempt = "/upload/, /api/files";
+        assert!(is_exempt(exempt, "/upload/video.mp4"));
+        assert!(is_exempt(exempt, "/api/files/1"));
+        assert!(!is_exempt(exempt, "/login"));
+        assert!(!is_exempt("", "/upload/video.mp4"));
+        assert!(!is_exempt(" , ", "/login"));
+    }
+
+    #[test]
+    fn exempt_and_unconfigured_paths_keep_a_ceiling() {
+        assert_eq!(body_limit(Some(1024), 4096, false).max_bytes, 1024);
+        let exempt = body_limit(Some(1024), 4096, true);
+        assert_eq!(
+            (exempt.max_bytes, exempt.var),
+            (4096, "MAX_EXEMPT_BODY_BYTES")
+        );
+        assert_eq!(body_limit(None, 4096, false).max_bytes, 4096);
+    }
+}
@@ -0,0 +1,201 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use worker::*;
+
+use crate::pipeline::{Flow, ResponseContext, Stage};
+
+const CANARY_COOKIE_NAME: &str = "CF_Validator_Backend";
+const BACKEND_HEADER: &str = "X-Validator-Backend";
+const DEFAULT_COOKIE_MAX_AGE_SECONDS: u32 = 24 * 60 * 60;
+
+// Splits a function's validated traffic between origins by weight, e.g.
+// { "backends": [{ "name": "legacy", "weight": 90 },
+//                { "name": "next", "upstream": "https://next.example.com", "weight": 10 }] }
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct CanaryPolicy {
+    pub backends: Vec<Backend>,
+    #[serde(default)]
+    pub sticky: Stickiness,
+    #[serde(default = "default_cookie_max_age")]
+    pub cookie_max_age_seconds: u32,
+}
+
+fn default_cookie_max_age() -> u32 {
+    DEFAULT_COOKIE_MAX_AGE_SECONDS
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Backend {
+    pub name: String,
+    // `None` keeps the function's `upstream`, or the request's own origin
+    pub upstream: Option<String>,
+    pub weight: u32,
+}
+
+// What keeps a client on the backend it was first sent to
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Stickiness {
+    // A hash of the client IP; moves with the client's address
+    #[default]
+    Ip,
+    // A random key in the `CF_Validator_Backend` cookie, handed out on the first request
+    Cookie,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Selection {
+    pub name: String,
+    pub upstream: Option<String>,
+    pub set_cookie: Option<String>,
+}
+
+// A key maps to a fixed point in [0, 1), and backends take consecutive shares of that range in
+// order. The same key keeps its backend while the weights stay the same, and with the new
+// backend listed first, ramping its weight up only ever moves clients onto it.
+fn pick<'a>(backends: &'a [Backend], key: &str) -> Option<&'a Backend> {
+    let total: u64 = backends
+        .iter()
+        .map(|backend| u64::from(backend.weight))
+        .sum();
+    if total == 0 {
+        return None;
+    }
+    let digest = Sha256::digest(key.as_bytes());
+    let mut point = [0u8; 8];
+    point.copy_from_slice(&digest[..8]);
+    let point = ((u128::from(u64::from_be_bytes(point)) * u128::from(total)) >> 64) as u64;
+    let mut upper = 0;
+    backends.iter().find(|backend| {
+        upper += u64::from(backend.weight);
+        point < upper
+    })
+}
+
+fn canary_cookie(headers: &Headers) -> Option<String> {
+    let cookies = headers.get("Cookie").ok()??;
+    cookies.split(';').find_map(|cookie| {
+        let (name, value) = cookie.split_once('=')?;
+        (name.trim() == CANARY_COOKIE_NAME && !value.trim().is_empty())
+            .then(|| value.trim().to_string())
+    })
+}
+
+// Only called once the request validated, so unvalidated traffic always stays on the primary.
+// The sticky key is not signed: a client that picks its own key only picks among the backends
+// it could be sent to anyway.
+pub fn select(
+    canary: &CanaryPolicy,
+    function_id: &str,
+    headers: &Headers,
+    client_ip: &str,
+    cookie_domain: Option<&str>,
+) -> Result<Option<Selection>> {
+    let (key, set_cookie) = match canary.sticky {
+        Stickiness::Ip => (client_ip.to_string(), None),
+        Stickiness::Cookie => match canary_cookie(headers) {
+            Some(key) => (key, None),
+            None => {
+                let mut key_bytes = [0u8; 16];
+                getrandom::getrandom(&mut key_bytes)
+                    .map_err(|e| Error::RustError(e.to_string()))?;
+                let key = hex::encode(key_bytes);
+                let cookie = format!(
+                    "{}={}; Path=/{}; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+                    CANARY_COOKIE_NAME,
+                    key,
+                    crate::cookies::domain_attribute(cookie_domain),
+                    canary.cookie_max_age_seconds
+                );
+                (key, Some(cookie))
+            }
+        },
+    };
+    let Some(backend) = pick(&canary.backends, &format!("{}:{}", function_id, key)) else {
+        return Ok(None);
+    };
+    console_log!(
+        "canary: function_id={} backend={}",
+        function_id,
+        backend.name
+    );
+    Ok(Some(Selection {
+        name: backend.name.clone(),
+        upstream: backend.upstream.clone(),
+        set_cookie,
+    }))
+}
+
+// Names the backend that answered, for debugging
+pub struct CanaryStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<ResponseContext<'a>> for CanaryStage {
+    async fn run(&self, cx: &mut ResponseContext<'a>) -> Result<Flow> {
+        let Some(selection) = cx.backend else {
+            return Ok(Flow::Continue);
+        };
+        cx.headers.set(BACKEND_HEADER, &selection.name)?;
+        if let Some(cookie) = &selection.set_cookie {
+            cx.set_cookies.push(cookie.clone());
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backends(weights: &[(&str, u32)]) -> Vec<Backend> {
+        weights
+            .iter()
+            .map(|(name, weight)| Backend {
+                name: name.to_string(),
+                upstream: None,
+                weight: *weight,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_by_weight_and_sticks() {
+        let split = backends(&[("legacy", 90), ("next", 10)]);
+        let picks: Vec<&str> = (0..1000)
+            .map(|i| {
+                pick(&split, &format!("APPS:192.0.2.{}", i))
+                    .unwrap()
+                    .name
+                    .as_str()
+            })
+            .collect();
+        let next = picks.iter().filter(|name| **name == "next").count();
+        assert!((50..150).contains(&next), "{} of 1000 went to next", next);
+        assert_eq!(
+            pick(&split, "APPS:192.0.2.7"),
+            pick(&split, "APPS:192.0.2.7")
+        );
+
+        assert_eq!(
+            pick(&backends(&[("legacy", 0), ("next", 5)]), "any")
+                .unwrap()
+                .name,
+            "next"
+        );
+        assert!(pick(&backends(&[("legacy", 0)]), "any").is_none());
+        assert!(pick(&[], "any").is_none());
+    }
+
+    #[test]
+    fn ramping_up_the_first_backend_only_moves_clients_onto_it() {
+        let before = backends(&[("next", 10), ("legacy", 90)]);
+        let after = backends(&[("next", 30), ("legacy", 70)]);
+        for i in 0..500 {
+            let key = format!("APPS:198.51.100.{}", i);
+            if pick(&before, &key).unwrap().name == "next" {
+                assert_eq!(pick(&after, &key).unwrap().name, "next");
+            }
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use worker::{
+    console_error, console_log, durable_object, AnalyticsEngineDataPointBuilder, Date, Env, Method,
+    ObjectNamespace, Request, Response, Result, State, Url,
+};
+
+use crate::do_budget::DoBudget;
+
+const DEFAULT_FAILURE_THRESHOLD: u32 = 20;
+const DEFAULT_WINDOW_SECONDS: u32 = 30;
+const DEFAULT_OPEN_SECONDS: u32 = 30;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    #[default]
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn name(self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Thresholds {
+    failures: u32,
+    window_ms: f64,
+    open_ms: f64,
+}
+
+// How the worker may treat one request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Closed,
+    // The single request let through a half-open circuit; its outcome decides the next state
+    Probe,
+    Open,
+}
+
+pub struct CircuitBreaker {
+    namespace: ObjectNamespace,
+    key: String,
+    failure_threshold: u32,
+    window_seconds: u32,
+    open_seconds: u32,
+}
+
+// When the circuit was last seen open or half-open by this isolate: requests are refused without
+// asking the object until `retry_at`
+thread_local! {
+    static LOCAL_CIRCUITS: RefCell<HashMap<String, f64>> = RefCell::new(HashMap::new());
+}
+
+// One breaker per origin host, needs the `CIRCUIT_BREAKER_DO` binding
+pub fn circuit_breaker(env: &Env, upstream: &Url) -> Option<CircuitBreaker> {
+    let namespace = env.durable_object("CIRCUIT_BREAKER_DO").ok()?;
+    Some(CircuitBreaker {
+        namespace,
+        key: upstream.host_str().unwrap_or_default().to_string(),
+        failure_threshold: crate::var_or(
+            env,
+            "CIRCUIT_FAILURE_THRESHOLD",
+            DEFAULT_FAILURE_THRESHOLD,
+        ),
+        window_seconds: crate::var_or(env, "CIRCUIT_WINDOW_SECONDS", DEFAULT_WINDOW_SECONDS),
+        open_seconds: crate::var_or(env, "CIRCUIT_OPEN_SECONDS", DEFAULT_OPEN_SECONDS),
+    })
+}
+
+// Origin failures are what trip the breaker; 4xx answers mean the origin is up
+pub fn is_failure(status: Option<u16>) -> bool {
+    status.is_none_or(|status| status >= 500)
+}
+
+impl CircuitBreaker {
+    // A closed circuit costs no Durable Object call: the object is only asked once a circuit this
+    // isolate saw open is due for a probe. An unreachable object lets the request through.
+    pub async fn admit(&self, budget: &DoBudget<'_>) -> Admission {
+        let now = Date::now().as_millis() as f64;
+        match LOCAL_CIRCUITS.with(|circuits| circuits.borrow().get(&self.key).copied()) {
+            None => return Admission::Closed,
+            Some(retry_at) if now < retry_at => return Admission::Open,
+            Some(_) => {}
+        }
+        match self.call("probe", budget).await {
+            Ok(outcome) if !outcome.admitted => Admission::Open,
+            Ok(outcome) if outcome.state == CircuitState::HalfOpen => Admission::Probe,
+            Ok(_) => Admission::Closed,
+            Err(e) => {
+                console_error!("Circuit breaker unavailable: {}", e);
+                Admission::Closed
+            }
+        }
+    }
+
+    // Failures are always reported; a success only matters when it answers a probe
+    pub async fn record(&self, admission: Admission, failed: bool, budget: &DoBudget<'_>) {
+        if !failed && admission != Admission::Probe {
+            return;
+        }
+        let action = if failed { "failure" } else { "success" };
+        if let Err(e) = self.call(action, budget).await {
+            console_error!("Failed to report {} to circuit breaker: {}", action, e);
+        }
+    }
+
+    async fn call(&self, action: &str, budget: &DoBudget<'_>) -> Result<CircuitOutcome> {
+        let stub = self.namespace.id_from_name(&self.key)?.get_stub()?;
+        let mut url = Url::parse(&format!("https://circuit-breaker/{}", action))?;
+        url.query_pairs_mut()
+            .append_pair("key", &self.key)
+            .append_pair("threshold", &self.failure_threshold.to_string())
+            .append_pair("window", &self.window_seconds.to_string())
+            .append_pair("open", &self.open_seconds.to_string());
+        let request = Request::new(url.as_str(), Method::Post)?;
+        let outcome: CircuitOutcome = budget
+            .fetch("circuit_breaker", &stub, request)
+            .await?
+            .json()
+            .await?;
+
+        LOCAL_CIRCUITS.with(|circuits| {
+            let mut circuits = circuits.borrow_mut();
+            match outcome.state {
+                CircuitState::Closed => circuits.remove(&self.key),
+                _ => circuits.insert(self.key.clone(), outcome.retry_at),
+            }
+        });
+        Ok(outcome)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CircuitOutcome {
+    state: CircuitState,
+    // Whether the request that asked may go to the origin
+    admitted: bool,
+    // When an open circuit next lets a probe through
+    retry_at: f64,
+}
+
+// Failures are counted in fixed windows; reaching the threshold opens the circuit for
+// `open_ms`, after which one probe decides between closing it and another open period
+#[derive(Debug, Default)]
+struct Breaker {
+    state: CircuitState,
+    failures: u32,
+    window_start: f64,
+    // When the circuit opened, or when the current probe was let through
+    since: f64,
+}
+
+type Transition = (CircuitState, CircuitState);
+
+impl Breaker {
+    fn transition(&mut self, to: CircuitState, now: f64) -> Option<Transition> {
+        let from = std::mem::replace(&mut self.state, to);
+        self.since = now;
+        self.failures = 0;
+        self.window_start = now;
+        Some((from, to))
+    }
+
+    fn failure(&mut self, now: f64, thresholds: Thresholds) -> Option<Transition> {
+        match self.state {
+            CircuitState::Closed => {
+                if now - self.window_start >= thresholds.window_ms {
+                    self.window_start = now;
+                    self.failures = 0;
+                }
+                self.failures += 1;
+                if self.failures >= thresholds.failures {
+                    return self.transition(CircuitState::Open, now);
+                }
+                None
+            }
+            CircuitState::HalfOpen => self.transition(CircuitState::Open, now),
+            CircuitState::Open => None,
+        }
+    }
+
+    fn success(&mut self, now: f64) -> Option<Transition> {
+        match self.state {
+            CircuitState::HalfOpen => self.transition(CircuitState::Closed, now),
+            _ => None,
+        }
+    }
+
+    // A probe that never reports back is replaced after another `open_ms`
+    fn probe(&mut self, now: f64, thresholds: Thresholds) -> (bool, Option<Transition>) {
+        let due = now - self.since >= thresholds.open_ms;
+        match self.state {
+            CircuitState::Closed => (true, None),
+            CircuitState::Open if due => (true, self.transition(CircuitState::HalfOpen, now)),
+            CircuitState::HalfOpen if due => {
+                self.since = now;
+                (true, None)
+            }
+            _ => (false, None),
+        }
+    }
+}
+
+// Shared breaker state for one origin host. Transitions are logged and, with a
+// `CIRCUIT_METRICS` Analytics Engine binding, written as data points: index = host,
+// blobs = [from, to], double = 1.
+#[durable_object]
+pub struct CircuitBreakerObject {
+    env: Env,
+    breaker: RefCell<Breaker>,
+}
+
+impl DurableObject for CircuitBreakerObject {
+    fn new(_state: State, env: Env) -> Self {
+        Self {
+            env,
+            breaker: RefCell::new(Breaker::default()),
+        }
+    }
+
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let param = |name: &str| url.query_pairs().find(|(k, _)| k == name).map(|(_, v)| v);
+        let seconds =
+            |name: &str, default: u32| param(name).and_then(|v| v.parse().ok()).unwrap_or(default);
+        let thresholds = Thresholds {
+            failures: seconds("threshold", DEFAULT_FAILURE_THRESHOLD).max(1),
+            window_ms: f64::from(seconds("window", DEFAULT_WINDOW_SECONDS)) * 1000.0,
+            open_ms: f64::from(seconds("open", DEFAULT_OPEN_SECONDS)) * 1000.0,
+        };
+        let key = param("key").unwrap_or_default().to_string();
+
+        let now = Date::now().as_millis() as f64;
+        let mut breaker = self.breaker.borrow_mut();
+        let (admitted, transition) = match url.path() {
+            "/failure" => (true, breaker.failure(now, thresholds)),
+            "/success" => (true, breaker.success(now)),
+            _ => breaker.probe(now, thresholds),
+        };
+        if let Some((from, to)) = transition {
+            self.record_transition(&key, from, to);
+        }
+
+        Response::from_json(&CircuitOutcome {
+            state: breaker.state,
+            admitted,
+            retry_at: breaker.since + thresholds.open_ms,
+        })
+    }
+}
+
+impl CircuitBreakerObject {
+    fn record_transition(&self, key: &str, from: CircuitState, to: CircuitState) {
+        console_log!(
+            "circuit_breaker: origin={} from={} to={}",
+            key,
+            from.name(),
+            to.name()
+        );
+        let Ok(dataset) = self.env.analytics_engine("CIRCUIT_METRICS") else {
+            return;
+        };
+        let point = AnalyticsEngineDataPointBuilder::new()
+            .indexes([key])
+            .add_blob(from.name())
+            .add_blob(to.name())
+            .add_double(1)
+            .build();
+        if let Err(e) = dataset.write_data_point(&point) {
+            console_error!("Failed to write circuit breaker metric: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: Thresholds = Thresholds {
+        failures: 3,
+        window_ms: 10_000.0,
+        open_ms: 30_000.0,
+    };
+
+    #[test]
+    fn opens_after_threshold_failures_in_one_window() {
+        let mut breaker = Breaker::default();
+        assert_eq!(breaker.failure(1_000.0, THRESHOLDS), None);
+        assert_eq!(breaker.failure(2_000.0, THRESHOLDS), None);
+        // A new window starts the count over
+        assert_eq!(breaker.failure(12_000.0, THRESHOLDS), None);
+        assert_eq!(breaker.failure(13_000.0, THRESHOLDS), None);
+        assert_eq!(
+            breaker.failure(14_000.0, THRESHOLDS),
+            Some((CircuitState::Closed, CircuitState::Open))
+        );
+        assert_eq!(breaker.probe(20_000.0, THRESHOLDS), (false, None));
+    }
+
+    #[test]
+    fn one_probe_decides_after_the_open_period() {
+        let mut breaker = Breaker::default();
+        for now in [1.0, 2.0, 3.0] {
+            breaker.failure(now, THRESHOLDS);
+        }
+        assert_eq!(
+            breaker.probe(40_000.0, THRESHOLDS),
+            (true, Some((CircuitState::Open, CircuitState::HalfOpen)))
+        );
+        // Only one probe at a time
+        assert_eq!(breaker.probe(41_000.0, THRESHOLDS), (false, None));
+        assert_eq!(
+            breaker.failure(42_000.0, THRESHOLDS),
+            Some((CircuitState::HalfOpen, CircuitState::Open))
+        );
+
+        assert_eq!(
+            breaker.probe(72_000.0, THRESHOLDS),
+            (true, Some((CircuitState::Open, CircuitState::HalfOpen)))
+        );
+        assert_eq!(
+            breaker.success(73_000.0),
+            Some((CircuitState::HalfOpen, CircuitState::Closed))
+        );
+        assert_eq!(breaker.probe(73_500.0, THRESHOLDS), (true, None));
+    }
+}
@@ -0,0 +1,165 @@
+use std::net::IpAddr;
+
+use serde::Deserialize;
+use worker::{console_error, Env, Headers};
+
+use crate::ip_policy::Cidr;
+
+const FALLBACK_CLIENT_IP: &str = "127.0.0.1";
+const FORWARDED_FOR: &str = "X-Forwarded-For";
+
+// The `CLIENT_IP_SOURCES` JSON var, e.g.
+// { "headers": ["CF-Connecting-IP", "X-Forwarded-For"], "trusted_proxies": ["10.0.0.0/8"] }
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ClientIpSources {
+    // Tried in order; the first header holding a usable address wins
+    pub headers: Vec<String>,
+    // Load balancers whose `X-Forwarded-For` entries are skipped
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for ClientIpSources {
+    fn default() -> Self {
+        Self {
+            headers: vec!["CF-Connecting-IP".to_string(), FORWARDED_FOR.to_string()],
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+pub fn client_ip(env: &Env, headers: &Headers) -> String {
+    let sources: ClientIpSources = crate::object_var(env, "CLIENT_IP_SOURCES").unwrap_or_default();
+    let trusted = trusted_proxies(&sources.trusted_proxies);
+    resolve(&sources.headers, &trusted, |name| {
+        headers.get(name).ok().flatten()
+    })
+    .unwrap_or_else(|| {
+        console_error!("No client IP found in headers, using default");
+        FALLBACK_CLIENT_IP.to_string()
+    })
+}
+
+fn trusted_proxies(cidrs: &[String]) -> Vec<Cidr> {
+    cidrs
+        .iter()
+        .filter_map(|value| {
+            let cidr = Cidr::parse(value);
+            if cidr.is_none() {
+                console_error!("Ignoring invalid trusted proxy {}", value);
+            }
+            cidr
+        })
+        .collect()
+}
+
+fn resolve(
+    header_names: &[String],
+    trusted: &[Cidr],
+    header: impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    header_names.iter().find_map(|name| {
+        let value = header(name)?;
+        let ip = if name.eq_ignore_ascii_case(FORWARDED_FOR) {
+            rightmost_untrusted(&value, trusted)
+        } else {
+            value.trim().parse::<IpAddr>().ok()
+        };
+        ip.map(|ip| ip.to_string())
+    })
+}
+
+// Every hop appends the address it received the request from, so only the entries added by our
+// own proxies can be believed. Reading from the right, the first address that is not one of them
+// is the client; anything further left was written by the client itself.
+fn rightmost_untrusted(forwarded_for: &str, trusted: &[Cidr]) -> Option<IpAddr> {
+    let hops = forwarded_for
+        .split(',')
+        .map(|hop| hop.trim().parse::<IpAddr>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    hops.iter()
+        .rev()
+        .find(|ip| !trusted.iter().any(|cidr| cidr.contains(**ip)))
+        // A chain of nothing but our proxies started at one of them
+        .or(hops.first())
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted() -> Vec<Cidr> {
+        trusted_proxies(&["10.0.0.0/8".to_string(), "2001:db8::/32".to_string()])
+    }
+
+    fn ip(value: &str) -> Option<IpAddr> {
+        value.parse().ok()
+    }
+
+    #[test]
+    fn skips_trusted_proxies_from_the_right() {
+        assert_eq!(
+            rightmost_untrusted("198.51.100.7, 203.0.113.9, 10.0.0.2, 10.1.1.1", &trusted()),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            rightmost_untrusted("203.0.113.9,2001:db8::1", &trusted()),
+            ip("203.0.113.9")
+        );
+        // Without trusted proxies only the last hop is believed
+        assert_eq!(
+            rightmost_untrusted("198.51.100.7, 203.0.113.9", &[]),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            rightmost_untrusted("10.0.0.3, 10.0.0.2", &trusted()),
+            ip("10.0.0.3")
+        );
+        // A forged entry that is not an address makes the whole header unusable
+        assert_eq!(rightmost_untrusted("spoofed, 203.0.113.9", &[]), None);
+    }
+
+    #[test]
+    fn follows_header_precedence() {
+        let sources = ClientIpSources::default();
+        let headers = |cf: Option<&str>, xff: Option<&str>| {
+            let cf = cf.map(str::to_string);
+            let xff = xff.map(str::to_string);
+            move |name: &str| match name {
+                "CF-Connecting-IP" => cf.clone(),
+                "X-Forwarded-For" => xff.clone(),
+                _ => None,
+            }
+        };
+        assert_eq!(
+            resolve(
+                &sources.headers,
+                &[],
+                headers(Some("198.51.100.7"), Some("1.2.3.4"))
+            )
+            .as_deref(),
+            Some("198.51.100.7")
+        );
+        assert_eq!(
+            resolve(
+                &sources.headers,
+                &trusted(),
+                headers(None, Some("1.2.3.4, 203.0.113.9, 10.0.0.1"))
+            )
+            .as_deref(),
+            Some("203.0.113.9")
+        );
+        // Unusable values fall through to the next header
+        assert_eq!(
+            resolve(
+                &sources.headers,
+                &[],
+                headers(Some("unknown"), Some("203.0.113.9"))
+            )
+            .as_deref(),
+            Some("203.0.113.9")
+        );
+        assert_eq!(resolve(&sources.headers, &[], headers(None, None)), None);
+    }
+}
@@ -0,0 +1,322 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use worker::{console_error, Env, Request, Response, Result, Url};
+
+use crate::token::{HashEncoding, HmacAlgorithm, HmacAlgorithms, TimestampUnit};
+
+const HEALTH_PATH: &str = "/__health";
+const DEFAULT_TOKEN_VALIDITY_SECONDS: f64 = 300.0;
+const DEFAULT_NONCE_TOKEN_VALIDITY_SECONDS: f64 = 30.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationMode {
+    Enforce,
+    DryRun,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenRefresh {
+    Off,
+    Header,
+    Cookie,
+    Both,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum WebSocketValidation {
+    Require,
+    Skip,
+}
+
+// The settings every protected request needs. Vars only change with a deployment, which starts
+// new isolates, so they are parsed once per isolate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    pub validation_mode: ValidationMode,
+    pub token_refresh: TokenRefresh,
+    pub token_validity_seconds: f64,
+    pub nonce_token_validity_seconds: f64,
+    // `None` lets refreshed tokens chain forever
+    pub max_session_seconds: Option<f64>,
+    pub websocket_validation_required: bool,
+    pub hmac_algorithm: HmacAlgorithm,
+    // What a token prefix may name; `hmac_algorithm` always, others only through `HMAC_ALLOWED_ALGS`
+    pub hmac_algorithms: HmacAlgorithms,
+    pub timestamp_unit: TimestampUnit,
+    pub hash_encoding: HashEncoding,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            validation_mode: ValidationMode::Enforce,
+            token_refresh: TokenRefresh::Off,
+            token_validity_seconds: DEFAULT_TOKEN_VALIDITY_SECONDS,
+            nonce_token_validity_seconds: DEFAULT_NONCE_TOKEN_VALIDITY_SECONDS,
+            max_session_seconds: None,
+            websocket_validation_required: true,
+            hmac_algorithm: HmacAlgorithm::Sha256,
+            hmac_algorithms: HmacAlgorithms::only(HmacAlgorithm::Sha256),
+            timestamp_unit: TimestampUnit::Auto,
+            hash_encoding: HashEncoding::Auto,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ConfigError {
+    pub var: &'static str,
+    pub value: String,
+    pub message: String,
+}
+
+// An invalid var keeps its default, so one typo does not take logins down, but it is logged
+// and turns the health check red
+#[derive(Debug)]
+pub struct LoadedConfig {
+    pub config: Config,
+    pub errors: Vec<ConfigError>,
+}
+
+struct Loader<F> {
+    var: F,
+    errors: Vec<ConfigError>,
+}
+
+impl<F: Fn(&str) -> Option<String>> Loader<F> {
+    fn read<T>(
+        &mut self,
+        name: &'static str,
+        default: T,
+        parse: impl Fn(&str) -> std::result::Result<T, String>,
+    ) -> T {
+        let Some(value) = (self.var)(name) else {
+            return default;
+        };
+        parse(value.trim()).unwrap_or_else(|message| {
+            self.errors.push(ConfigError {
+                var: name,
+                value,
+                message,
+            });
+            default
+        })
+    }
+
+    // Unit enum variants, with serde's message listing the accepted names
+    fn variant<T: DeserializeOwned>(&mut self, name: &'static str, default: T) -> T {
+        self.read(name, default, |value| {
+            serde_json::from_value(serde_json::Value::String(value.to_ascii_lowercase()))
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    fn seconds(&mut self, name: &'static str, default: f64) -> f64 {
+        self.read(name, default, |value| match value.parse::<f64>() {
+            Ok(seconds) if seconds.is_finite() && seconds > 0.0 => Ok(seconds),
+            _ => Err("expected a positive number of seconds".to_string()),
+        })
+    }
+}
+
+fn named<T>(parsed: Option<T>, expected: &str) -> std::result::Result<T, String> {
+    parsed.ok_or_else(|| format!("expected one of {}", expected))
+}
+
+impl Config {
+    pub fn load(var: impl Fn(&str) -> Option<String>) -> LoadedConfig {
+        let defaults = Config::default();
+        let mut loader = Loader {
+            var,
+            errors: Vec::new(),
+        };
+        let mut config = Config {
+            validation_mode: loader.variant("VALIDATION_MODE", defaults.validation_mode),
+            token_refresh: loader.variant("TOKEN_REFRESH", defaults.token_refresh),
+            token_validity_seconds: loader
+                .seconds("TOKEN_VALIDITY_SECONDS", defaults.token_validity_seconds),
+            nonce_token_validity_seconds: loader.seconds(
+                "NONCE_TOKEN_VALIDITY_SECONDS",
+                defaults.nonce_token_validity_seconds,
+            ),
+            max_session_seconds: loader.read(
+                "TOKEN_REFRESH_MAX_SESSION_SECONDS",
+                defaults.max_session_seconds,
+                |value| match value.parse::<u32>() {
+                    Ok(0) => Ok(None),
+                    Ok(seconds) => Ok(Some(f64::from(seconds))),
+                    Err(_) => {
+                        Err("expected a whole number of seconds, 0 for unlimited".to_string())
+                    }
+                },
+            ),
+            websocket_validation_required: loader
+                .variant("WEBSOCKET_VALIDATION", WebSocketValidation::Require)
+                == WebSocketValidation::Require,
+            hmac_algorithm: loader.read("HMAC_ALG", defaults.hmac_algorithm, |value| {
+                named(HmacAlgorithm::parse(value), "sha256, sha384, sha512")
+            }),
+            hmac_algorithms: defaults.hmac_algorithms,
+            timestamp_unit: loader.read("TIMESTAMP_UNIT", defaults.timestamp_unit, |value| {
+                named(TimestampUnit::parse(value), "auto, seconds, milliseconds")
+            }),
+            hash_encoding: loader.read("TOKEN_HASH_ENCODING", defaults.hash_encoding, |value| {
+                named(HashEncoding::parse(value), "auto, base64, base64url, hex")
+            }),
+        };
+        // Read once the default is known, which the list always includes
+        let default_algorithm = config.hmac_algorithm;
+        config.hmac_algorithms = loader.read(
+            "HMAC_ALLOWED_ALGS",
+            HmacAlgorithms::only(default_algorithm),
+            |value| {
+                named(
+                    HmacAlgorithms::parse(default_algorithm, value),
+                    "a comma-separated list of sha256, sha384, sha512",
+                )
+            },
+        );
+        LoadedConfig {
+            config,
+            errors: loader.errors,
+        }
+    }
+}
+
+thread_local! {
+    static LOADED: RefCell<Option<Rc<LoadedConfig>>> = const { RefCell::new(None) };
+}
+
+pub fn loaded(env: &Env) -> Rc<LoadedConfig> {
+    if let Some(loaded) = LOADED.with(|cached| cached.borrow().clone()) {
+        return loaded;
+    }
+    let loaded = Rc::new(Config::load(|name| {
+        env.var(name).ok().map(|value| value.to_string())
+    }));
+    for error in &loaded.errors {
+        console_error!(
+            "Invalid {} {:?}: {}, using the default",
+            error.var,
+            error.value,
+            error.message
+        );
+    }
+    LOADED.with(|cached| *cached.borrow_mut() = Some(loaded.clone()));
+    loaded
+}
+
+pub fn config(env: &Env) -> Config {
+    loaded(env).config
+}
+
+pub fn is_health_request(url: &Url) -> bool {
+    url.path() == HEALTH_PATH
+}
+
+#[derive(Serialize)]
+struct Health<'a> {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<&'a [ConfigError]>,
+}
+
+// `GET /__health` is `503` while any var is invalid. Which ones, and why, is only told to
+// callers holding `ADMIN_TOKEN`.
+pub fn health(req: &Request, env: &Env) -> Result<Response> {
+    let loaded = loaded(env);
+    // Monitors probe without credentials; only check the token when one is offered
+    let authorized = req.headers().has("Authorization").unwrap_or(false)
+        && crate::admin::is_authorized(req, env).unwrap_or(false);
+    let health = Health {
+        status: if loaded.errors.is_empty() {
+            "ok"
+        } else {
+            "invalid_config"
+        },
+        errors: authorized.then_some(loaded.errors.as_slice()),
+    };
+    let status = if loaded.errors.is_empty() { 200 } else { 503 };
+    Ok(Response::from_json(&health)?.with_status(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn load(vars: &[(&str, &str)]) -> LoadedConfig {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Config::load(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn unset_vars_use_defaults() {
+        let loaded = load(&[]);
+        assert_eq!(loaded.config, Config::default());
+        assert!(loaded.errors.is_empty());
+    }
+
+    #[test]
+    fn parses_valid_vars() {
+        let loaded = load(&[
+            ("VALIDATION_MODE", "dry_run"),
+            ("TOKEN_REFRESH", "Both"),
+            ("TOKEN_VALIDITY_SECONDS", "120.5"),
+            ("TOKEN_REFRESH_MAX_SESSION_SECONDS", "3600"),
+            ("WEBSOCKET_VALIDATION", "skip"),
+            ("HMAC_ALG", "sha512"),
+            ("HMAC_ALLOWED_ALGS", "sha256"),
+            ("TIMESTAMP_UNIT", "ms"),
+        ]);
+        assert!(loaded.errors.is_empty(), "{:?}", loaded.errors);
+        assert_eq!(loaded.config.validation_mode, ValidationMode::DryRun);
+        assert_eq!(loaded.config.token_refresh, TokenRefresh::Both);
+        assert_eq!(loaded.config.token_validity_seconds, 120.5);
+        assert_eq!(loaded.config.max_session_seconds, Some(3600.0));
+        assert!(!loaded.config.websocket_validation_required);
+        assert_eq!(loaded.config.hmac_algorithm, HmacAlgorithm::Sha512);
+        assert_eq!(
+            loaded.config.hmac_algorithms,
+            HmacAlgorithms::only(HmacAlgorithm::Sha512).with(HmacAlgorithm::Sha256)
+        );
+        assert_eq!(loaded.config.timestamp_unit, TimestampUnit::Milliseconds);
+    }
+
+    #[test]
+    fn reports_every_invalid_var_and_keeps_its_default() {
+        let loaded = load(&[
+            ("VALIDATION_MODE", "dryrun"),
+            ("TOKEN_VALIDITY_SECONDS", "5m"),
+            ("NONCE_TOKEN_VALIDITY_SECONDS", "-1"),
+            ("HMAC_ALG", "md5"),
+        ]);
+        assert_eq!(loaded.config, Config::default());
+        let vars: Vec<_> = loaded.errors.iter().map(|error| error.var).collect();
+        assert_eq!(
+            vars,
+            vec![
+                "VALIDATION_MODE",
+                "TOKEN_VALIDITY_SECONDS",
+                "NONCE_TOKEN_VALIDITY_SECONDS",
+                "HMAC_ALG"
+            ]
+        );
+        assert!(loaded.errors[0].message.contains("`enforce`"));
+        assert_eq!(loaded.errors[1].value, "5m");
+        assert_eq!(
+            loaded.errors[3].message,
+            "expected one of sha256, sha384, sha512"
+        );
+    }
+}
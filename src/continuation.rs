@@ -0,0 +1,231 @@
+use async_trait::async_trait;
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use url::Url;
+use worker::*;
+
+use crate::pipeline::{Flow, ResponseContext, Stage};
+use crate::token::constant_time_compare;
+
+const CONTINUATION_COOKIE_NAME: &str = "CF_Validator_Continue";
+const DEFAULT_CONTINUATION_TTL_SECONDS: u32 = 30;
+
+// `CONTINUATION_MODE=on` lets the hops of a redirect flow that drop the token ride on the check
+// of the first one. Returns how long a continuation lasts.
+pub fn continuation_ttl_seconds(env: &Env) -> Option<u32> {
+    if env.var("CONTINUATION_MODE").ok()?.to_string() != "on" {
+        return None;
+    }
+    Some(crate::var_or(
+        env,
+        "CONTINUATION_TTL_SECONDS",
+        DEFAULT_CONTINUATION_TTL_SECONDS,
+    ))
+}
+
+// What a continuation was issued for: the `token_digest` of the token whose check minted it, so
+// revoking that token also ends the continuation
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Continued {
+    pub token_digest: Option<String>,
+}
+
+// Who a continuation is for: one client, on one host and function
+#[derive(Clone, Copy, Debug)]
+pub struct Binding<'a> {
+    pub client_ip: &'a str,
+    pub host: &'a str,
+    pub function_id: &'a str,
+}
+
+// `Lax` rather than `Strict`, so the cookie survives redirect chains that began on another site
+pub fn issue(
+    secret: &str,
+    binding: Binding,
+    token_digest: Option<&str>,
+    now_seconds: f64,
+    ttl_seconds: u32,
+    cookie_domain: Option<&str>,
+) -> String {
+    let expires_at = (now_seconds as u64 + u64::from(ttl_seconds)).to_string();
+    let token_digest = token_digest.unwrap_or_default();
+    format!(
+        "{}={}.{}.{}; Path=/{}; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        CONTINUATION_COOKIE_NAME,
+        expires_at,
+        token_digest,
+        sign(secret, binding, token_digest, &expires_at),
+        crate::cookies::domain_attribute(cookie_domain),
+        ttl_seconds
+    )
+}
+
+// The continuation when the request carries an unexpired one issued to this client for this function
+pub fn resume(headers: &Headers, secret: &str, binding: Binding) -> Option<Continued> {
+    continuation_cookie(headers).and_then(|value| {
+        verified(
+            secret,
+            &value,
+            binding,
+            Date::now().as_millis() as f64 / 1000.0,
+        )
+    })
+}
+
+fn continuation_cookie(headers: &Headers) -> Option<String> {
+    let cookies = headers.get("Cookie").ok()??;
+    cookies.split(';').find_map(|cookie| {
+        let (name, value) = cookie.split_once('=')?;
+        (name.trim() == CONTINUATION_COOKIE_NAME).then(|| value.trim().to_string())
+    })
+}
+
+// Cookies are `{expires_at}.{token_digest}.{signature}`, the digest empty when the check that
+// minted it had no token. Both are signed, so the expiry cannot be pushed back nor the digest
+// swapped for one that is not revoked.
+fn verified(secret: &str, value: &str, binding: Binding, now_seconds: f64) -> Option<Continued> {
+    let mut parts = value.splitn(3, '.');
+    let (expires_at, token_digest, signature) = (parts.next()?, parts.next()?, parts.next()?);
+    let valid = expires_at
+        .parse::<u64>()
+        .is_ok_and(|expiry| now_seconds < expiry as f64)
+        && constant_time_compare(
+            sign(secret, binding, token_digest, expires_at).as_bytes(),
+            signature.as_bytes(),
+        );
+    valid.then(|| Continued {
+        token_digest: (!token_digest.is_empty()).then(|| token_digest.to_string()),
+    })
+}
+
+fn sign(secret: &str, binding: Binding, token_digest: &str, expires_at: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(
+        format!(
+            "continuation:{}:{}:{}:{}:{}",
+            binding.client_ip, binding.host, binding.function_id, token_digest, expires_at
+        )
+        .as_bytes(),
+    );
+    BASE64_URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+// Redirects back to this host keep the function id, so the next hop resolves the same policy and
+// the continuation issued for it applies. `None` leaves the header as the origin sent it.
+pub fn rewrite_location(location: &str, request_url: &Url, function_id: &str) -> Option<String> {
+    if function_id.is_empty() {
+        return None;
+    }
+    let mut target = request_url.join(location).ok()?;
+    if target.host_str() != request_url.host_str()
+        || target.query_pairs().any(|(name, _)| name == "function_id")
+    {
+        return None;
+    }
+    target
+        .query_pairs_mut()
+        .append_pair("function_id", function_id);
+    Some(target.to_string())
+}
+
+// Hands out the continuation minted by this request and rewrites the redirects of every request
+// that passed, including those that passed on a continuation
+pub struct ContinuationStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<ResponseContext<'a>> for ContinuationStage {
+    async fn run(&self, cx: &mut ResponseContext<'a>) -> Result<Flow> {
+        let Ok(verified) = cx.verdict else {
+            return Ok(Flow::Continue);
+        };
+        if continuation_ttl_seconds(cx.env).is_none() {
+            return Ok(Flow::Continue);
+        }
+        if let Some(cookie) = &verified.continuation_cookie {
+            cx.set_cookies.push(cookie.clone());
+        }
+        if (300..400).contains(&cx.status) {
+            if let Some(location) = cx
+                .headers
+                .get("Location")?
+                .and_then(|location| rewrite_location(&location, cx.url, cx.function_id))
+            {
+                cx.headers.set("Location", &location)?;
+            }
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continuations_expire_and_stay_with_their_client() {
+        let binding = |client_ip, function_id| Binding {
+            client_ip,
+            host: "login.example.com",
+            function_id,
+        };
+        let cookie = issue(
+            "secret",
+            binding("192.0.2.1", "F"),
+            Some("ab12"),
+            1000.0,
+            30,
+            None,
+        );
+        let value = cookie
+            .strip_prefix("CF_Validator_Continue=")
+            .and_then(|rest| rest.split_once(';'))
+            .map(|(value, _)| value)
+            .unwrap();
+        assert!(cookie.ends_with("; Path=/; Max-Age=30; HttpOnly; Secure; SameSite=Lax"));
+        let valid = |ip, function_id, now| {
+            verified("secret", value, binding(ip, function_id), now).is_some()
+        };
+        assert!(valid("192.0.2.1", "F", 1029.0));
+        assert!(!valid("192.0.2.1", "F", 1030.0));
+        assert!(!valid("192.0.2.2", "F", 1000.0));
+        assert!(!valid("192.0.2.1", "G", 1000.0));
+
+        assert_eq!(
+            verified("secret", value, binding("192.0.2.1", "F"), 1000.0),
+            Some(Continued {
+                token_digest: Some("ab12".to_string())
+            })
+        );
+
+        // Pushing the signed expiry back, or swapping the token digest, breaks the signature
+        let (_, signature) = value.rsplit_once('.').unwrap();
+        for forged in [
+            format!("9999999999.ab12.{}", signature),
+            format!("1030..{}", signature),
+            format!("1030.cd34.{}", signature),
+        ] {
+            assert!(verified("secret", &forged, binding("192.0.2.1", "F"), 1000.0).is_none());
+        }
+    }
+
+    #[test]
+    fn keeps_the_function_id_on_same_host_redirects() {
+        let url = Url::parse("https://login.example.com/start?function_id=F&oait=a++b").unwrap();
+        assert_eq!(
+            rewrite_location("/next?step=2", &url, "F").as_deref(),
+            Some("https://login.example.com/next?step=2&function_id=F")
+        );
+        assert_eq!(
+            rewrite_location("https://login.example.com/done", &url, "F").as_deref(),
+            Some("https://login.example.com/done?function_id=F")
+        );
+        assert_eq!(rewrite_location("/next?function_id=G", &url, "F"), None);
+        assert_eq!(
+            rewrite_location("https://apps.example.com/", &url, "F"),
+            None
+        );
+        assert_eq!(rewrite_location("/next", &url, ""), None);
+    }
+}
@@ -0,0 +1,250 @@
+use async_trait::async_trait;
+use worker::*;
+
+use crate::pipeline::{Flow, ResponseContext, Stage};
+use crate::tenant::TenantConfig;
+
+pub const ACCESS_COOKIE_NAME: &str = "CF_Authorization";
+
+// Decides what happens when the origin sets its own `CF_Authorization` cookie
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CookiePrecedence {
+    PreferOrigin,
+    PreferWorker,
+    // Both are kept; the worker's cookie is scoped to the request path so it wins there only
+    Merge,
+}
+
+impl CookiePrecedence {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "prefer_origin" => Some(Self::PreferOrigin),
+            "prefer_worker" => Some(Self::PreferWorker),
+            "merge" => Some(Self::Merge),
+            _ => None,
+        }
+    }
+}
+
+pub fn cookie_precedence(env: &Env) -> CookiePrecedence {
+    match env.var("COOKIE_PRECEDENCE") {
+        Ok(value) => CookiePrecedence::parse(&value.to_string()).unwrap_or_else(|| {
+            console_error!("Invalid COOKIE_PRECEDENCE {}, preferring worker", value);
+            CookiePrecedence::PreferWorker
+        }),
+        Err(_) => CookiePrecedence::PreferWorker,
+    }
+}
+
+// Cookies stay host-only unless a domain is configured, e.g. `example.com` to share them with
+// every subdomain
+pub fn cookie_domain(env: &Env, tenant: &TenantConfig) -> Option<String> {
+    tenant
+        .cookie_domain
+        .clone()
+        .or_else(|| env.var("COOKIE_DOMAIN").ok().map(|v| v.to_string()))
+        .filter(|domain| !domain.is_empty())
+}
+
+pub fn domain_attribute(domain: Option<&str>) -> String {
+    domain
+        .map(|domain| format!("; Domain={}", domain))
+        .unwrap_or_default()
+}
+
+// Combines the origin's `Set-Cookie` values with the worker's access cookie. Origin cookies
+// with other names always pass through unchanged.
+pub fn merge_access_cookie(
+    origin_cookies: &[String],
+    domain: Option<&str>,
+    access_token: &str,
+    request_path: &str,
+    precedence: CookiePrecedence,
+) -> Vec<String> {
+    let origin_sets_access = origin_sets_access_cookie(origin_cookies);
+
+    let path = match precedence {
+        CookiePrecedence::Merge if origin_sets_access => request_path,
+        _ => "/",
+    };
+    let worker_cookie = format!(
+        "{}={}; Path={}{}; HttpOnly; Secure; SameSite=Strict",
+        ACCESS_COOKIE_NAME,
+        access_token,
+        path,
+        domain_attribute(domain)
+    );
+
+    let mut merged: Vec<String> = origin_cookies
+        .iter()
+        .filter(|cookie| precedence != CookiePrecedence::PreferWorker || !is_access_cookie(cookie))
+        .cloned()
+        .collect();
+    if !(origin_sets_access && precedence == CookiePrecedence::PreferOrigin) {
+        merged.push(worker_cookie);
+    }
+    merged
+}
+
+pub fn origin_sets_access_cookie(origin_cookies: &[String]) -> bool {
+    origin_cookies.iter().any(|cookie| is_access_cookie(cookie))
+}
+
+fn is_access_cookie(set_cookie: &str) -> bool {
+    set_cookie
+        .split_once('=')
+        .is_some_and(|(name, _)| name.trim() == ACCESS_COOKIE_NAME)
+}
+
+// Never set for requests that only passed because of dry-run
+pub struct AccessCookieStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<ResponseContext<'a>> for AccessCookieStage {
+    async fn run(&self, cx: &mut ResponseContext<'a>) -> Result<Flow> {
+        if cx.verdict.is_err() || !cx.policy.set_auth_cookie || cx.access_token.is_empty() {
+            return Ok(Flow::Continue);
+        }
+        let precedence = cookie_precedence(cx.env);
+        if origin_sets_access_cookie(&cx.set_cookies) {
+            console_log!(
+                "origin also sets {}, applying {:?}",
+                ACCESS_COOKIE_NAME,
+                precedence
+            );
+        }
+        cx.set_cookies = merge_access_cookie(
+            &cx.set_cookies,
+            cx.cookie_domain.as_deref(),
+            cx.access_token,
+            cx.url.path(),
+            precedence,
+        );
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin(cookies: &[&str]) -> Vec<String> {
+        cookies.iter().map(|cookie| cookie.to_string()).collect()
+    }
+
+    #[test]
+    fn adds_worker_cookie_when_origin_sets_none() {
+        for precedence in [
+            CookiePrecedence::PreferOrigin,
+            CookiePrecedence::PreferWorker,
+            CookiePrecedence::Merge,
+        ] {
+            let merged = merge_access_cookie(
+                &origin(&["session=abc; Path=/"]),
+                None,
+                "jwt",
+                "/app/login",
+                precedence,
+            );
+            assert_eq!(
+                merged,
+                vec![
+                    "session=abc; Path=/".to_string(),
+                    "CF_Authorization=jwt; Path=/; HttpOnly; Secure; SameSite=Strict".to_string(),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn prefer_origin_keeps_origin_cookie_only() {
+        let merged = merge_access_cookie(
+            &origin(&["CF_Authorization=origin; Path=/", "session=abc"]),
+            None,
+            "jwt",
+            "/app/login",
+            CookiePrecedence::PreferOrigin,
+        );
+        assert_eq!(
+            merged,
+            origin(&["CF_Authorization=origin; Path=/", "session=abc"])
+        );
+    }
+
+    #[test]
+    fn prefer_worker_replaces_origin_cookie() {
+        let merged = merge_access_cookie(
+            &origin(&["CF_Authorization=origin; Path=/", "session=abc"]),
+            None,
+            "jwt",
+            "/app/login",
+            CookiePrecedence::PreferWorker,
+        );
+        assert_eq!(
+            merged,
+            vec![
+                "session=abc".to_string(),
+                "CF_Authorization=jwt; Path=/; HttpOnly; Secure; SameSite=Strict".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_scopes_worker_cookie_to_request_path() {
+        let merged = merge_access_cookie(
+            &origin(&["CF_Authorization=origin; Path=/"]),
+            None,
+            "jwt",
+            "/app/login",
+            CookiePrecedence::Merge,
+        );
+        assert_eq!(
+            merged,
+            vec![
+                "CF_Authorization=origin; Path=/".to_string(),
+                "CF_Authorization=jwt; Path=/app/login; HttpOnly; Secure; SameSite=Strict"
+                    .to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn scopes_worker_cookie_to_configured_domain() {
+        let merged = merge_access_cookie(
+            &[],
+            Some("example.com"),
+            "jwt",
+            "/app/login",
+            CookiePrecedence::PreferWorker,
+        );
+        assert_eq!(
+            merged,
+            vec![
+                "CF_Authorization=jwt; Path=/; Domain=example.com; HttpOnly; Secure; SameSite=Strict"
+                    .to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_cookie_name_exactly() {
+        assert!(is_access_cookie("CF_Authorization=x; Path=/"));
+        assert!(is_access_cookie(" CF_Authorization =x"));
+        assert!(!is_access_cookie("CF_Authorization_legacy=x"));
+        assert!(!is_access_cookie("cf_authorization=x"));
+        assert!(!is_access_cookie("session=CF_Authorization=x"));
+    }
+
+    #[test]
+    fn parses_precedence_names() {
+        assert_eq!(
+            CookiePrecedence::parse("prefer_origin"),
+            Some(CookiePrecedence::PreferOrigin)
+        );
+        assert_eq!(
+            CookiePrecedence::parse(" MERGE "),
+            Some(CookiePrecedence::Merge)
+        );
+        assert_eq!(CookiePrecedence::parse("origin"), None);
+    }
+}
@@ -0,0 +1,215 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use worker::{console_log, Env, Headers, Method, Response, Result};
+
+use crate::pipeline::{Flow, RequestContext, ResponseContext, Stage};
+
+// Settings from the `CORS_POLICY` JSON var, e.g.
+// { "allowed_origins": ["https://app.example.com"], "allowed_headers": ["Content-Type"] }
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CorsPolicy {
+    // Exact origins, or `*` for any when credentials are not allowed
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub max_age_seconds: u32,
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: Vec::new(),
+            max_age_seconds: 600,
+            allow_credentials: false,
+        }
+    }
+}
+
+pub fn cors_policy(env: &Env) -> Option<CorsPolicy> {
+    crate::object_var(env, "CORS_POLICY")
+}
+
+pub fn is_preflight(method: &Method, headers: &Headers) -> bool {
+    *method == Method::Options
+        && headers.has("Origin").unwrap_or(false)
+        && headers
+            .has("Access-Control-Request-Method")
+            .unwrap_or(false)
+}
+
+// The value for `Access-Control-Allow-Origin`. Browsers refuse `*` on credentialed requests, so
+// with credentials allowed the wildcard echoes the origin instead.
+fn allowed_origin(policy: &CorsPolicy, origin: &str) -> Option<String> {
+    if policy
+        .allowed_origins
+        .iter()
+        .any(|allowed| allowed == origin)
+    {
+        return Some(origin.to_string());
+    }
+    if policy.allowed_origins.iter().any(|allowed| allowed == "*") {
+        return Some(if policy.allow_credentials {
+            origin.to_string()
+        } else {
+            "*".to_string()
+        });
+    }
+    None
+}
+
+fn allows_method(policy: &CorsPolicy, method: &str) -> bool {
+    policy
+        .allowed_methods
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(method))
+}
+
+// Every requested header must be listed; names compare case-insensitively
+fn allows_headers(policy: &CorsPolicy, requested: &str) -> bool {
+    requested
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .all(|name| {
+            policy
+                .allowed_headers
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(name))
+        })
+}
+
+fn origin_headers(headers: &Headers, policy: &CorsPolicy, allow_origin: &str) -> Result<()> {
+    headers.set("Access-Control-Allow-Origin", allow_origin)?;
+    if policy.allow_credentials {
+        headers.set("Access-Control-Allow-Credentials", "true")?;
+    }
+    if allow_origin != "*" {
+        headers.append("Vary", "Origin")?;
+    }
+    Ok(())
+}
+
+// The answer to a preflight. A disallowed origin, method or header gets no CORS headers at all,
+// which browsers treat as a refusal.
+fn preflight_response(policy: &CorsPolicy, request_headers: &Headers) -> Result<Response> {
+    let header = |name: &str| request_headers.get(name).ok().flatten().unwrap_or_default();
+    let origin = header("Origin");
+    let method = header("Access-Control-Request-Method");
+    let requested_headers = header("Access-Control-Request-Headers");
+
+    let headers = Headers::new();
+    match allowed_origin(policy, &origin) {
+        Some(allow_origin)
+            if allows_method(policy, &method) && allows_headers(policy, &requested_headers) =>
+        {
+            origin_headers(&headers, policy, &allow_origin)?;
+            headers.set(
+                "Access-Control-Allow-Methods",
+                &policy.allowed_methods.join(", "),
+            )?;
+            if !policy.allowed_headers.is_empty() {
+                headers.set(
+                    "Access-Control-Allow-Headers",
+                    &policy.allowed_headers.join(", "),
+                )?;
+            }
+            headers.set(
+                "Access-Control-Max-Age",
+                &policy.max_age_seconds.to_string(),
+            )?;
+        }
+        _ => console_log!(
+            "cors: refused preflight origin={} method={}",
+            origin,
+            method
+        ),
+    }
+    Ok(Response::empty()?.with_status(204).with_headers(headers))
+}
+
+// Preflights carry no token, so they are answered at the edge before any check runs. Without
+// `CORS_POLICY` they go to the origin to answer.
+pub struct PreflightStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<RequestContext<'a>> for PreflightStage {
+    async fn run(&self, cx: &mut RequestContext<'a>) -> Result<Flow> {
+        if !is_preflight(&cx.req.method(), cx.req.headers()) {
+            return Ok(Flow::Continue);
+        }
+        match cors_policy(cx.env) {
+            Some(policy) => Ok(Flow::Respond(preflight_response(
+                &policy,
+                cx.req.headers(),
+            )?)),
+            None => Ok(Flow::Bypass),
+        }
+    }
+}
+
+// Lets the page read the origin's answer to a validated cross-origin request
+pub struct CorsStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<ResponseContext<'a>> for CorsStage {
+    async fn run(&self, cx: &mut ResponseContext<'a>) -> Result<Flow> {
+        let (Some(origin), Some(policy)) = (cx.origin, cors_policy(cx.env)) else {
+            return Ok(Flow::Continue);
+        };
+        if let Some(allow_origin) = allowed_origin(&policy, origin) {
+            origin_headers(&cx.headers, &policy, &allow_origin)?;
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &[&str], allow_credentials: bool) -> CorsPolicy {
+        CorsPolicy {
+            allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            allowed_headers: vec!["Content-Type".to_string(), "X-CSRF-Token".to_string()],
+            allow_credentials,
+            ..CorsPolicy::default()
+        }
+    }
+
+    #[test]
+    fn matches_origins_exactly_or_by_wildcard() {
+        let listed = policy(&["https://app.example.com"], false);
+        assert_eq!(
+            allowed_origin(&listed, "https://app.example.com").as_deref(),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            allowed_origin(&listed, "https://app.example.com.evil"),
+            None
+        );
+
+        assert_eq!(
+            allowed_origin(&policy(&["*"], false), "https://any.example").as_deref(),
+            Some("*")
+        );
+        // Browsers refuse `*` with credentials, so the origin is echoed instead
+        assert_eq!(
+            allowed_origin(&policy(&["*"], true), "https://any.example").as_deref(),
+            Some("https://any.example")
+        );
+    }
+
+    #[test]
+    fn checks_requested_methods_and_headers() {
+        let policy = policy(&["*"], false);
+        assert!(allows_method(&policy, "post"));
+        assert!(!allows_method(&policy, "DELETE"));
+        assert!(allows_headers(&policy, "content-type, x-csrf-token"));
+        assert!(allows_headers(&policy, ""));
+        assert!(!allows_headers(&policy, "Content-Type, Authorization"));
+    }
+}
@@ -0,0 +1,151 @@
+use async_trait::async_trait;
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use worker::*;
+
+use crate::errors::Rejection;
+use crate::pipeline::{Flow, RequestContext, ResponseContext, Stage};
+use crate::reason::Reason;
+use crate::token::constant_time_compare;
+
+const CSRF_COOKIE_NAME: &str = "CF_Validator_CSRF";
+const CSRF_HEADER: &str = "X-CSRF-Token";
+const CSRF_FIELD: &str = "csrf_token";
+
+// `CSRF_PROTECTION=on` adds a double-submit check to the protected functions: safe requests get
+// a signed `CF_Validator_CSRF` cookie, and state-changing ones must echo its value in the
+// `X-CSRF-Token` header or a `csrf_token` form field
+pub fn csrf_enabled(env: &Env) -> bool {
+    env.var("CSRF_PROTECTION")
+        .is_ok_and(|v| v.to_string() == "on")
+}
+
+pub fn is_safe_method(method: &Method) -> bool {
+    matches!(method, Method::Get | Method::Head | Method::Options)
+}
+
+// A fresh `Set-Cookie` value; not `HttpOnly`, since the page has to read it to submit it back
+pub fn issue(secret: &str, cookie_domain: Option<&str>) -> Result<String> {
+    let mut nonce_bytes = [0u8; 16];
+    getrandom::getrandom(&mut nonce_bytes).map_err(|e| Error::RustError(e.to_string()))?;
+    let nonce = BASE64_URL_SAFE_NO_PAD.encode(nonce_bytes);
+    Ok(format!(
+        "{}={}.{}; Path=/{}; Secure; SameSite=Strict",
+        CSRF_COOKIE_NAME,
+        nonce,
+        sign_nonce(secret, &nonce),
+        crate::cookies::domain_attribute(cookie_domain)
+    ))
+}
+
+// Whether the request already carries a cookie this worker signed, so no new one is needed
+pub fn has_valid_cookie(secret: &str, headers: &Headers) -> bool {
+    csrf_cookie(headers).is_some_and(|value| is_signed(secret, &value))
+}
+
+// The submitted token must equal the cookie, and the cookie must carry our signature, so a
+// cookie planted from a sibling subdomain does not pass either
+pub fn verify(secret: &str, headers: &Headers, body: &[u8]) -> bool {
+    let Some(cookie) = csrf_cookie(headers) else {
+        return false;
+    };
+    let submitted = headers.get(CSRF_HEADER).ok().flatten().or_else(|| {
+        let content_type = headers.get("Content-Type").ok()??;
+        crate::form_token::field(&content_type, body, CSRF_FIELD)
+    });
+    submitted.is_some_and(|submitted| matches(secret, &cookie, &submitted))
+}
+
+fn matches(secret: &str, cookie: &str, submitted: &str) -> bool {
+    constant_time_compare(cookie.as_bytes(), submitted.trim().as_bytes())
+        && is_signed(secret, cookie)
+}
+
+fn csrf_cookie(headers: &Headers) -> Option<String> {
+    let cookies = headers.get("Cookie").ok()??;
+    cookies.split(';').find_map(|cookie| {
+        let (name, value) = cookie.split_once('=')?;
+        (name.trim() == CSRF_COOKIE_NAME).then(|| value.trim().to_string())
+    })
+}
+
+// Tokens are `{nonce}.{signature}`
+fn is_signed(secret: &str, value: &str) -> bool {
+    value.split_once('.').is_some_and(|(nonce, signature)| {
+        constant_time_compare(sign_nonce(secret, nonce).as_bytes(), signature.as_bytes())
+    })
+}
+
+fn sign_nonce(secret: &str, nonce: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(format!("csrf:{}", nonce).as_bytes());
+    BASE64_URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+// Double-submit check for state-changing requests; safe ones are handed the cookie instead
+pub struct CsrfStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<RequestContext<'a>> for CsrfStage {
+    async fn run(&self, cx: &mut RequestContext<'a>) -> Result<Flow> {
+        if !csrf_enabled(cx.env) {
+            return Ok(Flow::Continue);
+        }
+        if is_safe_method(&cx.req.method()) {
+            if !has_valid_cookie(cx.secret, cx.req.headers()) {
+                let cookie_domain = crate::cookies::cookie_domain(cx.env, cx.tenant);
+                cx.csrf_cookie = issue(cx.secret, cookie_domain.as_deref())
+                    .map_err(|e| console_error!("Failed to issue CSRF cookie: {}", e))
+                    .ok();
+            }
+            return Ok(Flow::Continue);
+        }
+        if verify(cx.secret, cx.req.headers(), &cx.body) {
+            return Ok(Flow::Continue);
+        }
+        if crate::validation_mode(cx.env) == crate::ValidationMode::DryRun {
+            console_error!("dry-run: would reject with 403: CSRF token mismatch");
+            return Ok(Flow::Continue);
+        }
+        console_error!("CSRF token missing or mismatched");
+        Ok(Flow::Reject(Rejection::new(
+            403,
+            Reason::CsrfMismatch,
+            "CSRF token mismatch",
+        )))
+    }
+}
+
+pub struct CsrfCookieStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<ResponseContext<'a>> for CsrfCookieStage {
+    async fn run(&self, cx: &mut ResponseContext<'a>) -> Result<Flow> {
+        if let Some(cookie) = cx.csrf_cookie {
+            cx.set_cookies.push(cookie.to_string());
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn submitted_token_must_match_a_signed_cookie() {
+        let token = format!("abc.{}", sign_nonce("secret", "abc"));
+        // Forgeries keep the length of a real signature
+        let forged = format!("abc.{}", "A".repeat(43));
+        assert!(matches("secret", &token, &token));
+        assert!(matches("secret", &token, &format!(" {} ", token)));
+        assert!(!matches("secret", &token, &forged));
+        assert!(!matches("other-secret", &token, &token));
+
+        // Matching but unsigned values, e.g. a cookie set by another subdomain
+        assert!(!matches("secret", &forged, &forged));
+        assert!(!is_signed("secret", "abc"));
+    }
+}
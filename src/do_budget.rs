@@ -0,0 +1,123 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use worker::*;
+
+// The most one request can ask for: a rate limiter check, a session touch followed by a new
+// session when the touch fails, an idempotency claim and its stored response, and the circuit
+// breaker's admission and outcome
+const DEFAULT_DO_CALL_BUDGET: u32 = 7;
+
+// Caps the Durable Object calls one request may make, so stacking stateful features cannot
+// multiply latency or billing. Each call is counted and timed; when the request finishes the
+// totals are logged, and the calls made and skipped per feature are added to the metrics.
+pub struct DoBudget<'a> {
+    ctx: &'a Context,
+    env: &'a Env,
+    tally: RefCell<Tally>,
+    elapsed_ms: Cell<f64>,
+}
+
+// Calls made and skipped, per feature
+#[derive(Debug, Default)]
+struct Tally {
+    limit: u32,
+    features: BTreeMap<&'static str, (u32, u32)>,
+}
+
+impl Tally {
+    // Counts the call either way; `false` once the budget is spent
+    fn admit(&mut self, feature: &'static str) -> bool {
+        let admitted = self.made() < self.limit;
+        let (made, skipped) = self.features.entry(feature).or_default();
+        if admitted {
+            *made += 1;
+        } else {
+            *skipped += 1;
+        }
+        admitted
+    }
+
+    fn made(&self) -> u32 {
+        self.features.values().map(|(made, _)| made).sum()
+    }
+
+    fn skipped(&self) -> u32 {
+        self.features.values().map(|(_, skipped)| skipped).sum()
+    }
+}
+
+impl<'a> DoBudget<'a> {
+    pub fn new(ctx: &'a Context, env: &'a Env) -> Self {
+        Self {
+            ctx,
+            env,
+            tally: RefCell::new(Tally {
+                limit: crate::var_or(env, "DO_CALL_BUDGET", DEFAULT_DO_CALL_BUDGET),
+                features: BTreeMap::new(),
+            }),
+            elapsed_ms: Cell::new(0.0),
+        }
+    }
+
+    // Callers treat an exhausted budget like any other Durable Object failure
+    pub async fn fetch(
+        &self,
+        feature: &'static str,
+        stub: &Stub,
+        req: Request,
+    ) -> Result<Response> {
+        if !self.tally.borrow_mut().admit(feature) {
+            console_error!(
+                "Durable Object call budget of {} exhausted, skipping {}",
+                self.tally.borrow().limit,
+                feature
+            );
+            return Err(Error::RustError(
+                "Durable Object call budget exhausted".to_string(),
+            ));
+        }
+
+        let started = Date::now().as_millis();
+        let response = stub.fetch_with_request(req).await;
+        self.elapsed_ms
+            .set(self.elapsed_ms.get() + (Date::now().as_millis() - started) as f64);
+        response
+    }
+}
+
+impl Drop for DoBudget<'_> {
+    fn drop(&mut self) {
+        let tally = self.tally.borrow();
+        if tally.features.is_empty() {
+            return;
+        }
+        console_log!(
+            "durable objects: calls={} skipped={} elapsed_ms={}",
+            tally.made(),
+            tally.skipped(),
+            self.elapsed_ms.get()
+        );
+        crate::metrics::record_do_calls(self.ctx, self.env, &tally.features);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_over_the_budget_are_skipped_and_counted() {
+        let mut tally = Tally {
+            limit: 2,
+            ..Tally::default()
+        };
+        assert!(tally.admit("rate_limiter"));
+        assert!(tally.admit("sessions"));
+        assert!(!tally.admit("sessions"));
+        assert!(!tally.admit("idempotency"));
+        assert_eq!((tally.made(), tally.skipped()), (2, 2));
+        assert_eq!(tally.features["sessions"], (1, 1));
+        assert_eq!(tally.features["idempotency"], (0, 1));
+    }
+}
@@ -0,0 +1,168 @@
+use wasm_bindgen::JsValue;
+use worker::worker_sys::web_sys;
+use worker::{EncodeBody, Fetch, Headers, Request, Response, Result};
+
+// `fetch` decodes gzip and brotli bodies but keeps the origin's `Content-Encoding` and
+// `Content-Length`, and the runtime compresses an outgoing body to match its `Content-Encoding`.
+// A rebuilt response therefore went through a decode and an encode, with a `Content-Length`
+// that no longer matched the bytes sent. Origin bodies now stream through exactly as the origin
+// encoded them, unless a response stage has to read them.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyMode {
+    // The origin's bytes, compressed or not, stay untouched
+    Passthrough,
+    // The runtime decodes the body for the response stages; the result is compressed again
+    Decoded,
+}
+
+// The codings the runtime can compress a response with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Coding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Coding {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "identity" => Some(Coding::Identity),
+            "gzip" | "x-gzip" => Some(Coding::Gzip),
+            "br" => Some(Coding::Brotli),
+            _ => None,
+        }
+    }
+
+    fn header_value(self) -> Option<&'static str> {
+        match self {
+            Coding::Identity => None,
+            Coding::Gzip => Some("gzip"),
+            Coding::Brotli => Some("br"),
+        }
+    }
+}
+
+// Highest `q` wins, brotli on a tie; `*` stands for any coding not named. Identity is the
+// fallback even where the client refuses it, since an uncompressed body is always readable.
+pub fn negotiate(accept_encoding: &str) -> Coding {
+    let mut named = Vec::new();
+    let mut any = None;
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let Some(q) = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+        else {
+            continue;
+        };
+        if name == "*" {
+            any = Some(q);
+        } else if let Some(coding) = Coding::parse(name) {
+            named.push((coding, q));
+        }
+    }
+    let weight = |coding: Coding| {
+        named
+            .iter()
+            .find(|(named, _)| *named == coding)
+            .map(|(_, q)| *q)
+            .or(any)
+            .unwrap_or(0.0)
+    };
+    let (brotli, gzip) = (weight(Coding::Brotli), weight(Coding::Gzip));
+    if brotli > 0.0 && brotli >= gzip {
+        Coding::Brotli
+    } else if gzip > 0.0 {
+        Coding::Gzip
+    } else {
+        Coding::Identity
+    }
+}
+
+fn is_encoded(headers: &Headers) -> bool {
+    headers
+        .get("Content-Encoding")
+        .ok()
+        .flatten()
+        .is_some_and(|coding| !coding.trim().eq_ignore_ascii_case("identity"))
+}
+
+// A passthrough request's response arrives as the origin sent it
+pub fn prepare(req: Request, mode: BodyMode) -> Result<Request> {
+    if mode == BodyMode::Decoded {
+        return Ok(req);
+    }
+    let init = web_sys::RequestInit::new();
+    js_sys::Reflect::set(
+        &init,
+        &JsValue::from_str("encodeResponseBody"),
+        &JsValue::from_str("manual"),
+    )?;
+    Ok(web_sys::Request::new_with_request_and_init(req.inner(), &init)?.into())
+}
+
+// Marks a passthrough body as already encoded, so it is not compressed a second time
+pub fn received(response: Response, mode: BodyMode) -> Response {
+    if mode == BodyMode::Passthrough && is_encoded(response.headers()) {
+        response.with_encode_body(EncodeBody::Manual)
+    } else {
+        response
+    }
+}
+
+// For requests forwarded without any checks
+pub async fn send(req: Request) -> Result<Response> {
+    let response = Fetch::Request(prepare(req, BodyMode::Passthrough)?)
+        .send()
+        .await?;
+    Ok(received(response, BodyMode::Passthrough))
+}
+
+// Fetched headers are immutable, so changing them means a new response around the same body,
+// which keeps going out the way it came in
+pub fn rebuild(response: &Response, headers: Headers, status: u16) -> Result<Response> {
+    Ok(Response::from_body(response.body().clone())?
+        .with_headers(headers)
+        .with_status(status)
+        .with_encode_body(*response.encode_body()))
+}
+
+// A body the response stages read or rewrote. An origin-compressed body is compressed again
+// with the best coding the client accepts, by the runtime, which also sets the length.
+pub fn encoded(
+    body: Vec<u8>,
+    headers: Headers,
+    status: u16,
+    request_headers: &Headers,
+) -> Result<Response> {
+    headers.delete("Content-Length")?;
+    if is_encoded(&headers) {
+        let accept_encoding = request_headers.get("Accept-Encoding")?.unwrap_or_default();
+        match negotiate(&accept_encoding).header_value() {
+            Some(coding) => headers.set("Content-Encoding", coding)?,
+            None => headers.delete("Content-Encoding")?,
+        }
+    }
+    Ok(Response::from_bytes(body)?
+        .with_headers(headers)
+        .with_status(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_by_weight() {
+        assert_eq!(negotiate("gzip, deflate, br"), Coding::Brotli);
+        assert_eq!(negotiate("gzip;q=1.0, br;q=0.8"), Coding::Gzip);
+        assert_eq!(negotiate("br;q=0, gzip"), Coding::Gzip);
+        assert_eq!(negotiate("*;q=0.5, br;q=0"), Coding::Gzip);
+        assert_eq!(negotiate("deflate"), Coding::Identity);
+        assert_eq!(negotiate("gzip;q=0, br;q=0"), Coding::Identity);
+        assert_eq!(negotiate(""), Coding::Identity);
+        assert_eq!(negotiate("X-GZIP"), Coding::Gzip);
+    }
+}
@@ -0,0 +1,123 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::prelude::*;
+use serde::Deserialize;
+
+pub const PREFIX: &str = "aes256gcm";
+const NONCE_LEN: usize = 12;
+
+// `aes256gcm[.{kid}]:{base64url(nonce || ciphertext || tag)}`, which replaces the cloudflare token
+#[derive(Debug, PartialEq)]
+pub struct EncryptedToken<'a> {
+    pub kid: Option<&'a str>,
+    pub sealed: &'a str,
+}
+
+// The decrypted payload, e.g. { "ip": "192.0.2.1", "ts": 1693123456, "access_token": "..." }
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct EncryptedPayload {
+    pub ip: String,
+    pub ts: f64,
+    #[serde(default)]
+    pub access_token: String,
+}
+
+pub fn parse(token: &str) -> Option<EncryptedToken<'_>> {
+    let (prefix, sealed) = token.split_once(':')?;
+    let kid = match prefix.split_once('.') {
+        Some((PREFIX, kid)) if !kid.is_empty() => Some(kid),
+        None if prefix == PREFIX => None,
+        _ => return None,
+    };
+    Some(EncryptedToken { kid, sealed })
+}
+
+// Keys are 32 bytes of standard base64
+pub fn parse_key(encoded: &str) -> Option<[u8; 32]> {
+    BASE64_STANDARD.decode(encoded.trim()).ok()?.try_into().ok()
+}
+
+// `base64url(nonce || ciphertext || tag)`; fails on a wrong key or any modification of the bytes
+pub fn decrypt(key: &[u8; 32], sealed: &str) -> Option<Vec<u8>> {
+    let sealed = BASE64_URL_SAFE_NO_PAD
+        .decode(sealed.trim().trim_end_matches('='))
+        .ok()?;
+    if sealed.len() <= NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .ok()
+}
+
+// Fails like `decrypt`, or on a payload that is not valid JSON
+pub fn open(key: &[u8; 32], sealed: &str) -> Option<EncryptedPayload> {
+    serde_json::from_slice(&decrypt(key, sealed)?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn seal(key: &[u8; 32], nonce: [u8; NONCE_LEN], plaintext: &[u8]) -> String {
+        let ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .unwrap();
+        BASE64_URL_SAFE_NO_PAD.encode([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    #[test]
+    fn parses_prefix_and_key_id() {
+        assert_eq!(
+            parse("aes256gcm.2:abc"),
+            Some(EncryptedToken {
+                kid: Some("2"),
+                sealed: "abc"
+            })
+        );
+        assert_eq!(
+            parse("aes256gcm:abc"),
+            Some(EncryptedToken {
+                kid: None,
+                sealed: "abc"
+            })
+        );
+        assert_eq!(parse("aes256gcm.:abc"), None);
+        assert_eq!(parse("sha256:1693123456-abc"), None);
+    }
+
+    #[test]
+    fn opens_sealed_payloads() {
+        let sealed = seal(
+            &KEY,
+            [1; NONCE_LEN],
+            br#"{"ip":"192.0.2.1","ts":1693123456,"access_token":"secret-access"}"#,
+        );
+        assert_eq!(
+            open(&KEY, &sealed),
+            Some(EncryptedPayload {
+                ip: "192.0.2.1".to_string(),
+                ts: 1693123456.0,
+                access_token: "secret-access".to_string(),
+            })
+        );
+        assert_eq!(open(&[8; 32], &sealed), None);
+    }
+
+    #[test]
+    fn rejects_tampered_payloads() {
+        let sealed = seal(
+            &KEY,
+            [1; NONCE_LEN],
+            br#"{"ip":"192.0.2.1","ts":1693123456}"#,
+        );
+        let mut bytes = BASE64_URL_SAFE_NO_PAD.decode(&sealed).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert_eq!(open(&KEY, &BASE64_URL_SAFE_NO_PAD.encode(bytes)), None);
+        assert_eq!(open(&KEY, "c2hvcnQ"), None);
+    }
+}
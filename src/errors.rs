@@ -0,0 +1,331 @@
+use serde::Serialize;
+use worker::{console_error, Env, Headers, Response, Result, Url};
+
+use crate::reason::Reason;
+
+// Why a protected request was (or in dry-run would have been) refused
+#[derive(Clone, Debug)]
+pub struct Rejection {
+    pub status: u16,
+    pub code: Reason,
+    pub message: &'static str,
+}
+
+impl Rejection {
+    pub fn new(status: u16, code: Reason, message: &'static str) -> Self {
+        Self {
+            status,
+            code,
+            message,
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self.status {
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            422 => "Unprocessable Content",
+            429 => "Too Many Requests",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            _ => "Internal Server Error",
+        }
+    }
+
+    // Browsers that prefer HTML get the configured redirect or HTML template, everyone else
+    // problem details
+    pub async fn into_response(self, request_headers: &Headers, env: &Env) -> Result<Response> {
+        let accept = request_headers.get("Accept")?.unwrap_or_default();
+        if !prefers_html(&accept) {
+            return self.json_response(request_headers, env);
+        }
+        if let Some(redirect) = self.redirect_response(env)? {
+            return Ok(redirect);
+        }
+        self.html_response(env).await
+    }
+
+    pub fn problem_type(&self) -> ProblemType {
+        ProblemType::of(self.code)
+    }
+
+    // RFC 9457 problem details. Without `ERROR_TYPE_BASE_URL` the type is `about:blank` and the
+    // title the status phrase; with it each reason code gets its own type URI under its category.
+    pub fn json_response(&self, request_headers: &Headers, env: &Env) -> Result<Response> {
+        let base = env
+            .var("ERROR_TYPE_BASE_URL")
+            .ok()
+            .map(|v| v.to_string())
+            .filter(|base| !base.is_empty());
+        let instance = request_headers
+            .get("CF-Ray")?
+            .filter(|ray| !ray.is_empty())
+            .map(|ray| format!("urn:cf-ray:{}", ray));
+        let problem = self.problem_details(base.as_deref(), instance);
+        let headers = Headers::new();
+        headers.set("Content-Type", "application/problem+json")?;
+        Ok(Response::from_json(&problem)?
+            .with_headers(headers)
+            .with_status(self.status))
+    }
+
+    fn problem_details(&self, base: Option<&str>, instance: Option<String>) -> ProblemDetails {
+        let (problem_type, title) = match base {
+            Some(base) => (
+                format!(
+                    "{}/{}/{}",
+                    base.trim_end_matches('/'),
+                    self.problem_type().slug(),
+                    self.code.code()
+                ),
+                self.problem_type().title(),
+            ),
+            None => ("about:blank".to_string(), self.title()),
+        };
+        ProblemDetails {
+            problem_type,
+            title,
+            status: self.status,
+            detail: self.message,
+            instance,
+            code: self.code.code(),
+        }
+    }
+
+    // `ERROR_REDIRECT_URL` receives the failure as `status`, `reason` and `code` query parameters
+    fn redirect_response(&self, env: &Env) -> Result<Option<Response>> {
+        let Ok(target) = env.var("ERROR_REDIRECT_URL") else {
+            return Ok(None);
+        };
+        let mut url = match Url::parse(&target.to_string()) {
+            Ok(url) => url,
+            Err(e) => {
+                console_error!("Invalid ERROR_REDIRECT_URL: {}", e);
+                return Ok(None);
+            }
+        };
+        url.query_pairs_mut()
+            .append_pair("status", &self.status.to_string())
+            .append_pair("reason", self.message)
+            .append_pair("code", self.code.code());
+        Ok(Some(Response::redirect(url)?))
+    }
+
+    // Templates live in the `ERROR_TEMPLATES` KV namespace under the status code or `default`,
+    // with `{{status}}`, `{{title}}` and `{{message}}` placeholders
+    async fn html_response(&self, env: &Env) -> Result<Response> {
+        let template = match env.kv("ERROR_TEMPLATES") {
+            Ok(kv) => {
+                let by_status = kv.get(&self.status.to_string()).cache_ttl(300).text();
+                match by_status.await {
+                    Ok(Some(template)) => Some(template),
+                    Ok(None) => kv
+                        .get("default")
+                        .cache_ttl(300)
+                        .text()
+                        .await
+                        .unwrap_or_else(|e| {
+                            console_error!("Failed to read error template: {}", e);
+                            None
+                        }),
+                    Err(e) => {
+                        console_error!("Failed to read error template: {}", e);
+                        None
+                    }
+                }
+            }
+            Err(_) => None,
+        };
+
+        let html = match template {
+            Some(template) => template
+                .replace("{{status}}", &self.status.to_string())
+                .replace("{{title}}", self.title())
+                .replace("{{message}}", self.message),
+            None => self.message.to_string(),
+        };
+        Ok(Response::from_html(html)?.with_status(self.status))
+    }
+}
+
+// The error type hierarchy: every reason code belongs to one category, which is part of its
+// problem type URI
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProblemType {
+    // The credentials were missing or did not verify
+    Authentication,
+    // The credentials verified, or were not needed, but the request is not allowed
+    Authorization,
+    InvalidRequest,
+    RateLimited,
+    // The worker or something it relies on could not decide or serve the request
+    Unavailable,
+    Internal,
+}
+
+impl ProblemType {
+    pub fn of(reason: Reason) -> Self {
+        match reason {
+            Reason::MissingToken
+            | Reason::MalformedToken
+            | Reason::InvalidToken
+            | Reason::ProtectedParamInvalid
+            | Reason::TokenRevoked
+            | Reason::AccessJwtInvalid
+            | Reason::TurnstileFailed
+            | Reason::WebhookSignatureInvalid => ProblemType::Authentication,
+            Reason::UnknownFunction
+            | Reason::NetworkDenied
+            | Reason::GeoDenied
+            | Reason::RiskDenied
+            | Reason::CsrfMismatch
+            | Reason::OriginDenied => ProblemType::Authorization,
+            Reason::BodyTooLarge
+            | Reason::IdempotencyKeyInvalid
+            | Reason::IdempotencyInFlight
+            | Reason::IdempotencyKeyReused => ProblemType::InvalidRequest,
+            Reason::RateLimited => ProblemType::RateLimited,
+            Reason::Maintenance
+            | Reason::RiskUnavailable
+            | Reason::VerificationUnavailable
+            | Reason::OriginUnavailable
+            | Reason::OriginUnreachable
+            | Reason::OriginError => ProblemType::Unavailable,
+            Reason::InternalError => ProblemType::Internal,
+            // Passes never reach an error response
+            Reason::HmacV1
+            | Reason::Ed25519V1
+            | Reason::RequestSignatureV1
+            | Reason::EncryptedTokenV1
+            | Reason::AccessJwtV1
+            | Reason::SessionV1
+            | Reason::ContinuationV1 => ProblemType::Internal,
+        }
+    }
+
+    pub fn slug(self) -> &'static str {
+        match self {
+            ProblemType::Authentication => "authentication",
+            ProblemType::Authorization => "authorization",
+            ProblemType::InvalidRequest => "invalid_request",
+            ProblemType::RateLimited => "rate_limited",
+            ProblemType::Unavailable => "unavailable",
+            ProblemType::Internal => "internal",
+        }
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            ProblemType::Authentication => "Authentication failed",
+            ProblemType::Authorization => "Request not allowed",
+            ProblemType::InvalidRequest => "Invalid request",
+            ProblemType::RateLimited => "Rate limit exceeded",
+            ProblemType::Unavailable => "Service unavailable",
+            ProblemType::Internal => "Internal error",
+        }
+    }
+}
+
+// HTML only when the client ranks `text/html` above every JSON type. Browsers do; API clients,
+// `*/*` and requests without `Accept` get problem details.
+fn prefers_html(accept: &str) -> bool {
+    let html = quality(accept, "text/html");
+    html > 0.0
+        && html > quality(accept, "application/problem+json")
+        && html > quality(accept, "application/json")
+}
+
+// The q-value the most specific matching media range gives `media_type`
+fn quality(accept: &str, media_type: &str) -> f32 {
+    let (kind, _) = media_type.split_once('/').unwrap_or((media_type, ""));
+    accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let range = parts.next()?.trim().to_ascii_lowercase();
+            let specificity = if range == media_type {
+                2
+            } else if range.strip_suffix("/*") == Some(kind) {
+                1
+            } else if range == "*/*" {
+                0
+            } else {
+                return None;
+            };
+            let q = parts
+                .find_map(|param| {
+                    let (name, value) = param.split_once('=')?;
+                    (name.trim() == "q").then(|| value.trim().parse::<f32>().ok())?
+                })
+                .unwrap_or(1.0);
+            Some((specificity, q))
+        })
+        .max_by_key(|&(specificity, _)| specificity)
+        .map_or(0.0, |(_, q)| q)
+}
+
+#[derive(Serialize)]
+struct ProblemDetails {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: &'static str,
+    status: u16,
+    detail: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    code: &'static str,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_html_only_for_clients_that_prefer_it() {
+        let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        assert!(prefers_html(browser));
+        assert!(prefers_html("text/*"));
+        assert!(!prefers_html(""));
+        assert!(!prefers_html("*/*"));
+        assert!(!prefers_html("application/json"));
+        assert!(!prefers_html("text/html;q=0.5, application/problem+json"));
+        assert!(!prefers_html("text/html;q=0, */*"));
+        assert!(!prefers_html("text/html, application/json"));
+        assert_eq!(quality("Text/HTML ; q=0.3", "text/html"), 0.3);
+    }
+
+    #[test]
+    fn types_problems_by_category_and_code() {
+        let rejection = Rejection::new(403, Reason::GeoDenied, "Request not allowed");
+        let problem = rejection.problem_details(
+            Some("https://errors.example.com/"),
+            Some("urn:cf-ray:8a1b2c3d4e5f6a7b-SJC".to_string()),
+        );
+        assert_eq!(
+            serde_json::to_value(&problem).unwrap(),
+            serde_json::json!({
+                "type": "https://errors.example.com/authorization/geo_denied",
+                "title": "Request not allowed",
+                "status": 403,
+                "detail": "Request not allowed",
+                "instance": "urn:cf-ray:8a1b2c3d4e5f6a7b-SJC",
+                "code": "geo_denied",
+            })
+        );
+
+        let problem =
+            Rejection::new(401, Reason::MissingToken, "Missing token").problem_details(None, None);
+        let value = serde_json::to_value(&problem).unwrap();
+        assert_eq!(value["type"], "about:blank");
+        assert_eq!(value["title"], "Unauthorized");
+        assert!(value.get("instance").is_none());
+        assert_eq!(
+            ProblemType::of(Reason::OriginUnreachable),
+            ProblemType::Unavailable
+        );
+    }
+}
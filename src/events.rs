@@ -0,0 +1,244 @@
+use serde::{Deserialize, Serialize};
+
+// Every emitted event serializes as a flat JSON object tagged with `event` and carrying its own
+// `schema_version`. Within a version, fields may only be added (with serde defaults) so existing
+// consumers keep parsing; renames, removals and type changes require bumping the version.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    TokenValidated(ValidationEvent),
+    AuditDecision(AuditEvent),
+    AdminAction(AdminActionEvent),
+    ConfigChanged(ConfigChangeEvent),
+}
+
+// Sent to third-party endpoints, so the client IP is a keyed hash unless `FANOUT_RAW_CLIENT_IP`
+// opts in to the raw address as well. Version 2 replaced version 1's raw `client_ip`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ValidationEvent {
+    pub schema_version: u32,
+    pub host: String,
+    pub path: String,
+    pub hashed_ip: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
+    pub strategy: String,
+    pub has_access_token: bool,
+    pub validated_at: f64,
+}
+
+impl ValidationEvent {
+    pub const SCHEMA_VERSION: u32 = 2;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Allow,
+    Deny,
+}
+
+// One per allow/deny decision; the client IP is only ever recorded as a keyed hash
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub schema_version: u32,
+    pub timestamp: f64,
+    pub host: String,
+    pub path: String,
+    pub hashed_ip: String,
+    pub outcome: AuditOutcome,
+    // False for dry-run decisions, which never block the request
+    pub enforced: bool,
+    // Rejection status for denials, 200 for allows
+    pub status: u16,
+    pub reason: String,
+    // Stable code for `reason`, see `reason::Reason`
+    #[serde(default)]
+    pub code: String,
+    // Tenant key of the `ROUTES` rule that matched, if any
+    pub matched_rule: Option<String>,
+}
+
+impl AuditEvent {
+    pub const SCHEMA_VERSION: u32 = 1;
+}
+
+// One per state-changing admin API call, e.g. `revoke` with target `kid:2`. Targets never carry
+// a credential or a raw client IP.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AdminActionEvent {
+    pub schema_version: u32,
+    pub timestamp: f64,
+    pub action: String,
+    pub target: String,
+    // The admin client, hashed like the clients of audit decisions
+    pub hashed_ip: String,
+}
+
+impl AdminActionEvent {
+    pub const SCHEMA_VERSION: u32 = 1;
+}
+
+// One per accepted change to runtime configuration, identified by the `ETag`s on either side
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConfigChangeEvent {
+    pub schema_version: u32,
+    pub timestamp: f64,
+    // What changed; `rules` for the `/admin/rules` document
+    pub source: String,
+    pub previous_etag: String,
+    pub etag: String,
+    pub hashed_ip: String,
+    // The document replaced, in the audit entries kept next to the rules for rolling back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<String>,
+}
+
+impl ConfigChangeEvent {
+    pub const SCHEMA_VERSION: u32 = 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validation_event() -> Event {
+        Event::TokenValidated(ValidationEvent {
+            schema_version: ValidationEvent::SCHEMA_VERSION,
+            host: "login.example.com".to_string(),
+            path: "/login".to_string(),
+            hashed_ip: "5d41402abc4b2a76".to_string(),
+            client_ip: None,
+            strategy: "hmac-sha256".to_string(),
+            has_access_token: true,
+            validated_at: 1693123456000.0,
+        })
+    }
+
+    #[test]
+    fn validation_event_v2_wire_format() {
+        let json = serde_json::to_value(validation_event()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "event": "token_validated",
+                "schema_version": 2,
+                "host": "login.example.com",
+                "path": "/login",
+                "hashed_ip": "5d41402abc4b2a76",
+                "strategy": "hmac-sha256",
+                "has_access_token": true,
+                "validated_at": 1693123456000.0
+            })
+        );
+
+        // The raw address only goes out when the deployment opted in
+        let Event::TokenValidated(mut raw) = validation_event() else {
+            unreachable!()
+        };
+        raw.client_ip = Some("192.0.2.1".to_string());
+        let json = serde_json::to_value(Event::TokenValidated(raw)).unwrap();
+        assert_eq!(json["client_ip"], "192.0.2.1");
+    }
+
+    #[test]
+    fn audit_event_v1_wire_format() {
+        let event = Event::AuditDecision(AuditEvent {
+            schema_version: AuditEvent::SCHEMA_VERSION,
+            timestamp: 1693123456000.0,
+            host: "login.example.com".to_string(),
+            path: "/login".to_string(),
+            hashed_ip: "5d41402abc4b2a76".to_string(),
+            outcome: AuditOutcome::Deny,
+            enforced: true,
+            status: 403,
+            reason: "Token revoked".to_string(),
+            code: "token_revoked".to_string(),
+            matched_rule: None,
+        });
+        assert_eq!(
+            serde_json::to_value(event).unwrap(),
+            serde_json::json!({
+                "event": "audit_decision",
+                "schema_version": 1,
+                "timestamp": 1693123456000.0,
+                "host": "login.example.com",
+                "path": "/login",
+                "hashed_ip": "5d41402abc4b2a76",
+                "outcome": "deny",
+                "enforced": true,
+                "status": 403,
+                "reason": "Token revoked",
+                "code": "token_revoked",
+                "matched_rule": null
+            })
+        );
+    }
+
+    #[test]
+    fn admin_events_v1_round_trip() {
+        let action = Event::AdminAction(AdminActionEvent {
+            schema_version: AdminActionEvent::SCHEMA_VERSION,
+            timestamp: 1693123456000.0,
+            action: "revoke".to_string(),
+            target: "kid:2".to_string(),
+            hashed_ip: "5d41402abc4b2a76".to_string(),
+        });
+        let json = serde_json::to_value(&action).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "event": "admin_action",
+                "schema_version": 1,
+                "timestamp": 1693123456000.0,
+                "action": "revoke",
+                "target": "kid:2",
+                "hashed_ip": "5d41402abc4b2a76"
+            })
+        );
+        assert_eq!(serde_json::from_value::<Event>(json).unwrap(), action);
+
+        let change = Event::ConfigChanged(ConfigChangeEvent {
+            schema_version: ConfigChangeEvent::SCHEMA_VERSION,
+            timestamp: 1693123456000.0,
+            source: "rules".to_string(),
+            previous_etag: "\"abc\"".to_string(),
+            etag: "\"def\"".to_string(),
+            hashed_ip: "5d41402abc4b2a76".to_string(),
+            previous: None,
+        });
+        let json = serde_json::to_value(&change).unwrap();
+        assert_eq!(json["event"], "config_changed");
+        assert_eq!(json["schema_version"], 1);
+        assert!(json.get("previous").is_none());
+        assert_eq!(serde_json::from_value::<Event>(json).unwrap(), change);
+
+        let Event::ConfigChanged(mut stored) = change else {
+            unreachable!()
+        };
+        stored.previous = Some("{}".to_string());
+        let stored = Event::ConfigChanged(stored);
+        let json = serde_json::to_value(&stored).unwrap();
+        assert_eq!(json["previous"], "{}");
+        assert_eq!(serde_json::from_value::<Event>(json).unwrap(), stored);
+    }
+
+    #[test]
+    fn validation_event_ignores_unknown_fields() {
+        let json = r#"{
+            "event": "token_validated",
+            "schema_version": 2,
+            "host": "login.example.com",
+            "path": "/login",
+            "hashed_ip": "5d41402abc4b2a76",
+            "strategy": "hmac-sha256",
+            "has_access_token": true,
+            "validated_at": 1693123456000.0,
+            "added_later": "ignored"
+        }"#;
+        assert_eq!(
+            serde_json::from_str::<Event>(json).unwrap(),
+            validation_event()
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::pin::pin;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::future::{select, Either};
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::errors::Rejection;
+use crate::pipeline::{Flow, RequestContext, Stage};
+use crate::reason::Reason;
+
+const DEFAULT_TIMEOUT_MS: u32 = 1_000;
+
+// Settings from the `EXTERNAL_VERIFIER` JSON var, e.g.
+// { "url": "https://risk.example.com/v1/check", "timeout_ms": 500, "on_failure": "closed" }
+#[derive(Clone, Debug, Deserialize)]
+pub struct VerifierSettings {
+    pub url: String,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u32,
+    #[serde(default)]
+    pub on_failure: FailureMode,
+    // Only these functions are checked; empty checks every request with a function_id
+    #[serde(default)]
+    pub function_ids: Vec<String>,
+}
+
+fn default_timeout_ms() -> u32 {
+    DEFAULT_TIMEOUT_MS
+}
+
+// What happens when the verifier times out, errors or answers something unreadable
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureMode {
+    // Carry on as if it had allowed the request
+    #[default]
+    Open,
+    // Refuse the request with `503`
+    Closed,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskDecision {
+    Allow,
+    Deny,
+    // Validation is required even where the request would otherwise bypass it
+    StepUp,
+}
+
+#[derive(Deserialize)]
+struct RiskVerdict {
+    decision: RiskDecision,
+}
+
+// What the verifier learns about the request; the client IP only as the audit log's keyed hash
+#[derive(Serialize)]
+struct RiskSummary<'a> {
+    host: &'a str,
+    path: &'a str,
+    function_id: &'a str,
+    hashed_ip: String,
+    country: Option<String>,
+    asn: Option<u32>,
+    // Lets the verifier refuse replayed summaries
+    timestamp: f64,
+}
+
+pub fn verifier_settings(env: &Env) -> Option<VerifierSettings> {
+    crate::object_var(env, "EXTERNAL_VERIFIER")
+}
+
+fn applies_to(settings: &VerifierSettings, function_id: &str) -> bool {
+    settings.function_ids.is_empty() || settings.function_ids.iter().any(|id| id == function_id)
+}
+
+// `None` for a failed check, which `on_failure` settles
+fn settle(decision: Option<RiskDecision>, on_failure: FailureMode) -> Option<RiskDecision> {
+    match (decision, on_failure) {
+        (Some(decision), _) => Some(decision),
+        (None, FailureMode::Open) => Some(RiskDecision::Allow),
+        (None, FailureMode::Closed) => None,
+    }
+}
+
+// The summary is signed like fan-out notifications: `X-Validator-Signature: sha256=<base64>`,
+// an HMAC-SHA256 of the body under `EXTERNAL_VERIFIER_SECRET`
+async fn check(settings: &VerifierSettings, secret: &str, body: &str) -> Result<RiskDecision> {
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    headers.set(
+        "X-Validator-Signature",
+        &format!("sha256={}", crate::fanout::sign(secret, body)),
+    )?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(body.into()));
+    let fetch = Fetch::Request(Request::new_with_init(&settings.url, &init)?);
+
+    let controller = AbortController::default();
+    let signal = controller.signal();
+    let sending = pin!(fetch.send_with_signal(&signal));
+    let timeout = Delay::from(Duration::from_millis(u64::from(settings.timeout_ms)));
+    let mut response = match select(sending, timeout).await {
+        Either::Left((response, _)) => response?,
+        Either::Right(_) => {
+            controller.abort();
+            return Err(Error::RustError(format!(
+                "no answer within {} ms",
+                settings.timeout_ms
+            )));
+        }
+    };
+    if response.status_code() != 200 {
+        return Err(Error::RustError(format!(
+            "status {}",
+            response.status_code()
+        )));
+    }
+    Ok(response.json::<RiskVerdict>().await?.decision)
+}
+
+// Runs after the edge policies and before the function policy, so a step-up answer can still
+// turn a bypass into a validated request
+pub struct ExternalVerifierStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<RequestContext<'a>> for ExternalVerifierStage {
+    async fn run(&self, cx: &mut RequestContext<'a>) -> Result<Flow> {
+        let (Some(settings), Some(function_id)) =
+            (verifier_settings(cx.env), cx.function_id.as_deref())
+        else {
+            return Ok(Flow::Continue);
+        };
+        if !applies_to(&settings, function_id) {
+            return Ok(Flow::Continue);
+        }
+
+        let signals = crate::geo_policy::client_signals(cx.req);
+        let body = serde_json::to_string(&RiskSummary {
+            host: cx.url.host_str().unwrap_or_default(),
+            path: cx.url.path(),
+            function_id,
+            hashed_ip: crate::audit::hashed_ip(cx.env, &cx.client_ip),
+            country: signals.country,
+            asn: signals.asn,
+            timestamp: Date::now().as_millis() as f64,
+        })?;
+        let secret = cx
+            .env
+            .secret("EXTERNAL_VERIFIER_SECRET")
+            .map(|v| v.to_string())
+            .unwrap_or_else(|_| cx.secret.to_string());
+        let decision = match check(&settings, &secret, &body).await {
+            Ok(decision) => Some(decision),
+            Err(e) => {
+                console_error!(
+                    "external_verifier: check failed ({}), fail-{:?}",
+                    e,
+                    settings.on_failure
+                );
+                None
+            }
+        };
+
+        match settle(decision, settings.on_failure) {
+            Some(RiskDecision::Allow) => Ok(Flow::Continue),
+            Some(RiskDecision::StepUp) => {
+                console_log!("external_verifier: step-up function_id={}", function_id);
+                cx.step_up = true;
+                Ok(Flow::Continue)
+            }
+            Some(RiskDecision::Deny) => {
+                console_error!("external_verifier: deny function_id={}", function_id);
+                Ok(Flow::Reject(Rejection::new(
+                    403,
+                    Reason::RiskDenied,
+                    "Request not allowed",
+                )))
+            }
+            None => Ok(Flow::Reject(Rejection::new(
+                503,
+                Reason::RiskUnavailable,
+                "Risk check unavailable",
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_settings_and_verdicts() {
+        let settings: VerifierSettings =
+            serde_json::from_str(r#"{ "url": "https://risk.example.com/check" }"#).unwrap();
+        assert_eq!(settings.timeout_ms, DEFAULT_TIMEOUT_MS);
+        assert_eq!(settings.on_failure, FailureMode::Open);
+        assert!(applies_to(&settings, "APPS_LOGIN_DEFAULT"));
+
+        let settings: VerifierSettings = serde_json::from_str(
+            r#"{ "url": "https://risk.example.com/check", "on_failure": "closed",
+                 "function_ids": ["APPS_LOGIN_DEFAULT"] }"#,
+        )
+        .unwrap();
+        assert_eq!(settings.on_failure, FailureMode::Closed);
+        assert!(!applies_to(&settings, "APPS_STATUS"));
+
+        let verdict: RiskVerdict = serde_json::from_str(r#"{ "decision": "step_up" }"#).unwrap();
+        assert_eq!(verdict.decision, RiskDecision::StepUp);
+        assert!(serde_json::from_str::<RiskVerdict>(r#"{ "decision": "maybe" }"#).is_err());
+    }
+
+    #[test]
+    fn failures_follow_the_failure_mode() {
+        assert_eq!(
+            settle(Some(RiskDecision::Deny), FailureMode::Open),
+            Some(RiskDecision::Deny)
+        );
+        assert_eq!(settle(None, FailureMode::Open), Some(RiskDecision::Allow));
+        assert_eq!(settle(None, FailureMode::Closed), None);
+    }
+}
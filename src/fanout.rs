@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use worker::*;
+
+use crate::events::Event;
+
+const DEFAULT_MAX_RETRIES: u32 = 2;
+const DEFAULT_BACKOFF_MS: u64 = 200;
+
+// Entries of the `FANOUT_ENDPOINTS` JSON var
+#[derive(Clone, Debug, Deserialize)]
+pub struct FanoutEndpoint {
+    pub url: String,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+}
+
+fn default_max_retries() -> u32 {
+    DEFAULT_MAX_RETRIES
+}
+
+fn default_backoff_ms() -> u64 {
+    DEFAULT_BACKOFF_MS
+}
+
+// `FANOUT_RAW_CLIENT_IP=on` adds the raw client IP to validation events, next to its hash
+pub fn raw_client_ip(env: &Env) -> bool {
+    env.var("FANOUT_RAW_CLIENT_IP")
+        .is_ok_and(|v| v.to_string() == "on")
+}
+
+// Schedule a signed notification of `event` to every configured endpoint without delaying the response.
+// Endpoints are third parties, so they get a key of their own: without `FANOUT_SECRET` nothing is sent.
+pub fn notify(ctx: &Context, env: &Env, event: &Event) {
+    let Some(endpoints) = crate::object_var::<Vec<FanoutEndpoint>>(env, "FANOUT_ENDPOINTS") else {
+        return;
+    };
+    if endpoints.is_empty() {
+        return;
+    }
+    let signing_secret = match env.secret("FANOUT_SECRET") {
+        Ok(secret) if !secret.to_string().is_empty() => secret.to_string(),
+        _ => {
+            console_error!("FANOUT_ENDPOINTS is set without FANOUT_SECRET, skipping fan-out");
+            return;
+        }
+    };
+
+    let body = match serde_json::to_string(event) {
+        Ok(body) => body,
+        Err(e) => {
+            console_error!("Failed to serialize fan-out event: {}", e);
+            return;
+        }
+    };
+    let signature = sign(&signing_secret, &body);
+
+    for endpoint in endpoints {
+        let body = body.clone();
+        let signature = signature.clone();
+        ctx.wait_until(async move {
+            if let Err(e) = deliver(&endpoint, &body, &signature).await {
+                console_error!("Fan-out to {} failed: {}", endpoint.url, e);
+            }
+        });
+    }
+}
+
+async fn deliver(endpoint: &FanoutEndpoint, body: &str, signature: &str) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        let outcome = send_once(&endpoint.url, body, signature).await;
+        let retryable = match &outcome {
+            Ok(status) if *status < 500 => return Ok(()),
+            Ok(status) => format!("status {}", status),
+            Err(e) => e.to_string(),
+        };
+
+        if attempt >= endpoint.max_retries {
+            return Err(Error::RustError(format!(
+                "giving up after {} attempts ({})",
+                attempt + 1,
+                retryable
+            )));
+        }
+
+        let backoff_ms = crate::upstream::backoff_ms(endpoint.backoff_ms, attempt);
+        Delay::from(Duration::from_millis(backoff_ms)).await;
+        attempt += 1;
+    }
+}
+
+async fn send_once(url: &str, body: &str, signature: &str) -> Result<u16> {
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    headers.set("X-Validator-Signature", &format!("sha256={}", signature))?;
+
+    let mut request_init = RequestInit::new();
+    request_init
+        .with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(body.into()));
+
+    let request = Request::new_with_init(url, &request_init)?;
+    let response = Fetch::Request(request).send().await?;
+    Ok(response.status_code())
+}
+
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(body.as_bytes());
+    BASE64_STANDARD.encode(mac.finalize().into_bytes())
+}
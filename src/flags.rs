@@ -0,0 +1,101 @@
+use std::cell::RefCell;
+
+use async_trait::async_trait;
+use js_sys::Date;
+use serde::Deserialize;
+use worker::{console_error, console_log, Env};
+
+use crate::errors::Rejection;
+use crate::pipeline::{Flow, RequestContext, Stage};
+use crate::reason::Reason;
+
+const FLAGS_KEY: &str = "flags";
+const DEFAULT_FLAGS_TTL_SECONDS: u32 = 30;
+
+// Runtime toggles stored as JSON under `flags` in the `FEATURE_FLAGS` KV namespace, e.g.
+// { "maintenance": true } or { "validation_disabled": true }
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct FeatureFlags {
+    // Protected requests get the maintenance page instead of reaching the origin
+    pub maintenance: bool,
+    // Kill switch: protected requests are forwarded without any checks
+    pub validation_disabled: bool,
+}
+
+struct CachedFlags {
+    fetched_at: f64,
+    flags: FeatureFlags,
+}
+
+thread_local! {
+    static FLAGS_CACHE: RefCell<Option<CachedFlags>> = const { RefCell::new(None) };
+}
+
+// Read at most once per `FEATURE_FLAGS_TTL_SECONDS` per isolate. A failed read keeps the last
+// known flags, or none if there are none yet.
+pub async fn feature_flags(env: &Env) -> FeatureFlags {
+    let Ok(kv) = env.kv("FEATURE_FLAGS") else {
+        return FeatureFlags::default();
+    };
+
+    let ttl_ms = f64::from(crate::var_or(
+        env,
+        "FEATURE_FLAGS_TTL_SECONDS",
+        DEFAULT_FLAGS_TTL_SECONDS,
+    )) * 1000.0;
+    let now = Date::now();
+    let cached = FLAGS_CACHE.with(|cache| {
+        cache
+            .borrow()
+            .as_ref()
+            .filter(|cached| now - cached.fetched_at < ttl_ms)
+            .map(|cached| cached.flags.clone())
+    });
+    if let Some(flags) = cached {
+        return flags;
+    }
+
+    let flags = match kv.get(FLAGS_KEY).json::<FeatureFlags>().await {
+        Ok(flags) => flags.unwrap_or_default(),
+        Err(e) => {
+            console_error!("Failed to read feature flags: {}", e);
+            return FLAGS_CACHE.with(|cache| {
+                cache
+                    .borrow()
+                    .as_ref()
+                    .map(|cached| cached.flags.clone())
+                    .unwrap_or_default()
+            });
+        }
+    };
+    FLAGS_CACHE.with(|cache| {
+        *cache.borrow_mut() = Some(CachedFlags {
+            fetched_at: now,
+            flags: flags.clone(),
+        })
+    });
+    flags
+}
+
+pub struct FeatureFlagsStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<RequestContext<'a>> for FeatureFlagsStage {
+    async fn run(&self, cx: &mut RequestContext<'a>) -> worker::Result<Flow> {
+        let flags = feature_flags(cx.env).await;
+        if flags.maintenance {
+            console_log!("Maintenance mode - refusing protected request");
+            return Ok(Flow::Reject(Rejection::new(
+                503,
+                Reason::Maintenance,
+                "Down for maintenance",
+            )));
+        }
+        if flags.validation_disabled {
+            console_error!("validation_disabled flag set - bypassing HMAC validation");
+            return Ok(Flow::Bypass);
+        }
+        Ok(Flow::Continue)
+    }
+}
@@ -0,0 +1,272 @@
+use worker::*;
+
+const OAIT_FIELD: &str = "oait";
+const DEFAULT_MAX_BYTES: u32 = 64 * 1024;
+
+pub struct FormSettings {
+    max_bytes: usize,
+    strip: bool,
+}
+
+// `FORM_TOKEN_EXTRACTION=on` looks for oait in POSTed form bodies when the query string has none
+pub fn form_settings(env: &Env) -> Option<FormSettings> {
+    if env.var("FORM_TOKEN_EXTRACTION").ok()?.to_string() != "on" {
+        return None;
+    }
+    Some(FormSettings {
+        max_bytes: crate::var_or(env, "FORM_TOKEN_MAX_BYTES", DEFAULT_MAX_BYTES) as usize,
+        strip: env
+            .var("FORM_TOKEN_STRIP")
+            .is_ok_and(|v| v.to_string() == "on"),
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum FormEncoding {
+    UrlEncoded,
+    Multipart { boundary: String },
+}
+
+// A form body that carried oait, held so it can be forwarded with or without the token
+pub struct FormBody {
+    encoding: FormEncoding,
+    bytes: Vec<u8>,
+    strip: bool,
+    pub oait: String,
+}
+
+impl FormBody {
+    // With stripping on, the oait field keeps only the forms token, as it does in the query string
+    pub fn forwarded(self, forms_token: &str) -> Vec<u8> {
+        if !self.strip {
+            return self.bytes;
+        }
+        let replacement = Some(forms_token).filter(|token| !token.is_empty());
+        match &self.encoding {
+            FormEncoding::UrlEncoded => rewrite_urlencoded(&self.bytes, replacement),
+            FormEncoding::Multipart { boundary } => {
+                rewrite_multipart(&self.bytes, boundary, replacement)
+            }
+        }
+    }
+}
+
+// Reads the token from a POSTed form's body, already buffered for the upstream request. Bodies
+// over the size limit are not parsed, so the request is treated as carrying no token.
+pub fn read(settings: &FormSettings, req: &Request, body: &[u8]) -> Option<FormBody> {
+    if req.method() != Method::Post {
+        return None;
+    }
+    let encoding = form_encoding(&req.headers().get("Content-Type").ok()??)?;
+    if body.len() > settings.max_bytes {
+        console_error!(
+            "Form body of {} bytes exceeds FORM_TOKEN_MAX_BYTES",
+            body.len()
+        );
+        return None;
+    }
+    let bytes = body.to_vec();
+
+    let oait = match &encoding {
+        FormEncoding::UrlEncoded => urlencoded_field(&bytes, OAIT_FIELD),
+        FormEncoding::Multipart { boundary } => multipart_field(&bytes, boundary, OAIT_FIELD),
+    }?;
+    Some(FormBody {
+        encoding,
+        bytes,
+        strip: settings.strip,
+        oait,
+    })
+}
+
+fn form_encoding(content_type: &str) -> Option<FormEncoding> {
+    let mut params = content_type.split(';').map(str::trim);
+    match params.next()?.to_ascii_lowercase().as_str() {
+        "application/x-www-form-urlencoded" => Some(FormEncoding::UrlEncoded),
+        "multipart/form-data" => {
+            let boundary = params.find_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                name.eq_ignore_ascii_case("boundary")
+                    .then(|| value.trim_matches('"').to_string())
+            })?;
+            (!boundary.is_empty()).then_some(FormEncoding::Multipart { boundary })
+        }
+        _ => None,
+    }
+}
+
+// One field of a buffered form body, for checks other than oait
+pub fn field(content_type: &str, body: &[u8], name: &str) -> Option<String> {
+    match form_encoding(content_type)? {
+        FormEncoding::UrlEncoded => urlencoded_field(body, name),
+        FormEncoding::Multipart { boundary } => multipart_field(body, &boundary, name),
+    }
+}
+
+// Values are returned as sent, matching how oait is read from the query string
+fn urlencoded_field(body: &[u8], field: &str) -> Option<String> {
+    std::str::from_utf8(body)
+        .ok()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == field)
+        .map(|(_, value)| value.to_string())
+}
+
+fn rewrite_urlencoded(body: &[u8], replacement: Option<&str>) -> Vec<u8> {
+    let Ok(body) = std::str::from_utf8(body) else {
+        return body.to_vec();
+    };
+    body.split('&')
+        .filter_map(|pair| match pair.split_once('=') {
+            Some((OAIT_FIELD, _)) => replacement.map(|value| format!("{}={}", OAIT_FIELD, value)),
+            _ => Some(pair.to_string()),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+        .into_bytes()
+}
+
+// Splits a multipart body on its delimiter. The first segment is the preamble and the last
+// starts with `--`; each one between is `\r\n{headers}\r\n\r\n{value}\r\n`.
+fn multipart_segments<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut segments = Vec::new();
+    let mut rest = body;
+    while let Some(at) = rest
+        .windows(delimiter.len())
+        .position(|window| window == delimiter.as_slice())
+    {
+        segments.push(&rest[..at]);
+        rest = &rest[at + delimiter.len()..];
+    }
+    segments.push(rest);
+    segments
+}
+
+// (field name, value) of one part, or None for the preamble, the epilogue and malformed parts
+fn multipart_part(segment: &[u8]) -> Option<(String, &[u8])> {
+    let segment = segment.strip_prefix(b"\r\n")?;
+    let split = segment
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")?;
+    let headers = std::str::from_utf8(&segment[..split]).ok()?;
+    let value = &segment[split + 4..];
+    let value = value.strip_suffix(b"\r\n").unwrap_or(value);
+
+    let disposition = headers.split("\r\n").find(|line| {
+        line.split_once(':')
+            .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Disposition"))
+    })?;
+    let name = disposition.split(';').find_map(|param| {
+        let (key, value) = param.trim().split_once('=')?;
+        (key == "name").then(|| value.trim_matches('"').to_string())
+    })?;
+    Some((name, value))
+}
+
+fn multipart_field(body: &[u8], boundary: &str, field: &str) -> Option<String> {
+    multipart_segments(body, boundary)
+        .into_iter()
+        .filter_map(multipart_part)
+        .find(|(name, _)| name == field)
+        .and_then(|(_, value)| String::from_utf8(value.to_vec()).ok())
+}
+
+fn rewrite_multipart(body: &[u8], boundary: &str, replacement: Option<&str>) -> Vec<u8> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let segments = multipart_segments(body, boundary);
+
+    let mut rewritten = segments[0].to_vec();
+    for segment in &segments[1..] {
+        let rewritten_segment = match multipart_part(segment) {
+            Some((name, _)) if name == OAIT_FIELD => match replacement {
+                Some(value) => format!(
+                    "\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                    OAIT_FIELD, value
+                )
+                .into_bytes(),
+                None => continue,
+            },
+            _ => segment.to_vec(),
+        };
+        rewritten.extend_from_slice(&delimiter);
+        rewritten.extend_from_slice(&rewritten_segment);
+    }
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MULTIPART: &str = "--XyZ\r\n\
+        Content-Disposition: form-data; name=\"user\"\r\n\r\n\
+        alice\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"oait\"\r\n\r\n\
+        forms++abc+/=\r\n\
+        --XyZ--\r\n";
+
+    #[test]
+    fn parses_form_content_types() {
+        assert_eq!(
+            form_encoding("application/x-www-form-urlencoded; charset=UTF-8"),
+            Some(FormEncoding::UrlEncoded)
+        );
+        assert_eq!(
+            form_encoding("multipart/form-data; boundary=\"XyZ\""),
+            Some(FormEncoding::Multipart {
+                boundary: "XyZ".to_string()
+            })
+        );
+        assert_eq!(form_encoding("multipart/form-data"), None);
+        assert_eq!(form_encoding("application/json"), None);
+    }
+
+    #[test]
+    fn urlencoded_token_is_found_and_stripped() {
+        let body = b"user=alice&oait=forms++abc%2B&next=%2Fhome";
+        assert_eq!(
+            urlencoded_field(body, OAIT_FIELD).as_deref(),
+            Some("forms++abc%2B")
+        );
+        assert_eq!(
+            rewrite_urlencoded(body, Some("forms")),
+            b"user=alice&oait=forms&next=%2Fhome"
+        );
+        assert_eq!(rewrite_urlencoded(body, None), b"user=alice&next=%2Fhome");
+    }
+
+    #[test]
+    fn multipart_token_is_found() {
+        assert_eq!(
+            multipart_field(MULTIPART.as_bytes(), "XyZ", OAIT_FIELD).as_deref(),
+            Some("forms++abc+/=")
+        );
+        assert_eq!(
+            multipart_field(MULTIPART.as_bytes(), "XyZ", "user").as_deref(),
+            Some("alice")
+        );
+        assert_eq!(multipart_field(MULTIPART.as_bytes(), "other", "user"), None);
+    }
+
+    #[test]
+    fn multipart_token_is_stripped() {
+        let stripped = rewrite_multipart(MULTIPART.as_bytes(), "XyZ", None);
+        assert_eq!(
+            String::from_utf8(stripped).unwrap(),
+            "--XyZ\r\nContent-Disposition: form-data; name=\"user\"\r\n\r\nalice\r\n--XyZ--\r\n"
+        );
+
+        let replaced = rewrite_multipart(MULTIPART.as_bytes(), "XyZ", Some("forms"));
+        assert_eq!(
+            multipart_field(&replaced, "XyZ", OAIT_FIELD).as_deref(),
+            Some("forms")
+        );
+        assert_eq!(
+            multipart_field(&replaced, "XyZ", "user").as_deref(),
+            Some("alice")
+        );
+    }
+}
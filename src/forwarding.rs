@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use worker::{console_log, Headers, Result, Url};
+
+use crate::pipeline::{Flow, RequestContext, Stage};
+
+const VIA_PSEUDONYM: &str = "validate-token-rust";
+
+// RFC 9110 hop-by-hop headers; any header named in `Connection` is stripped as well
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// `UPSTREAM_HEADER_RULES` JSON var, applied after the standard proxy headers
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct HeaderRules {
+    #[serde(default)]
+    pub set: HashMap<String, String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+pub fn upstream_headers(
+    original: &Headers,
+    client_ip: &str,
+    url: &Url,
+    rules: &HeaderRules,
+    websocket_upgrade: bool,
+) -> Result<Headers> {
+    let connection_tokens: Vec<String> = original
+        .get("Connection")?
+        .map(|value| {
+            value
+                .split(',')
+                .map(|token| token.trim().to_ascii_lowercase())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // Copy rather than clone: cloned `Headers` share the immutable incoming header list
+    let headers = Headers::new();
+    for (name, value) in original.entries() {
+        let name_lower = name.to_ascii_lowercase();
+        // The upgrade handshake is the one hop-by-hop exchange the origin must see
+        let is_handshake =
+            websocket_upgrade && matches!(name_lower.as_str(), "connection" | "upgrade");
+        if !is_handshake
+            && (HOP_BY_HOP_HEADERS.contains(&name_lower.as_str())
+                || connection_tokens.contains(&name_lower))
+        {
+            continue;
+        }
+        // Only the edge may say how a request validated
+        if name.eq_ignore_ascii_case(crate::reason::VALIDATION_HEADER) {
+            continue;
+        }
+        headers.append(&name, &value)?;
+    }
+
+    let forwarded_for = match original.get("X-Forwarded-For")? {
+        Some(existing) if !existing.trim().is_empty() => format!("{}, {}", existing, client_ip),
+        _ => client_ip.to_string(),
+    };
+    headers.set("X-Forwarded-For", &forwarded_for)?;
+    headers.set("X-Forwarded-Proto", url.scheme())?;
+    if let Some(host) = url.host_str() {
+        headers.set("X-Forwarded-Host", host)?;
+    }
+
+    let forwarded_element = format!(
+        "for={};proto={}{}",
+        forwarded_node(client_ip),
+        url.scheme(),
+        url.host_str()
+            .map(|host| format!(";host={}", host))
+            .unwrap_or_default()
+    );
+    headers.append("Forwarded", &forwarded_element)?;
+    headers.append("Via", &format!("1.1 {}", VIA_PSEUDONYM))?;
+
+    for name in &rules.remove {
+        headers.delete(name)?;
+    }
+    for (name, value) in &rules.set {
+        headers.set(name, value)?;
+    }
+
+    Ok(headers)
+}
+
+pub fn is_websocket_upgrade(headers: &Headers) -> bool {
+    headers
+        .get("Upgrade")
+        .ok()
+        .flatten()
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+// IPv6 nodes must be quoted and bracketed in `Forwarded` (RFC 7239)
+fn forwarded_node(client_ip: &str) -> String {
+    if client_ip.contains(':') {
+        format!("\"[{}]\"", client_ip)
+    } else {
+        client_ip.to_string()
+    }
+}
+
+pub struct WebSocketStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<RequestContext<'a>> for WebSocketStage {
+    async fn run(&self, cx: &mut RequestContext<'a>) -> Result<Flow> {
+        if cx.websocket_upgrade && !cx.step_up && !crate::websocket_validation_required(cx.env) {
+            console_log!("WebSocket upgrade - bypassing HMAC validation");
+            return Ok(Flow::Bypass);
+        }
+        Ok(Flow::Continue)
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use wasm_bindgen::JsValue;
+use worker::{console_error, console_log, Env, Request, Result};
+
+use crate::errors::Rejection;
+use crate::pipeline::{Flow, RequestContext, Stage};
+use crate::reason::Reason;
+use crate::tenant::TenantConfig;
+
+// Rules from the `GEO_POLICY` JSON var, or the tenant's `geo_policy` in its place. Countries
+// are ISO 3166-1 alpha-2 codes as in `cf.country`; bot scores run from 1 (automated) to 99 (human).
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct GeoPolicy {
+    pub deny_countries: Vec<String>,
+    pub step_up_countries: Vec<String>,
+    pub deny_asns: Vec<u32>,
+    pub step_up_asns: Vec<u32>,
+    pub deny_bot_score_below: Option<u8>,
+    pub step_up_bot_score_below: Option<u8>,
+}
+
+// Validity windows from the `VALIDITY_OVERRIDES` JSON var that replace `TOKEN_VALIDITY_SECONDS`
+// for matching clients, e.g. { "countries": { "AU": 600 }, "asns": { "64496": 120 } }
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ValidityOverrides {
+    pub countries: HashMap<String, f64>,
+    pub asns: HashMap<u32, f64>,
+}
+
+pub fn validity_overrides(env: &Env) -> Option<ValidityOverrides> {
+    crate::object_var(env, "VALIDITY_OVERRIDES")
+}
+
+impl ValidityOverrides {
+    pub fn longest(&self) -> Option<f64> {
+        self.countries
+            .values()
+            .chain(self.asns.values())
+            .copied()
+            .reduce(f64::max)
+    }
+}
+
+// An ASN is the narrower match, so it wins over the country
+pub fn validity_override(
+    overrides: &ValidityOverrides,
+    signals: &ClientSignals,
+) -> Option<(f64, GeoReason)> {
+    if let Some((asn, seconds)) = signals
+        .asn
+        .and_then(|asn| Some((asn, *overrides.asns.get(&asn)?)))
+    {
+        return Some((seconds, GeoReason::Asn(asn)));
+    }
+    let country = signals.country.as_ref()?;
+    overrides
+        .countries
+        .iter()
+        .find(|(listed, _)| listed.eq_ignore_ascii_case(country))
+        .map(|(_, seconds)| (*seconds, GeoReason::Country(country.clone())))
+}
+
+// What Cloudflare's edge knows about the client
+#[derive(Clone, Debug, Default)]
+pub struct ClientSignals {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    // Only present with Bot Management
+    pub bot_score: Option<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GeoReason {
+    Country(String),
+    Asn(u32),
+    BotScore(u8),
+}
+
+impl fmt::Display for GeoReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GeoReason::Country(country) => write!(f, "country={}", country),
+            GeoReason::Asn(asn) => write!(f, "asn={}", asn),
+            GeoReason::BotScore(score) => write!(f, "bot_score={}", score),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GeoDecision {
+    Allow,
+    Deny(GeoReason),
+    // Validation is required even where the request would otherwise bypass it
+    StepUp(GeoReason),
+}
+
+pub fn geo_policy(env: &Env, tenant: &TenantConfig) -> Option<GeoPolicy> {
+    tenant
+        .geo_policy
+        .clone()
+        .or_else(|| crate::object_var(env, "GEO_POLICY"))
+}
+
+pub fn client_signals(req: &Request) -> ClientSignals {
+    let Some(cf) = req.cf() else {
+        return ClientSignals::default();
+    };
+    // Bot management has no typed accessor yet, so read it off the raw `cf` object
+    let bot_score = js_sys::Reflect::get(req.inner(), &JsValue::from_str("cf"))
+        .and_then(|cf| js_sys::Reflect::get(&cf, &JsValue::from_str("botManagement")))
+        .and_then(|bot| js_sys::Reflect::get(&bot, &JsValue::from_str("score")))
+        .ok()
+        .and_then(|score| score.as_f64())
+        .map(|score| score as u8);
+    ClientSignals {
+        country: cf.country(),
+        asn: cf.asn(),
+        bot_score,
+    }
+}
+
+// Deny rules are checked before step-up rules
+pub fn evaluate(policy: &GeoPolicy, signals: &ClientSignals) -> GeoDecision {
+    let matches = |countries: &[String], asns: &[u32], bot_score_below: Option<u8>| {
+        if let Some(country) = signals
+            .country
+            .as_ref()
+            .filter(|country| countries.iter().any(|c| c.eq_ignore_ascii_case(country)))
+        {
+            return Some(GeoReason::Country(country.clone()));
+        }
+        if let Some(asn) = signals.asn.filter(|asn| asns.contains(asn)) {
+            return Some(GeoReason::Asn(asn));
+        }
+        match (signals.bot_score, bot_score_below) {
+            (Some(score), Some(threshold)) if score < threshold => Some(GeoReason::BotScore(score)),
+            _ => None,
+        }
+    };
+
+    if let Some(reason) = matches(
+        &policy.deny_countries,
+        &policy.deny_asns,
+        policy.deny_bot_score_below,
+    ) {
+        return GeoDecision::Deny(reason);
+    }
+    if let Some(reason) = matches(
+        &policy.step_up_countries,
+        &policy.step_up_asns,
+        policy.step_up_bot_score_below,
+    ) {
+        return GeoDecision::StepUp(reason);
+    }
+    GeoDecision::Allow
+}
+
+// Edge signals come first, so denied clients never reach the origin at all
+pub struct GeoPolicyStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<RequestContext<'a>> for GeoPolicyStage {
+    async fn run(&self, cx: &mut RequestContext<'a>) -> Result<Flow> {
+        let Some(policy) = geo_policy(cx.env, cx.tenant) else {
+            return Ok(Flow::Continue);
+        };
+        match evaluate(&policy, &client_signals(cx.req)) {
+            GeoDecision::Deny(reason) => {
+                console_error!("geo_policy: deny {}", reason);
+                Ok(Flow::Reject(Rejection::new(
+                    403,
+                    Reason::GeoDenied,
+                    "Request not allowed",
+                )))
+            }
+            GeoDecision::StepUp(reason) => {
+                console_log!("geo_policy: step-up {}", reason);
+                cx.step_up = true;
+                Ok(Flow::Continue)
+            }
+            GeoDecision::Allow => Ok(Flow::Continue),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_validity_by_asn_then_country() {
+        let overrides: ValidityOverrides = serde_json::from_str(
+            r#"{ "countries": { "AU": 600, "nz": 900 }, "asns": { "64496": 120 } }"#,
+        )
+        .unwrap();
+        let signals = |country: &str, asn: u32| ClientSignals {
+            country: Some(country.to_string()),
+            asn: Some(asn),
+            bot_score: None,
+        };
+        assert_eq!(
+            validity_override(&overrides, &signals("AU", 64496)),
+            Some((120.0, GeoReason::Asn(64496)))
+        );
+        assert_eq!(
+            validity_override(&overrides, &signals("NZ", 13335)),
+            Some((900.0, GeoReason::Country("NZ".to_string())))
+        );
+        assert_eq!(validity_override(&overrides, &signals("US", 13335)), None);
+        assert_eq!(
+            validity_override(&overrides, &ClientSignals::default()),
+            None
+        );
+    }
+
+    fn policy() -> GeoPolicy {
+        GeoPolicy {
+            deny_countries: vec!["KP".to_string()],
+            step_up_countries: vec!["ru".to_string()],
+            deny_asns: vec![64496],
+            step_up_asns: vec![64511],
+            deny_bot_score_below: Some(5),
+            step_up_bot_score_below: Some(30),
+        }
+    }
+
+    fn signals(country: &str, asn: u32, bot_score: Option<u8>) -> ClientSignals {
+        ClientSignals {
+            country: Some(country.to_string()),
+            asn: Some(asn),
+            bot_score,
+        }
+    }
+
+    #[test]
+    fn denies_before_stepping_up() {
+        assert_eq!(
+            evaluate(&policy(), &signals("KP", 64511, None)),
+            GeoDecision::Deny(GeoReason::Country("KP".to_string()))
+        );
+        assert_eq!(
+            evaluate(&policy(), &signals("RU", 64496, None)),
+            GeoDecision::Deny(GeoReason::Asn(64496))
+        );
+        assert_eq!(
+            evaluate(&policy(), &signals("RU", 13335, Some(50))),
+            GeoDecision::StepUp(GeoReason::Country("RU".to_string()))
+        );
+        assert_eq!(
+            evaluate(&policy(), &signals("DE", 13335, Some(2))),
+            GeoDecision::Deny(GeoReason::BotScore(2))
+        );
+        assert_eq!(
+            evaluate(&policy(), &signals("DE", 13335, Some(12))),
+            GeoDecision::StepUp(GeoReason::BotScore(12))
+        );
+    }
+
+    #[test]
+    fn allows_when_no_rule_matches() {
+        assert_eq!(
+            evaluate(&policy(), &signals("DE", 13335, Some(90))),
+            GeoDecision::Allow
+        );
+        // Without Bot Management there is no score to judge
+        assert_eq!(
+            evaluate(&policy(), &signals("DE", 13335, None)),
+            GeoDecision::Allow
+        );
+        assert_eq!(
+            evaluate(&GeoPolicy::default(), &ClientSignals::default()),
+            GeoDecision::Allow
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+const HEARTBEAT_KEY: &str = "heartbeat";
+const DEFAULT_REVOCATION_RETENTION_SECONDS: u32 = 24 * 60 * 60;
+
+// What the last run did, kept under `heartbeat` in the `HOUSEKEEPING` KV namespace, so a monitor
+// can alert when the cron stops firing or a task keeps failing
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub ran_at: f64,
+    pub cron: String,
+    // Token revocations deleted once past `REVOCATION_RETENTION_SECONDS` and the token's lifetime
+    pub pruned_revocations: usize,
+    // Keys Access published, when the Access JWT check is on
+    pub jwks_keys: Option<usize>,
+    pub errors: Vec<String>,
+}
+
+// Work kept off the request path. Each task is optional and skipped when its binding is missing;
+// one that fails is recorded in the heartbeat without stopping the others.
+pub async fn run(env: &Env, event: &ScheduledEvent) {
+    let mut heartbeat = Heartbeat {
+        ran_at: event.schedule(),
+        cron: event.cron(),
+        ..Heartbeat::default()
+    };
+
+    futures::future::join3(
+        crate::audit::flush_pending(env),
+        crate::metrics::flush_pending(env),
+        crate::stats::flush_pending(env),
+    )
+    .await;
+
+    let (pruned, jwks) = futures::future::join(prune_revocations(env), refresh_jwks(env)).await;
+    match pruned {
+        Ok(pruned) => heartbeat.pruned_revocations = pruned,
+        Err(e) => heartbeat.errors.push(format!("revocations: {}", e)),
+    }
+    match jwks {
+        Ok(keys) => heartbeat.jwks_keys = keys,
+        Err(e) => heartbeat.errors.push(format!("jwks: {}", e)),
+    }
+
+    console_log!(
+        "housekeeping: cron={} pruned_revocations={} jwks_keys={} errors={}",
+        heartbeat.cron,
+        heartbeat.pruned_revocations,
+        heartbeat
+            .jwks_keys
+            .map_or_else(|| "-".to_string(), |keys| keys.to_string()),
+        heartbeat.errors.len()
+    );
+    for error in &heartbeat.errors {
+        console_error!("housekeeping: {}", error);
+    }
+    if let Err(e) = write_heartbeat(env, &heartbeat).await {
+        console_error!("housekeeping: failed to write heartbeat: {}", e);
+    }
+}
+
+async fn prune_revocations(env: &Env) -> Result<usize> {
+    let Ok(kv) = env.kv("REVOCATIONS") else {
+        return Ok(0);
+    };
+    let retention_seconds = crate::var_or(
+        env,
+        "REVOCATION_RETENTION_SECONDS",
+        DEFAULT_REVOCATION_RETENTION_SECONDS,
+    );
+    let validity_seconds = crate::longest_validity_seconds(env).await;
+    // Signed links carry the longest lifetimes, unless refresh chains are capped beyond them
+    let max_lifetime_seconds = crate::max_session_seconds(env)
+        .unwrap_or_default()
+        .max(crate::admin::MAX_SIGNED_URL_TTL_SECONDS as f64)
+        .max(validity_seconds);
+    let retention = crate::revocation::Retention {
+        retention_ms: f64::from(retention_seconds) * 1000.0,
+        validity_ms: validity_seconds * 1000.0,
+        max_lifetime_ms: max_lifetime_seconds * 1000.0,
+    };
+    crate::revocation::prune(&kv, &retention, Date::now().as_millis() as f64).await
+}
+
+async fn refresh_jwks(env: &Env) -> Result<Option<usize>> {
+    let Some(settings) = crate::access::access_settings(env) else {
+        return Ok(None);
+    };
+    crate::access::refresh_jwks(env, &settings).await.map(Some)
+}
+
+async fn write_heartbeat(env: &Env, heartbeat: &Heartbeat) -> Result<()> {
+    let Ok(kv) = env.kv("HOUSEKEEPING") else {
+        return Ok(());
+    };
+    kv.put(HEARTBEAT_KEY, serde_json::to_string(heartbeat)?)?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+// `GET /admin/housekeeping`
+pub async fn last_heartbeat(env: &Env) -> Result<Response> {
+    let Ok(kv) = env.kv("HOUSEKEEPING") else {
+        return Response::error("Housekeeping is not configured", 404);
+    };
+    match kv.get(HEARTBEAT_KEY).json::<Heartbeat>().await? {
+        Some(heartbeat) => Response::from_json(&heartbeat),
+        None => Response::error("Housekeeping has not run yet", 404),
+    }
+}
@@ -0,0 +1,437 @@
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::*;
+
+use crate::do_budget::DoBudget;
+use crate::errors::Rejection;
+use crate::reason::Reason;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+const MAX_KEY_LEN: usize = 255;
+const DEFAULT_WINDOW_SECONDS: u32 = 600;
+const DEFAULT_MAX_BODY_BYTES: u32 = 64 * 1024;
+// A claim whose request never finished, e.g. because the isolate died, stops blocking retries
+const IN_FLIGHT_TIMEOUT_MS: f64 = 120_000.0;
+
+pub struct IdempotencySettings {
+    namespace: ObjectNamespace,
+    window_seconds: u32,
+    max_body_bytes: u32,
+}
+
+// Idempotency needs `IDEMPOTENCY_MODE=on` and the `IDEMPOTENCY_DO` Durable Object binding
+pub fn idempotency_settings(env: &Env) -> Option<IdempotencySettings> {
+    if env.var("IDEMPOTENCY_MODE").ok()?.to_string() != "on" {
+        return None;
+    }
+
+    let namespace = env
+        .durable_object("IDEMPOTENCY_DO")
+        .map_err(|_| console_error!("IDEMPOTENCY_MODE is on but IDEMPOTENCY_DO is not bound"))
+        .ok()?;
+    Some(IdempotencySettings {
+        namespace,
+        window_seconds: crate::var_or(env, "IDEMPOTENCY_WINDOW_SECONDS", DEFAULT_WINDOW_SECONDS),
+        max_body_bytes: crate::var_or(env, "IDEMPOTENCY_MAX_BODY_BYTES", DEFAULT_MAX_BODY_BYTES),
+    })
+}
+
+// The methods upstream retries would otherwise never repeat
+pub fn applies(method: &Method) -> bool {
+    matches!(method, Method::Post | Method::Patch)
+}
+
+pub fn idempotency_key(headers: &Headers) -> Option<String> {
+    headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .ok()
+        .flatten()
+        .filter(|key| !key.is_empty())
+}
+
+fn is_valid_key(key: &str) -> bool {
+    key.len() <= MAX_KEY_LEN && key.bytes().all(|b| b.is_ascii_graphic())
+}
+
+// Keys are only unique per client, so one client's key never finds another's response
+fn scope(tenant: &str, function_id: &str, client_ip: &str, key: &str) -> String {
+    hex::encode(Sha256::digest(
+        format!("{}\n{}\n{}\n{}", tenant, function_id, client_ip, key).as_bytes(),
+    ))
+}
+
+// A reused key is only a duplicate when it comes with the same request
+fn fingerprint(method: &Method, url: &Url, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{} {}\n", method, &url[url::Position::BeforePath..]).as_bytes());
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+// A completed response as the object keeps it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    // Base64, exactly as the origin sent it
+    body: String,
+    // The body keeps the origin's `Content-Encoding` and must not be compressed again
+    encoded: bool,
+}
+
+impl StoredResponse {
+    fn into_response(self) -> Result<Response> {
+        let headers = Headers::new();
+        for (name, value) in &self.headers {
+            headers.append(name, value)?;
+        }
+        headers.set(REPLAYED_HEADER, "true")?;
+        let body = BASE64_STANDARD
+            .decode(&self.body)
+            .map_err(|e| Error::RustError(e.to_string()))?;
+        let response = Response::from_bytes(body)?
+            .with_headers(headers)
+            .with_status(self.status);
+        Ok(if self.encoded {
+            response.with_encode_body(EncodeBody::Manual)
+        } else {
+            response
+        })
+    }
+}
+
+pub struct Reservation {
+    scope: String,
+    fingerprint: String,
+}
+
+pub enum Claim {
+    // Send the request; with a reservation its response is kept for duplicates
+    Proceed(Option<Reservation>),
+    // A duplicate within the window, answered with the first response
+    Replay(Response),
+    Refuse(Rejection),
+}
+
+// Requests without a key get a generated one, which only the origin sees: it lets the origin
+// recognise the worker's own retries. A client key is claimed on the object first. If the
+// object cannot be reached, the request goes ahead unprotected rather than failing.
+#[allow(clippy::too_many_arguments)]
+pub async fn claim(
+    settings: &IdempotencySettings,
+    budget: &DoBudget<'_>,
+    req: &Request,
+    upstream_headers: &Headers,
+    tenant: &str,
+    function_id: &str,
+    client_ip: &str,
+    body: &[u8],
+) -> Result<Claim> {
+    let Some(key) = idempotency_key(req.headers()) else {
+        let mut key_bytes = [0u8; 16];
+        getrandom::getrandom(&mut key_bytes).map_err(|e| Error::RustError(e.to_string()))?;
+        upstream_headers.set(IDEMPOTENCY_KEY_HEADER, &hex::encode(key_bytes))?;
+        return Ok(Claim::Proceed(None));
+    };
+    if !is_valid_key(&key) {
+        return Ok(Claim::Refuse(Rejection::new(
+            400,
+            Reason::IdempotencyKeyInvalid,
+            "Invalid idempotency key",
+        )));
+    }
+
+    let reservation = Reservation {
+        scope: scope(tenant, function_id, client_ip, &key),
+        fingerprint: fingerprint(&req.method(), &req.url()?, body),
+    };
+    let begin = BeginRequest {
+        fingerprint: reservation.fingerprint.clone(),
+        window_ms: f64::from(settings.window_seconds) * 1000.0,
+    };
+    let outcome = match call(settings, budget, &reservation.scope, "begin", &begin).await {
+        Ok(mut response) => response.json::<BeginOutcome>().await,
+        Err(e) => Err(e),
+    };
+    match outcome {
+        Ok(BeginOutcome::Fresh) => Ok(Claim::Proceed(Some(reservation))),
+        Ok(BeginOutcome::Completed { response }) => {
+            console_log!("idempotency: replayed function_id={}", function_id);
+            Ok(Claim::Replay(response.into_response()?))
+        }
+        Ok(BeginOutcome::InFlight) => Ok(Claim::Refuse(Rejection::new(
+            409,
+            Reason::IdempotencyInFlight,
+            "Request already in progress",
+        ))),
+        Ok(BeginOutcome::Mismatch) => Ok(Claim::Refuse(Rejection::new(
+            422,
+            Reason::IdempotencyKeyReused,
+            "Idempotency key reused with a different request",
+        ))),
+        Err(e) => {
+            console_error!("Idempotency claim failed: {}", e);
+            Ok(Claim::Proceed(None))
+        }
+    }
+}
+
+// Keeps the response for duplicates. Server errors release the key so the client can try again,
+// and so do bodies without a `Content-Length` or over `IDEMPOTENCY_MAX_BODY_BYTES`, which are
+// passed on without being read.
+pub async fn complete(
+    settings: &IdempotencySettings,
+    budget: &DoBudget<'_>,
+    reservation: Reservation,
+    mut response: Response,
+) -> Result<Response> {
+    let storable = response.status_code() < 500
+        && response
+            .headers()
+            .get("Content-Length")?
+            .and_then(|len| len.parse::<u32>().ok())
+            .is_some_and(|len| len <= settings.max_body_bytes);
+    if !storable {
+        if let Err(e) = call(settings, budget, &reservation.scope, "release", &()).await {
+            console_error!("Failed to release idempotency key: {}", e);
+        }
+        return Ok(response);
+    }
+
+    let status = response.status_code();
+    let headers = response.headers().clone();
+    let encode_body = *response.encode_body();
+    let body = response.bytes().await?;
+    let stored = CompleteRequest {
+        fingerprint: reservation.fingerprint,
+        window_ms: f64::from(settings.window_seconds) * 1000.0,
+        response: StoredResponse {
+            status,
+            headers: headers.entries().collect(),
+            body: BASE64_STANDARD.encode(&body),
+            encoded: matches!(encode_body, EncodeBody::Manual),
+        },
+    };
+    if let Err(e) = call(settings, budget, &reservation.scope, "complete", &stored).await {
+        console_error!("Failed to store idempotent response: {}", e);
+    }
+    Ok(Response::from_bytes(body)?
+        .with_headers(headers)
+        .with_status(status)
+        .with_encode_body(encode_body))
+}
+
+async fn call<T: Serialize>(
+    settings: &IdempotencySettings,
+    budget: &DoBudget<'_>,
+    scope: &str,
+    action: &str,
+    body: &T,
+) -> Result<Response> {
+    let stub = settings.namespace.id_from_name(scope)?.get_stub()?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_body(Some(serde_json::to_string(body)?.into()));
+    let request = Request::new_with_init(&format!("https://idempotency/{}", action), &init)?;
+    let response = budget.fetch("idempotency", &stub, request).await?;
+    if response.status_code() >= 400 {
+        return Err(Error::RustError(format!(
+            "status {}",
+            response.status_code()
+        )));
+    }
+    Ok(response)
+}
+
+#[derive(Serialize, Deserialize)]
+struct BeginRequest {
+    fingerprint: String,
+    window_ms: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompleteRequest {
+    fingerprint: String,
+    window_ms: f64,
+    response: StoredResponse,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum BeginOutcome {
+    Fresh,
+    InFlight,
+    Mismatch,
+    Completed { response: StoredResponse },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Entry {
+    fingerprint: String,
+    started_at: f64,
+    // Set once the response is stored
+    completed: Option<(f64, StoredResponse)>,
+}
+
+// Expired entries count as absent, so the key can be claimed afresh
+fn begin(entry: Option<&Entry>, fingerprint: &str, now: f64, window_ms: f64) -> BeginOutcome {
+    let live = entry.filter(|entry| match &entry.completed {
+        Some((completed_at, _)) => now - completed_at < window_ms,
+        None => now - entry.started_at < IN_FLIGHT_TIMEOUT_MS,
+    });
+    match live {
+        None => BeginOutcome::Fresh,
+        Some(entry) if entry.fingerprint != fingerprint => BeginOutcome::Mismatch,
+        Some(Entry {
+            completed: Some((_, response)),
+            ..
+        }) => BeginOutcome::Completed {
+            response: response.clone(),
+        },
+        Some(_) => BeginOutcome::InFlight,
+    }
+}
+
+// One object per scoped key; an alarm clears the entry once nothing can match it any more
+#[durable_object]
+pub struct IdempotencyObject {
+    state: State,
+}
+
+impl DurableObject for IdempotencyObject {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let storage = self.state.storage();
+        let now = Date::now().as_millis() as f64;
+        match req.path().as_str() {
+            "/begin" => {
+                let request: BeginRequest = req.json().await?;
+                let entry = storage.get::<Entry>("entry").await.ok();
+                let outcome = begin(entry.as_ref(), &request.fingerprint, now, request.window_ms);
+                if outcome == BeginOutcome::Fresh {
+                    let entry = Entry {
+                        fingerprint: request.fingerprint,
+                        started_at: now,
+                        completed: None,
+                    };
+                    storage.put("entry", &entry).await?;
+                    storage.set_alarm(IN_FLIGHT_TIMEOUT_MS as i64).await?;
+                }
+                Response::from_json(&outcome)
+            }
+            "/complete" => {
+                let request: CompleteRequest = req.json().await?;
+                let Ok(mut entry) = storage.get::<Entry>("entry").await else {
+                    return Response::error("Unknown key", 404);
+                };
+                if entry.fingerprint != request.fingerprint {
+                    return Response::error("Key claimed by another request", 409);
+                }
+                entry.completed = Some((now, request.response));
+                storage.put("entry", &entry).await?;
+                storage.set_alarm(request.window_ms as i64).await?;
+                Response::empty().map(|response| response.with_status(204))
+            }
+            "/release" => {
+                storage.delete_all().await?;
+                Response::empty().map(|response| response.with_status(204))
+            }
+            _ => Response::error("Not found", 404),
+        }
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        self.state.storage().delete_all().await?;
+        Response::empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW_MS: f64 = 600_000.0;
+
+    fn stored() -> StoredResponse {
+        StoredResponse {
+            status: 201,
+            headers: vec![("content-type".to_string(), "application/json".to_string())],
+            body: BASE64_STANDARD.encode(b"{}"),
+            encoded: false,
+        }
+    }
+
+    fn entry(started_at: f64, completed_at: Option<f64>) -> Entry {
+        Entry {
+            fingerprint: "a".to_string(),
+            started_at,
+            completed: completed_at.map(|at| (at, stored())),
+        }
+    }
+
+    #[test]
+    fn replays_duplicates_within_the_window() {
+        assert_eq!(begin(None, "a", 0.0, WINDOW_MS), BeginOutcome::Fresh);
+
+        let in_flight = entry(1_000.0, None);
+        assert_eq!(
+            begin(Some(&in_flight), "a", 2_000.0, WINDOW_MS),
+            BeginOutcome::InFlight
+        );
+        // An abandoned claim stops blocking
+        assert_eq!(
+            begin(
+                Some(&in_flight),
+                "a",
+                1_000.0 + IN_FLIGHT_TIMEOUT_MS,
+                WINDOW_MS
+            ),
+            BeginOutcome::Fresh
+        );
+
+        let completed = entry(1_000.0, Some(3_000.0));
+        assert_eq!(
+            begin(Some(&completed), "a", 3_000.0 + WINDOW_MS - 1.0, WINDOW_MS),
+            BeginOutcome::Completed { response: stored() }
+        );
+        assert_eq!(
+            begin(Some(&completed), "b", 4_000.0, WINDOW_MS),
+            BeginOutcome::Mismatch
+        );
+        assert_eq!(
+            begin(Some(&completed), "b", 3_000.0 + WINDOW_MS, WINDOW_MS),
+            BeginOutcome::Fresh
+        );
+    }
+
+    #[test]
+    fn scopes_keys_and_fingerprints_requests() {
+        assert_ne!(
+            scope("login", "APPS", "192.0.2.1", "k1"),
+            scope("login", "APPS", "192.0.2.2", "k1")
+        );
+        let url = Url::parse("https://login.example.com/submit?step=2").unwrap();
+        let other = Url::parse("https://login.example.com/submit?step=3").unwrap();
+        assert_eq!(
+            fingerprint(&Method::Post, &url, b"a=1"),
+            fingerprint(&Method::Post, &url, b"a=1")
+        );
+        assert_ne!(
+            fingerprint(&Method::Post, &url, b"a=1"),
+            fingerprint(&Method::Post, &url, b"a=2")
+        );
+        assert_ne!(
+            fingerprint(&Method::Post, &url, b"a=1"),
+            fingerprint(&Method::Post, &other, b"a=1")
+        );
+
+        assert!(is_valid_key("7f1c2a9e-order-42"));
+        assert!(!is_valid_key("has space"));
+        assert!(!is_valid_key(&"k".repeat(MAX_KEY_LEN + 1)));
+    }
+}
@@ -0,0 +1,182 @@
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use worker::{console_error, console_log, Env};
+
+use crate::errors::Rejection;
+use crate::pipeline::{Flow, RequestContext, Stage};
+use crate::reason::Reason;
+
+const IP_LIST_CACHE_TTL_SECONDS: u64 = 60;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpAction {
+    // Forward without any token checks
+    AllowBypass,
+    Deny,
+    // Validate as usual; carves exceptions out of a broader allow list further down
+    RequireValidation,
+}
+
+// Entries of the `IP_POLICIES` JSON var. CIDRs are listed inline, read from `kv_key` in the
+// `IP_LISTS` KV namespace (a JSON array of strings), or both.
+#[derive(Debug, Deserialize)]
+pub struct IpList {
+    #[serde(default)]
+    pub name: String,
+    pub action: IpAction,
+    #[serde(default)]
+    pub cidrs: Vec<String>,
+    #[serde(default)]
+    pub kv_key: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    // A bare address is a single-host range
+    pub fn parse(value: &str) -> Option<Self> {
+        let (address, prefix) = match value.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix.parse::<u8>().ok()?)),
+            None => (value.trim(), None),
+        };
+        let network = address.parse::<IpAddr>().ok()?.to_canonical();
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max_prefix);
+        (prefix <= max_prefix).then_some(Self { network, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// The first list containing the client decides; `None` leaves the request to the usual checks.
+// A list that cannot be read from KV is skipped.
+pub async fn ip_action(env: &Env, client_ip: &str) -> Option<(String, IpAction)> {
+    let lists = crate::object_var::<Vec<IpList>>(env, "IP_POLICIES")?;
+    let ip = client_ip.parse::<IpAddr>().ok()?;
+    for list in lists {
+        let mut cidrs = list.cidrs.clone();
+        if let Some(kv_key) = &list.kv_key {
+            match read_kv_list(env, kv_key).await {
+                Ok(stored) => cidrs.extend(stored),
+                Err(e) => console_error!("Failed to read IP list {}: {}", kv_key, e),
+            }
+        }
+        if contains_any(&cidrs, ip) {
+            return Some((list.name, list.action));
+        }
+    }
+    None
+}
+
+async fn read_kv_list(env: &Env, key: &str) -> worker::Result<Vec<String>> {
+    let kv = env.kv("IP_LISTS")?;
+    Ok(kv
+        .get(key)
+        .cache_ttl(IP_LIST_CACHE_TTL_SECONDS)
+        .json::<Vec<String>>()
+        .await?
+        .unwrap_or_default())
+}
+
+fn contains_any(cidrs: &[String], ip: IpAddr) -> bool {
+    cidrs.iter().any(|value| match Cidr::parse(value) {
+        Some(cidr) => cidr.contains(ip),
+        None => {
+            console_error!("Ignoring invalid CIDR {}", value);
+            false
+        }
+    })
+}
+
+pub struct IpPolicyStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<RequestContext<'a>> for IpPolicyStage {
+    async fn run(&self, cx: &mut RequestContext<'a>) -> worker::Result<Flow> {
+        Ok(match ip_action(cx.env, &cx.client_ip).await {
+            Some((list, IpAction::Deny)) => {
+                console_error!("Client {} denied by IP list {}", cx.client_ip, list);
+                Flow::Reject(Rejection::new(
+                    403,
+                    Reason::NetworkDenied,
+                    "Network not allowed",
+                ))
+            }
+            Some((list, IpAction::AllowBypass)) if !cx.step_up => {
+                console_log!(
+                    "Client {} allowed by IP list {} - bypassing HMAC validation",
+                    cx.client_ip,
+                    list
+                );
+                Flow::Bypass
+            }
+            Some(_) | None => Flow::Continue,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_ipv4_and_ipv6_ranges() {
+        let office = Cidr::parse("203.0.113.0/24").unwrap();
+        assert!(office.contains(ip("203.0.113.7")));
+        assert!(!office.contains(ip("203.0.114.7")));
+        // IPv4-mapped IPv6 addresses are compared as IPv4
+        assert!(office.contains(ip("::ffff:203.0.113.7")));
+        assert!(!office.contains(ip("2001:db8::1")));
+
+        let v6 = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(v6.contains(ip("2001:db8:1234::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+
+        let host = Cidr::parse("198.51.100.1").unwrap();
+        assert!(host.contains(ip("198.51.100.1")));
+        assert!(!host.contains(ip("198.51.100.2")));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("192.0.2.1")));
+        assert!(Cidr::parse("::/0").unwrap().contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn rejects_invalid_cidrs() {
+        for value in [
+            "203.0.113.0/33",
+            "2001:db8::/129",
+            "203.0.113.0/",
+            "office",
+            "",
+        ] {
+            assert_eq!(Cidr::parse(value), None, "{}", value);
+        }
+    }
+}
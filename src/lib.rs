@@ -1,209 +1,1505 @@
-use base64::prelude::*;
-use hmac::{Hmac, Mac};
-use js_sys::Date;
-use sha2::Sha256;
+use serde::de::DeserializeOwned;
 use url::Url;
-use urlencoding;
-use wasm_bindgen::JsValue;
 use worker::*;
 
+mod access;
+mod admin;
+mod audit;
+mod audit_chain;
+mod body_limit;
+mod canary;
+mod circuit_breaker;
+mod client_ip;
+mod config;
+mod continuation;
+mod cookies;
+mod cors;
+mod csrf;
+mod do_budget;
+mod encoding;
+mod encrypted_token;
+mod errors;
+mod events;
+mod external_verifier;
+mod fanout;
+mod flags;
+mod form_token;
+mod forwarding;
+mod geo_policy;
+mod housekeeping;
+mod idempotency;
+mod ip_policy;
+mod lookup_budget;
+mod metrics;
+mod oait;
+mod origin_response;
+mod parking;
+mod pipeline;
+mod policy;
+mod protected_params;
+mod rate_limit;
+mod reason;
+mod request_log;
+mod request_signing;
+mod response_cache;
+mod revocation;
+mod routing;
+mod rules;
+mod security_headers;
+mod session;
+mod shadow;
+mod stats;
+mod synthetic;
+mod tenant;
+mod tenant_secrets;
+mod token;
+mod trace;
+mod turnstile;
+mod upstream;
+mod verify;
+mod webhook;
+
+use access::AccessJwtMode;
+use config::{TokenRefresh, ValidationMode};
+use errors::Rejection;
+use events::{AuditOutcome, Event, ValidationEvent};
+use pipeline::{Flow, RequestContext, ResponseContext, Stage};
+use policy::{FunctionPolicy, TokenField};
+use reason::Reason;
+use tenant::{SignatureMode, TenantConfig};
+use token::{canonical_kid, HashEncoding, HmacAlgorithm, HmacAlgorithms, TimestampUnit};
+use verify::Verified;
+
+// Entry points for the fuzz targets in `fuzz/` and the property tests; not part of the Worker's
+// interface. Everything reached from here must be safe to run natively: no logging, no clock.
+#[cfg(any(test, feature = "fuzzing"))]
+#[doc(hidden)]
+pub mod fuzzing {
+    use super::*;
+    use token::{parse_ed25519_public_key, parse_hmac_token};
+
+    // Runs one adversarial input through every parser a client can reach with it. Only panics
+    // and hangs are failures; rejecting the input is the expected outcome.
+    pub fn token_parsing(data: &[u8]) {
+        let Ok(input) = std::str::from_utf8(data) else {
+            return;
+        };
+
+        // Every prefix is allowed, so the whole grammar is reachable
+        let algorithms = HmacAlgorithms::only(HmacAlgorithm::Sha256)
+            .with(HmacAlgorithm::Sha384)
+            .with(HmacAlgorithm::Sha512);
+        for algorithms in [HmacAlgorithms::only(HmacAlgorithm::Sha256), algorithms] {
+            let Some(token) = parse_hmac_token(input, algorithms) else {
+                continue;
+            };
+            let _ = token.allows_audience("login.example.com");
+            let expected_tag = token::generate_tag(
+                token.algorithm,
+                "192.0.2.1",
+                DEFAULT_HMAC_SECRET,
+                token.timestamp,
+                &token.audiences,
+                token.lifetime,
+            );
+            for encoding in [
+                HashEncoding::Hex,
+                HashEncoding::Base64,
+                HashEncoding::Base64Url,
+            ] {
+                if let Some(provided_tag) = encoding.decode(token.hash) {
+                    assert!(!token::constant_time_compare(&expected_tag, &provided_tag));
+                }
+            }
+        }
+
+        for delimiter in [oait::DEFAULT_DELIMITER, "+", "|", ""] {
+            assert!(!oait::split(input, delimiter).is_empty());
+        }
+        for token_format in [
+            oait::TokenFormat::Oait,
+            oait::TokenFormat::Params,
+            oait::TokenFormat::Both,
+        ] {
+            let _ = get_url_query(Some(input), token_format);
+        }
+
+        if let Some(token) = encrypted_token::parse(input) {
+            assert!(encrypted_token::open(&[0; 32], token.sealed).is_none());
+        }
+        let _ = parse_ed25519_public_key(input);
+    }
+}
+
 const DEFAULT_HMAC_SECRET: &str = "default-secret";
-const TOKEN_VALIDITY_SECONDS: f64 = 300.0;
+const PRODUCTION_ENVIRONMENT: &str = "production";
 
 #[event(fetch)]
-async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
+async fn fetch(req: Request, env: Env, ctx: Context) -> Result<Response> {
     console_error_panic_hook::set_once();
 
-    let secret: String = env
-        .secret("HMAC_SECRET")
-        .map(|v| v.to_string())
-        .unwrap_or(DEFAULT_HMAC_SECRET.to_string());
+    let url = req.url()?;
+    if config::is_health_request(&url) {
+        return config::health(&req, &env);
+    }
+    if metrics::is_metrics_request(&url, &env) {
+        return metrics::handle(req, &env).await;
+    }
+    if admin::is_admin_request(&url, &env) {
+        return admin::handle(req, &env, &ctx).await;
+    }
+
+    if let Some(synthetic) = synthetic::synthetic_response(&env, url.path()) {
+        return synthetic.into_response();
+    }
+
+    let host = url.host_str().unwrap_or_default().to_string();
+    let rules = rules::current(&env).await;
+    let tenant = tenant::tenant_config(&env, &rules, &host, url.path());
+
+    let request_headers = req.headers().clone();
+    let response = match handle_request(req, &env, &ctx, &host, &rules, &tenant).await {
+        Ok(response) => response,
+        Err(e) => {
+            console_error!("Request failed: {}", e);
+            return Rejection::new(500, Reason::InternalError, "Internal error")
+                .json_response(&request_headers, &env);
+        }
+    };
+    match debug_summary(&env, &tenant) {
+        Some(summary) => with_debug_header(response, &summary),
+        None => Ok(response),
+    }
+}
+
+#[event(queue)]
+async fn queue(
+    batch: MessageBatch<parking::ParkedRequest>,
+    _env: Env,
+    _ctx: Context,
+) -> Result<()> {
+    parking::replay(batch).await
+}
+
+#[event(scheduled)]
+async fn scheduled(event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    console_error_panic_hook::set_once();
+    housekeeping::run(&env, &event).await;
+}
+
+async fn handle_request(
+    req: Request,
+    env: &Env,
+    ctx: &Context,
+    host: &str,
+    rules: &rules::RuleSet,
+    tenant: &TenantConfig,
+) -> Result<Response> {
+    let lookups = lookup_budget::LookupBudget::new(env);
+    let Some(secret) =
+        tenant_secrets::resolve(env, &lookups, tenant.secret.as_ref(), hmac_secret(env)).await
+    else {
+        return Rejection::new(
+            503,
+            Reason::VerificationUnavailable,
+            "Token verification unavailable",
+        )
+        .into_response(req.headers(), env)
+        .await;
+    };
 
     // Parse URL once
     let url_str = req.url().expect("URL not provided");
-    let url = Url::parse(&url_str.to_string())?;
+    let url = Url::parse(url_str.as_ref())?;
+
+    // Webhook endpoints carry no function_id or oait; their signature header is the whole check
+    if let Some(webhook) = &tenant.webhook {
+        if let Err(rejection) = verify_webhook(env, &secret, webhook, &req).await {
+            return rejection.into_response(req.headers(), env).await;
+        }
+        return encoding::send(reason::without_validation_header(req)?).await;
+    }
+
+    let UrlQuery {
+        function_id,
+        oait: oait_param_opt,
+        token_params,
+        retained_params,
+    } = get_url_query(url.query(), oait::token_format(env));
+
+    let mut request_cx = RequestContext {
+        req: &req,
+        env,
+        url: &url,
+        tenant,
+        rules,
+        secret: &secret,
+        client_ip: client_ip::client_ip(env, req.headers()),
+        function_id,
+        websocket_upgrade: forwarding::is_websocket_upgrade(req.headers()),
+        step_up: false,
+        policy: FunctionPolicy::default(),
+        body: Vec::new(),
+        csrf_cookie: None,
+    };
+    // Preflights carry no token and are answered first. Edge signals and network lists come next,
+    // so denied clients never reach the origin at all.
+    let request_stages: [&dyn Stage<_>; 9] = [
+        &cors::PreflightStage,
+        &geo_policy::GeoPolicyStage,
+        &ip_policy::IpPolicyStage,
+        &external_verifier::ExternalVerifierStage,
+        &policy::FunctionPolicyStage,
+        &flags::FeatureFlagsStage,
+        &forwarding::WebSocketStage,
+        &body_limit::BodyLimitStage,
+        &csrf::CsrfStage,
+    ];
+    let flow = pipeline::run(&request_stages, &mut request_cx).await?;
+    let RequestContext {
+        client_ip,
+        function_id,
+        websocket_upgrade,
+        policy,
+        body,
+        csrf_cookie,
+        ..
+    } = request_cx;
+    match flow {
+        Flow::Continue => {}
+        Flow::Bypass => return encoding::send(reason::without_validation_header(req)?).await,
+        Flow::Reject(rejection) => return rejection.into_response(req.headers(), env).await,
+        Flow::Respond(response) => return Ok(response),
+    }
+    let function_id = function_id.unwrap_or_default();
+
+    let tenant_key = tenant::tenant_key(env, host, url.path());
+    let log = request_log::RequestLog::new(request_log::log_settings(env, rules, tenant));
+    let tracer = trace::Tracer::from_request(env, ctx, req.headers());
+    let validation_span = tracer.as_ref().map(trace::Tracer::validation_span);
+    let upstream_span = tracer.as_ref().map(trace::Tracer::upstream_span);
+    let access_settings = access::access_settings(env);
+    // A verified Access JWT stands in for the cloudflare token; oait then only carries the other parts
+    let jwt_replaces_oait = access_settings
+        .as_ref()
+        .is_some_and(|settings| settings.mode == AccessJwtMode::Replace);
+
+    // Signed requests carry their proof in headers, so the cloudflare token is optional for them too
+    let cloudflare_token_optional =
+        jwt_replaces_oait || tenant.signature_mode == SignatureMode::Request;
+
+    // Legacy forms may post oait in the body instead of the query string
+    let form_body = match (
+        &oait_param_opt,
+        &token_params,
+        form_token::form_settings(env),
+    ) {
+        (None, None, Some(settings)) => form_token::read(&settings, &req, &body),
+        _ => None,
+    };
+    let oait_param_opt =
+        oait_param_opt.or_else(|| form_body.as_ref().map(|form| form.oait.clone()));
+    let parsed_tokens = match token_params {
+        Some(params) if oait_param_opt.is_none() => {
+            parse_token_params(params, cloudflare_token_optional)
+        }
+        _ => parse_oait(
+            oait_param_opt,
+            &oait::delimiter(env),
+            cloudflare_token_optional,
+        ),
+    }
+    .and_then(|tokens| require_fields(tokens, &policy.required_fields));
+    if let Ok(tokens) = &parsed_tokens {
+        log.line(format!(
+            "formsToken:{}, clientIP:{}, providedToken:{}, accessToken:{}",
+            log.token(&tokens.forms_token),
+            client_ip,
+            log.token(&tokens.cloudflare_token),
+            log.token(&tokens.access_token)
+        ));
+    }
 
-    let (is_login_function, oait_param_opt, retained_pairs): (
-        bool,
-        Option<String>,
-        Vec<(String, String)>,
-    ) = get_url_query(url.query());
+    let do_budget = do_budget::DoBudget::new(ctx, env);
+    let verifier = verify::Verifier {
+        env,
+        host,
+        tenant,
+        client_ip: &client_ip,
+        req: &req,
+        body: &body,
+        url: &url,
+        secret: &secret,
+        policy: &policy,
+        access_settings: access_settings.as_ref(),
+        do_budget: &do_budget,
+        lookups: &lookups,
+        log: &log,
+    };
+    let verification = verifier.request(&parsed_tokens);
 
-    if !is_login_function {
-        console_error!("missing function_id - bypassing HMAC validation");
-        return Fetch::Request(req).send().await;
+    let header_rules: forwarding::HeaderRules =
+        object_var(env, "UPSTREAM_HEADER_RULES").unwrap_or_default();
+    let upstream_headers = forwarding::upstream_headers(
+        req.headers(),
+        &client_ip,
+        &url,
+        &header_rules,
+        websocket_upgrade,
+    )?;
+    if let (Some(tracer), Some(span)) = (&tracer, &upstream_span) {
+        tracer.propagate(span, &upstream_headers)?;
     }
+    let retained_params = protected_params::rewrite(
+        &policy.protected_params,
+        &url,
+        retained_params,
+        &upstream_headers,
+    )?;
+
+    let response_stages: [&dyn Stage<ResponseContext>; 9] = [
+        &origin_response::OriginResponseStage,
+        &cookies::AccessCookieStage,
+        &session::SessionCookieStage,
+        &continuation::ContinuationStage,
+        &csrf::CsrfCookieStage,
+        &RefreshedTokenStage,
+        &canary::CanaryStage,
+        &cors::CorsStage,
+        &security_headers::SecurityHeadersStage,
+    ];
+    let body_mode = if response_stages.iter().any(|stage| stage.reads_body()) {
+        encoding::BodyMode::Decoded
+    } else {
+        encoding::BodyMode::Passthrough
+    };
+
+    let (tokens, verdict, mut new_response, backend) = match validation_mode(env) {
+        ValidationMode::Enforce => {
+            let sessions = session::session_settings(env);
+            let session = match &sessions {
+                Some(sessions) => {
+                    session::resume(sessions, req.headers(), &secret, &client_ip, &do_budget).await
+                }
+                None => None,
+            };
+            let resumed = session.is_some();
+            let continuation_ttl = continuation::continuation_ttl_seconds(env);
+            let binding = continuation::Binding {
+                client_ip: &client_ip,
+                host,
+                function_id: &function_id,
+            };
+            let continuation = match (resumed, continuation_ttl) {
+                (false, Some(_)) => continuation::resume(req.headers(), &secret, binding),
+                _ => None,
+            };
+            let continued = continuation.is_some();
+            // A live session or continuation stands in for the token check; oait then only
+            // carries the other parts. The checks that can change after the token was accepted
+            // still run.
+            let verified = if resumed || continued {
+                let (kid, token_digest) = match (session, continuation) {
+                    (Some(session), _) => (session.kid, session.token_digest),
+                    (None, continuation) => (
+                        None,
+                        continuation.and_then(|continuation| continuation.token_digest),
+                    ),
+                };
+                verifier
+                    .resumed(token_digest.as_deref(), kid.as_deref())
+                    .await
+                    .map(|()| {
+                        let verified = Verified {
+                            resumed_session: resumed,
+                            continued,
+                            kid,
+                            token_digest,
+                            ..Verified::default()
+                        };
+                        (verified, parsed_tokens.clone().unwrap_or_default())
+                    })
+            } else {
+                trace::in_span(
+                    tracer.as_ref(),
+                    validation_span,
+                    verification,
+                    verdict_status,
+                )
+                .await
+                .and_then(|verified| parsed_tokens.map(|tokens| (verified, tokens)))
+            };
+            let (mut verified, tokens) = match verified {
+                Ok(verified) => verified,
+                Err(rejection) => {
+                    record_decision(
+                        ctx,
+                        env,
+                        &log,
+                        &audit::Decision {
+                            host,
+                            path: url.path(),
+                            tenant: &tenant_key,
+                            function_id: &function_id,
+                            client_ip: &client_ip,
+                            outcome: AuditOutcome::Deny,
+                            enforced: true,
+                            status: rejection.status,
+                            reason: rejection.message,
+                            code: rejection.code,
+                        },
+                    );
+                    return rejection.into_response(req.headers(), env).await;
+                }
+            };
+            if let (Some(sessions), false) = (&sessions, resumed) {
+                let cookie_domain = cookies::cookie_domain(env, tenant);
+                match session::establish(
+                    sessions,
+                    &secret,
+                    &client_ip,
+                    verified.kid.as_deref(),
+                    verified.token_digest.as_deref(),
+                    cookie_domain.as_deref(),
+                    &do_budget,
+                )
+                .await
+                {
+                    Ok(cookie) => verified.session_cookie = Some(cookie),
+                    Err(e) => console_error!("Failed to establish session: {}", e),
+                }
+            }
+            // Only a token check mints a continuation, so a redirect chain cannot extend its own
+            if let (Some(ttl_seconds), false, false) = (continuation_ttl, resumed, continued) {
+                verified.continuation_cookie = Some(continuation::issue(
+                    &secret,
+                    binding,
+                    verified.token_digest.as_deref(),
+                    js_sys::Date::now() / 1000.0,
+                    ttl_seconds,
+                    cookies::cookie_domain(env, tenant).as_deref(),
+                ));
+            }
+            let backend = match &policy.canary {
+                Some(canary) => canary::select(
+                    canary,
+                    &function_id,
+                    req.headers(),
+                    &client_ip,
+                    cookies::cookie_domain(env, tenant).as_deref(),
+                )?,
+                None => None,
+            };
+            let upstream = backend
+                .as_ref()
+                .and_then(|backend| backend.upstream.as_deref())
+                .or(policy.upstream.as_deref());
+            let reason = if resumed {
+                Reason::SessionV1
+            } else if continued {
+                Reason::ContinuationV1
+            } else {
+                pass_reason(tenant, jwt_replaces_oait)
+            };
+            upstream_headers.set(
+                reason::VALIDATION_HEADER,
+                &reason::validation_header(reason, verified.kid.as_deref()),
+            )?;
+            let idempotency = idempotency::idempotency_settings(env)
+                .filter(|_| idempotency::applies(&req.method()));
+            let claim = match &idempotency {
+                Some(settings) => {
+                    idempotency::claim(
+                        settings,
+                        &do_budget,
+                        &req,
+                        &upstream_headers,
+                        &tenant_key,
+                        &function_id,
+                        &client_ip,
+                        &body,
+                    )
+                    .await?
+                }
+                None => idempotency::Claim::Proceed(None),
+            };
+            // A duplicate goes through the response stages like the answer it replays
+            let new_response = match claim {
+                idempotency::Claim::Refuse(rejection) => {
+                    return rejection.into_response(req.headers(), env).await
+                }
+                idempotency::Claim::Replay(response) => response,
+                idempotency::Claim::Proceed(reservation) => {
+                    let new_req = build_upstream_request(
+                        &req,
+                        &url,
+                        &retained_params,
+                        &tokens,
+                        form_body,
+                        &body,
+                        upstream,
+                        upstream_headers,
+                    )?;
+                    let new_response = trace::in_span(
+                        tracer.as_ref(),
+                        upstream_span,
+                        forward(env, ctx, new_req, req.headers(), &do_budget, body_mode),
+                        response_status,
+                    )
+                    .await?;
+                    match (&idempotency, reservation) {
+                        (Some(settings), Some(reservation)) => {
+                            idempotency::complete(settings, &do_budget, reservation, new_response)
+                                .await?
+                        }
+                        _ => new_response,
+                    }
+                }
+            };
+            (tokens, Ok(verified), new_response, backend)
+        }
+        // Log-only: validation runs alongside the upstream fetch so it adds almost no latency
+        ValidationMode::DryRun => {
+            let tokens = parsed_tokens.clone().unwrap_or_default();
+            let new_req = build_upstream_request(
+                &req,
+                &url,
+                &retained_params,
+                &tokens,
+                form_body,
+                &body,
+                policy.upstream.as_deref(),
+                upstream_headers,
+            )?;
+            let (verdict, new_response) = futures::future::join(
+                trace::in_span(
+                    tracer.as_ref(),
+                    validation_span,
+                    verification,
+                    verdict_status,
+                ),
+                trace::in_span(
+                    tracer.as_ref(),
+                    upstream_span,
+                    forward(env, ctx, new_req, req.headers(), &do_budget, body_mode),
+                    response_status,
+                ),
+            )
+            .await;
+            if let Err(rejection) = &verdict {
+                console_error!(
+                    "dry-run: would reject with {} ({}): {}",
+                    rejection.status,
+                    rejection.code.code(),
+                    rejection.message
+                );
+            }
+            (tokens, verdict, new_response?, None)
+        }
+    };
 
+    let (outcome, status, reason, code) = match &verdict {
+        Ok(verified) if verified.resumed_session => {
+            (AuditOutcome::Allow, 200, "session", Reason::SessionV1)
+        }
+        Ok(verified) if verified.continued => (
+            AuditOutcome::Allow,
+            200,
+            "continuation",
+            Reason::ContinuationV1,
+        ),
+        Ok(_) => (
+            AuditOutcome::Allow,
+            200,
+            "validated",
+            pass_reason(tenant, jwt_replaces_oait),
+        ),
+        Err(rejection) => (
+            AuditOutcome::Deny,
+            rejection.status,
+            rejection.message,
+            rejection.code,
+        ),
+    };
+    // An encrypted token's access token only becomes known once the token verified
+    let access_token = verdict
+        .as_ref()
+        .ok()
+        .and_then(|verified| verified.access_token.clone())
+        .unwrap_or_else(|| tokens.access_token.clone());
+    record_decision(
+        ctx,
+        env,
+        &log,
+        &audit::Decision {
+            host,
+            path: url.path(),
+            tenant: &tenant_key,
+            function_id: &function_id,
+            client_ip: &client_ip,
+            outcome,
+            enforced: validation_mode(env) == ValidationMode::Enforce,
+            status,
+            reason,
+            code,
+        },
+    );
+
+    if verdict.is_ok() {
+        fanout::notify(
+            ctx,
+            env,
+            &Event::TokenValidated(ValidationEvent {
+                schema_version: ValidationEvent::SCHEMA_VERSION,
+                host: host.to_string(),
+                path: url.path().to_string(),
+                hashed_ip: audit::hashed_ip(env, &client_ip),
+                client_ip: fanout::raw_client_ip(env).then(|| client_ip.clone()),
+                strategy: if verdict
+                    .as_ref()
+                    .is_ok_and(|verified| verified.resumed_session)
+                {
+                    "session".to_string()
+                } else if verdict.as_ref().is_ok_and(|verified| verified.continued) {
+                    "continuation".to_string()
+                } else if jwt_replaces_oait {
+                    "access-jwt".to_string()
+                } else {
+                    strategy_name(env, tenant)
+                },
+                has_access_token: !access_token.is_empty(),
+                validated_at: js_sys::Date::now(),
+            }),
+        );
+    }
+
+    // The upgrade response carries the socket pair and must reach the client untouched
+    if websocket_upgrade {
+        return Ok(new_response);
+    }
+
+    // Fetched response headers are immutable, so copy everything but the cookies, which are merged below
+    let new_headers = Headers::new();
+    for (name, value) in new_response.headers().entries() {
+        if !name.eq_ignore_ascii_case("Set-Cookie") {
+            new_headers.append(&name, &value)?;
+        }
+    }
+    let origin = req.headers().get("Origin")?;
+    let mut response_cx = ResponseContext {
+        env,
+        tenant,
+        url: &url,
+        request_headers: req.headers(),
+        function_id: &function_id,
+        status: new_response.status_code(),
+        policy: &policy,
+        verdict: &verdict,
+        access_token: &access_token,
+        csrf_cookie: csrf_cookie.as_deref(),
+        origin: origin.as_deref(),
+        backend: backend.as_ref(),
+        cookie_domain: cookies::cookie_domain(env, tenant),
+        headers: new_headers,
+        set_cookies: new_response.headers().get_all("Set-Cookie")?,
+        body: match body_mode {
+            encoding::BodyMode::Decoded => Some(new_response.bytes().await?),
+            encoding::BodyMode::Passthrough => None,
+        },
+    };
+    if let Flow::Respond(response) = pipeline::run(&response_stages, &mut response_cx).await? {
+        return Ok(response);
+    }
+    for cookie in &response_cx.set_cookies {
+        response_cx.headers.append("Set-Cookie", cookie)?;
+    }
+
+    let status = new_response.status_code();
+    match response_cx.body {
+        Some(body) => encoding::encoded(body, response_cx.headers, status, req.headers()),
+        None => encoding::rebuild(&new_response, response_cx.headers, status),
+    }
+}
+
+// Delivers a token refreshed during validation as `TOKEN_REFRESH` says
+struct RefreshedTokenStage;
+
+#[async_trait::async_trait(?Send)]
+impl<'a> Stage<ResponseContext<'a>> for RefreshedTokenStage {
+    async fn run(&self, cx: &mut ResponseContext<'a>) -> Result<Flow> {
+        let Some((refreshed_token, validity_seconds)) =
+            cx.verdict.as_ref().ok().and_then(|verified| {
+                Some((
+                    verified.refreshed_token.as_ref()?,
+                    verified.validity_seconds,
+                ))
+            })
+        else {
+            return Ok(Flow::Continue);
+        };
+        let refresh_mode = token_refresh_mode(cx.env);
+        if matches!(refresh_mode, TokenRefresh::Header | TokenRefresh::Both) {
+            cx.headers
+                .set("X-Validator-Refreshed-Token", refreshed_token)?;
+        }
+        if matches!(refresh_mode, TokenRefresh::Cookie | TokenRefresh::Both) {
+            // Readable by page scripts, which splice the fresh token back into oait
+            cx.set_cookies.push(format!(
+                "CF_Validator_Token={}; Path=/{}; Max-Age={}; Secure; SameSite=Strict",
+                urlencoding::encode(refreshed_token),
+                cookies::domain_attribute(cx.cookie_domain.as_deref()),
+                validity_seconds as u64
+            ));
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct OaitTokens {
+    forms_token: String,
+    cloudflare_token: String,
+    access_token: String,
+}
+
+// The oait parts carried as separate query parameters; each is `None` when absent
+#[derive(Clone, Debug, Default)]
+struct TokenParams {
+    forms_token: Option<String>,
+    cf_token: Option<String>,
+    access_token: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct UrlQuery {
+    function_id: Option<String>,
+    oait: Option<String>,
+    // Only set when `TOKEN_FORMAT` accepts separate parameters and the query has at least one
+    token_params: Option<TokenParams>,
+    // Every other parameter exactly as sent, in order and including repeats and bare keys
+    retained_params: Vec<String>,
+}
+
+fn parse_oait(
+    oait_param_opt: Option<String>,
+    delimiter: &str,
+    cloudflare_token_optional: bool,
+) -> std::result::Result<OaitTokens, Rejection> {
     let oait_param: String = match oait_param_opt {
         Some(v) => v,
+        None if cloudflare_token_optional => String::new(),
         None => {
             console_error!("Missing oait parameter");
-            return Ok(Response::from_html("Missing oait parameter")?.with_status(400));
+            return Err(Rejection::new(
+                400,
+                Reason::MissingToken,
+                "Missing oait parameter",
+            ));
         }
     };
 
-    let tokens: Vec<&str> = oait_param.split("++").collect();
-    if tokens.len() < 2 {
-        console_error!("Invalid token format-oaitParam: {}", oait_param);
-        return Ok(Response::from_html("Invalid token format")?.with_status(403));
+    let tokens = oait::split(&oait_param, delimiter);
+    if tokens.len() < 2 && !cloudflare_token_optional {
+        console_error!("Invalid token format ({} bytes)", oait_param.len());
+        return Err(Rejection::new(
+            403,
+            Reason::MalformedToken,
+            "Invalid token format",
+        ));
     }
 
-    let forms_token = tokens[0];
-    let cloudflare_token = urlencoding::decode(tokens[1].trim()).map_err(|e| {
-        console_error!("Failed to decode token: {}", e);
-        Error::RustError("Invalid token encoding".into())
-    })?;
-    let access_token = tokens.get(2).unwrap_or(&"");
+    Ok(OaitTokens {
+        forms_token: tokens[0].clone(),
+        cloudflare_token: decode_cloudflare_token(tokens.get(1).map_or("", String::as_str))?,
+        access_token: tokens.get(2).cloned().unwrap_or_default(),
+    })
+}
 
-    let client_ip = extract_client_ip(&req.headers());
-    console_log!(
-        "formsToken:{}, clientIP:{}, providedToken:{}, accessToken:{}",
-        forms_token,
-        client_ip,
+fn parse_token_params(
+    params: TokenParams,
+    cloudflare_token_optional: bool,
+) -> std::result::Result<OaitTokens, Rejection> {
+    let cloudflare_token = match params.cf_token {
+        Some(token) => decode_cloudflare_token(&token)?,
+        None if cloudflare_token_optional => String::new(),
+        None => {
+            console_error!("Missing {} parameter", oait::CF_TOKEN_PARAM);
+            return Err(Rejection::new(
+                400,
+                Reason::MissingToken,
+                "Missing cf_token parameter",
+            ));
+        }
+    };
+
+    Ok(OaitTokens {
+        forms_token: params.forms_token.unwrap_or_default(),
         cloudflare_token,
-        access_token
-    );
+        access_token: params.access_token.unwrap_or_default(),
+    })
+}
 
-    let token_validity_seconds = env
-        .var("TOKEN_VALIDITY_SECONDS")
-        .map(|value| value.to_string().parse::<f64>().unwrap())
-        .unwrap_or(TOKEN_VALIDITY_SECONDS);
+fn decode_cloudflare_token(token: &str) -> std::result::Result<String, Rejection> {
+    urlencoding::decode(token.trim())
+        .map(|token| token.into_owned())
+        .map_err(|e| {
+            console_error!("Failed to decode token: {}", e);
+            Rejection::new(500, Reason::MalformedToken, "Invalid token encoding")
+        })
+}
 
-    if !verify_hmac_token(
-        &client_ip,
-        &cloudflare_token,
-        &secret,
-        token_validity_seconds,
-    ) {
-        return Ok(Response::from_html("Invalid or expired token")?.with_status(403));
+fn require_fields(
+    tokens: OaitTokens,
+    required_fields: &[TokenField],
+) -> std::result::Result<OaitTokens, Rejection> {
+    let missing = required_fields.iter().find(|field| match field {
+        TokenField::Forms => tokens.forms_token.is_empty(),
+        TokenField::Cloudflare => tokens.cloudflare_token.is_empty(),
+        TokenField::Access => tokens.access_token.is_empty(),
+    });
+    match missing {
+        Some(field) => {
+            console_error!("Missing required {:?} token", field);
+            Err(Rejection::new(
+                400,
+                Reason::MissingToken,
+                "Missing required token",
+            ))
+        }
+        None => Ok(tokens),
     }
+}
 
-    // Rebuild URL: start from parsed url, clear query and append retained pairs (avoids reparsing original string)
-    let mut new_url = Url::parse(&url_str.to_string())?;
-    new_url.query_pairs_mut().clear();
-    for (k, v) in retained_pairs {
-        new_url.query_pairs_mut().append_pair(&k, &v);
-    }
-    if !forms_token.is_empty() {
-        new_url.query_pairs_mut().append_pair("oait", &forms_token);
+#[allow(clippy::too_many_arguments)]
+fn build_upstream_request(
+    req: &Request,
+    url: &Url,
+    retained_params: &[String],
+    tokens: &OaitTokens,
+    form_body: Option<form_token::FormBody>,
+    body: &[u8],
+    upstream: Option<&str>,
+    headers: Headers,
+) -> Result<Request> {
+    let mut new_url = url.clone();
+    if let Some(upstream) = upstream {
+        match Url::parse(upstream) {
+            Ok(origin) => {
+                let _ = new_url.set_scheme(origin.scheme());
+                let _ = new_url.set_host(origin.host_str());
+                let _ = new_url.set_port(origin.port());
+            }
+            Err(e) => console_error!("Invalid upstream {}: {}", upstream, e),
+        }
     }
+    // A token posted in the form body is forwarded there instead
+    let forms_token =
+        Some(tokens.forms_token.as_str()).filter(|token| !token.is_empty() && form_body.is_none());
+    rewrite_query(&mut new_url, retained_params, forms_token);
 
     let mut request_init = RequestInit::new();
     request_init.with_method(req.method());
-    request_init.with_headers(req.headers().clone());
-    // clone only to read body if necessary (keeps original `req` available for headers/method)
-    let body = req.clone()?.bytes().await?;
+    let body = match form_body {
+        Some(form_body) => {
+            // The rewritten form may be shorter; let the runtime set the length
+            headers.delete("Content-Length")?;
+            std::borrow::Cow::Owned(form_body.forwarded(&tokens.forms_token))
+        }
+        None => std::borrow::Cow::Borrowed(body),
+    };
+    request_init.with_headers(headers);
     if !body.is_empty() {
-        request_init.with_body(Some(JsValue::from(body)));
+        request_init.with_body(Some(js_sys::Uint8Array::from(&*body).into()));
     }
-    let new_req = Request::new_with_init(&new_url.to_string(), &request_init)?;
+    Request::new_with_init(new_url.as_ref(), &request_init)
+}
 
-    let new_response = Fetch::Request(new_req).send().await?;
-    let new_headers = new_response.headers().clone();
+fn verdict_status(verdict: &std::result::Result<Verified, Rejection>) -> u16 {
+    verdict
+        .as_ref()
+        .map_or_else(|rejection| rejection.status, |_| 200)
+}
 
-    // Add access token cookie if available
-    if !access_token.is_empty() {
-        new_headers.set(
-            "Set-Cookie",
-            &format!(
-                "CF_Authorization={}; Path=/; HttpOnly; Secure; SameSite=Strict",
-                access_token
-            ),
-        )?;
+// Unreachable origins count as a bad gateway
+fn response_status(response: &Result<Response>) -> u16 {
+    response.as_ref().map_or(502, Response::status_code)
+}
+
+// Every allow/deny decision feeds the audit queue, the usage stats and the metrics
+fn record_decision(
+    ctx: &Context,
+    env: &Env,
+    log: &request_log::RequestLog,
+    decision: &audit::Decision,
+) {
+    log.record(decision);
+    audit::record(ctx, env, decision);
+    stats::record(ctx, env, decision);
+    metrics::record_decision(ctx, env, decision);
+}
+
+// Validated GETs may be answered from the Cache API, and idempotent POSTs parked on a queue while
+// the origin is down; everything else goes straight upstream
+async fn fetch_upstream(
+    env: &Env,
+    ctx: &Context,
+    new_req: Request,
+    body_mode: encoding::BodyMode,
+    client_keyed: bool,
+) -> Result<Response> {
+    let upstream = upstream::upstream_settings(env);
+    if let Some(queue) =
+        parking::park_queue(env).filter(|_| parking::is_parkable(&new_req.method(), client_keyed))
+    {
+        let parked = new_req.clone()?;
+        match upstream::send(&upstream, new_req, body_mode, client_keyed).await {
+            Ok(response) if !parking::origin_unavailable(response.status_code()) => {
+                return Ok(response)
+            }
+            Ok(response) => console_error!("Origin answered {}", response.status_code()),
+            Err(e) => console_error!("Origin unreachable: {}", e),
+        }
+        return parking::park(&queue, parked).await;
     }
 
-    Ok(Response::from_body(new_response.body().clone())?
-        .with_headers(new_headers)
-        .with_status(new_response.status_code()))
+    let settings = match response_cache::cache_settings(env) {
+        Some(settings) if new_req.method() == Method::Get => settings,
+        _ => return upstream::send(&upstream, new_req, body_mode, client_keyed).await,
+    };
+
+    let key = response_cache::cache_key(&new_req.url()?);
+    match response_cache::lookup(&key).await {
+        Ok(Some(cached)) => return Ok(cached),
+        Ok(None) => {}
+        Err(e) => console_error!("Cache lookup failed: {}", e),
+    }
+
+    let response = upstream::send(&upstream, new_req, body_mode, client_keyed).await?;
+    response_cache::store(ctx, &settings, key, response)
+}
+
+// Sends the validated request through the origin's circuit breaker, if one is bound. An open
+// circuit gets a 503 and an origin that never answered a 504, both rendered like any rejection.
+// Retries and parking are decided from the client's own headers, not the upstream request's.
+async fn forward(
+    env: &Env,
+    ctx: &Context,
+    new_req: Request,
+    request_headers: &Headers,
+    do_budget: &do_budget::DoBudget<'_>,
+    body_mode: encoding::BodyMode,
+) -> Result<Response> {
+    let breaker = circuit_breaker::circuit_breaker(env, &new_req.url()?);
+    let admission = match &breaker {
+        Some(breaker) => breaker.admit(do_budget).await,
+        None => circuit_breaker::Admission::Closed,
+    };
+    if admission == circuit_breaker::Admission::Open {
+        metrics::record_circuit_open(ctx, env);
+        return Rejection::new(503, Reason::OriginUnavailable, "Origin unavailable")
+            .into_response(request_headers, env)
+            .await;
+    }
+
+    let started = Date::now().as_millis();
+    let client_keyed = idempotency::idempotency_key(request_headers).is_some();
+    let response = fetch_upstream(env, ctx, new_req, body_mode, client_keyed).await;
+    let status = response.as_ref().ok().map(Response::status_code);
+    let elapsed_seconds = (Date::now().as_millis() - started) as f64 / 1000.0;
+    metrics::record_upstream(ctx, env, elapsed_seconds, status);
+    if let Some(breaker) = &breaker {
+        breaker
+            .record(admission, circuit_breaker::is_failure(status), do_budget)
+            .await;
+    }
+    match response {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            console_error!("Origin unreachable: {}", e);
+            Rejection::new(504, Reason::OriginUnreachable, "Origin unreachable")
+                .into_response(request_headers, env)
+                .await
+        }
+    }
+}
+
+// Object vars are not strings, so probe for them directly rather than through `env.var`
+fn object_var<T: DeserializeOwned>(env: &Env, name: &str) -> Option<T> {
+    if !js_sys::Reflect::has(env, &name.into()).unwrap_or(false) {
+        return None;
+    }
+
+    env.object_var(name)
+        .map_err(|e| console_error!("Invalid {} config: {}", name, e))
+        .ok()
 }
 
-fn extract_client_ip(headers: &Headers) -> String {
-    headers
-        .get("CF-Connecting-IP")
+fn var_or(env: &Env, name: &str, default: u32) -> u32 {
+    env.var(name)
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .unwrap_or(default)
+}
+
+fn validation_mode(env: &Env) -> ValidationMode {
+    config::config(env).validation_mode
+}
+
+// `WEBSOCKET_VALIDATION=skip` lets upgrade requests through without a token
+fn websocket_validation_required(env: &Env) -> bool {
+    config::config(env).websocket_validation_required
+}
+
+// `TOKEN_REFRESH_MAX_SESSION_SECONDS` caps how long a chain of refreshed tokens can last
+fn max_session_seconds(env: &Env) -> Option<f64> {
+    config::config(env).max_session_seconds
+}
+
+fn token_refresh_mode(env: &Env) -> TokenRefresh {
+    config::config(env).token_refresh
+}
+
+fn token_validity_seconds(env: &Env) -> f64 {
+    config::config(env).token_validity_seconds
+}
+
+// Nonce tokens are not tied to the client, so they get a much shorter window
+fn nonce_token_validity_seconds(env: &Env) -> f64 {
+    config::config(env).nonce_token_validity_seconds
+}
+
+// A function policy's validity window wins over a `VALIDITY_OVERRIDES` match, which wins over
+// `TOKEN_VALIDITY_SECONDS`. Also names where the window came from, for the logs.
+fn validity_seconds(
+    env: &Env,
+    policy: &FunctionPolicy,
+    signals: &geo_policy::ClientSignals,
+) -> (f64, String) {
+    if let Some(seconds) = policy.validity_seconds {
+        return (seconds, "function_policy".to_string());
+    }
+    match geo_policy::validity_overrides(env)
+        .and_then(|overrides| geo_policy::validity_override(&overrides, signals))
+    {
+        Some((seconds, reason)) => (seconds, reason.to_string()),
+        None => (token_validity_seconds(env), "default".to_string()),
+    }
+}
+
+// The longest any token without an expiry of its own may be accepted, across every policy, override
+// and the nonce window
+async fn longest_validity_seconds(env: &Env) -> f64 {
+    let rules = rules::current(env).await;
+    [
+        policy::longest_validity_seconds(env, &rules),
+        geo_policy::validity_overrides(env).and_then(|overrides| overrides.longest()),
+        Some(nonce_token_validity_seconds(env)),
+    ]
+    .into_iter()
+    .flatten()
+    .fold(token_validity_seconds(env), f64::max)
+}
+
+fn hmac_algorithm(env: &Env) -> HmacAlgorithm {
+    config::config(env).hmac_algorithm
+}
+
+fn hmac_algorithms(env: &Env) -> HmacAlgorithms {
+    config::config(env).hmac_algorithms
+}
+
+async fn verify_webhook(
+    env: &Env,
+    secret: &str,
+    webhook: &webhook::WebhookConfig,
+    req: &Request,
+) -> std::result::Result<(), Rejection> {
+    let verifier = webhook::verifier(webhook.profile);
+    let signature = req
+        .headers()
+        .get(verifier.signature_header())
         .ok()
         .flatten()
-        .or_else(|| {
-            headers
-                .get("X-Forwarded-For")
-                .ok()
-                .flatten()
-                .and_then(|xff| xff.split(',').next().map(|ip| ip.trim().to_string()))
-        })
-        .unwrap_or_else(|| {
-            console_error!("No client IP found in headers, using default");
-            "127.0.0.1".to_string()
-        })
+        .ok_or_else(|| {
+            console_error!("Missing {} header", verifier.signature_header());
+            Rejection::new(
+                401,
+                Reason::WebhookSignatureInvalid,
+                "Invalid webhook signature",
+            )
+        })?;
+    let Some(webhook_secret) = hmac_secret_for(env, secret, webhook.key_id.as_deref()) else {
+        return Err(Rejection::new(
+            500,
+            Reason::VerificationUnavailable,
+            "Webhook verification unavailable",
+        ));
+    };
+    // The body is buffered to be signed, so it gets the same ceiling as a protected request's
+    let limit = body_limit::max_body_bytes(env, &req.path());
+    let body = match body_limit::read(req, limit.max_bytes).await {
+        Ok(Some(body)) => body,
+        Ok(None) => {
+            console_error!("Webhook body exceeds {}", limit.var);
+            return Err(Rejection::new(
+                413,
+                Reason::BodyTooLarge,
+                "Request body too large",
+            ));
+        }
+        Err(e) => {
+            console_error!("Failed to read webhook body: {}", e);
+            Vec::new()
+        }
+    };
+
+    if verifier.verify(
+        &signature,
+        &body,
+        &webhook_secret,
+        js_sys::Date::now() / 1000.0,
+        f64::from(webhook.tolerance_seconds),
+    ) {
+        Ok(())
+    } else {
+        console_error!("Webhook signature rejected ({:?})", webhook.profile);
+        Err(Rejection::new(
+            401,
+            Reason::WebhookSignatureInvalid,
+            "Invalid webhook signature",
+        ))
+    }
+}
+
+// Key ids select `HMAC_SECRET_{KID}`; requests without one use `HMAC_SECRET`
+fn hmac_secret(env: &Env) -> String {
+    env.secret("HMAC_SECRET")
+        .map(|v| v.to_string())
+        .unwrap_or(DEFAULT_HMAC_SECRET.to_string())
+}
+
+fn hmac_secret_for(env: &Env, default_secret: &str, kid: Option<&str>) -> Option<String> {
+    let Some(kid) = kid else {
+        return Some(default_secret.to_string());
+    };
+    match env.secret(&format!("HMAC_SECRET_{}", canonical_kid(kid))) {
+        Ok(secret) => Some(secret.to_string()),
+        Err(_) => {
+            console_error!("Unknown HMAC key id {}", kid);
+            None
+        }
+    }
 }
 
-fn get_url_query(query: Option<&str>) -> (bool, Option<String>, Vec<(String, String)>) {
+// Key ids select `TOKEN_ENCRYPTION_KEY_{KID}`; tokens without one use `TOKEN_ENCRYPTION_KEY`
+fn encryption_key_for(env: &Env, kid: Option<&str>) -> Option<[u8; 32]> {
+    let name = match kid {
+        Some(kid) => format!("TOKEN_ENCRYPTION_KEY_{}", canonical_kid(kid)),
+        None => "TOKEN_ENCRYPTION_KEY".to_string(),
+    };
+    let Ok(key) = env.secret(&name) else {
+        console_error!("{} is not set", name);
+        return None;
+    };
+    let key = encrypted_token::parse_key(&key.to_string());
+    if key.is_none() {
+        console_error!("{} must be 32 bytes of base64", name);
+    }
+    key
+}
+
+// `HMAC_TRUNCATION` maps key ids (or `default` for tokens without one) to a tag length in bits
+fn hmac_truncation(env: &Env, kid: Option<&str>, algorithm: HmacAlgorithm) -> Option<usize> {
+    let rules: std::collections::HashMap<String, u32> = object_var(env, "HMAC_TRUNCATION")?;
+    let bits = *rules.get(kid.unwrap_or("default"))?;
+    let truncated_len = token::truncated_tag_len(algorithm, bits);
+    if truncated_len.is_none() {
+        console_error!(
+            "Invalid HMAC_TRUNCATION of {} bits for {}; tags must be whole bytes between {} and {} bits",
+            bits,
+            algorithm.name(),
+            token::MIN_TRUNCATED_TAG_BITS,
+            algorithm.tag_bits()
+        );
+    }
+    truncated_len
+}
+
+fn timestamp_unit(env: &Env) -> TimestampUnit {
+    config::config(env).timestamp_unit
+}
+
+fn hash_encoding(env: &Env) -> HashEncoding {
+    config::config(env).hash_encoding
+}
+
+fn pass_reason(tenant: &TenantConfig, jwt_replaces_oait: bool) -> Reason {
+    if jwt_replaces_oait {
+        return Reason::AccessJwtV1;
+    }
+    match tenant.signature_mode {
+        SignatureMode::Hmac => Reason::HmacV1,
+        SignatureMode::Ed25519 => Reason::Ed25519V1,
+        SignatureMode::Request => Reason::RequestSignatureV1,
+        SignatureMode::Encrypted => Reason::EncryptedTokenV1,
+    }
+}
+
+fn strategy_name(env: &Env, tenant: &TenantConfig) -> String {
+    match tenant.signature_mode {
+        SignatureMode::Hmac => format!("hmac-{}", hmac_algorithm(env).name()),
+        SignatureMode::Ed25519 => "ed25519".to_string(),
+        SignatureMode::Request => "request-signing".to_string(),
+        SignatureMode::Encrypted => encrypted_token::PREFIX.to_string(),
+    }
+}
+
+// Summary of the active validation setup for staging verification; never emitted in production
+fn debug_summary(env: &Env, tenant: &TenantConfig) -> Option<String> {
+    let environment = env
+        .var("ENVIRONMENT")
+        .map(|v| v.to_string())
+        .unwrap_or(PRODUCTION_ENVIRONMENT.to_string());
+    if environment == PRODUCTION_ENVIRONMENT {
+        return None;
+    }
+
+    let config_version = env
+        .var("CONFIG_VERSION")
+        .map(|v| v.to_string())
+        .unwrap_or("unset".to_string());
+
+    let access_jwt = match access::access_settings(env).map(|settings| settings.mode) {
+        Some(AccessJwtMode::Additional) => "additional",
+        Some(AccessJwtMode::Replace) => "replace",
+        None => "off",
+    };
+
+    Some(format!(
+        "env={}; strategy={}; validity={}; dry_run={}; access_jwt={}; config_version={}",
+        environment,
+        strategy_name(env, tenant),
+        token_validity_seconds(env),
+        validation_mode(env) == ValidationMode::DryRun,
+        access_jwt,
+        config_version
+    ))
+}
+
+fn with_debug_header(response: Response, summary: &str) -> Result<Response> {
+    // Rebuilding a 101 response would drop its WebSocket
+    if response.status_code() == 101 {
+        return Ok(response);
+    }
+
+    // Fetched responses carry immutable headers, so copy them before adding ours
+    let headers = Headers::new();
+    for (name, value) in response.headers().entries() {
+        headers.append(&name, &value)?;
+    }
+    headers.set("X-Validator-Debug", summary)?;
+
+    encoding::rebuild(&response, headers, response.status_code())
+}
+
+fn get_url_query(query: Option<&str>, token_format: oait::TokenFormat) -> UrlQuery {
     let Some(query) = query else {
-        return (false, None, Vec::new());
+        return UrlQuery::default();
     };
 
-    let mut is_login = false;
-    let mut oait = None;
+    let mut parsed = UrlQuery::default();
+    let mut token_params = TokenParams::default();
+    let mut found_token_param = false;
 
-    let retained_pairs: Vec<_> = query
+    parsed.retained_params = query
         .split('&')
-        .filter_map(|pair| pair.split_once('='))
-        .filter_map(|(k, v)| match k {
-            "function_id" => {
-                is_login = v == "APPS_LOGIN_DEFAULT";
-                None
-            }
-            "oait" => {
-                oait = Some(v.to_string());
-                None
-            }
-            _ => Some((k.to_string(), v.to_string())),
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            // Bare keys such as `?debug` carry nothing we read, so they are always kept
+            let Some((k, v)) = pair.split_once('=') else {
+                return Some(pair.to_string());
+            };
+            let slot = match k {
+                "function_id" => &mut parsed.function_id,
+                "oait" if token_format.accepts_oait() => &mut parsed.oait,
+                oait::FORMS_TOKEN_PARAM if token_format.accepts_params() => {
+                    &mut token_params.forms_token
+                }
+                oait::CF_TOKEN_PARAM if token_format.accepts_params() => &mut token_params.cf_token,
+                oait::ACCESS_TOKEN_PARAM if token_format.accepts_params() => {
+                    &mut token_params.access_token
+                }
+                _ => return Some(pair.to_string()),
+            };
+            found_token_param |= oait::is_token_param(k);
+            *slot = Some(v.to_string());
+            None
         })
         .collect();
 
-    (is_login, oait, retained_pairs)
+    parsed.token_params = found_token_param.then_some(token_params);
+    parsed
 }
 
-fn verify_hmac_token(
-    client_ip: &str,
-    provided_token: &str,
-    secret: &str,
-    validity_seconds: f64,
-) -> bool {
-    let token_parts: Vec<&str> = provided_token.split('-').collect();
-    if token_parts.len() != 2 {
-        return false;
+// The retained parameters are joined back unencoded, so values reach the origin byte for byte;
+// only the forms token, if any, is appended as `oait`
+fn rewrite_query(url: &mut Url, retained_params: &[String], forms_token: Option<&str>) {
+    let query = retained_params.join("&");
+    url.set_query(Some(query.as_str()).filter(|query| !query.is_empty()));
+    if let Some(forms_token) = forms_token {
+        url.query_pairs_mut().append_pair("oait", forms_token);
     }
+}
 
-    let timestamp: f64 = match token_parts[0].parse() {
-        Ok(ts) => ts,
-        Err(_) => return false,
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use token::issue_hmac_token;
+
+    // xorshift64, so failures reproduce from the seed without a dependency
+    struct Inputs(u64);
 
-    let provided_hash = token_parts[1];
-    let current_time = Date::now() / 1000.0;
+    impl Inputs {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
 
-    if current_time - timestamp > validity_seconds {
-        return false;
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
     }
-    let expected_hash = generate_hash(client_ip, secret, timestamp);
-    constant_time_compare(&expected_hash, provided_hash)
-}
 
-fn generate_hash(client_ip: &str, hmac_secret: &str, timestamp: f64) -> String {
-    let message = format!("{}:{}", client_ip, timestamp);
-    let mut mac = Hmac::<Sha256>::new_from_slice(hmac_secret.as_bytes())
-        .expect("HMAC can take key of any size");
-    mac.update(message.as_bytes());
-    BASE64_STANDARD.encode(mac.finalize().into_bytes())
-}
+    #[test]
+    fn adversarial_tokens_never_panic() {
+        // Bytes the token grammars give meaning to, plus some that no grammar expects
+        const ALPHABET: &[u8] =
+            b"sha256512.;:,-=+/_%&?ai nonceexpsinceanyipaes256gcm0123456789AF\xff\xc3\xa9";
+        let issued = [
+            issue_hmac_token(
+                HmacAlgorithm::Sha256,
+                Some("2"),
+                &["login.example.com"],
+                Some("192.0.2.1"),
+                "issuer-secret",
+                1693123456.0,
+                token::TokenLifetime {
+                    expires_at: Some(1693727256.0),
+                    session_start: Some(1693120000.0),
+                },
+                None,
+            ),
+            format!("nonce:{}:1693123456-abc", "n".repeat(24)),
+            "forms++1693123456-abc%2B%3D++access".to_string(),
+            "oait=a++b&function_id=F&cf_token=x&&q".to_string(),
+            "aes256gcm.2:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string(),
+        ];
 
-fn constant_time_compare(a: &str, b: &str) -> bool {
-    if a.len() != b.len() {
-        console_error!("length mismatch!");
-        return false;
+        let mut inputs = Inputs(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..20_000 {
+            let mut input = issued[inputs.below(issued.len())].as_bytes().to_vec();
+            match inputs.below(4) {
+                // Random bytes from the alphabet
+                0 => {
+                    let len = inputs.below(64);
+                    input = (0..len)
+                        .map(|_| ALPHABET[inputs.below(ALPHABET.len())])
+                        .collect();
+                }
+                // A byte of a real token replaced
+                1 if !input.is_empty() => {
+                    let at = inputs.below(input.len());
+                    input[at] = ALPHABET[inputs.below(ALPHABET.len())];
+                }
+                // A real token truncated
+                2 => input.truncate(inputs.below(input.len() + 1)),
+                // Bytes spliced into a real token
+                _ => {
+                    let at = inputs.below(input.len() + 1);
+                    let len = inputs.below(8);
+                    let spliced: Vec<u8> = (0..len)
+                        .map(|_| ALPHABET[inputs.below(ALPHABET.len())])
+                        .collect();
+                    input.splice(at..at, spliced);
+                }
+            }
+            fuzzing::token_parsing(&input);
+        }
+    }
+
+    fn rewritten(query: &str, forms_token: Option<&str>) -> String {
+        let parsed = get_url_query(Some(query), oait::TokenFormat::Oait);
+        let mut url = Url::parse(&format!("https://example.com/login?{}", query)).unwrap();
+        rewrite_query(&mut url, &parsed.retained_params, forms_token);
+        url.query().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn keeps_repeated_parameters_in_order() {
+        assert_eq!(
+            rewritten("id=1&function_id=F&id=2&oait=a++b&id=1", Some("a")),
+            "id=1&id=2&id=1&oait=a"
+        );
+        assert_eq!(
+            rewritten("tag[]=x&oait=a++b&tag[]=y&sort=name", None),
+            "tag[]=x&tag[]=y&sort=name"
+        );
+    }
+
+    #[test]
+    fn keeps_empty_values_and_bare_keys() {
+        assert_eq!(
+            rewritten("q=&debug&oait=a++b&&next=%2Fhome%20page", None),
+            "q=&debug&next=%2Fhome%20page"
+        );
+        assert_eq!(rewritten("oait=a++b", None), "");
+        assert_eq!(rewritten("oait=a++b", Some("a+/")), "oait=a%2B%2F");
+    }
+
+    #[test]
+    fn splits_oait_into_its_parts() {
+        let tokens = parse_oait(
+            Some("forms++1693123456-abc%2B%3D++access".to_string()),
+            "++",
+            false,
+        )
+        .unwrap();
+        assert_eq!(tokens.forms_token, "forms");
+        // The cloudflare token is percent-decoded; the others are passed on as sent
+        assert_eq!(tokens.cloudflare_token, "1693123456-abc+=");
+        assert_eq!(tokens.access_token, "access");
+
+        // Access JWT and request signing make the cloudflare token optional
+        let tokens = parse_oait(None, "++", true).unwrap();
+        assert!(tokens.forms_token.is_empty() && tokens.cloudflare_token.is_empty());
     }
 
-    a.bytes()
-        .zip(b.bytes())
-        .fold(0u8, |acc, (a, b)| acc | (a ^ b))
-        == 0
+    #[test]
+    fn required_fields_pass_when_present() {
+        let tokens = OaitTokens {
+            forms_token: "forms".to_string(),
+            cloudflare_token: "1693123456-abc".to_string(),
+            access_token: "access".to_string(),
+        };
+        assert!(require_fields(
+            tokens,
+            &[
+                TokenField::Forms,
+                TokenField::Cloudflare,
+                TokenField::Access
+            ]
+        )
+        .is_ok());
+        assert!(require_fields(OaitTokens::default(), &[]).is_ok());
+    }
+
+    #[test]
+    fn reads_token_parameters_out_of_the_query() {
+        let parsed = get_url_query(Some("function_id=F&oait=a++b&x=1"), oait::TokenFormat::Oait);
+        assert_eq!(parsed.function_id.as_deref(), Some("F"));
+        assert_eq!(parsed.oait.as_deref(), Some("a++b"));
+        assert_eq!(parsed.retained_params, vec!["x=1".to_string()]);
+    }
 }
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::pin;
+use std::time::Duration;
+
+use futures::future::{select, Either};
+use js_sys::Date;
+use serde::Deserialize;
+use worker::{console_error, Delay, Env};
+
+// KV reads the validation path waits on. Each degrades differently when it runs out of time.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Dependency {
+    // Falls back to the last known tenant secret, then the global one
+    TenantSecret,
+    // Falls back to `ED25519_PUBLIC_KEY` unless listed in `fail_closed`
+    PublicKey,
+    // Fails open unless listed in `fail_closed`
+    Revocation,
+}
+
+impl Dependency {
+    pub fn name(self) -> &'static str {
+        match self {
+            Dependency::TenantSecret => "tenant_secret",
+            Dependency::PublicKey => "public_key",
+            Dependency::Revocation => "revocation",
+        }
+    }
+}
+
+// `LOOKUP_BUDGET`, e.g. `{ "budget_ms": 150, "timeouts_ms": { "revocation": 50 }, "fail_closed":
+// ["revocation"] }`. Without it lookups wait as long as KV takes.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+struct LookupSettings {
+    // Shared by every lookup of one request, counted from when it arrived
+    budget_ms: Option<u32>,
+    timeouts_ms: HashMap<Dependency, u32>,
+    fail_closed: Vec<Dependency>,
+}
+
+// Caps how long one request waits on KV. Independent lookups run concurrently, so the budget
+// bounds the slowest of them rather than their sum.
+pub struct LookupBudget {
+    settings: LookupSettings,
+    started: f64,
+}
+
+impl LookupBudget {
+    pub fn new(env: &Env) -> Self {
+        Self {
+            settings: crate::object_var(env, "LOOKUP_BUDGET").unwrap_or_default(),
+            started: Date::now(),
+        }
+    }
+
+    pub fn fails_closed(&self, dependency: Dependency) -> bool {
+        self.settings.fail_closed.contains(&dependency)
+    }
+
+    // The lookup's own timeout, cut short by whatever is left of the budget
+    fn timeout_ms(&self, dependency: Dependency, elapsed_ms: f64) -> Option<u64> {
+        let remaining = self
+            .settings
+            .budget_ms
+            .map(|budget_ms| (f64::from(budget_ms) - elapsed_ms).max(0.0) as u64);
+        let own = self
+            .settings
+            .timeouts_ms
+            .get(&dependency)
+            .map(|&timeout_ms| u64::from(timeout_ms));
+        match (own, remaining) {
+            (Some(own), Some(remaining)) => Some(own.min(remaining)),
+            (own, remaining) => own.or(remaining),
+        }
+    }
+
+    // `None` means the lookup ran out of time and the caller degrades. A lookup that is already
+    // done, such as a cache hit, always wins, even once the budget is spent.
+    pub async fn within<T>(
+        &self,
+        dependency: Dependency,
+        lookup: impl Future<Output = T>,
+    ) -> Option<T> {
+        let Some(timeout_ms) = self.timeout_ms(dependency, Date::now() - self.started) else {
+            return Some(lookup.await);
+        };
+        let lookup = pin!(lookup);
+        let timeout = Delay::from(Duration::from_millis(timeout_ms));
+        match select(lookup, timeout).await {
+            Either::Left((value, _)) => Some(value),
+            Either::Right(_) => {
+                console_error!(
+                    "{} lookup exceeded {} ms, failing {}",
+                    dependency.name(),
+                    timeout_ms,
+                    if self.fails_closed(dependency) {
+                        "closed"
+                    } else {
+                        "open"
+                    }
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(settings: &str) -> LookupBudget {
+        LookupBudget {
+            settings: serde_json::from_str(settings).unwrap(),
+            started: 0.0,
+        }
+    }
+
+    #[test]
+    fn bounds_each_lookup_by_what_is_left_of_the_budget() {
+        let unlimited = budget("{}");
+        assert_eq!(unlimited.timeout_ms(Dependency::Revocation, 500.0), None);
+
+        let budget = budget(
+            r#"{ "budget_ms": 150, "timeouts_ms": { "revocation": 50 }, "fail_closed": ["revocation"] }"#,
+        );
+        assert_eq!(budget.timeout_ms(Dependency::Revocation, 0.0), Some(50));
+        assert_eq!(budget.timeout_ms(Dependency::Revocation, 120.0), Some(30));
+        assert_eq!(budget.timeout_ms(Dependency::PublicKey, 20.0), Some(130));
+        assert_eq!(budget.timeout_ms(Dependency::PublicKey, 200.0), Some(0));
+        assert!(budget.fails_closed(Dependency::Revocation));
+        assert!(!budget.fails_closed(Dependency::TenantSecret));
+    }
+}
@@ -0,0 +1,328 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::audit::Decision;
+use crate::events::AuditOutcome;
+
+const METRICS_PATH: &str = "/__metrics";
+const METRICS_OBJECT_NAME: &str = "global";
+
+// Upper bounds of the upstream latency histogram, in seconds
+const UPSTREAM_BUCKETS: [f64; 9] = [0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// Counts gathered since the last flush, or since the object started when it holds them
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetricsBatch {
+    // Keyed by metric name, then by the rendered label set
+    counters: BTreeMap<String, BTreeMap<String, u64>>,
+    // Per bucket, not cumulative; the last slot is `+Inf`
+    upstream_buckets: Vec<u64>,
+    upstream_sum_seconds: f64,
+    upstream_count: u64,
+}
+
+impl MetricsBatch {
+    fn count(&mut self, name: &str, labels: &[(&str, &str)]) {
+        self.add(name, labels, 1);
+    }
+
+    fn add(&mut self, name: &str, labels: &[(&str, &str)], value: u64) {
+        let labels = labels
+            .iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+        *self
+            .counters
+            .entry(name.to_string())
+            .or_default()
+            .entry(labels)
+            .or_default() += value;
+    }
+
+    fn observe_upstream(&mut self, seconds: f64) {
+        if self.upstream_buckets.is_empty() {
+            self.upstream_buckets = vec![0; UPSTREAM_BUCKETS.len() + 1];
+        }
+        let bucket = UPSTREAM_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(UPSTREAM_BUCKETS.len());
+        self.upstream_buckets[bucket] += 1;
+        self.upstream_sum_seconds += seconds;
+        self.upstream_count += 1;
+    }
+
+    fn merge(&mut self, other: MetricsBatch) {
+        for (name, series) in other.counters {
+            let merged = self.counters.entry(name).or_default();
+            for (labels, count) in series {
+                *merged.entry(labels).or_default() += count;
+            }
+        }
+        if !other.upstream_buckets.is_empty() {
+            if self.upstream_buckets.is_empty() {
+                self.upstream_buckets = vec![0; UPSTREAM_BUCKETS.len() + 1];
+            }
+            for (total, count) in self.upstream_buckets.iter_mut().zip(other.upstream_buckets) {
+                *total += count;
+            }
+        }
+        self.upstream_sum_seconds += other.upstream_sum_seconds;
+        self.upstream_count += other.upstream_count;
+    }
+
+    fn add_do_calls(&mut self, features: &BTreeMap<&'static str, (u32, u32)>) {
+        for (feature, &(made, skipped)) in features {
+            for (outcome, calls) in [("made", made), ("skipped", skipped)] {
+                if calls > 0 {
+                    self.add(
+                        "validator_do_calls_total",
+                        &[("feature", feature), ("outcome", outcome)],
+                        u64::from(calls),
+                    );
+                }
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.counters.is_empty() && self.upstream_count == 0
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn help(name: &str) -> &'static str {
+    match name {
+        "validator_validations_total" => "Validation decisions by outcome and reason",
+        "validator_errors_total" => "Upstream failures by class",
+        "validator_do_calls_total" => {
+            "Durable Object calls by feature, made or skipped over budget"
+        }
+        _ => "",
+    }
+}
+
+// Prometheus text exposition format, version 0.0.4
+pub fn render(batch: &MetricsBatch) -> String {
+    let mut out = String::new();
+    for (name, series) in &batch.counters {
+        let _ = writeln!(out, "# HELP {} {}", name, help(name));
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (labels, count) in series {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, count);
+        }
+    }
+
+    let name = "validator_upstream_duration_seconds";
+    let _ = writeln!(out, "# HELP {} Time until the origin answered", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    let mut cumulative = 0;
+    for (i, bound) in UPSTREAM_BUCKETS.iter().enumerate() {
+        cumulative += batch.upstream_buckets.get(i).copied().unwrap_or(0);
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    let _ = writeln!(
+        out,
+        "{}_bucket{{le=\"+Inf\"}} {}",
+        name, batch.upstream_count
+    );
+    let _ = writeln!(out, "{}_sum {}", name, batch.upstream_sum_seconds);
+    let _ = writeln!(out, "{}_count {}", name, batch.upstream_count);
+    out
+}
+
+thread_local! {
+    static PENDING: RefCell<MetricsBatch> = RefCell::new(MetricsBatch::default());
+}
+
+// Counts are buffered per isolate and flushed to the `METRICS_DO` object in the background, the
+// way audit events are, so concurrent requests share one call
+fn record(ctx: &Context, env: &Env, update: impl FnOnce(&mut MetricsBatch)) {
+    let Ok(namespace) = env.durable_object("METRICS_DO") else {
+        return;
+    };
+    PENDING.with(|pending| update(&mut pending.borrow_mut()));
+    ctx.wait_until(flush(namespace));
+}
+
+pub fn record_decision(ctx: &Context, env: &Env, decision: &Decision) {
+    let outcome = match decision.outcome {
+        AuditOutcome::Allow => "allow",
+        AuditOutcome::Deny => "deny",
+    };
+    record(ctx, env, |batch| {
+        batch.count(
+            "validator_validations_total",
+            &[("outcome", outcome), ("reason", decision.reason)],
+        )
+    });
+}
+
+// `status` is `None` when the origin never answered
+pub fn record_upstream(ctx: &Context, env: &Env, seconds: f64, status: Option<u16>) {
+    record(ctx, env, |batch| {
+        batch.observe_upstream(seconds);
+        match status {
+            None => batch.count(
+                "validator_errors_total",
+                &[("class", "upstream_unreachable")],
+            ),
+            Some(500..) => batch.count("validator_errors_total", &[("class", "upstream_5xx")]),
+            Some(_) => {}
+        }
+    });
+}
+
+pub fn record_circuit_open(ctx: &Context, env: &Env) {
+    record(ctx, env, |batch| {
+        batch.count("validator_errors_total", &[("class", "circuit_open")])
+    });
+}
+
+// A request's Durable Object calls, as (made, skipped) per feature
+pub fn record_do_calls(ctx: &Context, env: &Env, features: &BTreeMap<&'static str, (u32, u32)>) {
+    record(ctx, env, |batch| batch.add_do_calls(features));
+}
+
+// Sends whatever this isolate still holds, for the scheduled housekeeping run
+pub async fn flush_pending(env: &Env) {
+    if let Ok(namespace) = env.durable_object("METRICS_DO") {
+        flush(namespace).await;
+    }
+}
+
+async fn flush(namespace: ObjectNamespace) {
+    let batch = PENDING.with(|pending| std::mem::take(&mut *pending.borrow_mut()));
+    if batch.is_empty() {
+        return;
+    }
+    if let Err(e) = send(&namespace, &batch).await {
+        console_error!("Failed to flush metrics: {}", e);
+    }
+}
+
+async fn send(namespace: &ObjectNamespace, batch: &MetricsBatch) -> Result<()> {
+    let stub = namespace.id_from_name(METRICS_OBJECT_NAME)?.get_stub()?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_body(Some(serde_json::to_string(batch)?.into()));
+    stub.fetch_with_request(Request::new_with_init("https://metrics/record", &init)?)
+        .await?;
+    Ok(())
+}
+
+// `GET /__metrics`, behind the `ADMIN_TOKEN` bearer token like the admin API
+pub async fn handle(req: Request, env: &Env) -> Result<Response> {
+    if !crate::admin::is_authorized(&req, env)? {
+        return Response::error("Unauthorized", 401);
+    }
+    let namespace = env.durable_object("METRICS_DO")?;
+    let stub = namespace.id_from_name(METRICS_OBJECT_NAME)?.get_stub()?;
+    let mut response = stub.fetch_with_str("https://metrics/render").await?;
+    let headers = Headers::new();
+    headers.set("Content-Type", "text/plain; version=0.0.4")?;
+    Ok(Response::ok(response.text().await?)?.with_headers(headers))
+}
+
+// Metrics are only intercepted with both the admin token and the metrics object configured
+pub fn is_metrics_request(url: &Url, env: &Env) -> bool {
+    url.path() == METRICS_PATH
+        && env.secret("ADMIN_TOKEN").is_ok()
+        && env.durable_object("METRICS_DO").is_ok()
+}
+
+// Totals since the object last started; Prometheus treats the drop after a restart as a
+// counter reset
+#[durable_object]
+pub struct MetricsObject {
+    totals: RefCell<MetricsBatch>,
+}
+
+impl DurableObject for MetricsObject {
+    fn new(_state: State, _env: Env) -> Self {
+        Self {
+            totals: RefCell::new(MetricsBatch::default()),
+        }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        match req.path().as_str() {
+            "/record" => {
+                let batch: MetricsBatch = req.json().await?;
+                self.totals.borrow_mut().merge(batch);
+                Response::empty()
+            }
+            _ => Response::ok(render(&self.totals.borrow())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_counters_and_cumulative_buckets() {
+        let mut batch = MetricsBatch::default();
+        batch.count(
+            "validator_validations_total",
+            &[("outcome", "allow"), ("reason", "validated")],
+        );
+        let mut other = MetricsBatch::default();
+        other.count(
+            "validator_validations_total",
+            &[("outcome", "allow"), ("reason", "validated")],
+        );
+        other.count(
+            "validator_validations_total",
+            &[("outcome", "deny"), ("reason", "Invalid \"token\"")],
+        );
+        other.observe_upstream(0.03);
+        other.observe_upstream(0.2);
+        other.observe_upstream(12.0);
+        batch.merge(other);
+
+        let text = render(&batch);
+        assert!(text.contains("# TYPE validator_validations_total counter\n"));
+        assert!(text
+            .contains("validator_validations_total{outcome=\"allow\",reason=\"validated\"} 2\n"));
+        assert!(text.contains(
+            "validator_validations_total{outcome=\"deny\",reason=\"Invalid \\\"token\\\"\"} 1\n"
+        ));
+        assert!(text.contains("validator_upstream_duration_seconds_bucket{le=\"0.025\"} 0\n"));
+        assert!(text.contains("validator_upstream_duration_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(text.contains("validator_upstream_duration_seconds_bucket{le=\"0.25\"} 2\n"));
+        assert!(text.contains("validator_upstream_duration_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(text.contains("validator_upstream_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("validator_upstream_duration_seconds_count 3\n"));
+    }
+
+    #[test]
+    fn counts_durable_object_calls_over_budget() {
+        let mut batch = MetricsBatch::default();
+        batch.add_do_calls(&BTreeMap::from([
+            ("rate_limiter", (1, 0)),
+            ("sessions", (1, 2)),
+        ]));
+        batch.add_do_calls(&BTreeMap::from([("sessions", (0, 1))]));
+
+        let text = render(&batch);
+        assert!(text
+            .contains("validator_do_calls_total{feature=\"rate_limiter\",outcome=\"made\"} 1\n"));
+        assert!(!text.contains("feature=\"rate_limiter\",outcome=\"skipped\""));
+        assert!(
+            text.contains("validator_do_calls_total{feature=\"sessions\",outcome=\"skipped\"} 3\n")
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use worker::{console_error, Env};
+
+pub const DEFAULT_DELIMITER: &str = "++";
+
+// Names of the separate query parameters that can carry the oait parts
+pub const FORMS_TOKEN_PARAM: &str = "forms_token";
+pub const CF_TOKEN_PARAM: &str = "cf_token";
+pub const ACCESS_TOKEN_PARAM: &str = "access_token";
+
+pub fn is_token_param(name: &str) -> bool {
+    matches!(
+        name,
+        FORMS_TOKEN_PARAM | CF_TOKEN_PARAM | ACCESS_TOKEN_PARAM
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenFormat {
+    // `oait={forms}++{cloudflare}++{access}`
+    Oait,
+    // `forms_token={forms}&cf_token={cloudflare}&access_token={access}`
+    Params,
+    // Either, for migrating issuers; oait wins when a request carries both
+    Both,
+}
+
+impl TokenFormat {
+    pub fn accepts_oait(self) -> bool {
+        self != Self::Params
+    }
+
+    pub fn accepts_params(self) -> bool {
+        self != Self::Oait
+    }
+}
+
+pub fn token_format(env: &Env) -> TokenFormat {
+    match env.var("TOKEN_FORMAT").map(|v| v.to_string()).as_deref() {
+        Ok("params") => TokenFormat::Params,
+        Ok("both") => TokenFormat::Both,
+        Ok("oait") | Err(_) => TokenFormat::Oait,
+        Ok(other) => {
+            console_error!("Unknown TOKEN_FORMAT {}, using oait", other);
+            TokenFormat::Oait
+        }
+    }
+}
+
+pub fn delimiter(env: &Env) -> String {
+    env.var("OAIT_DELIMITER")
+        .map(|v| v.to_string())
+        .ok()
+        .filter(|delimiter| !delimiter.is_empty())
+        .unwrap_or(DEFAULT_DELIMITER.to_string())
+}
+
+// Splits oait into its parts. A value whose delimiter arrived percent-encoded (`%2B%2B`) is
+// decoded first. When the delimiter repeats one character, a longer run of it is read as the
+// delimiter at the end of the run, so a part ending in that character (a base64 hash ending in
+// `+`) survives.
+pub fn split(oait: &str, delimiter: &str) -> Vec<String> {
+    // An empty delimiter would match everywhere without advancing
+    if delimiter.is_empty() {
+        return vec![oait.to_string()];
+    }
+    if !oait.contains(delimiter) {
+        let encoded = urlencoding::encode(delimiter).into_owned();
+        if encoded != delimiter && oait.to_ascii_uppercase().contains(&encoded) {
+            if let Ok(decoded) = urlencoding::decode(oait) {
+                return split(&decoded, delimiter);
+            }
+        }
+    }
+
+    let repeated = delimiter
+        .chars()
+        .next()
+        .filter(|first| delimiter.chars().all(|c| c == *first));
+    let mut parts = Vec::new();
+    let mut rest = oait;
+    while let Some(mut at) = rest.find(delimiter) {
+        if let Some(c) = repeated {
+            while rest[at + delimiter.len()..].starts_with(c) {
+                at += c.len_utf8();
+            }
+        }
+        parts.push(rest[..at].to_string());
+        rest = &rest[at + delimiter.len()..];
+    }
+    parts.push(rest.to_string());
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_on_the_default_delimiter() {
+        assert_eq!(
+            split("forms++1693123456-abc++access", DEFAULT_DELIMITER),
+            vec!["forms", "1693123456-abc", "access"]
+        );
+        assert_eq!(split("forms", DEFAULT_DELIMITER), vec!["forms"]);
+        assert_eq!(
+            split("++1693123456-abc", DEFAULT_DELIMITER),
+            vec!["", "1693123456-abc"]
+        );
+    }
+
+    #[test]
+    fn keeps_plus_signs_at_the_end_of_a_part() {
+        assert_eq!(
+            split("forms++1693123456-ab+/c+++access", DEFAULT_DELIMITER),
+            vec!["forms", "1693123456-ab+/c+", "access"]
+        );
+    }
+
+    #[test]
+    fn decodes_an_encoded_delimiter() {
+        assert_eq!(
+            split("forms%2B%2B1693123456-abc%2b%2Baccess", DEFAULT_DELIMITER),
+            vec!["forms", "1693123456-abc", "access"]
+        );
+    }
+
+    #[test]
+    fn custom_delimiters() {
+        assert_eq!(
+            split("forms|1693123456-ab++c|access", "|"),
+            vec!["forms", "1693123456-ab++c", "access"]
+        );
+        assert_eq!(
+            split("forms.~.1693123456-abc", ".~."),
+            vec!["forms", "1693123456-abc"]
+        );
+    }
+}
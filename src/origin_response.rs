@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use worker::{console_error, Env, Headers, Result};
+
+use crate::errors::Rejection;
+use crate::pipeline::{Flow, ResponseContext, Stage};
+use crate::reason::Reason;
+use crate::tenant::TenantConfig;
+
+// The body cannot be read without these, so an allowlist always keeps them
+const ENTITY_HEADERS: [&str; 4] = [
+    "content-type",
+    "content-encoding",
+    "content-length",
+    "transfer-encoding",
+];
+
+// `RESPONSE_POLICY`, or the tenant's `response_policy` in its place, e.g.
+// { "deny": ["Server", "X-Powered-By", "X-Debug-*"], "status_map": { "401": 403 } }
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResponsePolicy {
+    // Origin headers removed; a trailing `*` matches by prefix
+    pub deny: Vec<String>,
+    // When set, the only origin headers kept besides the entity headers
+    pub allow: Option<Vec<String>>,
+    // Origin statuses answered with the worker's own error response instead
+    pub status_map: HashMap<u16, u16>,
+}
+
+impl ResponsePolicy {
+    // The checks serde cannot express, for `/admin/rules`
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        for (from, to) in &self.status_map {
+            if !(100..=599).contains(from) || !(400..=599).contains(to) {
+                return Err(format!(
+                    "status_map: {} cannot map to {}; targets must be error statuses",
+                    from, to
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn drops(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        if self.deny.iter().any(|pattern| matches(pattern, &name)) {
+            return true;
+        }
+        match &self.allow {
+            Some(allow) => {
+                !ENTITY_HEADERS.contains(&name.as_str())
+                    && !allow.iter().any(|pattern| matches(pattern, &name))
+            }
+            None => false,
+        }
+    }
+
+    fn mapped_status(&self, status: u16) -> Option<Rejection> {
+        let &to = self.status_map.get(&status)?;
+        // A mapped 4xx means the origin refused the request, a 5xx that it failed to serve it
+        Some(if to < 500 {
+            Rejection::new(to, Reason::OriginDenied, "Request not allowed")
+        } else {
+            Rejection::new(to, Reason::OriginError, "Origin error")
+        })
+    }
+}
+
+fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+pub fn response_policy(env: &Env, tenant: &TenantConfig) -> Option<ResponsePolicy> {
+    tenant
+        .response_policy
+        .clone()
+        .or_else(|| crate::object_var(env, "RESPONSE_POLICY"))
+}
+
+pub fn scrub(headers: &Headers, policy: &ResponsePolicy) -> Result<()> {
+    let dropped: Vec<String> = headers.keys().filter(|name| policy.drops(name)).collect();
+    for name in dropped {
+        headers.delete(&name)?;
+    }
+    Ok(())
+}
+
+// Runs before the other response stages, so it only ever sees the origin's headers, never those
+// the worker adds. A mapped status is rendered like a rejection, which drops the origin's body
+// and cookies along with it.
+pub struct OriginResponseStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<ResponseContext<'a>> for OriginResponseStage {
+    async fn run(&self, cx: &mut ResponseContext<'a>) -> Result<Flow> {
+        let Some(policy) = response_policy(cx.env, cx.tenant) else {
+            return Ok(Flow::Continue);
+        };
+        if let Some(rejection) = policy.mapped_status(cx.status) {
+            console_error!(
+                "Origin answered {}, sending {}",
+                cx.status,
+                rejection.status
+            );
+            return Ok(Flow::Respond(
+                rejection.into_response(cx.request_headers, cx.env).await?,
+            ));
+        }
+        scrub(&cx.headers, &policy)?;
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(json: &str) -> ResponsePolicy {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn drops_denied_and_unlisted_headers() {
+        let denylist = policy(r#"{ "deny": ["Server", "x-powered-by", "X-Debug-*"] }"#);
+        assert!(denylist.drops("server"));
+        assert!(denylist.drops("X-Powered-By"));
+        assert!(denylist.drops("x-debug-sql"));
+        assert!(!denylist.drops("x-debugger"));
+        assert!(!denylist.drops("cache-control"));
+
+        let allowlist =
+            policy(r#"{ "allow": ["Cache-Control", "X-App-*"], "deny": ["X-App-Trace"] }"#);
+        assert!(!allowlist.drops("cache-control"));
+        assert!(!allowlist.drops("x-app-version"));
+        assert!(!allowlist.drops("content-type"));
+        assert!(allowlist.drops("x-app-trace"));
+        assert!(allowlist.drops("server"));
+    }
+
+    #[test]
+    fn maps_origin_statuses_to_error_responses() {
+        let mapping = policy(r#"{ "status_map": { "401": 403, "500": 502 } }"#);
+        assert!(mapping.validate().is_ok());
+        let denied = mapping.mapped_status(401).unwrap();
+        assert_eq!((denied.status, denied.code), (403, Reason::OriginDenied));
+        let failed = mapping.mapped_status(500).unwrap();
+        assert_eq!((failed.status, failed.code), (502, Reason::OriginError));
+        assert!(mapping.mapped_status(404).is_none());
+
+        assert!(policy(r#"{ "status_map": { "401": 200 } }"#)
+            .validate()
+            .is_err());
+        assert!(serde_json::from_str::<ResponsePolicy>(r#"{ "strip": [] }"#).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::idempotency::idempotency_key;
+
+const REPLAY_RETRY_DELAY_SECONDS: u32 = 30;
+
+// A validated upstream request, captured so the queue consumer can send it again later
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ParkedRequest {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub idempotency_key: String,
+    pub parked_at: f64,
+}
+
+// Parking needs `PARK_FAILED_POSTS=on` and the `PARKED_REQUESTS` queue producer binding
+pub fn park_queue(env: &Env) -> Option<Queue> {
+    if env.var("PARK_FAILED_POSTS").ok()?.to_string() != "on" {
+        return None;
+    }
+    env.queue("PARKED_REQUESTS")
+        .map_err(|_| console_error!("PARK_FAILED_POSTS is on but PARKED_REQUESTS is not bound"))
+        .ok()
+}
+
+// Only POSTs the client marked idempotent are safe to replay, since the origin may have
+// processed the original before failing. A key the worker generated for the upstream request
+// does not make it parkable.
+pub fn is_parkable(method: &Method, client_keyed: bool) -> bool {
+    *method == Method::Post && client_keyed
+}
+
+pub fn origin_unavailable(status: u16) -> bool {
+    matches!(status, 502..=504) || (520..=530).contains(&status)
+}
+
+pub async fn park(queue: &Queue, mut req: Request) -> Result<Response> {
+    let idempotency_key = idempotency_key(req.headers()).unwrap_or_default();
+    let parked = ParkedRequest {
+        url: req.url()?.to_string(),
+        headers: req.headers().entries().collect(),
+        body: BASE64_STANDARD.encode(req.bytes().await?),
+        idempotency_key: idempotency_key.clone(),
+        parked_at: Date::now().as_millis() as f64,
+    };
+    queue.send(&parked).await?;
+    console_log!("Parked request {} for replay", idempotency_key);
+
+    Ok(Response::from_json(&serde_json::json!({
+        "status": "queued",
+        "idempotency_key": idempotency_key,
+    }))?
+    .with_status(202))
+}
+
+// Queue consumer: replays each parked request, retrying while the origin is still unavailable
+pub async fn replay(batch: MessageBatch<ParkedRequest>) -> Result<()> {
+    let retry = QueueRetryOptionsBuilder::new()
+        .with_delay_seconds(REPLAY_RETRY_DELAY_SECONDS)
+        .build();
+    for message in batch.messages()? {
+        let parked = message.body();
+        match send(parked).await {
+            Ok(status) if status < 500 => {
+                console_log!("Replayed {} with status {}", parked.idempotency_key, status);
+                message.ack();
+            }
+            Ok(status) => {
+                console_error!("Replay of {} got {}", parked.idempotency_key, status);
+                message.retry_with_options(&retry);
+            }
+            Err(e) => {
+                console_error!("Replay of {} failed: {}", parked.idempotency_key, e);
+                message.retry_with_options(&retry);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn send(parked: &ParkedRequest) -> Result<u16> {
+    let headers = Headers::new();
+    for (name, value) in &parked.headers {
+        headers.append(name, value)?;
+    }
+    let body = BASE64_STANDARD
+        .decode(&parked.body)
+        .map_err(|e| Error::RustError(e.to_string()))?;
+
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(JsValue::from(body)));
+    let request = Request::new_with_init(&parked.url, &init)?;
+    Ok(Fetch::Request(request).send().await?.status_code())
+}
@@ -0,0 +1,145 @@
+use async_trait::async_trait;
+use url::Url;
+use worker::{Env, Headers, Request, Response, Result};
+
+use crate::do_budget::DoBudget;
+use crate::errors::Rejection;
+use crate::policy::FunctionPolicy;
+use crate::rules::RuleSet;
+use crate::tenant::TenantConfig;
+
+// A request passes policy stages, then validation checks, is rewritten and proxied, and the
+// origin's response finally goes through the response stages. Each phase is a list of stages
+// run in order, so a new check is one `Stage` impl and one entry in its list.
+
+// What a stage decided
+pub enum Flow {
+    // On to the next stage
+    Continue,
+    // Forward the original request untouched, skipping every later stage
+    Bypass,
+    Reject(Rejection),
+    // A response the stage rendered itself
+    Respond(Response),
+}
+
+#[async_trait(?Send)]
+pub trait Stage<X> {
+    async fn run(&self, cx: &mut X) -> Result<Flow>;
+
+    // Response stages that read the origin's body return `true`; the body then arrives decoded
+    // in `ResponseContext::body` instead of streaming through untouched
+    fn reads_body(&self) -> bool {
+        false
+    }
+}
+
+// Stops at the first stage that does not continue
+pub async fn run<X>(stages: &[&dyn Stage<X>], cx: &mut X) -> Result<Flow> {
+    for stage in stages {
+        match stage.run(cx).await? {
+            Flow::Continue => {}
+            flow => return Ok(flow),
+        }
+    }
+    Ok(Flow::Continue)
+}
+
+// What the policy stages see and decide, before any token is looked at
+pub struct RequestContext<'a> {
+    pub req: &'a Request,
+    pub env: &'a Env,
+    pub url: &'a Url,
+    pub tenant: &'a TenantConfig,
+    pub rules: &'a RuleSet,
+    pub secret: &'a str,
+    pub client_ip: String,
+    pub function_id: Option<String>,
+    pub websocket_upgrade: bool,
+    // Set by the geo policy: validation is required even where it would be bypassed
+    pub step_up: bool,
+    // Set by the function policy stage
+    pub policy: FunctionPolicy,
+    // Set by the body limit stage
+    pub body: Vec<u8>,
+    // `Set-Cookie` value for a CSRF cookie to hand out with the response
+    pub csrf_cookie: Option<String>,
+}
+
+// The checks run before the token itself is verified
+pub struct ValidationContext<'a> {
+    pub req: &'a Request,
+    pub env: &'a Env,
+    pub url: &'a Url,
+    pub host: &'a str,
+    pub client_ip: &'a str,
+    pub access_settings: Option<&'a crate::access::AccessSettings>,
+    pub do_budget: &'a DoBudget<'a>,
+}
+
+// The response stages add to `headers` and `set_cookies`; the cookies are appended after the
+// last stage. A stage that responds itself replaces the origin's response.
+pub struct ResponseContext<'a> {
+    pub env: &'a Env,
+    pub tenant: &'a TenantConfig,
+    pub url: &'a Url,
+    pub request_headers: &'a Headers,
+    pub function_id: &'a str,
+    // The origin's status, which the response keeps
+    pub status: u16,
+    pub policy: &'a FunctionPolicy,
+    pub verdict: &'a std::result::Result<crate::verify::Verified, Rejection>,
+    pub access_token: &'a str,
+    pub csrf_cookie: Option<&'a str>,
+    // The request's `Origin`, for cross-origin responses
+    pub origin: Option<&'a str>,
+    // The canary backend the request was sent to
+    pub backend: Option<&'a crate::canary::Selection>,
+    pub cookie_domain: Option<String>,
+    pub headers: Headers,
+    pub set_cookies: Vec<String>,
+    // The decoded origin body when a stage reads it; what is left here is sent
+    pub body: Option<Vec<u8>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    use super::*;
+
+    struct Push(u8, bool);
+
+    #[async_trait(?Send)]
+    impl Stage<Vec<u8>> for Push {
+        async fn run(&self, cx: &mut Vec<u8>) -> Result<Flow> {
+            cx.push(self.0);
+            Ok(if self.1 { Flow::Continue } else { Flow::Bypass })
+        }
+    }
+
+    fn ready<T>(future: impl Future<Output = T>) -> T {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(value) => value,
+            Poll::Pending => panic!("stage did not complete"),
+        }
+    }
+
+    #[test]
+    fn runs_stages_in_order_until_one_stops() {
+        let mut seen = Vec::new();
+        let flow = ready(run(
+            &[&Push(1, true), &Push(2, false), &Push(3, true)],
+            &mut seen,
+        ));
+        assert!(matches!(flow, Ok(Flow::Bypass)));
+        assert_eq!(seen, vec![1, 2]);
+
+        let mut seen = Vec::new();
+        let flow = ready(run(&[&Push(1, true), &Push(2, true)], &mut seen));
+        assert!(matches!(flow, Ok(Flow::Continue)));
+        assert_eq!(seen, vec![1, 2]);
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use worker::{console_error, Env};
+
+use crate::errors::Rejection;
+use crate::pipeline::{Flow, RequestContext, Stage};
+use crate::reason::Reason;
+use crate::rules::RuleSet;
+
+// The only protected function when `FUNCTION_POLICIES` is not set
+const DEFAULT_FUNCTION_ID: &str = "APPS_LOGIN_DEFAULT";
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenField {
+    Forms,
+    Cloudflare,
+    Access,
+}
+
+// Per-function settings from the `FUNCTION_POLICIES` JSON var, e.g.
+// { "APPS_LOGIN_DEFAULT": { "required_fields": ["access"], "validity_seconds": 120 } }
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct FunctionPolicy {
+    // `false` forwards the function's requests without any checks
+    pub require_validation: bool,
+    // oait parts that must be non-empty on top of what the signature mode needs
+    pub required_fields: Vec<TokenField>,
+    // Overrides `TOKEN_VALIDITY_SECONDS`
+    pub validity_seconds: Option<f64>,
+    // Origin (scheme, host and port) the function's requests are sent to instead of the request's
+    pub upstream: Option<String>,
+    // `false` never sets `CF_Authorization`, even when oait carries an access token
+    pub set_auth_cookie: bool,
+    // Weighted origins for validated requests
+    pub canary: Option<crate::canary::CanaryPolicy>,
+    // Query parameters verified alongside oait, each against its own secret
+    pub protected_params: Vec<crate::protected_params::ProtectedParam>,
+}
+
+impl Default for FunctionPolicy {
+    fn default() -> Self {
+        Self {
+            require_validation: true,
+            required_fields: Vec::new(),
+            validity_seconds: None,
+            upstream: None,
+            set_auth_cookie: true,
+            canary: None,
+            protected_params: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnknownFunctionAction {
+    Bypass,
+    Deny,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PolicyDecision {
+    Validate(FunctionPolicy),
+    Bypass,
+    Deny,
+}
+
+// Requests without a function_id are always bypassed; ids missing from the map follow
+// `UNKNOWN_FUNCTION_ACTION`. Policies managed through `/admin/rules` take the place of the var.
+pub fn function_policy(env: &Env, rules: &RuleSet, function_id: Option<&str>) -> PolicyDecision {
+    let unknown = unknown_function_action(env);
+    if let Some(policies) = &rules.function_policies {
+        return resolve(policies, function_id, unknown);
+    }
+    let policies = crate::object_var(env, "FUNCTION_POLICIES").unwrap_or_else(|| {
+        HashMap::from([(DEFAULT_FUNCTION_ID.to_string(), FunctionPolicy::default())])
+    });
+    resolve(&policies, function_id, unknown)
+}
+
+// The longest `validity_seconds` of any policy in effect
+pub fn longest_validity_seconds(env: &Env, rules: &RuleSet) -> Option<f64> {
+    let longest = |policies: &HashMap<String, FunctionPolicy>| {
+        policies
+            .values()
+            .filter_map(|policy| policy.validity_seconds)
+            .reduce(f64::max)
+    };
+    match &rules.function_policies {
+        Some(policies) => longest(policies),
+        None => longest(&crate::object_var(env, "FUNCTION_POLICIES")?),
+    }
+}
+
+fn resolve(
+    policies: &HashMap<String, FunctionPolicy>,
+    function_id: Option<&str>,
+    unknown: UnknownFunctionAction,
+) -> PolicyDecision {
+    let Some(function_id) = function_id else {
+        return PolicyDecision::Bypass;
+    };
+    match policies.get(function_id) {
+        Some(policy) if policy.require_validation => PolicyDecision::Validate(policy.clone()),
+        Some(_) => PolicyDecision::Bypass,
+        None if unknown == UnknownFunctionAction::Deny => PolicyDecision::Deny,
+        None => PolicyDecision::Bypass,
+    }
+}
+
+fn unknown_function_action(env: &Env) -> UnknownFunctionAction {
+    match env
+        .var("UNKNOWN_FUNCTION_ACTION")
+        .map(|v| v.to_string())
+        .as_deref()
+    {
+        Ok("deny") => UnknownFunctionAction::Deny,
+        Ok("bypass") | Err(_) => UnknownFunctionAction::Bypass,
+        Ok(other) => {
+            console_error!("Unknown UNKNOWN_FUNCTION_ACTION {}, bypassing", other);
+            UnknownFunctionAction::Bypass
+        }
+    }
+}
+
+pub struct FunctionPolicyStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<RequestContext<'a>> for FunctionPolicyStage {
+    async fn run(&self, cx: &mut RequestContext<'a>) -> worker::Result<Flow> {
+        cx.policy = match function_policy(cx.env, cx.rules, cx.function_id.as_deref()) {
+            PolicyDecision::Validate(policy) => policy,
+            // Stepped-up clients are held to the default policy where others would bypass
+            PolicyDecision::Bypass if cx.step_up => FunctionPolicy::default(),
+            PolicyDecision::Bypass => {
+                console_error!(
+                    "function_id {} is not protected - bypassing HMAC validation",
+                    cx.function_id.as_deref().unwrap_or("missing")
+                );
+                return Ok(Flow::Bypass);
+            }
+            PolicyDecision::Deny => {
+                console_error!(
+                    "Unknown function_id {}",
+                    cx.function_id.as_deref().unwrap_or_default()
+                );
+                return Ok(Flow::Reject(Rejection::new(
+                    403,
+                    Reason::UnknownFunction,
+                    "Unknown function",
+                )));
+            }
+        };
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policies() -> HashMap<String, FunctionPolicy> {
+        serde_json::from_str(
+            r#"{
+                "APPS_LOGIN_DEFAULT": { "required_fields": ["access"], "validity_seconds": 120 },
+                "APPS_HEALTH": { "require_validation": false }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn policies_fill_in_defaults() {
+        let PolicyDecision::Validate(policy) = resolve(
+            &policies(),
+            Some("APPS_LOGIN_DEFAULT"),
+            UnknownFunctionAction::Bypass,
+        ) else {
+            panic!("expected validation");
+        };
+        assert_eq!(policy.required_fields, vec![TokenField::Access]);
+        assert_eq!(policy.validity_seconds, Some(120.0));
+        assert!(policy.set_auth_cookie);
+        assert_eq!(policy.upstream, None);
+    }
+
+    #[test]
+    fn unknown_and_missing_ids() {
+        let policies = policies();
+        assert_eq!(
+            resolve(&policies, Some("APPS_HEALTH"), UnknownFunctionAction::Deny),
+            PolicyDecision::Bypass
+        );
+        assert_eq!(
+            resolve(&policies, Some("APPS_OTHER"), UnknownFunctionAction::Deny),
+            PolicyDecision::Deny
+        );
+        assert_eq!(
+            resolve(&policies, Some("APPS_OTHER"), UnknownFunctionAction::Bypass),
+            PolicyDecision::Bypass
+        );
+        assert_eq!(
+            resolve(&policies, None, UnknownFunctionAction::Deny),
+            PolicyDecision::Bypass
+        );
+    }
+}
@@ -0,0 +1,285 @@
+use serde::Deserialize;
+use url::Url;
+use worker::{console_error, Env, Headers, Result};
+
+use crate::encrypted_token;
+use crate::errors::Rejection;
+use crate::lookup_budget::LookupBudget;
+use crate::reason::Reason;
+use crate::tenant_secrets::SecretSource;
+use crate::token::{self, HashEncoding, HmacAlgorithms, TimestampUnit};
+
+// Parameters the worker reads itself, which a protected parameter cannot take the name of
+const RESERVED_PARAMS: [&str; 5] = [
+    "function_id",
+    "oait",
+    crate::oait::FORMS_TOKEN_PARAM,
+    crate::oait::CF_TOKEN_PARAM,
+    crate::oait::ACCESS_TOKEN_PARAM,
+];
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamFormat {
+    // The cloudflare token's HMAC format, bound to the client IP unless it says `anyip`
+    #[default]
+    Hmac,
+    // An AES-GCM sealed `{ "ip", "ts" }` payload; the secret is the 32-byte key in base64
+    Encrypted,
+}
+
+// What the origin sees of a parameter that verified
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnSuccess {
+    #[default]
+    Keep,
+    Strip,
+    // Moved from the query string into this request header
+    Header(String),
+}
+
+// A query parameter verified alongside oait, e.g.
+// { "name": "sso_ticket", "secret": { "binding": "SSO_TICKET_SECRET" }, "on_success": "strip" }
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ProtectedParam {
+    pub name: String,
+    #[serde(default)]
+    pub format: ParamFormat,
+    pub secret: SecretSource,
+    // Requests without the parameter pass; one that is present must still verify
+    #[serde(default)]
+    pub optional: bool,
+    #[serde(default)]
+    pub on_success: OnSuccess,
+}
+
+// The checks serde cannot express, for `/admin/rules`
+pub fn validate(params: &[ProtectedParam]) -> std::result::Result<(), String> {
+    for (i, param) in params.iter().enumerate() {
+        if param.name.is_empty() || RESERVED_PARAMS.contains(&param.name.as_str()) {
+            return Err(format!("{:?} cannot be a protected parameter", param.name));
+        }
+        if params[..i].iter().any(|other| other.name == param.name) {
+            return Err(format!("{:?} is protected twice", param.name));
+        }
+        if let OnSuccess::Header(header) = &param.on_success {
+            if header.is_empty()
+                || !header
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            {
+                return Err(format!("{:?} is not a header name", header));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub struct ParamCheck<'a> {
+    pub host: &'a str,
+    pub client_ip: &'a str,
+    pub validity_seconds: f64,
+    pub timestamp_unit: TimestampUnit,
+    pub algorithms: HmacAlgorithms,
+    pub hash_encoding: HashEncoding,
+}
+
+// Every parameter must verify against its own secret. The secrets are read concurrently; one that
+// cannot be read fails the request rather than falling back to another secret.
+pub async fn verify(
+    env: &Env,
+    lookups: &LookupBudget,
+    params: &[ProtectedParam],
+    url: &Url,
+    check: &ParamCheck<'_>,
+) -> std::result::Result<(), Rejection> {
+    if params.is_empty() {
+        return Ok(());
+    }
+    let secrets = futures::future::join_all(params.iter().map(|param| {
+        crate::tenant_secrets::resolve(env, lookups, Some(&param.secret), String::new())
+    }))
+    .await;
+
+    for (param, secret) in params.iter().zip(secrets) {
+        let Some(value) = param_value(url, &param.name) else {
+            if param.optional {
+                continue;
+            }
+            console_error!("Missing protected parameter {}", param.name);
+            return Err(Rejection::new(
+                400,
+                Reason::MissingToken,
+                "Missing protected parameter",
+            ));
+        };
+        let Some(secret) = secret else {
+            console_error!("No secret for protected parameter {}", param.name);
+            return Err(Rejection::new(
+                500,
+                Reason::VerificationUnavailable,
+                "Token verification unavailable",
+            ));
+        };
+        if !verifies(param.format, &value, &secret, check) {
+            console_error!("Protected parameter {} failed verification", param.name);
+            return Err(Rejection::new(
+                403,
+                Reason::ProtectedParamInvalid,
+                "Invalid or expired protected parameter",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn verifies(format: ParamFormat, value: &str, secret: &str, check: &ParamCheck) -> bool {
+    match format {
+        // Key ids and nonce tokens have no meaning with a single secret per parameter
+        ParamFormat::Hmac => token::parse_hmac_token(value, check.algorithms)
+            .filter(|token| token.kid.is_none() && token.nonce.is_none())
+            .filter(|token| token.allows_audience(check.host))
+            .is_some_and(|token| {
+                token::verify_hmac_token(
+                    check.client_ip,
+                    &token,
+                    secret,
+                    check.validity_seconds,
+                    check.timestamp_unit,
+                    check.hash_encoding,
+                    None,
+                )
+            }),
+        ParamFormat::Encrypted => {
+            let Some(key) = encrypted_token::parse_key(secret) else {
+                return false;
+            };
+            encrypted_token::parse(value)
+                .filter(|token| token.kid.is_none())
+                .and_then(|token| encrypted_token::open(&key, token.sealed))
+                .is_some_and(|payload| {
+                    payload.ip == check.client_ip
+                        && token::is_fresh(payload.ts, check.timestamp_unit, check.validity_seconds)
+                })
+        }
+    }
+}
+
+// Percent-decoded only: a `+` stays a `+`, as in oait, since base64 hashes contain it
+fn param_value(url: &Url, name: &str) -> Option<String> {
+    url.query()?
+        .split('&')
+        .find_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            (decoded(key) == name).then(|| decoded(value))
+        })
+        .filter(|value| !value.is_empty())
+}
+
+fn decoded(component: &str) -> String {
+    urlencoding::decode(component).map_or_else(|_| component.to_string(), |c| c.into_owned())
+}
+
+// Applies each parameter's `on_success` to what is sent upstream. A header a parameter moves into
+// is always cleared first, so a client cannot supply it directly.
+pub fn rewrite(
+    params: &[ProtectedParam],
+    url: &Url,
+    retained_params: Vec<String>,
+    headers: &Headers,
+) -> Result<Vec<String>> {
+    for param in params {
+        if let OnSuccess::Header(header) = &param.on_success {
+            headers.delete(header)?;
+            if let Some(value) = param_value(url, &param.name) {
+                headers.set(header, &value)?;
+            }
+        }
+    }
+    Ok(unprotected(params, retained_params))
+}
+
+fn unprotected(params: &[ProtectedParam], retained_params: Vec<String>) -> Vec<String> {
+    retained_params
+        .into_iter()
+        .filter(|pair| {
+            let key = decoded(pair.split_once('=').map_or(pair.as_str(), |(key, _)| key));
+            !params
+                .iter()
+                .any(|param| param.name == key && param.on_success != OnSuccess::Keep)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(json: &str) -> Vec<ProtectedParam> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn parses_and_validates_parameters() {
+        let parsed = params(
+            r#"[
+                { "name": "sso_ticket", "secret": { "binding": "SSO_TICKET_SECRET" } },
+                { "name": "state", "format": "encrypted", "secret": { "kv": "state-key" },
+                  "optional": true, "on_success": { "header": "X-State" } }
+            ]"#,
+        );
+        assert_eq!(parsed[0].format, ParamFormat::Hmac);
+        assert_eq!(parsed[0].on_success, OnSuccess::Keep);
+        assert!(!parsed[0].optional);
+        assert_eq!(
+            parsed[1].on_success,
+            OnSuccess::Header("X-State".to_string())
+        );
+        assert!(validate(&parsed).is_ok());
+
+        assert!(validate(&params(r#"[{ "name": "oait", "secret": { "kv": "k" } }]"#)).is_err());
+        assert!(validate(&params(
+            r#"[{ "name": "t", "secret": { "kv": "k" } }, { "name": "t", "secret": { "kv": "j" } }]"#
+        ))
+        .is_err());
+        assert!(validate(&params(
+            r#"[{ "name": "t", "secret": { "kv": "k" }, "on_success": { "header": "X Bad" } }]"#
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn strips_parameters_that_leave_the_query() {
+        let parsed = params(
+            r#"[
+                { "name": "sso_ticket", "secret": { "kv": "k" }, "on_success": "strip" },
+                { "name": "state", "secret": { "kv": "k" }, "on_success": { "header": "X-State" } },
+                { "name": "kept", "secret": { "kv": "k" } }
+            ]"#,
+        );
+        let retained = [
+            "id=1",
+            "sso_ticket=abc",
+            "sso%5Fticket=def",
+            "state=xyz",
+            "kept=1",
+            "debug",
+        ]
+        .map(str::to_string)
+        .to_vec();
+        assert_eq!(
+            unprotected(&parsed, retained),
+            vec!["id=1", "kept=1", "debug"]
+        );
+
+        let url =
+            Url::parse("https://login.example.com/?sso%5Fticket=1693123456-ab+c%3D&x=").unwrap();
+        assert_eq!(
+            param_value(&url, "sso_ticket").as_deref(),
+            Some("1693123456-ab+c=")
+        );
+        assert_eq!(param_value(&url, "x"), None);
+    }
+}
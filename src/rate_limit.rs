@@ -0,0 +1,238 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use worker::{
+    console_error, durable_object, Date, Env, ObjectNamespace, Request, Response, Result, State,
+};
+
+use crate::do_budget::DoBudget;
+use crate::errors::Rejection;
+use crate::pipeline::{Flow, Stage, ValidationContext};
+use crate::reason::Reason;
+
+const DEFAULT_RATE_LIMIT_REQUESTS: u32 = 10;
+const DEFAULT_RATE_LIMIT_PERIOD_SECONDS: u32 = 60;
+const DEFAULT_RATE_LIMIT_BATCH_SIZE: u32 = 1;
+const DEFAULT_RATE_LIMIT_BATCH_MS: u32 = 1000;
+
+#[async_trait(?Send)]
+pub trait RateLimiter {
+    fn backend(&self) -> &'static str;
+
+    // Records one request for `key` and reports whether it is within the limit
+    async fn allow(&self, key: &str, budget: &DoBudget<'_>) -> Result<bool>;
+}
+
+// Workers rate-limiting binding; limit and period are configured on the binding itself
+pub struct NativeRateLimiter(worker::RateLimiter);
+
+#[async_trait(?Send)]
+impl RateLimiter for NativeRateLimiter {
+    fn backend(&self) -> &'static str {
+        "native"
+    }
+
+    async fn allow(&self, key: &str, _budget: &DoBudget<'_>) -> Result<bool> {
+        Ok(self.0.limit(key.to_string()).await?.success)
+    }
+}
+
+pub struct DurableObjectRateLimiter {
+    namespace: ObjectNamespace,
+    limit: u32,
+    period_seconds: u32,
+    batch_size: u32,
+    batch_ms: u32,
+}
+
+// Hits counted in this isolate but not yet sent to the object, plus the object's count at the
+// last sync
+#[derive(Default)]
+struct LocalWindow {
+    pending: u32,
+    last_known: u32,
+    synced_at: f64,
+}
+
+thread_local! {
+    static LOCAL_WINDOWS: RefCell<HashMap<String, LocalWindow>> = RefCell::new(HashMap::new());
+}
+
+#[async_trait(?Send)]
+impl RateLimiter for DurableObjectRateLimiter {
+    fn backend(&self) -> &'static str {
+        "durable_object"
+    }
+
+    // With batching, hits are counted locally and sent every `batch_size` hits or `batch_ms`,
+    // whichever comes first, as long as the local estimate stays under the limit. Hits still
+    // pending when traffic stops are never sent, which can only under-count.
+    async fn allow(&self, key: &str, budget: &DoBudget<'_>) -> Result<bool> {
+        let now = Date::now().as_millis() as f64;
+        let pending = LOCAL_WINDOWS.with(|windows| {
+            let mut windows = windows.borrow_mut();
+            let window = windows.entry(key.to_string()).or_default();
+            window.pending += 1;
+            let within_batch = window.pending < self.batch_size
+                && now - window.synced_at < f64::from(self.batch_ms)
+                && window.last_known + window.pending <= self.limit;
+            (!within_batch).then(|| std::mem::take(&mut window.pending))
+        });
+        let Some(pending) = pending else {
+            return Ok(true);
+        };
+
+        let stub = self.namespace.id_from_name(key)?.get_stub()?;
+        let request = Request::new(
+            &format!(
+                "https://rate-limiter/check?limit={}&period={}&count={}",
+                self.limit, self.period_seconds, pending
+            ),
+            worker::Method::Get,
+        )?;
+        let outcome = match budget.fetch("rate_limiter", &stub, request).await {
+            Ok(mut response) => response.json::<RateLimitOutcome>().await,
+            Err(e) => Err(e),
+        };
+        let outcome = match outcome {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                // Keep the hits so the next sync still reports them
+                LOCAL_WINDOWS.with(|windows| {
+                    if let Some(window) = windows.borrow_mut().get_mut(key) {
+                        window.pending += pending;
+                    }
+                });
+                return Err(e);
+            }
+        };
+
+        LOCAL_WINDOWS.with(|windows| {
+            if let Some(window) = windows.borrow_mut().get_mut(key) {
+                window.last_known = outcome.count;
+                window.synced_at = now;
+            }
+        });
+        Ok(outcome.success)
+    }
+}
+
+// `RATE_LIMIT_BACKEND` picks a backend explicitly; otherwise the native binding is preferred when bound
+pub fn rate_limiter(env: &Env) -> Option<Box<dyn RateLimiter>> {
+    let backend = env.var("RATE_LIMIT_BACKEND").map(|v| v.to_string()).ok();
+
+    if matches!(backend.as_deref(), None | Some("native")) {
+        if let Ok(binding) = env.get_binding::<worker::RateLimiter>("RATE_LIMITER") {
+            return Some(Box::new(NativeRateLimiter(binding)));
+        }
+    }
+
+    if matches!(backend.as_deref(), None | Some("durable_object")) {
+        if let Ok(namespace) = env.durable_object("RATE_LIMITER_DO") {
+            return Some(Box::new(DurableObjectRateLimiter {
+                namespace,
+                limit: crate::var_or(env, "RATE_LIMIT_REQUESTS", DEFAULT_RATE_LIMIT_REQUESTS),
+                period_seconds: crate::var_or(
+                    env,
+                    "RATE_LIMIT_PERIOD_SECONDS",
+                    DEFAULT_RATE_LIMIT_PERIOD_SECONDS,
+                ),
+                batch_size: crate::var_or(
+                    env,
+                    "RATE_LIMIT_BATCH_SIZE",
+                    DEFAULT_RATE_LIMIT_BATCH_SIZE,
+                ),
+                batch_ms: crate::var_or(env, "RATE_LIMIT_BATCH_MS", DEFAULT_RATE_LIMIT_BATCH_MS),
+            }));
+        }
+    }
+
+    if let Some(backend) = backend {
+        console_error!("Rate limit backend {} is not bound, skipping", backend);
+    }
+    None
+}
+
+#[derive(Serialize, Deserialize)]
+struct RateLimitOutcome {
+    success: bool,
+    // Hits in the current window, including the ones just reported
+    #[serde(default)]
+    count: u32,
+}
+
+// Fixed-window counter, one object per rate-limit key
+#[durable_object]
+pub struct RateLimiterObject {
+    window_start: Cell<f64>,
+    count: Cell<u32>,
+}
+
+impl DurableObject for RateLimiterObject {
+    fn new(_state: State, _env: Env) -> Self {
+        Self {
+            window_start: Cell::new(0.0),
+            count: Cell::new(0),
+        }
+    }
+
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let param = |name: &str, default: u32| {
+            url.query_pairs()
+                .find(|(k, _)| k == name)
+                .and_then(|(_, v)| v.parse().ok())
+                .unwrap_or(default)
+        };
+        let limit = param("limit", DEFAULT_RATE_LIMIT_REQUESTS);
+        let hits = param("count", 1);
+        let period_ms = f64::from(param("period", DEFAULT_RATE_LIMIT_PERIOD_SECONDS)) * 1000.0;
+
+        let now = Date::now().as_millis() as f64;
+        if now - self.window_start.get() >= period_ms {
+            self.window_start.set(now);
+            self.count.set(0);
+        }
+        self.count.set(self.count.get() + hits);
+
+        Response::from_json(&RateLimitOutcome {
+            success: self.count.get() <= limit,
+            count: self.count.get(),
+        })
+    }
+}
+
+// Keyed by host and client IP
+pub struct RateLimitCheck;
+
+#[async_trait(?Send)]
+impl<'a> Stage<ValidationContext<'a>> for RateLimitCheck {
+    async fn run(&self, cx: &mut ValidationContext<'a>) -> Result<Flow> {
+        let Some(limiter) = rate_limiter(cx.env) else {
+            return Ok(Flow::Continue);
+        };
+        match limiter
+            .allow(&format!("{}:{}", cx.host, cx.client_ip), cx.do_budget)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                console_error!(
+                    "Rate limit exceeded for {} ({})",
+                    cx.client_ip,
+                    limiter.backend()
+                );
+                return Ok(Flow::Reject(Rejection::new(
+                    429,
+                    Reason::RateLimited,
+                    "Too many requests",
+                )));
+            }
+            // Fail open so a limiter outage does not take down logins
+            Err(e) => console_error!("Rate limiter {} failed: {}", limiter.backend(), e),
+        }
+        Ok(Flow::Continue)
+    }
+}
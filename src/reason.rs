@@ -0,0 +1,136 @@
+use worker::{Request, Result};
+
+// Tells the origin the edge validated the request, e.g. `pass; reason=hmac_v1; kid=2`. Clients
+// cannot send it: it is removed from every request on its way to the origin.
+pub const VALIDATION_HEADER: &str = "X-Token-Validation";
+
+// Stable codes for why a request passed or was refused, shared by the validation header, the
+// logs, audit events and error responses. Codes are never renamed or reused; a check that changes
+// meaning gets a new one. The `_v1` suffix names the token format a pass was checked against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reason {
+    // Passes
+    HmacV1,
+    Ed25519V1,
+    RequestSignatureV1,
+    EncryptedTokenV1,
+    AccessJwtV1,
+    SessionV1,
+    ContinuationV1,
+    // Refusals
+    MissingToken,
+    MalformedToken,
+    InvalidToken,
+    ProtectedParamInvalid,
+    TokenRevoked,
+    AccessJwtInvalid,
+    TurnstileFailed,
+    RateLimited,
+    UnknownFunction,
+    NetworkDenied,
+    GeoDenied,
+    RiskDenied,
+    RiskUnavailable,
+    CsrfMismatch,
+    BodyTooLarge,
+    IdempotencyKeyInvalid,
+    IdempotencyInFlight,
+    IdempotencyKeyReused,
+    WebhookSignatureInvalid,
+    Maintenance,
+    VerificationUnavailable,
+    OriginUnavailable,
+    OriginUnreachable,
+    OriginDenied,
+    OriginError,
+    InternalError,
+}
+
+impl Reason {
+    pub fn code(self) -> &'static str {
+        match self {
+            Reason::HmacV1 => "hmac_v1",
+            Reason::Ed25519V1 => "ed25519_v1",
+            Reason::RequestSignatureV1 => "request_signature_v1",
+            Reason::EncryptedTokenV1 => "encrypted_token_v1",
+            Reason::AccessJwtV1 => "access_jwt_v1",
+            Reason::SessionV1 => "session_v1",
+            Reason::ContinuationV1 => "continuation_v1",
+            Reason::MissingToken => "missing_token",
+            Reason::MalformedToken => "malformed_token",
+            Reason::InvalidToken => "invalid_token",
+            Reason::ProtectedParamInvalid => "protected_param_invalid",
+            Reason::TokenRevoked => "token_revoked",
+            Reason::AccessJwtInvalid => "access_jwt_invalid",
+            Reason::TurnstileFailed => "turnstile_failed",
+            Reason::RateLimited => "rate_limited",
+            Reason::UnknownFunction => "unknown_function",
+            Reason::NetworkDenied => "network_denied",
+            Reason::GeoDenied => "geo_denied",
+            Reason::RiskDenied => "risk_denied",
+            Reason::RiskUnavailable => "risk_unavailable",
+            Reason::CsrfMismatch => "csrf_mismatch",
+            Reason::BodyTooLarge => "body_too_large",
+            Reason::IdempotencyKeyInvalid => "idempotency_key_invalid",
+            Reason::IdempotencyInFlight => "idempotency_in_flight",
+            Reason::IdempotencyKeyReused => "idempotency_key_reused",
+            Reason::WebhookSignatureInvalid => "webhook_signature_invalid",
+            Reason::Maintenance => "maintenance",
+            Reason::VerificationUnavailable => "verification_unavailable",
+            Reason::OriginUnavailable => "origin_unavailable",
+            Reason::OriginUnreachable => "origin_unreachable",
+            Reason::OriginDenied => "origin_denied",
+            Reason::OriginError => "origin_error",
+            Reason::InternalError => "internal_error",
+        }
+    }
+}
+
+// Key ids come from the token, so one that could break the header's syntax is left out
+pub fn validation_header(reason: Reason, kid: Option<&str>) -> String {
+    let kid = kid.filter(|kid| {
+        !kid.is_empty()
+            && kid
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+    });
+    match kid {
+        Some(kid) => format!("pass; reason={}; kid={}", reason.code(), kid),
+        None => format!("pass; reason={}", reason.code()),
+    }
+}
+
+// For requests forwarded as the client sent them; the copy is only made when needed
+pub fn without_validation_header(req: Request) -> Result<Request> {
+    if !req.headers().has(VALIDATION_HEADER)? {
+        return Ok(req);
+    }
+    let mut stripped = req.clone_mut()?;
+    stripped.headers_mut()?.delete(VALIDATION_HEADER)?;
+    Ok(stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_the_validation_header() {
+        assert_eq!(
+            validation_header(Reason::HmacV1, Some("2")),
+            "pass; reason=hmac_v1; kid=2"
+        );
+        assert_eq!(
+            validation_header(Reason::SessionV1, None),
+            "pass; reason=session_v1"
+        );
+        assert_eq!(
+            validation_header(Reason::HmacV1, Some("2; reason=forged")),
+            "pass; reason=hmac_v1"
+        );
+        assert_eq!(
+            validation_header(Reason::HmacV1, Some("")),
+            "pass; reason=hmac_v1"
+        );
+    }
+}
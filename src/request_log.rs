@@ -0,0 +1,250 @@
+use std::cell::RefCell;
+
+use serde::Deserialize;
+use worker::{console_log, Env};
+
+use crate::audit::Decision;
+use crate::events::AuditOutcome;
+use crate::rules::RuleSet;
+use crate::tenant::TenantConfig;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    // Token values are reduced to their length
+    #[default]
+    Info,
+    // Token values are logged as received
+    Debug,
+}
+
+impl LogLevel {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(Self::Off),
+            "info" => Some(Self::Info),
+            "debug" => Some(Self::Debug),
+            _ => None,
+        }
+    }
+}
+
+// A `logging` section in the rules document or a tenant, e.g. `{ "level": "debug",
+// "allow_sample_rate": 1.0 }`. Fields left out fall through to the next layer.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: Option<LogLevel>,
+    // Share of requests logged, from 0.0 to 1.0, by how they were decided
+    pub allow_sample_rate: Option<f64>,
+    pub deny_sample_rate: Option<f64>,
+}
+
+impl LogConfig {
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        for rate in [self.allow_sample_rate, self.deny_sample_rate]
+            .into_iter()
+            .flatten()
+        {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(format!("sample rates must lie in 0.0..=1.0, not {}", rate));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LogSettings {
+    pub level: LogLevel,
+    pub allow_sample_rate: f64,
+    pub deny_sample_rate: f64,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::Info,
+            allow_sample_rate: 1.0,
+            deny_sample_rate: 1.0,
+        }
+    }
+}
+
+impl LogSettings {
+    // Layers come in order of precedence; the first one to set a field wins
+    fn layered(base: LogSettings, layers: &[Option<&LogConfig>]) -> LogSettings {
+        let layers: Vec<&LogConfig> = layers.iter().flatten().copied().collect();
+        LogSettings {
+            level: layers
+                .iter()
+                .find_map(|layer| layer.level)
+                .unwrap_or(base.level),
+            allow_sample_rate: layers
+                .iter()
+                .find_map(|layer| layer.allow_sample_rate)
+                .unwrap_or(base.allow_sample_rate),
+            deny_sample_rate: layers
+                .iter()
+                .find_map(|layer| layer.deny_sample_rate)
+                .unwrap_or(base.deny_sample_rate),
+        }
+    }
+
+    fn sampled(&self, outcome: AuditOutcome, draw: f64) -> bool {
+        let rate = match outcome {
+            AuditOutcome::Allow => self.allow_sample_rate,
+            AuditOutcome::Deny => self.deny_sample_rate,
+        };
+        draw < rate
+    }
+}
+
+fn rate_var(env: &Env, name: &str) -> Option<f64> {
+    env.var(name)
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .filter(|rate| (0.0..=1.0).contains(rate))
+}
+
+// The rules document wins, so logging can be turned up through `/admin/rules` during an incident
+// without a deploy. The tenant comes next, then `LOG_LEVEL` and `LOG_SAMPLE_ALLOW`/`_DENY`.
+pub fn log_settings(env: &Env, rules: &RuleSet, tenant: &TenantConfig) -> LogSettings {
+    let defaults = LogSettings::default();
+    let base = LogSettings {
+        level: env
+            .var("LOG_LEVEL")
+            .ok()
+            .and_then(|v| LogLevel::parse(&v.to_string()))
+            .unwrap_or(defaults.level),
+        allow_sample_rate: rate_var(env, "LOG_SAMPLE_ALLOW").unwrap_or(defaults.allow_sample_rate),
+        deny_sample_rate: rate_var(env, "LOG_SAMPLE_DENY").unwrap_or(defaults.deny_sample_rate),
+    };
+    LogSettings::layered(base, &[rules.logging.as_ref(), tenant.logging.as_ref()])
+}
+
+// Collects one request's log lines and writes them together once the request is decided, if the
+// request is sampled. Requests that end before a decision log nothing from here; errors are
+// still logged where they happen.
+pub struct RequestLog {
+    settings: LogSettings,
+    lines: RefCell<Vec<String>>,
+    decision: RefCell<Option<(AuditOutcome, String)>>,
+}
+
+impl RequestLog {
+    pub fn new(settings: LogSettings) -> Self {
+        Self {
+            settings,
+            lines: RefCell::new(Vec::new()),
+            decision: RefCell::new(None),
+        }
+    }
+
+    pub fn line(&self, line: String) {
+        if self.settings.level > LogLevel::Off {
+            self.lines.borrow_mut().push(line);
+        }
+    }
+
+    // A token value, or only its length below `debug`
+    pub fn token(&self, value: &str) -> String {
+        redacted(self.settings.level, value)
+    }
+
+    pub fn record(&self, decision: &Decision) {
+        let outcome = match decision.outcome {
+            AuditOutcome::Allow => "allow",
+            AuditOutcome::Deny => "deny",
+        };
+        let summary = format!(
+            "request: outcome={} code={} status={} enforced={} tenant={} function_id={} path={}",
+            outcome,
+            decision.code.code(),
+            decision.status,
+            decision.enforced,
+            decision.tenant,
+            decision.function_id,
+            decision.path
+        );
+        *self.decision.borrow_mut() = Some((decision.outcome, summary));
+    }
+}
+
+impl Drop for RequestLog {
+    fn drop(&mut self) {
+        let Some((outcome, summary)) = self.decision.get_mut().take() else {
+            return;
+        };
+        if self.settings.level == LogLevel::Off
+            || !self.settings.sampled(outcome, js_sys::Math::random())
+        {
+            return;
+        }
+        for line in self.lines.get_mut().iter() {
+            console_log!("{}", line);
+        }
+        console_log!("{}", summary);
+    }
+}
+
+fn redacted(level: LogLevel, value: &str) -> String {
+    match level {
+        LogLevel::Debug => value.to_string(),
+        _ if value.is_empty() => String::new(),
+        _ => format!("<{} bytes>", value.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> LogConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn layers_rules_over_tenant_over_vars() {
+        let base = LogSettings {
+            level: LogLevel::Info,
+            allow_sample_rate: 0.01,
+            deny_sample_rate: 1.0,
+        };
+        assert_eq!(LogSettings::layered(base, &[None, None]), base);
+
+        let incident = config(r#"{ "level": "debug" }"#);
+        let tenant = config(r#"{ "level": "off", "allow_sample_rate": 0.5 }"#);
+        assert_eq!(
+            LogSettings::layered(base, &[Some(&incident), Some(&tenant)]),
+            LogSettings {
+                level: LogLevel::Debug,
+                allow_sample_rate: 0.5,
+                deny_sample_rate: 1.0,
+            }
+        );
+
+        assert!(config(r#"{ "deny_sample_rate": 1.5 }"#).validate().is_err());
+        assert!(serde_json::from_str::<LogConfig>(r#"{ "verbosity": "debug" }"#).is_err());
+    }
+
+    #[test]
+    fn samples_by_outcome_and_redacts_tokens() {
+        let settings = LogSettings {
+            level: LogLevel::Info,
+            allow_sample_rate: 0.01,
+            deny_sample_rate: 1.0,
+        };
+        assert!(settings.sampled(AuditOutcome::Deny, 0.999));
+        assert!(settings.sampled(AuditOutcome::Allow, 0.005));
+        assert!(!settings.sampled(AuditOutcome::Allow, 0.5));
+
+        assert_eq!(redacted(LogLevel::Info, "1693123456-abc"), "<14 bytes>");
+        assert_eq!(redacted(LogLevel::Info, ""), "");
+        assert_eq!(
+            redacted(LogLevel::Debug, "1693123456-abc"),
+            "1693123456-abc"
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use url::Url;
+use worker::Request;
+
+use crate::token::constant_time_compare;
+
+pub const SIGNING_ALGORITHM: &str = "VALIDATOR-HMAC-SHA256";
+pub const DATE_HEADER: &str = "x-validator-date";
+
+// Headers every signature must cover, so a signature cannot be replayed against another host or time
+const REQUIRED_SIGNED_HEADERS: [&str; 2] = ["host", DATE_HEADER];
+
+// `Authorization: VALIDATOR-HMAC-SHA256 Credential={kid}, SignedHeaders=host;x-validator-date, Signature={hex}`
+#[derive(Debug, PartialEq)]
+pub struct SignatureHeader {
+    pub kid: Option<String>,
+    pub signed_headers: Vec<String>,
+    pub signature: String,
+}
+
+pub fn parse_authorization(value: &str) -> Option<SignatureHeader> {
+    let params = value.strip_prefix(SIGNING_ALGORITHM)?.strip_prefix(' ')?;
+
+    let mut kid = None;
+    let mut signed_headers = None;
+    let mut signature = None;
+    for param in params.split(',') {
+        let (name, value) = param.trim().split_once('=')?;
+        match name {
+            "Credential" if !value.is_empty() => kid = Some(value.to_string()),
+            "SignedHeaders" => {
+                signed_headers = Some(
+                    value
+                        .split(';')
+                        .map(str::to_ascii_lowercase)
+                        .collect::<Vec<_>>(),
+                )
+            }
+            "Signature" => signature = Some(value.to_ascii_lowercase()),
+            _ => return None,
+        }
+    }
+
+    let signed_headers = signed_headers?;
+    if !REQUIRED_SIGNED_HEADERS
+        .iter()
+        .all(|required| signed_headers.iter().any(|name| name == required))
+    {
+        return None;
+    }
+    Some(SignatureHeader {
+        kid,
+        signed_headers,
+        signature: signature?,
+    })
+}
+
+// Query pairs are percent-encoded (RFC 3986 unreserved characters left as is) and sorted by name, then value
+pub fn canonical_query(url: &Url) -> String {
+    let mut pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| {
+            (
+                urlencoding::encode(&k).into_owned(),
+                urlencoding::encode(&v).into_owned(),
+            )
+        })
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// method, path, canonical query, `name:value` per signed header, the signed header list and the
+// hex SHA-256 of the body, one per line
+pub fn canonical_request(
+    method: &str,
+    url: &Url,
+    signed_headers: &[(String, String)],
+    body: &[u8],
+) -> String {
+    let mut sorted = signed_headers.to_vec();
+    sorted.sort();
+    let header_lines: String = sorted
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let header_names = sorted
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method.to_ascii_uppercase(),
+        url.path(),
+        canonical_query(url),
+        header_lines,
+        header_names,
+        hex::encode(Sha256::digest(body))
+    )
+}
+
+pub fn string_to_sign(timestamp: &str, canonical_request: &str) -> String {
+    format!(
+        "{}\n{}\n{}",
+        SIGNING_ALGORITHM,
+        timestamp,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    )
+}
+
+pub fn sign(secret: &str, string_to_sign: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(string_to_sign.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Recomputes the signature over the request as received, `body` being the one already buffered
+// for the upstream request. `secret_for` maps the credential key id to its secret; the date
+// header must lie within `validity_seconds` of now in either direction.
+pub fn verify(
+    req: &Request,
+    url: &Url,
+    body: &[u8],
+    secret_for: impl Fn(Option<&str>) -> Option<String>,
+    validity_seconds: f64,
+) -> Option<SignatureHeader> {
+    let headers = req.headers();
+    let header = headers
+        .get("Authorization")
+        .ok()
+        .flatten()
+        .and_then(|value| parse_authorization(&value))?;
+
+    let timestamp = headers.get(DATE_HEADER).ok().flatten()?;
+    let signed_at: f64 = timestamp.parse().ok()?;
+    if (js_sys::Date::now() / 1000.0 - signed_at).abs() > validity_seconds {
+        return None;
+    }
+
+    let mut signed_values = Vec::with_capacity(header.signed_headers.len());
+    for name in &header.signed_headers {
+        signed_values.push((name.clone(), headers.get(name).ok().flatten()?));
+    }
+    let secret = secret_for(header.kid.as_deref())?;
+    let canonical = canonical_request(req.method().as_ref(), url, &signed_values, body);
+    let expected = sign(&secret, &string_to_sign(&timestamp, &canonical));
+    constant_time_compare(expected.as_bytes(), header.signature.as_bytes()).then_some(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_authorization_header() {
+        let header = parse_authorization(
+            "VALIDATOR-HMAC-SHA256 Credential=2, SignedHeaders=Host;X-Validator-Date;content-type, Signature=ABCDEF",
+        )
+        .unwrap();
+        assert_eq!(
+            header,
+            SignatureHeader {
+                kid: Some("2".to_string()),
+                signed_headers: vec![
+                    "host".to_string(),
+                    "x-validator-date".to_string(),
+                    "content-type".to_string()
+                ],
+                signature: "abcdef".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn requires_host_and_date_to_be_signed() {
+        assert!(
+            parse_authorization("VALIDATOR-HMAC-SHA256 SignedHeaders=host, Signature=abcdef")
+                .is_none()
+        );
+        assert!(parse_authorization(
+            "AWS4-HMAC-SHA256 SignedHeaders=host;x-validator-date, Signature=abcdef"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn canonical_query_sorts_and_encodes() {
+        let url = Url::parse("https://api.example.com/v1?b=2&a=x%20y&a=1&c=%2F").unwrap();
+        assert_eq!(canonical_query(&url), "a=1&a=x%20y&b=2&c=%2F");
+    }
+
+    #[test]
+    fn canonical_request_layout() {
+        let url = Url::parse("https://api.example.com/v1/orders?b=2&a=1").unwrap();
+        let headers = vec![
+            ("x-validator-date".to_string(), "1693123456".to_string()),
+            ("host".to_string(), " api.example.com ".to_string()),
+        ];
+        assert_eq!(
+            canonical_request("post", &url, &headers, b""),
+            "POST\n/v1/orders\na=1&b=2\nhost:api.example.com\nx-validator-date:1693123456\n\n\
+             host;x-validator-date\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn signature_covers_the_body() {
+        let url = Url::parse("https://api.example.com/v1/orders").unwrap();
+        let headers = vec![("host".to_string(), "api.example.com".to_string())];
+        let signature = |body: &[u8]| {
+            let canonical = canonical_request("POST", &url, &headers, body);
+            sign("secret", &string_to_sign("1693123456", &canonical))
+        };
+        assert_eq!(signature(b"{}"), signature(b"{}"));
+        assert_ne!(signature(b"{}"), signature(b"{\"amount\":1}"));
+    }
+}
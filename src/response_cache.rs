@@ -0,0 +1,101 @@
+use worker::*;
+
+// Enabled by `RESPONSE_CACHE=on`; `RESPONSE_CACHE_TTL` (seconds) overrides the origin's max-age
+#[derive(Clone, Debug)]
+pub struct CacheSettings {
+    pub ttl_override: Option<u64>,
+}
+
+pub fn cache_settings(env: &Env) -> Option<CacheSettings> {
+    if env
+        .var("RESPONSE_CACHE")
+        .map(|v| v.to_string())
+        .ok()?
+        .as_str()
+        != "on"
+    {
+        return None;
+    }
+
+    let ttl_override = env.var("RESPONSE_CACHE_TTL").ok().and_then(|v| {
+        let value = v.to_string();
+        value
+            .parse()
+            .map_err(|_| console_error!("Invalid RESPONSE_CACHE_TTL {}", value))
+            .ok()
+    });
+    Some(CacheSettings { ttl_override })
+}
+
+// Keys drop `oait` entirely so one cached copy serves every client of the same asset, and
+// `function_id` so purging by the public URL hits the same entry
+pub fn cache_key(upstream_url: &Url) -> String {
+    let mut key = upstream_url.clone();
+    let pairs: Vec<(String, String)> = upstream_url
+        .query_pairs()
+        .filter(|(k, _)| k != "oait" && k != "function_id")
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    key.set_query(None);
+    if !pairs.is_empty() {
+        key.query_pairs_mut().extend_pairs(pairs);
+    }
+    key.to_string()
+}
+
+pub async fn lookup(key: &str) -> Result<Option<Response>> {
+    Cache::default().get(key, false).await
+}
+
+// Stores a copy in the background when the origin allows it; returns the response to send
+pub fn store(
+    ctx: &Context,
+    settings: &CacheSettings,
+    key: String,
+    mut response: Response,
+) -> Result<Response> {
+    if !is_cacheable(&response)? {
+        return Ok(response);
+    }
+
+    // A clone starts out with automatic encoding; keep the original's
+    let copy = response.cloned()?.with_encode_body(*response.encode_body());
+    let headers = Headers::new();
+    for (name, value) in copy.headers().entries() {
+        headers.append(&name, &value)?;
+    }
+    if let Some(ttl) = settings.ttl_override {
+        headers.set("Cache-Control", &format!("public, max-age={}", ttl))?;
+    }
+    let copy = crate::encoding::rebuild(&copy, headers, copy.status_code())?;
+
+    ctx.wait_until(async move {
+        if let Err(e) = Cache::default().put(key.as_str(), copy).await {
+            console_error!("Failed to cache response: {}", e);
+        }
+    });
+    Ok(response)
+}
+
+pub async fn purge(url: &Url) -> Result<bool> {
+    let key = cache_key(url);
+    Ok(matches!(
+        Cache::default().delete(key.as_str(), false).await?,
+        CacheDeletionOutcome::Success
+    ))
+}
+
+fn is_cacheable(response: &Response) -> Result<bool> {
+    if response.status_code() != 200 || response.headers().has("Set-Cookie")? {
+        return Ok(false);
+    }
+
+    let cache_control = response
+        .headers()
+        .get("Cache-Control")?
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    Ok(!["no-store", "private", "no-cache"]
+        .iter()
+        .any(|directive| cache_control.contains(directive)))
+}
@@ -0,0 +1,383 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use worker::{kv::KvStore, Date, Result};
+
+use crate::config::Config;
+use crate::token::{canonical_kid, parse_hmac_token};
+
+// KV reads are edge-cached for this long, so a new revocation takes up to a minute to apply everywhere
+const REVOCATION_CACHE_TTL_SECONDS: u64 = 60;
+// A KV list page holds at most 1000 keys; one housekeeping run walks at most this many pages
+const MAX_PRUNE_PAGES: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RevocationScope {
+    // A single token, stored by its SHA-256 so revocation entries never hold live credentials
+    Token,
+    // Every token presented from a client IP
+    Ip,
+    // Every token signed with a key id
+    Kid,
+}
+
+impl RevocationScope {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Token => "token",
+            Self::Ip => "ip",
+            Self::Kid => "kid",
+        }
+    }
+
+    fn prefix(&self) -> &'static str {
+        match self {
+            Self::Token => "token:",
+            Self::Ip => "ip:",
+            Self::Kid => "kid:",
+        }
+    }
+
+    fn key(&self, value: &str, config: &Config) -> String {
+        match self {
+            Self::Token => format!("{}{}", self.prefix(), token_digest(value, config)),
+            Self::Ip => format!("{}{}", self.prefix(), value),
+            Self::Kid => format!("{}{}", self.prefix(), canonical_kid(value)),
+        }
+    }
+}
+
+// What a token is revoked by: the SHA-256 of its canonical form, so revoking one spelling refuses
+// all of them. Tokens that do not parse as HMAC tokens are hashed as given. Sessions and
+// continuations keep the digest of the token that opened them, never the token itself.
+pub fn token_digest(token: &str, config: &Config) -> String {
+    let canonical = parse_hmac_token(token, config.hmac_algorithms)
+        .and_then(|token| token.canonical(config.hash_encoding))
+        .unwrap_or_else(|| token.to_string());
+    hex::encode(Sha256::digest(canonical.as_bytes()))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Revocation {
+    pub revoked_at: f64,
+    pub reason: Option<String>,
+}
+
+// Also stored as the entry's KV metadata, so pruning can judge entries from the key list alone.
+// Times are epoch milliseconds.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct RevocationMetadata {
+    revoked_at: f64,
+    // When a revoked HMAC token was issued, and the expiry it carries, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    issued_at: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<f64>,
+}
+
+impl RevocationMetadata {
+    fn new(scope: RevocationScope, value: &str, config: &Config, revoked_at: f64) -> Self {
+        let token = match scope {
+            RevocationScope::Token => parse_hmac_token(value, config.hmac_algorithms),
+            RevocationScope::Ip | RevocationScope::Kid => None,
+        };
+        Self {
+            revoked_at,
+            issued_at: token
+                .as_ref()
+                .map(|token| config.timestamp_unit.to_seconds(token.timestamp) * 1000.0),
+            expires_at: token
+                .and_then(|token| token.lifetime.expires_at)
+                .map(|expires_at| expires_at * 1000.0),
+        }
+    }
+
+    // Past the retention, and past the last moment the revoked token could still have verified
+    fn is_stale(&self, retention: &Retention, now_ms: f64) -> bool {
+        let usable_until = match (self.expires_at, self.issued_at) {
+            (Some(expires_at), _) => expires_at,
+            (None, Some(issued_at)) => issued_at + retention.validity_ms,
+            // Tokens of other signature modes, and entries written before lifetimes were stored
+            (None, None) => self.revoked_at + retention.max_lifetime_ms,
+        };
+        now_ms - self.revoked_at > retention.retention_ms && now_ms > usable_until
+    }
+}
+
+// How long token revocations must be kept, in milliseconds
+#[derive(Clone, Copy, Debug)]
+pub struct Retention {
+    // `REVOCATION_RETENTION_SECONDS`, counted from the revocation
+    pub retention_ms: f64,
+    // The longest validity window a token without an expiry may be accepted in
+    pub validity_ms: f64,
+    // The longest any token may live, for entries whose lifetime is unknown
+    pub max_lifetime_ms: f64,
+}
+
+// Consults every applicable scope concurrently and returns the first one that matched. The token
+// is given by its `token_digest`.
+pub async fn find_revocation(
+    kv: &KvStore,
+    config: &Config,
+    token_digest: Option<&str>,
+    client_ip: &str,
+    kid: Option<&str>,
+) -> Result<Option<RevocationScope>> {
+    let lookup = |key: String| kv.get(&key).cache_ttl(REVOCATION_CACHE_TTL_SECONDS).text();
+
+    let (by_token, by_ip, by_kid) = futures::future::join3(
+        async {
+            match token_digest {
+                Some(digest) => {
+                    lookup(format!("{}{}", RevocationScope::Token.prefix(), digest)).await
+                }
+                None => Ok(None),
+            }
+        },
+        lookup(RevocationScope::Ip.key(client_ip, config)),
+        async {
+            match kid {
+                Some(kid) => lookup(RevocationScope::Kid.key(kid, config)).await,
+                None => Ok(None),
+            }
+        },
+    )
+    .await;
+
+    for (scope, entry) in [
+        (RevocationScope::Token, by_token?),
+        (RevocationScope::Ip, by_ip?),
+        (RevocationScope::Kid, by_kid?),
+    ] {
+        if entry.is_some() {
+            return Ok(Some(scope));
+        }
+    }
+    Ok(None)
+}
+
+pub async fn revoke(
+    kv: &KvStore,
+    config: &Config,
+    scope: RevocationScope,
+    value: &str,
+    reason: Option<String>,
+    ttl_seconds: Option<u64>,
+) -> Result<()> {
+    let entry = Revocation {
+        revoked_at: Date::now().as_millis() as f64,
+        reason,
+    };
+    let mut put = kv
+        .put(&scope.key(value, config), serde_json::to_string(&entry)?)?
+        .metadata(RevocationMetadata::new(
+            scope,
+            value,
+            config,
+            entry.revoked_at,
+        ))?;
+    if let Some(ttl_seconds) = ttl_seconds {
+        put = put.expiration_ttl(ttl_seconds);
+    }
+    Ok(put.execute().await?)
+}
+
+pub async fn unrevoke(
+    kv: &KvStore,
+    config: &Config,
+    scope: RevocationScope,
+    value: &str,
+) -> Result<()> {
+    Ok(kv.delete(&scope.key(value, config)).await?)
+}
+
+// Only token revocations can outlive their purpose: once the token's own expiry or validity window
+// has passed, it no longer verifies. Entries with a TTL expire by themselves, and IP and key id
+// revocations stay until they are lifted. Returns how many entries were deleted.
+pub async fn prune(kv: &KvStore, retention: &Retention, now_ms: f64) -> Result<usize> {
+    let mut pruned = 0;
+    let mut cursor = None;
+    for _ in 0..MAX_PRUNE_PAGES {
+        let mut list = kv
+            .list()
+            .prefix(RevocationScope::Token.prefix().to_string());
+        if let Some(cursor) = cursor.take() {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+
+        let mut stale = Vec::new();
+        for key in page.keys.iter().filter(|key| key.expiration.is_none()) {
+            let metadata =
+                match metadata(key.metadata.as_ref()) {
+                    Some(metadata) => Some(metadata),
+                    // Entries written before metadata was stored
+                    None => kv.get(&key.name).json::<Revocation>().await?.map(|entry| {
+                        RevocationMetadata {
+                            revoked_at: entry.revoked_at,
+                            issued_at: None,
+                            expires_at: None,
+                        }
+                    }),
+                };
+            if metadata.is_some_and(|metadata| metadata.is_stale(retention, now_ms)) {
+                stale.push(key.name.as_str());
+            }
+        }
+        for result in futures::future::join_all(stale.iter().map(|name| kv.delete(name))).await {
+            result?;
+        }
+        pruned += stale.len();
+
+        if page.list_complete {
+            break;
+        }
+        cursor = page.cursor;
+    }
+    Ok(pruned)
+}
+
+fn metadata(metadata: Option<&serde_json::Value>) -> Option<RevocationMetadata> {
+    serde_json::from_value(metadata?.clone()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn reads_revocation_time_from_metadata() {
+        let stored = serde_json::json!({ "revoked_at": 1693123456000.0 });
+        assert_eq!(
+            metadata(Some(&stored)).map(|metadata| metadata.revoked_at),
+            Some(1693123456000.0)
+        );
+        assert_eq!(metadata(Some(&serde_json::json!({}))), None);
+        assert_eq!(metadata(None), None);
+        assert!(RevocationScope::Token
+            .key("1693123456-abc", &Config::default())
+            .starts_with(RevocationScope::Token.prefix()));
+    }
+
+    #[test]
+    fn keeps_revoked_tokens_until_they_can_no_longer_verify() {
+        const DAY_MS: f64 = 24.0 * 60.0 * 60.0 * 1000.0;
+        let retention = Retention {
+            retention_ms: DAY_MS,
+            validity_ms: 5.0 * 60.0 * 1000.0,
+            max_lifetime_ms: 30.0 * DAY_MS,
+        };
+        let config = Config::default();
+        let revoked_at = 1693123456000.0;
+
+        // A signed link good for 30 days outlives the retention
+        let link = RevocationMetadata::new(
+            RevocationScope::Token,
+            &format!(
+                "sha256;anyip;exp={}:1693123456-abcd",
+                1693123456 + 30 * 86400
+            ),
+            &config,
+            revoked_at,
+        );
+        assert_eq!(link.expires_at, Some(revoked_at + 30.0 * DAY_MS));
+        assert!(!link.is_stale(&retention, revoked_at + 2.0 * DAY_MS));
+        assert!(!link.is_stale(&retention, revoked_at + 29.0 * DAY_MS));
+        assert!(link.is_stale(&retention, revoked_at + 31.0 * DAY_MS));
+
+        // An ordinary token is gone once the retention passes, its window having closed long before
+        let token = RevocationMetadata::new(
+            RevocationScope::Token,
+            "1693123456-abcd",
+            &config,
+            revoked_at,
+        );
+        assert_eq!(token.issued_at, Some(revoked_at));
+        assert!(!token.is_stale(&retention, revoked_at + DAY_MS / 2.0));
+        assert!(token.is_stale(&retention, revoked_at + 2.0 * DAY_MS));
+
+        // Without a known lifetime the longest issuable one is assumed
+        let unknown = RevocationMetadata::new(
+            RevocationScope::Token,
+            "ed25519:1693123456-abcd",
+            &config,
+            revoked_at,
+        );
+        assert!(!unknown.is_stale(&retention, revoked_at + 2.0 * DAY_MS));
+        assert!(unknown.is_stale(&retention, revoked_at + 31.0 * DAY_MS));
+    }
+
+    #[test]
+    fn re_encoded_tokens_share_a_revocation_key() {
+        let config = Config::default();
+        let tag = [0xfbu8; 32];
+        let hex_tag = hex::encode(tag);
+        let key = RevocationScope::Token.key(&format!("1693123456-{}", hex_tag), &config);
+        // Sessions look the token up by the digest they were opened with
+        assert_eq!(
+            key,
+            format!(
+                "token:{}",
+                token_digest(&format!("sha256:1693123456-{}", hex_tag), &config)
+            )
+        );
+
+        for spelling in [
+            format!("1693123456-{}", hex_tag.to_uppercase()),
+            format!("sha256:1693123456-{}", hex_tag),
+            format!("1693123456.0-{}", hex_tag),
+            format!("1693123456-{}", BASE64_STANDARD.encode(tag)),
+            format!("1693123456-{}", BASE64_STANDARD_NO_PAD.encode(tag)),
+            format!("1693123456-{}", BASE64_URL_SAFE.encode(tag)),
+            format!("sha256:1693123456-{}", BASE64_URL_SAFE_NO_PAD.encode(tag)),
+        ] {
+            assert_eq!(
+                RevocationScope::Token.key(&spelling, &config),
+                key,
+                "{}",
+                spelling
+            );
+        }
+
+        // Key ids pick their secret case-insensitively, so their case cannot dodge a revocation
+        let kid_key =
+            RevocationScope::Token.key(&format!("sha256.k1:1693123456-{}", hex_tag), &config);
+        for spelling in [
+            format!("sha256.K1:1693123456-{}", hex_tag),
+            format!(
+                "sha256.k1:1693123456-{}",
+                BASE64_URL_SAFE_NO_PAD.encode(tag)
+            ),
+        ] {
+            assert_eq!(
+                RevocationScope::Token.key(&spelling, &config),
+                kid_key,
+                "{}",
+                spelling
+            );
+        }
+        assert_ne!(kid_key, key);
+        assert_eq!(
+            RevocationScope::Kid.key("k1", &config),
+            RevocationScope::Kid.key("K1", &config)
+        );
+
+        // A different token, or the same tag under other signed attributes, stays distinct
+        assert_ne!(
+            RevocationScope::Token.key(&format!("1693123457-{}", hex_tag), &config),
+            key
+        );
+        assert_ne!(
+            RevocationScope::Token.key(&format!("sha256;anyip:1693123456-{}", hex_tag), &config),
+            key
+        );
+        // Tokens of other signature modes are keyed as given
+        assert_ne!(
+            RevocationScope::Token.key("ed25519:1693123456-abc", &config),
+            RevocationScope::Token.key("1693123456-abc", &config)
+        );
+    }
+}
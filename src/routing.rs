@@ -0,0 +1,198 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use serde::Deserialize;
+use worker::Env;
+
+const WILDCARD_LABEL: &str = "*";
+
+// Entries of the `ROUTES` JSON var, e.g.
+// [{ "host": "*.example.com", "path_prefix": "/partners", "tenant": "partners" }]
+#[derive(Clone, Debug, Deserialize)]
+pub struct RouteRule {
+    pub host: String,
+    #[serde(default)]
+    pub path_prefix: String,
+    pub tenant: String,
+}
+
+// Hosts are stored label by label from the TLD down, and each host holds a trie of path
+// segments, so a lookup costs one step per label and segment however many rules exist
+#[derive(Debug, Default)]
+pub struct RouteTable {
+    root: HostNode,
+}
+
+#[derive(Debug, Default)]
+struct HostNode {
+    children: HashMap<String, HostNode>,
+    paths: Option<PathNode>,
+}
+
+#[derive(Debug, Default)]
+struct PathNode {
+    children: HashMap<String, PathNode>,
+    tenant: Option<String>,
+}
+
+impl RouteTable {
+    pub fn compile(rules: &[RouteRule]) -> Self {
+        let mut table = Self::default();
+        for rule in rules {
+            let mut host_node = &mut table.root;
+            for label in host_labels(&rule.host) {
+                host_node = host_node.children.entry(label).or_default();
+            }
+
+            let mut path_node = host_node.paths.get_or_insert_with(PathNode::default);
+            for segment in path_segments(&rule.path_prefix) {
+                path_node = path_node.children.entry(segment.to_string()).or_default();
+            }
+            // The first rule for a host and prefix wins, matching the order in the config
+            path_node.tenant.get_or_insert_with(|| rule.tenant.clone());
+        }
+        table
+    }
+
+    // Exact hosts beat wildcards and deeper wildcards beat shallower ones; within a host the
+    // longest matching path prefix wins
+    pub fn resolve(&self, host: &str, path: &str) -> Option<&str> {
+        let labels = host_labels(host);
+        let mut candidates = Vec::new();
+        let mut node = Some(&self.root);
+        for label in &labels {
+            let Some(current) = node else { break };
+            // `*` stands for one or more labels, so it only applies while labels remain
+            if let Some(wildcard) = current.children.get(WILDCARD_LABEL) {
+                candidates.push(wildcard);
+            }
+            node = current.children.get(label);
+        }
+
+        node.into_iter()
+            .chain(candidates.into_iter().rev())
+            .find_map(|host_node| host_node.paths.as_ref()?.resolve(path))
+    }
+}
+
+impl PathNode {
+    fn resolve(&self, path: &str) -> Option<&str> {
+        let mut node = self;
+        let mut matched = node.tenant.as_deref();
+        for segment in path_segments(path) {
+            let Some(child) = node.children.get(segment) else {
+                break;
+            };
+            node = child;
+            matched = node.tenant.as_deref().or(matched);
+        }
+        matched
+    }
+}
+
+fn host_labels(host: &str) -> Vec<String> {
+    host.trim_end_matches('.')
+        .rsplit('.')
+        .map(str::to_ascii_lowercase)
+        .collect()
+}
+
+fn path_segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+thread_local! {
+    static ROUTE_TABLE: RefCell<Option<Rc<RouteTable>>> = const { RefCell::new(None) };
+}
+
+// Vars are fixed for the lifetime of an isolate, so the table is compiled on first use only
+pub fn route_table(env: &Env) -> Rc<RouteTable> {
+    ROUTE_TABLE.with(|cache| {
+        cache
+            .borrow_mut()
+            .get_or_insert_with(|| {
+                let rules: Vec<RouteRule> = crate::object_var(env, "ROUTES").unwrap_or_default();
+                Rc::new(RouteTable::compile(&rules))
+            })
+            .clone()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(rules: &[(&str, &str, &str)]) -> RouteTable {
+        let rules: Vec<RouteRule> = rules
+            .iter()
+            .map(|(host, path_prefix, tenant)| RouteRule {
+                host: host.to_string(),
+                path_prefix: path_prefix.to_string(),
+                tenant: tenant.to_string(),
+            })
+            .collect();
+        RouteTable::compile(&rules)
+    }
+
+    #[test]
+    fn longest_path_prefix_wins() {
+        let routes = table(&[
+            ("login.example.com", "", "default"),
+            ("login.example.com", "/partners", "partners"),
+            ("login.example.com", "/partners/acme", "acme"),
+        ]);
+        assert_eq!(routes.resolve("login.example.com", "/"), Some("default"));
+        assert_eq!(
+            routes.resolve("login.example.com", "/partners/login"),
+            Some("partners")
+        );
+        assert_eq!(
+            routes.resolve("login.example.com", "/partners/acme/login"),
+            Some("acme")
+        );
+    }
+
+    #[test]
+    fn path_prefixes_match_whole_segments() {
+        let routes = table(&[("login.example.com", "/app", "app")]);
+        assert_eq!(routes.resolve("login.example.com", "/app"), Some("app"));
+        assert_eq!(routes.resolve("login.example.com", "/app/"), Some("app"));
+        assert_eq!(routes.resolve("login.example.com", "/apple"), None);
+    }
+
+    #[test]
+    fn exact_hosts_beat_wildcards() {
+        let routes = table(&[
+            ("*.com", "", "any-com"),
+            ("*.example.com", "", "example"),
+            ("login.example.com", "/app", "login"),
+        ]);
+        assert_eq!(routes.resolve("login.example.com", "/app"), Some("login"));
+        // Falls back to the wildcard when the exact host has no matching path
+        assert_eq!(
+            routes.resolve("login.example.com", "/other"),
+            Some("example")
+        );
+        assert_eq!(routes.resolve("a.b.example.com", "/"), Some("example"));
+        assert_eq!(routes.resolve("other.com", "/"), Some("any-com"));
+        // A wildcard needs at least one label of its own
+        assert_eq!(routes.resolve("example.com", "/"), Some("any-com"));
+        assert_eq!(routes.resolve("com", "/"), None);
+    }
+
+    #[test]
+    fn hosts_are_case_insensitive() {
+        let routes = table(&[("Login.Example.com", "", "login")]);
+        assert_eq!(routes.resolve("LOGIN.example.COM.", "/"), Some("login"));
+    }
+
+    #[test]
+    fn first_rule_wins_for_duplicates() {
+        let routes = table(&[
+            ("login.example.com", "/app", "first"),
+            ("login.example.com", "/app/", "second"),
+        ]);
+        assert_eq!(routes.resolve("login.example.com", "/app"), Some("first"));
+    }
+}
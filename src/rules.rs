@@ -0,0 +1,306 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use js_sys::Date;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use worker::*;
+
+use crate::events::{ConfigChangeEvent, Event};
+use crate::policy::FunctionPolicy;
+use crate::request_log::LogConfig;
+use crate::tenant::TenantConfig;
+
+const RULES_KEY: &str = "rules";
+const AUDIT_KEY_PREFIX: &str = "audit:";
+const DEFAULT_RULES_TTL_SECONDS: u32 = 30;
+const AUDIT_TTL_SECONDS: u64 = 90 * 24 * 60 * 60;
+
+// Policies and tenants stored as JSON under `rules` in the `RULES` KV namespace, managed through
+// `/admin/rules`. A section that is present replaces the `FUNCTION_POLICIES` or `TENANTS` var
+// as a whole; a missing one leaves the var in charge. `logging` overrides every tenant's.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleSet {
+    pub function_policies: Option<HashMap<String, FunctionPolicy>>,
+    pub tenants: Option<HashMap<String, TenantConfig>>,
+    pub logging: Option<LogConfig>,
+}
+
+// Parses a document the way the worker will read it, plus the checks serde cannot express
+pub fn validate(document: &str) -> std::result::Result<RuleSet, String> {
+    let rules: RuleSet = serde_json::from_str(document).map_err(|e| e.to_string())?;
+    for (function_id, policy) in rules.function_policies.iter().flatten() {
+        if function_id.is_empty() {
+            return Err("function_policies: function ids must not be empty".to_string());
+        }
+        crate::protected_params::validate(&policy.protected_params)
+            .map_err(|e| format!("function_policies.{}.protected_params: {}", function_id, e))?;
+        let canary_upstreams = policy
+            .canary
+            .iter()
+            .flat_map(|canary| &canary.backends)
+            .filter_map(|backend| backend.upstream.as_ref());
+        for upstream in policy.upstream.iter().chain(canary_upstreams) {
+            if Url::parse(upstream).is_err() {
+                return Err(format!(
+                    "function_policies.{}.upstream: invalid URL {:?}",
+                    function_id, upstream
+                ));
+            }
+        }
+    }
+    if rules
+        .tenants
+        .iter()
+        .flatten()
+        .any(|(host, _)| host.is_empty())
+    {
+        return Err("tenants: tenant keys must not be empty".to_string());
+    }
+    if let Some(logging) = &rules.logging {
+        logging.validate().map_err(|e| format!("logging: {}", e))?;
+    }
+    for (host, tenant) in rules.tenants.iter().flatten() {
+        if let Some(logging) = &tenant.logging {
+            logging
+                .validate()
+                .map_err(|e| format!("tenants.{}.logging: {}", host, e))?;
+        }
+        if let Some(policy) = &tenant.response_policy {
+            policy
+                .validate()
+                .map_err(|e| format!("tenants.{}.response_policy: {}", host, e))?;
+        }
+    }
+    Ok(rules)
+}
+
+// A strong validator over the stored bytes, quoted as `ETag` requires
+pub fn etag(document: &str) -> String {
+    format!("\"{}\"", hex::encode(Sha256::digest(document.as_bytes())))
+}
+
+fn if_match_allows(if_match: &str, current: &str) -> bool {
+    if_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == current)
+}
+
+struct CachedRules {
+    fetched_at: f64,
+    rules: Rc<RuleSet>,
+}
+
+thread_local! {
+    static RULES_CACHE: RefCell<Option<CachedRules>> = const { RefCell::new(None) };
+}
+
+fn cache(rules: RuleSet) -> Rc<RuleSet> {
+    let rules = Rc::new(rules);
+    RULES_CACHE.with(|cache| {
+        *cache.borrow_mut() = Some(CachedRules {
+            fetched_at: Date::now(),
+            rules: rules.clone(),
+        })
+    });
+    rules
+}
+
+// Read at most once per `RULES_TTL_SECONDS` per isolate. A failed read or an unparsable document
+// keeps the last known rules, or none if there are none yet.
+pub async fn current(env: &Env) -> Rc<RuleSet> {
+    let Ok(kv) = env.kv("RULES") else {
+        return Rc::default();
+    };
+
+    let ttl_ms = f64::from(crate::var_or(
+        env,
+        "RULES_TTL_SECONDS",
+        DEFAULT_RULES_TTL_SECONDS,
+    )) * 1000.0;
+    let now = Date::now();
+    let last_known = RULES_CACHE.with(|cache| {
+        cache
+            .borrow()
+            .as_ref()
+            .map(|cached| (now - cached.fetched_at < ttl_ms, cached.rules.clone()))
+    });
+    if let Some((true, rules)) = &last_known {
+        return rules.clone();
+    }
+
+    let fallback = || last_known.map(|(_, rules)| rules).unwrap_or_default();
+    match kv.get(RULES_KEY).text().await {
+        Ok(None) => cache(RuleSet::default()),
+        Ok(Some(document)) => match validate(&document) {
+            Ok(rules) => cache(rules),
+            Err(e) => {
+                console_error!("Ignoring invalid rules in KV: {}", e);
+                fallback()
+            }
+        },
+        Err(e) => {
+            console_error!("Failed to read rules: {}", e);
+            fallback()
+        }
+    }
+}
+
+// Changes are stored under `audit:{milliseconds}-{random}`: the time keeps a prefix listing in
+// order, and the suffix keeps two changes in the same millisecond from overwriting each other
+fn audit_key(timestamp: f64, suffix: [u8; 4]) -> String {
+    format!(
+        "{}{:013}-{}",
+        AUDIT_KEY_PREFIX,
+        timestamp as u64,
+        hex::encode(suffix)
+    )
+}
+
+fn etag_response(body: String, etag: &str, status: u16) -> Result<Response> {
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    headers.set("ETag", etag)?;
+    Ok(Response::ok(body)?
+        .with_status(status)
+        .with_headers(headers))
+}
+
+async fn stored(kv: &kv::KvStore) -> Result<String> {
+    Ok(kv
+        .get(RULES_KEY)
+        .text()
+        .await?
+        .unwrap_or_else(|| "{}".to_string()))
+}
+
+// `GET /admin/rules`: the stored document as is, `{}` before the first write
+pub async fn get(env: &Env) -> Result<Response> {
+    let Ok(kv) = env.kv("RULES") else {
+        return Response::error("Runtime rules are not configured", 404);
+    };
+    let document = stored(&kv).await?;
+    let etag = etag(&document);
+    etag_response(document, &etag, 200)
+}
+
+// `PUT /admin/rules` replaces the whole document. `If-Match` must carry the `ETag` the edit was
+// based on. KV has no compare-and-swap, so two writers within the same instant can both pass;
+// the check catches the usual case of an editor working from a stale copy.
+pub async fn put(mut req: Request, env: &Env, ctx: &Context) -> Result<Response> {
+    let Ok(kv) = env.kv("RULES") else {
+        return Response::error("Runtime rules are not configured", 404);
+    };
+    let Some(if_match) = req.headers().get("If-Match")? else {
+        return Response::error("If-Match is required", 428);
+    };
+
+    let previous = stored(&kv).await?;
+    let previous_etag = etag(&previous);
+    if !if_match_allows(&if_match, &previous_etag) {
+        return etag_response(previous, &previous_etag, 412);
+    }
+
+    let document = req.text().await?;
+    let rules = match validate(&document) {
+        Ok(rules) => rules,
+        Err(e) => return Response::error(format!("Invalid rules: {}", e), 400),
+    };
+    let etag = etag(&document);
+
+    let timestamp = Date::now();
+    let client_ip = crate::client_ip::client_ip(env, req.headers());
+    let mut change = ConfigChangeEvent {
+        schema_version: ConfigChangeEvent::SCHEMA_VERSION,
+        timestamp,
+        source: "rules".to_string(),
+        previous_etag,
+        etag: etag.clone(),
+        hashed_ip: crate::audit::hashed_ip(env, &client_ip),
+        previous: Some(previous),
+    };
+    kv.put(RULES_KEY, document.as_str())?.execute().await?;
+    // Other isolates pick the change up within `RULES_TTL_SECONDS`
+    cache(rules);
+
+    // Stored only once the change took, so every entry is a change that happened. The change is
+    // not undone when its entry cannot be stored; the missing entry is logged instead.
+    let mut suffix = [0u8; 4];
+    getrandom::getrandom(&mut suffix).map_err(|e| Error::RustError(e.to_string()))?;
+    let entry = serde_json::to_string(&Event::ConfigChanged(change.clone()))?;
+    let stored = async {
+        kv.put(&audit_key(timestamp, suffix), entry)?
+            .expiration_ttl(AUDIT_TTL_SECONDS)
+            .execute()
+            .await
+    };
+    if let Err(e) = stored.await {
+        console_error!(
+            "Failed to store the audit entry for rules etag={}: {}",
+            etag,
+            e
+        );
+    }
+
+    // The previous document stays in KV; queue messages are kept small
+    change.previous = None;
+    crate::audit::record_event(ctx, env, Event::ConfigChanged(change));
+    console_log!("admin: rules updated etag={}", etag);
+    etag_response(document, &etag, 200)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audit_keys_sort_by_time_and_stay_unique_within_a_millisecond() {
+        let first = audit_key(1693123456789.0, [0, 0, 0, 1]);
+        assert_eq!(first, "audit:1693123456789-00000001");
+        assert_ne!(first, audit_key(1693123456789.0, [0, 0, 0, 2]));
+        assert!(audit_key(999.0, [0xff; 4]) < first);
+    }
+
+    #[test]
+    fn validates_documents() {
+        let rules = validate(
+            r#"{
+                "function_policies": { "APPS_LOGIN_DEFAULT": { "upstream": "https://origin.example.com" } },
+                "tenants": { "login.example.com": { "signature_mode": "ed25519" } }
+            }"#,
+        )
+        .unwrap();
+        assert!(rules
+            .function_policies
+            .unwrap()
+            .contains_key("APPS_LOGIN_DEFAULT"));
+        assert!(rules.tenants.unwrap().contains_key("login.example.com"));
+
+        let rules = validate("{}").unwrap();
+        assert!(rules.function_policies.is_none() && rules.tenants.is_none());
+
+        assert!(validate(r#"{ "policies": {} }"#).is_err());
+        assert!(validate(r#"{ "tenants": { "a": { "signature_mode": "rsa" } } }"#).is_err());
+        assert!(
+            validate(r#"{ "function_policies": { "F": { "upstream": "origin" } } }"#)
+                .unwrap_err()
+                .contains("function_policies.F.upstream")
+        );
+    }
+
+    #[test]
+    fn if_match_compares_strong_etags() {
+        let current = etag("{}");
+        assert_eq!(current.len(), 66);
+        assert!(if_match_allows(&current, &current));
+        assert!(if_match_allows(
+            &format!("\"stale\", {}", current),
+            &current
+        ));
+        assert!(if_match_allows("*", &current));
+        assert!(!if_match_allows(&etag("{ }"), &current));
+    }
+}
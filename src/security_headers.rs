@@ -0,0 +1,84 @@
+use std::collections::{BTreeMap, HashMap};
+
+use async_trait::async_trait;
+use worker::{Env, Headers, Result};
+
+use crate::pipeline::{Flow, ResponseContext, Stage};
+use crate::tenant::TenantConfig;
+
+// Headers from the `SECURITY_HEADERS` JSON var, with the tenant's `security_headers` merged over
+// them. An empty tenant value drops that header for the tenant, leaving the origin's value.
+pub fn security_headers(env: &Env, tenant: &TenantConfig) -> Vec<(String, String)> {
+    let defaults: HashMap<String, String> =
+        crate::object_var(env, "SECURITY_HEADERS").unwrap_or_default();
+    merge(&defaults, &tenant.security_headers)
+}
+
+// Header names compare case-insensitively, so both maps are keyed by the lowercased name
+fn merge(
+    defaults: &HashMap<String, String>,
+    overrides: &HashMap<String, String>,
+) -> Vec<(String, String)> {
+    let mut merged: BTreeMap<String, (String, String)> = BTreeMap::new();
+    for (name, value) in defaults.iter().chain(overrides) {
+        merged.insert(name.to_ascii_lowercase(), (name.clone(), value.clone()));
+    }
+    merged
+        .into_values()
+        .filter(|(_, value)| !value.is_empty())
+        .collect()
+}
+
+// The edge values replace whatever the origin sent
+pub fn apply(headers: &Headers, security_headers: &[(String, String)]) -> Result<()> {
+    for (name, value) in security_headers {
+        headers.set(name, value)?;
+    }
+    Ok(())
+}
+
+pub struct SecurityHeadersStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<ResponseContext<'a>> for SecurityHeadersStage {
+    async fn run(&self, cx: &mut ResponseContext<'a>) -> Result<Flow> {
+        apply(&cx.headers, &security_headers(cx.env, cx.tenant))?;
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenant_overrides_replace_and_drop_defaults() {
+        let defaults = HashMap::from([
+            (
+                "Strict-Transport-Security".to_string(),
+                "max-age=31536000".to_string(),
+            ),
+            ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+            ("Referrer-Policy".to_string(), "no-referrer".to_string()),
+        ]);
+        let overrides = HashMap::from([
+            ("referrer-policy".to_string(), "strict-origin".to_string()),
+            ("Strict-Transport-Security".to_string(), String::new()),
+            (
+                "Content-Security-Policy".to_string(),
+                "default-src 'self'".to_string(),
+            ),
+        ]);
+        assert_eq!(
+            merge(&defaults, &overrides),
+            vec![
+                (
+                    "Content-Security-Policy".to_string(),
+                    "default-src 'self'".to_string()
+                ),
+                ("referrer-policy".to_string(), "strict-origin".to_string()),
+                ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+            ]
+        );
+    }
+}
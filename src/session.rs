@@ -0,0 +1,301 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use base64::prelude::*;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use worker::*;
+
+use crate::do_budget::DoBudget;
+use crate::pipeline::{Flow, ResponseContext, Stage};
+use crate::token::constant_time_compare;
+
+const SESSION_COOKIE_NAME: &str = "CF_Validator_Session";
+const DEFAULT_SESSION_IDLE_TIMEOUT_SECONDS: u32 = 900;
+const DEFAULT_SESSION_ABSOLUTE_TIMEOUT_SECONDS: u32 = 8 * 60 * 60;
+const DEFAULT_SESSION_TOUCH_INTERVAL_MS: u32 = 0;
+
+pub struct SessionSettings {
+    namespace: ObjectNamespace,
+    idle_timeout_seconds: u32,
+    absolute_timeout_seconds: u32,
+    touch_interval_ms: u32,
+}
+
+thread_local! {
+    // Session id to (client IP, time of the last successful touch, the session) for sessions seen
+    // in this isolate
+    static RECENT_TOUCHES: RefCell<HashMap<String, (String, f64, Resumed)>> =
+        RefCell::new(HashMap::new());
+}
+
+// Sessions need `SESSION_MODE=on` and the `SESSIONS_DO` Durable Object binding
+pub fn session_settings(env: &Env) -> Option<SessionSettings> {
+    if env.var("SESSION_MODE").ok()?.to_string() != "on" {
+        return None;
+    }
+
+    let namespace = env
+        .durable_object("SESSIONS_DO")
+        .map_err(|_| console_error!("SESSION_MODE is on but SESSIONS_DO is not bound"))
+        .ok()?;
+    Some(SessionSettings {
+        namespace,
+        idle_timeout_seconds: crate::var_or(
+            env,
+            "SESSION_IDLE_TIMEOUT_SECONDS",
+            DEFAULT_SESSION_IDLE_TIMEOUT_SECONDS,
+        ),
+        absolute_timeout_seconds: crate::var_or(
+            env,
+            "SESSION_ABSOLUTE_TIMEOUT_SECONDS",
+            DEFAULT_SESSION_ABSOLUTE_TIMEOUT_SECONDS,
+        ),
+        touch_interval_ms: crate::var_or(
+            env,
+            "SESSION_TOUCH_INTERVAL_MS",
+            DEFAULT_SESSION_TOUCH_INTERVAL_MS,
+        ),
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionRecord {
+    client_ip: String,
+    created_at: f64,
+    last_seen: f64,
+    idle_timeout_ms: f64,
+    absolute_timeout_ms: f64,
+    // The key id and `token_digest` of the token that opened the session, so revoking either the
+    // key or the token also ends it
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    token_digest: Option<String>,
+}
+
+// What a resumed session was opened with, returned by the object on each touch
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Resumed {
+    pub kid: Option<String>,
+    #[serde(default)]
+    pub token_digest: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TouchRequest {
+    client_ip: String,
+}
+
+// Creates a session for a freshly validated client and returns the `Set-Cookie` value for it
+pub async fn establish(
+    settings: &SessionSettings,
+    secret: &str,
+    client_ip: &str,
+    kid: Option<&str>,
+    token_digest: Option<&str>,
+    cookie_domain: Option<&str>,
+    budget: &DoBudget<'_>,
+) -> Result<String> {
+    let mut id_bytes = [0u8; 16];
+    getrandom::getrandom(&mut id_bytes).map_err(|e| Error::RustError(e.to_string()))?;
+    let session_id = hex::encode(id_bytes);
+
+    let now = Date::now().as_millis() as f64;
+    let record = SessionRecord {
+        client_ip: client_ip.to_string(),
+        created_at: now,
+        last_seen: now,
+        idle_timeout_ms: f64::from(settings.idle_timeout_seconds) * 1000.0,
+        absolute_timeout_ms: f64::from(settings.absolute_timeout_seconds) * 1000.0,
+        kid: kid.map(str::to_string),
+        token_digest: token_digest.map(str::to_string),
+    };
+    let response = session_request(settings, budget, &session_id, "create", &record).await?;
+    if response.status_code() != 204 {
+        return Err(Error::RustError(format!(
+            "session create returned {}",
+            response.status_code()
+        )));
+    }
+
+    Ok(format!(
+        "{}={}.{}; Path=/{}; Max-Age={}; HttpOnly; Secure; SameSite=Strict",
+        SESSION_COOKIE_NAME,
+        session_id,
+        sign_session_id(secret, &session_id),
+        crate::cookies::domain_attribute(cookie_domain),
+        settings.absolute_timeout_seconds
+    ))
+}
+
+// The session when the request carries a correctly signed cookie for a live session of this
+// client IP. Within `touch_interval_ms` of the last successful touch the object is not asked
+// again, trading that much idle-timeout precision for fewer Durable Object calls.
+pub async fn resume(
+    settings: &SessionSettings,
+    headers: &Headers,
+    secret: &str,
+    client_ip: &str,
+    budget: &DoBudget<'_>,
+) -> Option<Resumed> {
+    let session_id = session_cookie(headers)
+        .as_deref()
+        .and_then(|value| verified_session_id(secret, value))?;
+
+    let now = Date::now().as_millis() as f64;
+    let recently_touched = RECENT_TOUCHES.with(|touches| {
+        touches
+            .borrow()
+            .get(&session_id)
+            .filter(|(ip, touched_at, _)| {
+                ip == client_ip && now - touched_at < f64::from(settings.touch_interval_ms)
+            })
+            .map(|(_, _, resumed)| resumed.clone())
+    });
+    if recently_touched.is_some() {
+        return recently_touched;
+    }
+
+    let touch = TouchRequest {
+        client_ip: client_ip.to_string(),
+    };
+    let live = match session_request(settings, budget, &session_id, "touch", &touch).await {
+        Ok(mut response) if response.status_code() == 200 => response.json::<Resumed>().await.ok(),
+        Ok(_) => None,
+        Err(e) => {
+            console_error!("Failed to resume session: {}", e);
+            None
+        }
+    };
+    if settings.touch_interval_ms > 0 {
+        RECENT_TOUCHES.with(|touches| {
+            let mut touches = touches.borrow_mut();
+            // Entries are only useful for one interval, so drop the stale ones as we go
+            touches.retain(|_, (_, touched_at, _)| {
+                now - *touched_at < f64::from(settings.touch_interval_ms)
+            });
+            match &live {
+                Some(resumed) => {
+                    touches.insert(session_id, (client_ip.to_string(), now, resumed.clone()));
+                }
+                None => {
+                    touches.remove(&session_id);
+                }
+            }
+        });
+    }
+    live
+}
+
+async fn session_request<T: Serialize>(
+    settings: &SessionSettings,
+    budget: &DoBudget<'_>,
+    session_id: &str,
+    action: &str,
+    body: &T,
+) -> Result<Response> {
+    let stub = settings.namespace.id_from_name(session_id)?.get_stub()?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_body(Some(serde_json::to_string(body)?.into()));
+    let request = Request::new_with_init(&format!("https://session/{}", action), &init)?;
+    budget.fetch("sessions", &stub, request).await
+}
+
+fn session_cookie(headers: &Headers) -> Option<String> {
+    let cookies = headers.get("Cookie").ok()??;
+    cookies.split(';').find_map(|cookie| {
+        let (name, value) = cookie.split_once('=')?;
+        (name.trim() == SESSION_COOKIE_NAME).then(|| value.trim().to_string())
+    })
+}
+
+// Cookies are `{session_id}.{signature}` so forged ids are rejected without a Durable Object call
+fn verified_session_id(secret: &str, cookie_value: &str) -> Option<String> {
+    let (session_id, signature) = cookie_value.split_once('.')?;
+    let expected = sign_session_id(secret, session_id);
+    constant_time_compare(expected.as_bytes(), signature.as_bytes()).then(|| session_id.to_string())
+}
+
+fn sign_session_id(secret: &str, session_id: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(format!("session:{}", session_id).as_bytes());
+    BASE64_URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+// Hands out the cookie of a session established by this request
+pub struct SessionCookieStage;
+
+#[async_trait(?Send)]
+impl<'a> Stage<ResponseContext<'a>> for SessionCookieStage {
+    async fn run(&self, cx: &mut ResponseContext<'a>) -> Result<Flow> {
+        if let Some(cookie) = cx
+            .verdict
+            .as_ref()
+            .ok()
+            .and_then(|verified| verified.session_cookie.as_ref())
+        {
+            cx.set_cookies.push(cookie.clone());
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+// One object per session id; an alarm clears the record once the absolute timeout passes
+#[durable_object]
+pub struct SessionObject {
+    state: State,
+}
+
+impl DurableObject for SessionObject {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let storage = self.state.storage();
+        match req.path().as_str() {
+            "/create" => {
+                let record: SessionRecord = req.json().await?;
+                let expires_in_ms = record.absolute_timeout_ms as i64;
+                storage.put("session", &record).await?;
+                storage.set_alarm(expires_in_ms).await?;
+                Response::empty().map(|response| response.with_status(204))
+            }
+            "/touch" => {
+                let touch: TouchRequest = req.json().await?;
+                let Ok(mut record) = storage.get::<SessionRecord>("session").await else {
+                    return Response::error("Unknown session", 404);
+                };
+
+                let now = Date::now().as_millis() as f64;
+                if now - record.last_seen > record.idle_timeout_ms
+                    || now - record.created_at > record.absolute_timeout_ms
+                {
+                    storage.delete_all().await?;
+                    return Response::error("Session expired", 404);
+                }
+                if record.client_ip != touch.client_ip {
+                    return Response::error("Session bound to another client", 403);
+                }
+
+                record.last_seen = now;
+                storage.put("session", &record).await?;
+                Response::from_json(&Resumed {
+                    kid: record.kid.clone(),
+                    token_digest: record.token_digest.clone(),
+                })
+            }
+            _ => Response::error("Not found", 404),
+        }
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        self.state.storage().delete_all().await?;
+        Response::empty()
+    }
+}
@@ -0,0 +1,67 @@
+use worker::*;
+
+// Candidate secret for a key id during rotation: `HMAC_SHADOW_SECRET_{KID}`, or
+// `HMAC_SHADOW_SECRET` for tokens without one
+pub fn shadow_secret(env: &Env, kid: Option<&str>) -> Option<String> {
+    let name = match kid {
+        Some(kid) => format!("HMAC_SHADOW_SECRET_{}", crate::token::canonical_kid(kid)),
+        None => "HMAC_SHADOW_SECRET".to_string(),
+    };
+    env.secret(&name).ok().map(|secret| secret.to_string())
+}
+
+pub fn outcome_name(primary_valid: bool, candidate_valid: bool) -> &'static str {
+    match (primary_valid, candidate_valid) {
+        (_, true) => "match",
+        (true, false) => "mismatch",
+        (false, false) => "invalid",
+    }
+}
+
+// Logs the candidate's result next to the primary's and, with a `SHADOW_METRICS` Analytics Engine
+// binding, writes a data point per check: index = host, blobs = [key id, outcome], double = 1.
+// The decision itself only ever uses the primary secret.
+pub fn record(
+    env: &Env,
+    host: &str,
+    kid: Option<&str>,
+    primary_valid: bool,
+    candidate_valid: bool,
+) {
+    let kid = kid.unwrap_or("default");
+    let outcome = outcome_name(primary_valid, candidate_valid);
+    console_log!(
+        "shadow_secret: host={} kid={} primary_valid={} candidate_valid={} outcome={}",
+        host,
+        kid,
+        primary_valid,
+        candidate_valid,
+        outcome
+    );
+
+    let Ok(dataset) = env.analytics_engine("SHADOW_METRICS") else {
+        return;
+    };
+    let point = AnalyticsEngineDataPointBuilder::new()
+        .indexes([host])
+        .add_blob(kid)
+        .add_blob(outcome)
+        .add_double(1)
+        .build();
+    if let Err(e) = dataset.write_data_point(&point) {
+        console_error!("Failed to write shadow secret metric: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outcomes_follow_the_candidate() {
+        assert_eq!(outcome_name(true, true), "match");
+        assert_eq!(outcome_name(false, true), "match");
+        assert_eq!(outcome_name(true, false), "mismatch");
+        assert_eq!(outcome_name(false, false), "invalid");
+    }
+}
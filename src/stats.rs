@@ -0,0 +1,129 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::audit::Decision;
+use crate::events::AuditOutcome;
+
+const DAY_MS: f64 = 24.0 * 60.0 * 60.0 * 1000.0;
+pub const DEFAULT_STATS_DAYS: u32 = 7;
+pub const MAX_STATS_DAYS: u32 = 90;
+
+// Schema lives in `migrations/0001_token_usage.sql`
+const UPSERT_USAGE: &str = "INSERT INTO token_usage (day, tenant, function_id, allowed, denied) \
+     VALUES (?1, ?2, ?3, ?4, ?5) \
+     ON CONFLICT (day, tenant, function_id) DO UPDATE SET \
+     allowed = allowed + excluded.allowed, denied = denied + excluded.denied";
+const SELECT_USAGE: &str = "SELECT day, tenant, function_id, allowed, denied FROM token_usage \
+     WHERE day >= ?1 ORDER BY day DESC, tenant, function_id";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct UsageKey {
+    day: String,
+    tenant: String,
+    function_id: String,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct UsageCounts {
+    allowed: u32,
+    denied: u32,
+}
+
+thread_local! {
+    static PENDING: RefCell<HashMap<UsageKey, UsageCounts>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DailyUsage {
+    pub day: String,
+    pub tenant: String,
+    pub function_id: String,
+    pub allowed: u32,
+    pub denied: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageSummary {
+    pub days: u32,
+    pub since: String,
+    pub usage: Vec<DailyUsage>,
+}
+
+// Counts the decision against today's row when a `STATS_DB` D1 binding exists. Counts are summed
+// per isolate and written in one background batch, so concurrent requests share the writes.
+pub fn record(ctx: &Context, env: &Env, decision: &Decision) {
+    let Ok(db) = env.d1("STATS_DB") else {
+        return;
+    };
+
+    let key = UsageKey {
+        day: utc_day(Date::now().as_millis() as f64),
+        tenant: decision.tenant.to_string(),
+        function_id: decision.function_id.to_string(),
+    };
+    PENDING.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        let counts = pending.entry(key).or_default();
+        match decision.outcome {
+            AuditOutcome::Allow => counts.allowed += 1,
+            AuditOutcome::Deny => counts.denied += 1,
+        }
+    });
+    ctx.wait_until(flush(db));
+}
+
+pub async fn flush_pending(env: &Env) {
+    if let Ok(db) = env.d1("STATS_DB") {
+        flush(db).await;
+    }
+}
+
+async fn flush(db: D1Database) {
+    let pending = PENDING.with(|pending| std::mem::take(&mut *pending.borrow_mut()));
+    if pending.is_empty() {
+        return;
+    }
+
+    let statements: Result<Vec<D1PreparedStatement>> = pending
+        .iter()
+        .map(|(key, counts)| {
+            db.prepare(UPSERT_USAGE).bind(&[
+                JsValue::from(key.day.as_str()),
+                JsValue::from(key.tenant.as_str()),
+                JsValue::from(key.function_id.as_str()),
+                JsValue::from(counts.allowed),
+                JsValue::from(counts.denied),
+            ])
+        })
+        .collect();
+    let result = match statements {
+        Ok(statements) => db.batch(statements).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        console_error!("Failed to write {} usage rows: {}", pending.len(), e);
+    }
+}
+
+// Rows for the last `days` UTC days, today included
+pub async fn summary(db: &D1Database, days: u32) -> Result<UsageSummary> {
+    let since = utc_day(Date::now().as_millis() as f64 - f64::from(days - 1) * DAY_MS);
+    let usage = db
+        .prepare(SELECT_USAGE)
+        .bind(&[JsValue::from(since.as_str())])?
+        .all()
+        .await?
+        .results::<DailyUsage>()?;
+    Ok(UsageSummary { days, since, usage })
+}
+
+fn utc_day(timestamp_ms: f64) -> String {
+    let iso: String = js_sys::Date::new(&JsValue::from(timestamp_ms))
+        .to_iso_string()
+        .into();
+    iso[..10].to_string()
+}
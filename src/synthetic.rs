@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use worker::{Env, Headers, Response, Result};
+
+const DEFAULT_STATUS: u16 = 200;
+
+// Values of the `SYNTHETIC_RESPONSES` JSON var, keyed by exact request path
+#[derive(Clone, Debug, Deserialize)]
+pub struct SyntheticResponse {
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+}
+
+fn default_status() -> u16 {
+    DEFAULT_STATUS
+}
+
+pub fn synthetic_response(env: &Env, path: &str) -> Option<SyntheticResponse> {
+    crate::object_var::<HashMap<String, SyntheticResponse>>(env, "SYNTHETIC_RESPONSES")
+        .and_then(|mut responses| responses.remove(path))
+}
+
+impl SyntheticResponse {
+    pub fn into_response(self) -> Result<Response> {
+        let headers = Headers::new();
+        if !self.body.is_empty() {
+            headers.set("Content-Type", "text/plain; charset=utf-8")?;
+        }
+        for (name, value) in &self.headers {
+            headers.set(name, value)?;
+        }
+
+        let response = if self.body.is_empty() {
+            Response::empty()?
+        } else {
+            Response::ok(self.body)?
+        };
+        Ok(response.with_headers(headers).with_status(self.status))
+    }
+}
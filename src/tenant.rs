@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use worker::{console_error, Env};
+
+use crate::lookup_budget::{Dependency, LookupBudget};
+use crate::rules::RuleSet;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureMode {
+    #[default]
+    Hmac,
+    Ed25519,
+    // Callers sign method, path, query, selected headers and body; see `request_signing`
+    Request,
+    // The cloudflare token is an AES-GCM sealed payload; see `encrypted_token`
+    Encrypted,
+}
+
+// Per-host settings from the `TENANTS` JSON var, e.g.
+// { "login.example.com": { "signature_mode": "ed25519", "ed25519_public_key": "..." } }
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TenantConfig {
+    #[serde(default)]
+    pub signature_mode: SignatureMode,
+    pub ed25519_public_key: Option<String>,
+    // Name matched against token audience sets; defaults to the request host
+    pub audience: Option<String>,
+    // `Domain` for the cookies the worker sets, overriding `COOKIE_DOMAIN`
+    pub cookie_domain: Option<String>,
+    // Turns the tenant's paths into webhook endpoints checked by a signature profile
+    pub webhook: Option<crate::webhook::WebhookConfig>,
+    // Merged over `SECURITY_HEADERS`; an empty value drops a header for this tenant
+    #[serde(default)]
+    pub security_headers: HashMap<String, String>,
+    // Replaces `GEO_POLICY` for this tenant
+    pub geo_policy: Option<crate::geo_policy::GeoPolicy>,
+    // Accept `nonce:` tokens, which are not bound to the client IP
+    #[serde(default)]
+    pub nonce_tokens: bool,
+    // Replaces `HMAC_SECRET` for this tenant; key ids still select `HMAC_SECRET_{KID}`
+    pub secret: Option<crate::tenant_secrets::SecretSource>,
+    // Layered over `LOG_LEVEL` and the sample rates for this tenant
+    pub logging: Option<crate::request_log::LogConfig>,
+    // Replaces `RESPONSE_POLICY` for this tenant
+    pub response_policy: Option<crate::origin_response::ResponsePolicy>,
+}
+
+// A matching `ROUTES` rule names the tenant; otherwise the host itself is the tenant key
+pub fn tenant_key(env: &Env, host: &str, path: &str) -> String {
+    crate::routing::route_table(env)
+        .resolve(host, path)
+        .unwrap_or(host)
+        .to_string()
+}
+
+// Tenants managed through `/admin/rules` take the place of the `TENANTS` var
+pub fn tenant_config(env: &Env, rules: &RuleSet, host: &str, path: &str) -> TenantConfig {
+    let key = tenant_key(env, host, path);
+    match &rules.tenants {
+        Some(tenants) => tenants.get(&key).cloned(),
+        None => crate::object_var::<HashMap<String, TenantConfig>>(env, "TENANTS")
+            .and_then(|mut tenants| tenants.remove(&key)),
+    }
+    .unwrap_or_default()
+}
+
+// Public key lookup order: inline tenant key, `TOKEN_KEYS` KV entry for the host, then `ED25519_PUBLIC_KEY`
+pub async fn ed25519_public_key(
+    env: &Env,
+    lookups: &LookupBudget,
+    host: &str,
+    tenant: &TenantConfig,
+) -> Option<String> {
+    if let Some(key) = &tenant.ed25519_public_key {
+        return Some(key.clone());
+    }
+
+    if let Ok(kv) = env.kv("TOKEN_KEYS") {
+        let key = format!("ed25519:{}", host);
+        match lookups
+            .within(Dependency::PublicKey, kv.get(&key).text())
+            .await
+        {
+            Some(Ok(Some(key))) => return Some(key),
+            Some(Ok(None)) => {}
+            Some(Err(e)) => console_error!("Failed to read public key from KV: {}", e),
+            None if lookups.fails_closed(Dependency::PublicKey) => return None,
+            None => {}
+        }
+    }
+
+    env.var("ED25519_PUBLIC_KEY").ok().map(|v| v.to_string())
+}
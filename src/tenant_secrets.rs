@@ -0,0 +1,186 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use js_sys::Date;
+use serde::Deserialize;
+use worker::{console_error, Env};
+
+use crate::lookup_budget::{Dependency, LookupBudget};
+
+const DEFAULT_SECRETS_TTL_SECONDS: u32 = 300;
+
+// Where a tenant's HMAC secret lives, e.g. `{ "kv": "login" }` or `{ "secrets_store": "LOGIN_HMAC" }`.
+// Protected parameters name their secrets the same way.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretSource {
+    // A key in the `TENANT_SECRETS` KV namespace, sealed with `TENANT_SECRETS_KEY`
+    Kv(String),
+    // A Secrets Store binding holding the secret itself
+    SecretsStore(String),
+    // A Worker secret, set with `wrangler secret put`
+    Binding(String),
+}
+
+impl SecretSource {
+    fn cache_key(&self) -> String {
+        match self {
+            SecretSource::Kv(key) => format!("kv:{}", key),
+            SecretSource::SecretsStore(binding) => format!("secrets_store:{}", binding),
+            SecretSource::Binding(name) => format!("binding:{}", name),
+        }
+    }
+}
+
+struct CachedSecret {
+    fetched_at: f64,
+    secret: String,
+}
+
+thread_local! {
+    static SECRETS_CACHE: RefCell<HashMap<String, CachedSecret>> = RefCell::new(HashMap::new());
+}
+
+// KV values are `base64url(nonce || ciphertext || tag)`, the framing encrypted tokens use
+fn unseal(master_key: &[u8; 32], sealed: &str) -> Option<String> {
+    String::from_utf8(crate::encrypted_token::decrypt(master_key, sealed)?)
+        .ok()
+        .filter(|secret| !secret.is_empty())
+}
+
+async fn fetch(env: &Env, source: &SecretSource) -> Result<String, String> {
+    match source {
+        SecretSource::Kv(key) => {
+            let master_key = env
+                .secret("TENANT_SECRETS_KEY")
+                .ok()
+                .and_then(|key| crate::encrypted_token::parse_key(&key.to_string()))
+                .ok_or("TENANT_SECRETS_KEY must be 32 bytes of base64")?;
+            let kv = env.kv("TENANT_SECRETS").map_err(|e| e.to_string())?;
+            let sealed = kv
+                .get(key)
+                .text()
+                .await
+                .map_err(|e| e.to_string())?
+                .ok_or("no such key")?;
+            unseal(&master_key, &sealed).ok_or_else(|| "cannot be unsealed".to_string())
+        }
+        SecretSource::SecretsStore(binding) => env
+            .secret_store(binding)
+            .map_err(|e| e.to_string())?
+            .get()
+            .await
+            .map_err(|e| e.to_string())?
+            .filter(|secret| !secret.is_empty())
+            .ok_or_else(|| "not set".to_string()),
+        SecretSource::Binding(name) => env
+            .secret(name)
+            .map(|secret| secret.to_string())
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .ok_or_else(|| "not set".to_string()),
+    }
+}
+
+// Read at most once per `TENANT_SECRETS_TTL_SECONDS` per isolate. A failed or timed out read keeps
+// the last known secret; with none yet it is `None`, never the global secret, which would let the
+// tenant accept tokens signed with another key. `global` is only used without a source.
+pub async fn resolve(
+    env: &Env,
+    lookups: &LookupBudget,
+    source: Option<&SecretSource>,
+    global: String,
+) -> Option<String> {
+    let Some(source) = source else {
+        return Some(global);
+    };
+
+    let cache_key = source.cache_key();
+    let ttl_ms = f64::from(crate::var_or(
+        env,
+        "TENANT_SECRETS_TTL_SECONDS",
+        DEFAULT_SECRETS_TTL_SECONDS,
+    )) * 1000.0;
+    let now = Date::now();
+    let cached = SECRETS_CACHE.with(|cache| {
+        cache
+            .borrow()
+            .get(&cache_key)
+            .filter(|cached| now - cached.fetched_at < ttl_ms)
+            .map(|cached| cached.secret.clone())
+    });
+    if cached.is_some() {
+        return cached;
+    }
+
+    let fetched = lookups
+        .within(Dependency::TenantSecret, fetch(env, source))
+        .await
+        .unwrap_or_else(|| Err("timed out".to_string()));
+    match fetched {
+        Ok(secret) => {
+            SECRETS_CACHE.with(|cache| {
+                cache.borrow_mut().insert(
+                    cache_key,
+                    CachedSecret {
+                        fetched_at: now,
+                        secret: secret.clone(),
+                    },
+                )
+            });
+            Some(secret)
+        }
+        Err(e) => {
+            console_error!("Failed to read tenant secret {}: {}", cache_key, e);
+            SECRETS_CACHE.with(|cache| {
+                cache
+                    .borrow()
+                    .get(&cache_key)
+                    .map(|cached| cached.secret.clone())
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::aead::{Aead, KeyInit};
+    use aes_gcm::{Aes256Gcm, Key, Nonce};
+    use base64::prelude::*;
+
+    const MASTER_KEY: [u8; 32] = [9; 32];
+
+    fn seal(plaintext: &[u8]) -> String {
+        let nonce = [3u8; 12];
+        let ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&MASTER_KEY))
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .unwrap();
+        BASE64_URL_SAFE_NO_PAD.encode([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    #[test]
+    fn parses_sources() {
+        assert_eq!(
+            serde_json::from_str::<SecretSource>(r#"{ "kv": "login" }"#).unwrap(),
+            SecretSource::Kv("login".to_string())
+        );
+        assert_eq!(
+            serde_json::from_str::<SecretSource>(r#"{ "secrets_store": "LOGIN_HMAC" }"#).unwrap(),
+            SecretSource::SecretsStore("LOGIN_HMAC".to_string())
+        );
+        assert!(serde_json::from_str::<SecretSource>(r#"{ "vault": "login" }"#).is_err());
+    }
+
+    #[test]
+    fn unseals_only_with_the_master_key() {
+        let sealed = seal(b"tenant-secret");
+        assert_eq!(
+            unseal(&MASTER_KEY, &sealed).as_deref(),
+            Some("tenant-secret")
+        );
+        assert_eq!(unseal(&[1; 32], &sealed), None);
+        assert_eq!(unseal(&MASTER_KEY, &seal(b"")), None);
+        assert_eq!(unseal(&MASTER_KEY, "not-sealed"), None);
+    }
+}
@@ -0,0 +1,844 @@
+use base64::prelude::*;
+use ed25519_dalek::{Signature, VerifyingKey};
+use hmac::{Hmac, Mac};
+use js_sys::Date;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use subtle::ConstantTimeEq;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HmacAlgorithm {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HmacAlgorithm {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "sha256" => Some(Self::Sha256),
+            "sha384" => Some(Self::Sha384),
+            "sha512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha384 => "sha384",
+            Self::Sha512 => "sha512",
+        }
+    }
+
+    pub fn tag_bits(&self) -> u32 {
+        match self {
+            Self::Sha256 => 256,
+            Self::Sha384 => 384,
+            Self::Sha512 => 512,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        match self {
+            Self::Sha256 => 1,
+            Self::Sha384 => 2,
+            Self::Sha512 => 4,
+        }
+    }
+}
+
+// The algorithms a token's prefix may name. The default verifies unprefixed tokens and is always
+// allowed; without `HMAC_ALLOWED_ALGS` it is the only one, so a client cannot pick the algorithm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HmacAlgorithms {
+    pub default: HmacAlgorithm,
+    allowed: u8,
+}
+
+impl HmacAlgorithms {
+    pub fn only(default: HmacAlgorithm) -> Self {
+        Self {
+            default,
+            allowed: default.bit(),
+        }
+    }
+
+    // A comma-separated list such as `sha256,sha512`
+    pub fn parse(default: HmacAlgorithm, list: &str) -> Option<Self> {
+        list.split(',')
+            .filter(|name| !name.trim().is_empty())
+            .try_fold(Self::only(default), |algorithms, name| {
+                Some(algorithms.with(HmacAlgorithm::parse(name)?))
+            })
+    }
+
+    pub fn with(self, algorithm: HmacAlgorithm) -> Self {
+        Self {
+            allowed: self.allowed | algorithm.bit(),
+            ..self
+        }
+    }
+
+    pub fn allows(self, algorithm: HmacAlgorithm) -> bool {
+        self.allowed & algorithm.bit() != 0
+    }
+}
+
+// Truncating the tag trades forgery resistance for shorter URLs: a tag of n bits is guessed with
+// probability 2^-n per attempt. 128 bits keeps online guessing (rate limited, and bound to a client
+// IP and a validity window) far out of reach, so nothing shorter is accepted; lengths must also be
+// whole bytes and no longer than the algorithm's output.
+pub const MIN_TRUNCATED_TAG_BITS: u32 = 128;
+
+pub fn truncated_tag_len(algorithm: HmacAlgorithm, bits: u32) -> Option<usize> {
+    (bits >= MIN_TRUNCATED_TAG_BITS && bits.is_multiple_of(8) && bits <= algorithm.tag_bits())
+        .then_some((bits / 8) as usize)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashEncoding {
+    Auto,
+    Base64,
+    Base64Url,
+    Hex,
+}
+
+impl HashEncoding {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "auto" => Some(Self::Auto),
+            "base64" => Some(Self::Base64),
+            "base64url" => Some(Self::Base64Url),
+            "hex" => Some(Self::Hex),
+            _ => None,
+        }
+    }
+
+    // Padding is optional for both base64 variants since issuers disagree on it
+    pub(crate) fn decode(&self, encoded: &str) -> Option<Vec<u8>> {
+        let unpadded = encoded.trim_end_matches('=');
+        match self {
+            Self::Auto => None,
+            Self::Base64 => BASE64_STANDARD_NO_PAD.decode(unpadded).ok(),
+            Self::Base64Url => BASE64_URL_SAFE_NO_PAD.decode(unpadded).ok(),
+            Self::Hex => hex::decode(encoded).ok(),
+        }
+    }
+
+    // The encodings a tag is tried in; `Auto` accepts the first one that verifies
+    fn candidates(&self) -> &[HashEncoding] {
+        match self {
+            Self::Auto => &[Self::Hex, Self::Base64, Self::Base64Url],
+            encoding => std::slice::from_ref(encoding),
+        }
+    }
+}
+
+// Epoch milliseconds passed 10^12 in 2001, and epoch seconds will not reach it for millennia
+const MILLISECONDS_THRESHOLD: f64 = 1e12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampUnit {
+    // Values above 10^12 are milliseconds, anything else seconds
+    Auto,
+    Seconds,
+    Milliseconds,
+}
+
+impl TimestampUnit {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "auto" => Some(Self::Auto),
+            "seconds" | "s" => Some(Self::Seconds),
+            "milliseconds" | "ms" => Some(Self::Milliseconds),
+            _ => None,
+        }
+    }
+
+    // The unit a given timestamp is in; never `Auto`
+    pub fn detect(self, timestamp: f64) -> Self {
+        match self {
+            Self::Auto if timestamp > MILLISECONDS_THRESHOLD => Self::Milliseconds,
+            Self::Auto => Self::Seconds,
+            unit => unit,
+        }
+    }
+
+    pub fn to_seconds(self, timestamp: f64) -> f64 {
+        match self.detect(timestamp) {
+            Self::Milliseconds => timestamp / 1000.0,
+            _ => timestamp,
+        }
+    }
+
+    // `seconds` in the unit `like` was given in, for tokens minted in reply to it
+    pub fn in_unit_of(self, seconds: f64, like: f64) -> f64 {
+        match self.detect(like) {
+            Self::Milliseconds => (seconds * 1000.0).round(),
+            _ => seconds,
+        }
+    }
+}
+
+// Signed attributes that change how long a token lasts, in seconds
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TokenLifetime {
+    // Signed links carry their own expiry instead of the validity window
+    pub expires_at: Option<f64>,
+    // When the refresh chain a token belongs to began, for capping its total length
+    pub session_start: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HmacToken<'a> {
+    pub algorithm: HmacAlgorithm,
+    pub kid: Option<&'a str>,
+    // Hosts (or tenant audience names) the token may be used on; empty means any host
+    pub audiences: Vec<&'a str>,
+    pub lifetime: TokenLifetime,
+    // Unbound tokens are signed for any client IP
+    pub ip_bound: bool,
+    // Nonce tokens are signed over a random nonce instead of the client IP
+    pub nonce: Option<&'a str>,
+    pub timestamp: f64,
+    pub hash: &'a str,
+}
+
+// Key ids select their secrets by upper-cased name and are not signed, so `k1` and `K1` are the
+// same key. Everything that compares key ids goes through this.
+pub fn canonical_kid(kid: &str) -> String {
+    kid.to_uppercase()
+}
+
+// Stands in for the client IP in the signed message of unbound tokens
+const ANY_CLIENT_IP: &str = "*";
+const NONCE_TOKEN_PREFIX: &str = "nonce:";
+const MIN_NONCE_LEN: usize = 16;
+const MAX_NONCE_LEN: usize = 64;
+
+// Tokens may carry their algorithm and key id as a prefix (`sha512:{timestamp}-{hash}`,
+// `sha512.{kid}:{timestamp}-{hash}`), optionally followed by `;`-separated attributes: an
+// audience set (`sha256;login.example.com,apps.example.com:...`), an expiry (`;exp={seconds}`)
+// and `;anyip` for tokens not bound to a client IP. Unprefixed tokens use the configured default
+// algorithm, and a prefix naming an algorithm that is not allowed fails the parse.
+//
+// The whole token may also be typed as a nonce token, `nonce:{nonce}:{token}`. Its hash covers
+// `nonce:{nonce}` where other tokens have the client IP, for clients whose IP changes between
+// issuance and validation.
+pub fn parse_hmac_token(provided_token: &str, algorithms: HmacAlgorithms) -> Option<HmacToken<'_>> {
+    let mut token = HmacToken {
+        algorithm: algorithms.default,
+        kid: None,
+        audiences: Vec::new(),
+        lifetime: TokenLifetime::default(),
+        ip_bound: true,
+        nonce: None,
+        timestamp: 0.0,
+        hash: "",
+    };
+    let provided_token = match provided_token.strip_prefix(NONCE_TOKEN_PREFIX) {
+        Some(rest) => {
+            let (nonce, rest) = rest.split_once(':')?;
+            if !is_valid_nonce(nonce) {
+                return None;
+            }
+            token.nonce = Some(nonce);
+            rest
+        }
+        None => provided_token,
+    };
+    let provided_token = match provided_token.split_once(':') {
+        Some((prefix, rest)) => {
+            let mut attributes = prefix.split(';');
+            let algorithm = attributes.next()?;
+            (token.algorithm, token.kid) = match algorithm.split_once('.') {
+                Some((algorithm, kid)) if !kid.is_empty() => {
+                    (HmacAlgorithm::parse(algorithm)?, Some(kid))
+                }
+                Some(_) => return None,
+                None => (HmacAlgorithm::parse(algorithm)?, None),
+            };
+            if !algorithms.allows(token.algorithm) {
+                return None;
+            }
+            for attribute in attributes {
+                if let Some(expires_at) = attribute.strip_prefix("exp=") {
+                    token.lifetime.expires_at = Some(expires_at.parse().ok()?);
+                } else if let Some(session_start) = attribute.strip_prefix("since=") {
+                    token.lifetime.session_start = Some(session_start.parse().ok()?);
+                } else if attribute == "anyip" {
+                    token.ip_bound = false;
+                } else if token.audiences.is_empty() {
+                    token.audiences = attribute.split(',').collect();
+                    if token.audiences.iter().any(|audience| audience.is_empty()) {
+                        return None;
+                    }
+                } else {
+                    return None;
+                }
+            }
+            rest
+        }
+        None => provided_token,
+    };
+
+    (token.timestamp, token.hash) = parse_timed_token(provided_token)?;
+    // A nonce token is already unbound
+    if token.nonce.is_some() && !token.ip_bound {
+        return None;
+    }
+    Some(token)
+}
+
+// Random base64url, long enough that two tokens never share one
+fn is_valid_nonce(nonce: &str) -> bool {
+    (MIN_NONCE_LEN..=MAX_NONCE_LEN).contains(&nonce.len())
+        && nonce
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+impl HmacToken<'_> {
+    pub fn allows_audience(&self, audience: &str) -> bool {
+        self.audiences.is_empty()
+            || self
+                .audiences
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(audience))
+    }
+
+    // One spelling for every way of writing the same token: the prefix resolved to an algorithm,
+    // the timestamp read as a number and the tag as its decoded bytes, so padding, tag encoding
+    // or an explicit default algorithm do not make a different string. `None` when the tag does
+    // not decode.
+    pub fn canonical(&self, hash_encoding: HashEncoding) -> Option<String> {
+        let tag = hash_encoding
+            .candidates()
+            .iter()
+            .find_map(|encoding| encoding.decode(self.hash))?;
+        Some(
+            serde_json::json!([
+                self.algorithm.name(),
+                self.kid.map(canonical_kid),
+                self.nonce,
+                self.audiences,
+                self.lifetime.expires_at,
+                self.lifetime.session_start,
+                self.ip_bound,
+                self.timestamp,
+                hex::encode(tag),
+            ])
+            .to_string(),
+        )
+    }
+
+    fn subject(&self, client_ip: &str) -> String {
+        match self.nonce {
+            Some(nonce) => format!("{}{}", NONCE_TOKEN_PREFIX, nonce),
+            None if self.ip_bound => client_ip.to_string(),
+            None => ANY_CLIENT_IP.to_string(),
+        }
+    }
+
+    fn is_fresh(&self, timestamp_unit: TimestampUnit, validity_seconds: f64) -> bool {
+        match self.lifetime.expires_at {
+            Some(expires_at) => before_expiry(
+                timestamp_unit.to_seconds(self.timestamp),
+                expires_at,
+                Date::now() / 1000.0,
+                validity_seconds,
+            ),
+            None => is_fresh(self.timestamp, timestamp_unit, validity_seconds),
+        }
+    }
+}
+
+// With `truncated_len` set, only base64url tags of exactly that many bytes are accepted and
+// compared against the leading bytes of the full tag
+pub fn verify_hmac_token(
+    client_ip: &str,
+    token: &HmacToken,
+    secret: &str,
+    validity_seconds: f64,
+    timestamp_unit: TimestampUnit,
+    hash_encoding: HashEncoding,
+    truncated_len: Option<usize>,
+) -> bool {
+    if !token.is_fresh(timestamp_unit, validity_seconds) {
+        return false;
+    }
+    let mut expected_tag = generate_tag(
+        token.algorithm,
+        &token.subject(client_ip),
+        secret,
+        token.timestamp,
+        &token.audiences,
+        token.lifetime,
+    );
+    if let Some(len) = truncated_len {
+        expected_tag.truncate(len);
+    }
+    let candidates = match truncated_len {
+        Some(_) => &[HashEncoding::Base64Url],
+        None => hash_encoding.candidates(),
+    };
+
+    candidates.iter().any(|encoding| {
+        encoding
+            .decode(token.hash)
+            .is_some_and(|provided_tag| constant_time_compare(&expected_tag, &provided_tag))
+    })
+}
+
+// Ed25519 tokens are `[ed25519:]{timestamp}-{base64_signature}`, signed over `{client_ip}:{timestamp}`
+pub fn verify_ed25519_token(
+    client_ip: &str,
+    provided_token: &str,
+    public_key: &VerifyingKey,
+    validity_seconds: f64,
+    timestamp_unit: TimestampUnit,
+) -> bool {
+    let provided_token = provided_token
+        .strip_prefix("ed25519:")
+        .unwrap_or(provided_token);
+
+    let Some((timestamp, provided_signature)) = parse_timed_token(provided_token) else {
+        return false;
+    };
+
+    if !is_fresh(timestamp, timestamp_unit, validity_seconds) {
+        return false;
+    }
+
+    let signature = match BASE64_STANDARD
+        .decode(provided_signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+    {
+        Some(signature) => signature,
+        None => return false,
+    };
+
+    let message = format!("{}:{}", client_ip, timestamp);
+    public_key
+        .verify_strict(message.as_bytes(), &signature)
+        .is_ok()
+}
+
+pub fn parse_ed25519_public_key(encoded: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = BASE64_STANDARD
+        .decode(encoded.trim())
+        .ok()?
+        .try_into()
+        .ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+// Split on the first `-` only: base64url hashes may contain `-` themselves
+fn parse_timed_token(provided_token: &str) -> Option<(f64, &str)> {
+    let (timestamp, hash) = provided_token.split_once('-')?;
+    let timestamp: f64 = timestamp.parse().ok()?;
+    Some((timestamp, hash))
+}
+
+pub fn is_fresh(timestamp: f64, unit: TimestampUnit, validity_seconds: f64) -> bool {
+    within_window(
+        unit.to_seconds(timestamp),
+        Date::now() / 1000.0,
+        validity_seconds,
+    )
+}
+
+// Timestamps more than the validity window ahead are rejected too, so a timestamp read in the
+// wrong unit cannot stay valid indefinitely
+fn within_window(issued_at: f64, now: f64, validity_seconds: f64) -> bool {
+    (now - issued_at).abs() <= validity_seconds
+}
+
+// A token with its own expiry is good until then, but may still not be issued in the future
+fn before_expiry(issued_at: f64, expires_at: f64, now: f64, validity_seconds: f64) -> bool {
+    issued_at - now <= validity_seconds && now <= expires_at
+}
+
+// The lifetime of a token refreshed at `now` from one issued at `issued_at`. With a cap on the
+// session, refreshed tokens carry when the chain began and the last one expires exactly at the
+// cap; `None` once the cap is reached.
+pub fn refreshed_lifetime(
+    token: &HmacToken,
+    issued_at: f64,
+    now: f64,
+    validity_seconds: f64,
+    max_session_seconds: Option<f64>,
+) -> Option<TokenLifetime> {
+    let Some(max_session_seconds) = max_session_seconds else {
+        return Some(TokenLifetime::default());
+    };
+    let session_start = token.lifetime.session_start.unwrap_or(issued_at);
+    let session_end = session_start + max_session_seconds;
+    if now >= session_end {
+        return None;
+    }
+    Some(TokenLifetime {
+        expires_at: (now + validity_seconds > session_end).then_some(session_end),
+        session_start: Some(session_start),
+    })
+}
+
+// Mints a token in the prefixed form accepted by `parse_hmac_token`; without a client IP the
+// token is unbound
+#[allow(clippy::too_many_arguments)]
+pub fn issue_hmac_token(
+    algorithm: HmacAlgorithm,
+    kid: Option<&str>,
+    audiences: &[&str],
+    client_ip: Option<&str>,
+    secret: &str,
+    timestamp: f64,
+    lifetime: TokenLifetime,
+    truncated_len: Option<usize>,
+) -> String {
+    let subject = client_ip.unwrap_or(ANY_CLIENT_IP);
+    let mut tag = generate_tag(algorithm, subject, secret, timestamp, audiences, lifetime);
+    let hash = match truncated_len {
+        Some(len) => {
+            tag.truncate(len);
+            BASE64_URL_SAFE_NO_PAD.encode(tag)
+        }
+        None => BASE64_STANDARD.encode(tag),
+    };
+    let mut prefix = algorithm.name().to_string();
+    if let Some(kid) = kid {
+        prefix = format!("{}.{}", prefix, kid);
+    }
+    if !audiences.is_empty() {
+        prefix = format!("{};{}", prefix, audiences.join(","));
+    }
+    if let Some(expires_at) = lifetime.expires_at {
+        prefix = format!("{};exp={}", prefix, expires_at);
+    }
+    if let Some(session_start) = lifetime.session_start {
+        prefix = format!("{};since={}", prefix, session_start);
+    }
+    if client_ip.is_none() {
+        prefix = format!("{};anyip", prefix);
+    }
+    format!("{}:{}-{}", prefix, timestamp, hash)
+}
+
+// Tokens with an audience set sign `{client_ip}:{timestamp}:{audiences}` so the set cannot be
+// edited. An expiry and a session start are appended as `:exp={seconds}` and `:since={seconds}`.
+// Unbound tokens sign `*` for the IP.
+pub fn generate_tag(
+    algorithm: HmacAlgorithm,
+    client_ip: &str,
+    hmac_secret: &str,
+    timestamp: f64,
+    audiences: &[&str],
+    lifetime: TokenLifetime,
+) -> Vec<u8> {
+    let mut message = if audiences.is_empty() {
+        format!("{}:{}", client_ip, timestamp)
+    } else {
+        format!("{}:{}:{}", client_ip, timestamp, audiences.join(","))
+    };
+    if let Some(expires_at) = lifetime.expires_at {
+        message = format!("{}:exp={}", message, expires_at);
+    }
+    if let Some(session_start) = lifetime.session_start {
+        message = format!("{}:since={}", message, session_start);
+    }
+    match algorithm {
+        HmacAlgorithm::Sha256 => compute_mac::<Hmac<Sha256>>(hmac_secret, &message),
+        HmacAlgorithm::Sha384 => compute_mac::<Hmac<Sha384>>(hmac_secret, &message),
+        HmacAlgorithm::Sha512 => compute_mac::<Hmac<Sha512>>(hmac_secret, &message),
+    }
+}
+
+fn compute_mac<M: Mac + hmac::digest::KeyInit>(secret: &str, message: &str) -> Vec<u8> {
+    let mut mac =
+        <M as Mac>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(message.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Both sides are hashed to a fixed length first, so the time taken reveals neither where the
+// inputs differ nor whether their lengths do; a length mismatch is folded into the same result
+pub fn constant_time_compare(a: &[u8], b: &[u8]) -> bool {
+    let same_len = (a.len() as u64).ct_eq(&(b.len() as u64));
+    let same_digest = Sha256::digest(a)
+        .as_slice()
+        .ct_eq(Sha256::digest(b).as_slice());
+    (same_len & same_digest).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_without_leaking_lengths() {
+        assert!(constant_time_compare(b"tag", b"tag"));
+        assert!(constant_time_compare(b"", b""));
+        assert!(!constant_time_compare(b"tag", b"tah"));
+        assert!(!constant_time_compare(b"tag", b"tag\0"));
+        assert!(!constant_time_compare(b"", b"tag"));
+    }
+
+    #[test]
+    fn refuses_prefixes_outside_the_allowed_algorithms() {
+        let strict = HmacAlgorithms::only(HmacAlgorithm::Sha512);
+        assert!(parse_hmac_token("sha256:1693123456-abc", strict).is_none());
+        assert!(parse_hmac_token("sha256.2:1693123456-abc", strict).is_none());
+        assert!(parse_hmac_token("nonce:k3Jx9_Qz-7LmN2pA:sha384:1693123456-abc", strict).is_none());
+        let token = parse_hmac_token("1693123456-abc", strict).unwrap();
+        assert_eq!(token.algorithm, HmacAlgorithm::Sha512);
+        assert!(parse_hmac_token("sha512:1693123456-abc", strict).is_some());
+
+        let rotating = HmacAlgorithms::parse(HmacAlgorithm::Sha512, "sha256, sha512").unwrap();
+        assert!(parse_hmac_token("sha256:1693123456-abc", rotating).is_some());
+        assert!(parse_hmac_token("sha384:1693123456-abc", rotating).is_none());
+        assert_eq!(
+            HmacAlgorithms::parse(HmacAlgorithm::Sha256, "sha256,md5"),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_audience_sets() {
+        let token = parse_hmac_token(
+            "sha256.2;login.example.com,apps.example.com:1693123456-abc",
+            HmacAlgorithms::only(HmacAlgorithm::Sha512).with(HmacAlgorithm::Sha256),
+        )
+        .unwrap();
+        assert_eq!(token.algorithm, HmacAlgorithm::Sha256);
+        assert_eq!(token.kid, Some("2"));
+        assert_eq!(
+            token.audiences,
+            vec!["login.example.com", "apps.example.com"]
+        );
+        assert!(token.allows_audience("APPS.example.com"));
+        assert!(!token.allows_audience("other.example.com"));
+
+        let token = parse_hmac_token(
+            "1693123456-abc",
+            HmacAlgorithms::only(HmacAlgorithm::Sha256),
+        )
+        .unwrap();
+        assert!(token.audiences.is_empty());
+        assert!(token.allows_audience("any.example.com"));
+
+        assert!(parse_hmac_token(
+            "sha256;a,,b:1693123456-abc",
+            HmacAlgorithms::only(HmacAlgorithm::Sha256)
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn issued_tokens_round_trip_with_audiences() {
+        let issued = issue_hmac_token(
+            HmacAlgorithm::Sha256,
+            Some("2"),
+            &["login.example.com", "apps.example.com"],
+            Some("192.0.2.1"),
+            "secret",
+            1693123456.0,
+            TokenLifetime::default(),
+            None,
+        );
+        assert!(issued.starts_with("sha256.2;login.example.com,apps.example.com:1693123456-"));
+        let token = parse_hmac_token(
+            &issued,
+            HmacAlgorithms::only(HmacAlgorithm::Sha512).with(HmacAlgorithm::Sha256),
+        )
+        .unwrap();
+        assert_eq!(
+            token.audiences,
+            vec!["login.example.com", "apps.example.com"]
+        );
+        // The audience set is part of the signed message
+        assert_ne!(
+            generate_tag(
+                HmacAlgorithm::Sha256,
+                "192.0.2.1",
+                "secret",
+                1693123456.0,
+                &[],
+                TokenLifetime::default()
+            ),
+            generate_tag(
+                HmacAlgorithm::Sha256,
+                "192.0.2.1",
+                "secret",
+                1693123456.0,
+                &token.audiences,
+                TokenLifetime::default()
+            )
+        );
+    }
+
+    #[test]
+    fn signed_links_carry_expiry_and_binding() {
+        let issued = issue_hmac_token(
+            HmacAlgorithm::Sha256,
+            None,
+            &[],
+            None,
+            "secret",
+            1693123456.0,
+            TokenLifetime {
+                expires_at: Some(1693727256.0),
+                session_start: None,
+            },
+            None,
+        );
+        assert!(issued.starts_with("sha256;exp=1693727256;anyip:1693123456-"));
+        let token = parse_hmac_token(
+            &issued,
+            HmacAlgorithms::only(HmacAlgorithm::Sha512).with(HmacAlgorithm::Sha256),
+        )
+        .unwrap();
+        assert_eq!(token.lifetime.expires_at, Some(1693727256.0));
+        assert!(!token.ip_bound);
+        assert_eq!(token.subject("192.0.2.1"), "*");
+
+        // Attributes after the audience set; a second audience set is malformed
+        let token = parse_hmac_token(
+            "sha256;login.example.com;exp=1693727256:1693123456-abc",
+            HmacAlgorithms::only(HmacAlgorithm::Sha256),
+        )
+        .unwrap();
+        assert_eq!(token.audiences, vec!["login.example.com"]);
+        assert!(token.ip_bound);
+        assert!(parse_hmac_token(
+            "sha256;a;b:1693123456-abc",
+            HmacAlgorithms::only(HmacAlgorithm::Sha256)
+        )
+        .is_none());
+        assert!(parse_hmac_token(
+            "sha256;exp=soon:1693123456-abc",
+            HmacAlgorithms::only(HmacAlgorithm::Sha256)
+        )
+        .is_none());
+
+        assert!(before_expiry(1000.0, 5000.0, 4000.0, 300.0));
+        assert!(!before_expiry(1000.0, 5000.0, 5001.0, 300.0));
+        assert!(!before_expiry(5000.0, 9000.0, 4000.0, 300.0));
+    }
+
+    #[test]
+    fn refresh_chains_stop_at_the_session_cap() {
+        let token = parse_hmac_token(
+            "sha256:1000-abc",
+            HmacAlgorithms::only(HmacAlgorithm::Sha256),
+        )
+        .unwrap();
+        assert_eq!(
+            refreshed_lifetime(&token, 1000.0, 1200.0, 300.0, None),
+            Some(TokenLifetime::default())
+        );
+        assert_eq!(
+            refreshed_lifetime(&token, 1000.0, 1200.0, 300.0, Some(3600.0)),
+            Some(TokenLifetime {
+                expires_at: None,
+                session_start: Some(1000.0),
+            })
+        );
+
+        // Later links in the chain keep the original start; the last one ends at the cap
+        let token = parse_hmac_token(
+            "sha256;since=1000:4400-abc",
+            HmacAlgorithms::only(HmacAlgorithm::Sha256),
+        )
+        .unwrap();
+        assert_eq!(
+            refreshed_lifetime(&token, 4400.0, 4500.0, 300.0, Some(3600.0)),
+            Some(TokenLifetime {
+                expires_at: Some(4600.0),
+                session_start: Some(1000.0),
+            })
+        );
+        assert_eq!(
+            refreshed_lifetime(&token, 4400.0, 4600.0, 300.0, Some(3600.0)),
+            None
+        );
+    }
+
+    #[test]
+    fn nonce_tokens_sign_the_nonce_instead_of_the_ip() {
+        let token = parse_hmac_token(
+            "nonce:k3Jx9_Qz-7LmN2pA:sha256.2:1693123456-abc",
+            HmacAlgorithms::only(HmacAlgorithm::Sha512).with(HmacAlgorithm::Sha256),
+        )
+        .unwrap();
+        assert_eq!(token.nonce, Some("k3Jx9_Qz-7LmN2pA"));
+        assert_eq!(token.algorithm, HmacAlgorithm::Sha256);
+        assert_eq!(token.kid, Some("2"));
+        assert_eq!(token.subject("192.0.2.1"), "nonce:k3Jx9_Qz-7LmN2pA");
+
+        let token = parse_hmac_token(
+            "nonce:k3Jx9_Qz-7LmN2pA:1693123456-abc",
+            HmacAlgorithms::only(HmacAlgorithm::Sha256),
+        )
+        .unwrap();
+        assert_eq!(token.algorithm, HmacAlgorithm::Sha256);
+
+        for token in [
+            "nonce:short:1693123456-abc",
+            "nonce:k3Jx9+Qz/7LmN2pA:1693123456-abc",
+            "nonce:k3Jx9_Qz-7LmN2pA:sha256;anyip:1693123456-abc",
+            "nonce:1693123456-abc",
+        ] {
+            assert!(
+                parse_hmac_token(token, HmacAlgorithms::only(HmacAlgorithm::Sha256)).is_none(),
+                "{}",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn timestamp_units_are_detected() {
+        assert_eq!(
+            TimestampUnit::Auto.to_seconds(1693123456789.0),
+            1693123456.789
+        );
+        assert_eq!(TimestampUnit::Auto.to_seconds(1693123456.0), 1693123456.0);
+        assert_eq!(
+            TimestampUnit::Milliseconds.to_seconds(1693123456000.0),
+            1693123456.0
+        );
+        assert_eq!(
+            TimestampUnit::Seconds.to_seconds(1693123456789.0),
+            1693123456789.0
+        );
+        assert_eq!(
+            TimestampUnit::Auto.in_unit_of(1693123500.5, 1693123456789.0),
+            1693123500500.0
+        );
+        assert_eq!(
+            TimestampUnit::Auto.in_unit_of(1693123500.5, 1693123456.0),
+            1693123500.5
+        );
+    }
+
+    #[test]
+    fn validity_window_applies_both_ways() {
+        let now = 1693123456.0;
+        assert!(within_window(now - 300.0, now, 300.0));
+        assert!(!within_window(now - 301.0, now, 300.0));
+        assert!(within_window(now + 30.0, now, 300.0));
+        // A millisecond timestamp read as seconds lies far in the future
+        assert!(!within_window(now * 1000.0, now, 300.0));
+    }
+
+    #[test]
+    fn truncation_accepts_whole_bytes_from_128_bits() {
+        assert_eq!(truncated_tag_len(HmacAlgorithm::Sha256, 128), Some(16));
+        assert_eq!(truncated_tag_len(HmacAlgorithm::Sha256, 256), Some(32));
+        assert_eq!(truncated_tag_len(HmacAlgorithm::Sha512, 192), Some(24));
+        assert_eq!(truncated_tag_len(HmacAlgorithm::Sha256, 120), None);
+        assert_eq!(truncated_tag_len(HmacAlgorithm::Sha256, 130), None);
+        assert_eq!(truncated_tag_len(HmacAlgorithm::Sha256, 384), None);
+    }
+}
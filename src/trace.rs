@@ -0,0 +1,320 @@
+use std::cell::RefCell;
+use std::future::Future;
+
+use serde_json::{json, Value};
+use worker::*;
+
+const DEFAULT_SERVICE_NAME: &str = "validate-token-rust";
+const SCOPE_NAME: &str = "validate-token-rust";
+
+// OTLP span kinds and status codes
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_CLIENT: u8 = 3;
+const STATUS_UNSET: u8 = 0;
+const STATUS_ERROR: u8 = 2;
+
+// W3C trace context carried in `traceparent: 00-{trace_id}-{parent_id}-{flags}`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub parent_id: Option<String>,
+    pub flags: String,
+}
+
+pub fn parse_traceparent(value: &str) -> Option<TraceContext> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    // Later versions may append fields; version 00 must not
+    if version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    let is_hex_id = |id: &str, len: usize| {
+        id.len() == len
+            && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+            && id.bytes().any(|b| b != b'0')
+    };
+    if version.len() != 2
+        || !is_hex_id(trace_id, 32)
+        || !is_hex_id(parent_id, 16)
+        || flags.len() != 2
+        || u8::from_str_radix(flags, 16).is_err()
+    {
+        return None;
+    }
+    Some(TraceContext {
+        trace_id: trace_id.to_string(),
+        parent_id: Some(parent_id.to_string()),
+        flags: flags.to_string(),
+    })
+}
+
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    if getrandom::getrandom(&mut bytes).is_err() {
+        console_error!("Failed to generate trace id");
+    }
+    hex::encode(bytes)
+}
+
+// A span id reserved before the work starts, so it can be propagated upstream
+pub struct Span {
+    name: &'static str,
+    kind: u8,
+    span_id: String,
+}
+
+struct FinishedSpan {
+    name: &'static str,
+    kind: u8,
+    span_id: String,
+    start_ms: f64,
+    end_ms: f64,
+    status: u16,
+}
+
+// Collects the spans of one request and exports them over OTLP/HTTP when dropped.
+// Only active when `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub struct Tracer<'a> {
+    ctx: &'a Context,
+    endpoint: String,
+    authorization: Option<String>,
+    service_name: String,
+    context: TraceContext,
+    tracestate: Option<String>,
+    spans: RefCell<Vec<FinishedSpan>>,
+}
+
+impl<'a> Tracer<'a> {
+    // Continues the incoming trace, or starts a sampled one when the request carries none
+    pub fn from_request(env: &Env, ctx: &'a Context, headers: &Headers) -> Option<Self> {
+        let endpoint = env.var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?.to_string();
+        let context = headers
+            .get("traceparent")
+            .ok()
+            .flatten()
+            .and_then(|value| parse_traceparent(&value));
+        // tracestate is only meaningful alongside the traceparent it came with
+        let tracestate = context
+            .as_ref()
+            .and_then(|_| headers.get("tracestate").ok().flatten());
+        Some(Self {
+            ctx,
+            endpoint,
+            authorization: env
+                .secret("OTEL_EXPORTER_OTLP_AUTHORIZATION")
+                .ok()
+                .map(|v| v.to_string()),
+            service_name: env
+                .var("OTEL_SERVICE_NAME")
+                .map(|v| v.to_string())
+                .unwrap_or(DEFAULT_SERVICE_NAME.to_string()),
+            context: context.unwrap_or_else(|| TraceContext {
+                trace_id: random_hex(16),
+                parent_id: None,
+                flags: "01".to_string(),
+            }),
+            tracestate,
+            spans: RefCell::new(Vec::new()),
+        })
+    }
+
+    pub fn validation_span(&self) -> Span {
+        self.span("token.validate", SPAN_KIND_INTERNAL)
+    }
+
+    pub fn upstream_span(&self) -> Span {
+        self.span("upstream.fetch", SPAN_KIND_CLIENT)
+    }
+
+    fn span(&self, name: &'static str, kind: u8) -> Span {
+        Span {
+            name,
+            kind,
+            span_id: random_hex(8),
+        }
+    }
+
+    // Headers that make `span` the parent of the origin's own spans
+    pub fn propagate(&self, span: &Span, headers: &Headers) -> Result<()> {
+        headers.set(
+            "traceparent",
+            &format!(
+                "00-{}-{}-{}",
+                self.context.trace_id, span.span_id, self.context.flags
+            ),
+        )?;
+        match &self.tracestate {
+            Some(tracestate) => headers.set("tracestate", tracestate),
+            None => headers.delete("tracestate"),
+        }
+    }
+
+    fn finish(&self, span: Span, start_ms: f64, status: u16) {
+        self.spans.borrow_mut().push(FinishedSpan {
+            name: span.name,
+            kind: span.kind,
+            span_id: span.span_id,
+            start_ms,
+            end_ms: Date::now().as_millis() as f64,
+            status,
+        });
+    }
+}
+
+impl Drop for Tracer<'_> {
+    fn drop(&mut self) {
+        let spans = std::mem::take(&mut *self.spans.borrow_mut());
+        if spans.is_empty() {
+            return;
+        }
+        let body = otlp_body(&self.service_name, &self.context, &spans);
+        let endpoint = self.endpoint.clone();
+        let authorization = self.authorization.clone();
+        self.ctx.wait_until(async move {
+            if let Err(e) = export(&endpoint, authorization.as_deref(), &body).await {
+                console_error!("Failed to export {} spans: {}", spans.len(), e);
+            }
+        });
+    }
+}
+
+// Runs `future` as `span`; `status` maps its output to the status code recorded on the span
+pub async fn in_span<T>(
+    tracer: Option<&Tracer<'_>>,
+    span: Option<Span>,
+    future: impl Future<Output = T>,
+    status: impl FnOnce(&T) -> u16,
+) -> T {
+    let start_ms = Date::now().as_millis() as f64;
+    let output = future.await;
+    if let (Some(tracer), Some(span)) = (tracer, span) {
+        tracer.finish(span, start_ms, status(&output));
+    }
+    output
+}
+
+fn otlp_body(service_name: &str, context: &TraceContext, spans: &[FinishedSpan]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let status_key = match span.kind {
+                SPAN_KIND_CLIENT => "http.response.status_code",
+                _ => "validator.status_code",
+            };
+            json!({
+                "traceId": context.trace_id,
+                "spanId": span.span_id,
+                "parentSpanId": context.parent_id.clone().unwrap_or_default(),
+                "name": span.name,
+                "kind": span.kind,
+                "startTimeUnixNano": unix_nanos(span.start_ms),
+                "endTimeUnixNano": unix_nanos(span.end_ms),
+                "attributes": [
+                    { "key": status_key, "value": { "intValue": span.status.to_string() } }
+                ],
+                "status": {
+                    "code": if span.status >= 400 { STATUS_ERROR } else { STATUS_UNSET }
+                },
+            })
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": service_name } }
+                ]
+            },
+            "scopeSpans": [{ "scope": { "name": SCOPE_NAME }, "spans": spans }]
+        }]
+    })
+}
+
+// OTLP/JSON encodes 64-bit integers as strings
+fn unix_nanos(ms: f64) -> String {
+    format!("{}", (ms as u64) * 1_000_000)
+}
+
+async fn export(endpoint: &str, authorization: Option<&str>, body: &Value) -> Result<()> {
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    if let Some(authorization) = authorization {
+        headers.set("Authorization", authorization)?;
+    }
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(body.to_string().into()));
+    let response = Fetch::Request(Request::new_with_init(endpoint, &init)?)
+        .send()
+        .await?;
+    if response.status_code() >= 300 {
+        return Err(Error::RustError(format!(
+            "collector answered {}",
+            response.status_code()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_traceparent() {
+        assert_eq!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            Some(TraceContext {
+                trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+                parent_id: Some("00f067aa0ba902b7".to_string()),
+                flags: "01".to_string(),
+            })
+        );
+        // Future versions may carry extra fields
+        assert!(
+            parse_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra")
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_traceparent() {
+        for value in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-zz",
+        ] {
+            assert_eq!(parse_traceparent(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn otlp_body_links_spans_to_the_incoming_parent() {
+        let context =
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        let spans = [FinishedSpan {
+            name: "upstream.fetch",
+            kind: SPAN_KIND_CLIENT,
+            span_id: "b7ad6b7169203331".to_string(),
+            start_ms: 1693123456000.0,
+            end_ms: 1693123456250.0,
+            status: 502,
+        }];
+        let body = otlp_body("validator", &context, &spans);
+        let span = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(span["startTimeUnixNano"], "1693123456000000000");
+        assert_eq!(span["endTimeUnixNano"], "1693123456250000000");
+        assert_eq!(span["status"]["code"], STATUS_ERROR);
+        assert_eq!(span["attributes"][0]["key"], "http.response.status_code");
+    }
+}
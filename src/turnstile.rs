@@ -0,0 +1,133 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use url::form_urlencoded;
+use worker::*;
+
+use crate::errors::Rejection;
+use crate::pipeline::{Flow, Stage, ValidationContext};
+use crate::reason::Reason;
+
+const SITEVERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+const RESPONSE_FIELD: &str = "cf-turnstile-response";
+
+#[derive(Debug, Deserialize)]
+pub struct SiteverifyOutcome {
+    pub success: bool,
+    #[serde(rename = "error-codes", default)]
+    pub error_codes: Vec<String>,
+    pub hostname: Option<String>,
+    pub action: Option<String>,
+}
+
+// Looks for the widget response in the query string first, then in a form body
+pub async fn extract_response(req: &Request, url: &Url) -> Result<Option<String>> {
+    if let Some((_, value)) = url.query_pairs().find(|(k, _)| k == RESPONSE_FIELD) {
+        return Ok(Some(value.into_owned()));
+    }
+
+    let content_type = req.headers().get("Content-Type")?.unwrap_or_default();
+    if !content_type.starts_with("application/x-www-form-urlencoded")
+        && !content_type.starts_with("multipart/form-data")
+    {
+        return Ok(None);
+    }
+
+    // Read from a clone so the body is still available for the upstream request
+    match req.clone()?.form_data().await?.get(RESPONSE_FIELD) {
+        Some(FormEntry::Field(value)) => Ok(Some(value)),
+        _ => Ok(None),
+    }
+}
+
+pub async fn siteverify(
+    secret: &str,
+    response: &str,
+    client_ip: &str,
+) -> Result<SiteverifyOutcome> {
+    let body: String = form_urlencoded::Serializer::new(String::new())
+        .append_pair("secret", secret)
+        .append_pair("response", response)
+        .append_pair("remoteip", client_ip)
+        .finish();
+
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/x-www-form-urlencoded")?;
+    let mut request_init = RequestInit::new();
+    request_init
+        .with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(body.into()));
+
+    let request = Request::new_with_init(SITEVERIFY_URL, &request_init)?;
+    Fetch::Request(request).send().await?.json().await
+}
+
+// Enabled by the `TURNSTILE_SECRET` secret
+pub struct TurnstileCheck;
+
+#[async_trait(?Send)]
+impl<'a> Stage<ValidationContext<'a>> for TurnstileCheck {
+    async fn run(&self, cx: &mut ValidationContext<'a>) -> Result<Flow> {
+        let Ok(secret) = cx.env.secret("TURNSTILE_SECRET") else {
+            return Ok(Flow::Continue);
+        };
+        Ok(
+            match verify(&secret.to_string(), cx.req, cx.url, cx.client_ip).await {
+                Ok(()) => Flow::Continue,
+                Err(rejection) => Flow::Reject(rejection),
+            },
+        )
+    }
+}
+
+async fn verify(
+    secret: &str,
+    req: &Request,
+    url: &Url,
+    client_ip: &str,
+) -> std::result::Result<(), Rejection> {
+    let rejection = Rejection::new(
+        403,
+        Reason::TurnstileFailed,
+        "Turnstile verification failed",
+    );
+
+    let response = match extract_response(req, url).await {
+        Ok(Some(response)) => response,
+        Ok(None) => {
+            console_error!("turnstile: success=false reason=missing-response");
+            return Err(rejection);
+        }
+        Err(e) => {
+            console_error!(
+                "turnstile: success=false reason=unreadable-body error={}",
+                e
+            );
+            return Err(rejection);
+        }
+    };
+
+    match siteverify(secret, &response, client_ip).await {
+        Ok(outcome) => {
+            console_log!(
+                "turnstile: success={} error_codes={:?} hostname={} action={}",
+                outcome.success,
+                outcome.error_codes,
+                outcome.hostname.as_deref().unwrap_or("-"),
+                outcome.action.as_deref().unwrap_or("-")
+            );
+            if outcome.success {
+                Ok(())
+            } else {
+                Err(rejection)
+            }
+        }
+        Err(e) => {
+            console_error!(
+                "turnstile: success=false reason=siteverify-error error={}",
+                e
+            );
+            Err(rejection)
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::pin::pin;
+use std::time::Duration;
+
+use futures::future::{select, Either};
+use worker::*;
+
+use crate::encoding::{self, BodyMode};
+
+const DEFAULT_TIMEOUT_MS: u32 = 30_000;
+const DEFAULT_MAX_RETRIES: u32 = 0;
+const DEFAULT_RETRY_BACKOFF_MS: u32 = 100;
+// No single retry waits longer than this, however many attempts came before it
+const MAX_RETRY_BACKOFF_MS: u64 = 30_000;
+
+pub struct UpstreamSettings {
+    // 0 waits as long as the runtime does
+    timeout_ms: u32,
+    max_retries: u32,
+    backoff_ms: u32,
+}
+
+pub fn upstream_settings(env: &Env) -> UpstreamSettings {
+    UpstreamSettings {
+        timeout_ms: crate::var_or(env, "UPSTREAM_TIMEOUT_MS", DEFAULT_TIMEOUT_MS),
+        max_retries: crate::var_or(env, "UPSTREAM_MAX_RETRIES", DEFAULT_MAX_RETRIES),
+        backoff_ms: crate::var_or(env, "UPSTREAM_RETRY_BACKOFF_MS", DEFAULT_RETRY_BACKOFF_MS),
+    }
+}
+
+// Only methods the origin must tolerate seeing twice are retried
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        method,
+        Method::Get | Method::Head | Method::Options | Method::Put | Method::Delete | Method::Trace
+    )
+}
+
+// `None` is an attempt that got no response at all (network error or timeout)
+fn is_transient(status: Option<u16>) -> bool {
+    status.is_none_or(|status| matches!(status, 502..=504))
+}
+
+// The wait before retry `attempt + 1`: `base_ms` doubled per earlier retry, saturating rather
+// than wrapping and clamped to `MAX_RETRY_BACKOFF_MS`. Fan-out delivery backs off the same way.
+pub fn backoff_ms(base_ms: u64, attempt: u32) -> u64 {
+    let factor = 1u64.checked_shl(attempt).unwrap_or(u64::MAX);
+    base_ms.saturating_mul(factor).min(MAX_RETRY_BACKOFF_MS)
+}
+
+// A request the client keyed lets the origin tell the attempts apart. A key the worker generated
+// does not count: the client never agreed to the request being sent twice.
+fn max_retries(settings: &UpstreamSettings, method: &Method, client_keyed: bool) -> u32 {
+    if client_keyed || is_idempotent(method) {
+        settings.max_retries
+    } else {
+        0
+    }
+}
+
+// Sends `req`, retrying transient failures of idempotent or client-keyed requests with exponential
+// backoff. An error means the origin never answered, not even on the last attempt.
+pub async fn send(
+    settings: &UpstreamSettings,
+    req: Request,
+    mode: BodyMode,
+    client_keyed: bool,
+) -> Result<Response> {
+    let max_retries = max_retries(settings, &req.method(), client_keyed);
+    let mut attempt = 0;
+    loop {
+        if attempt >= max_retries {
+            return send_once(req, settings.timeout_ms, mode).await;
+        }
+        let outcome = send_once(req.clone()?, settings.timeout_ms, mode).await;
+        let retryable = match outcome {
+            Ok(response) if !is_transient(Some(response.status_code())) => return Ok(response),
+            Ok(response) => format!("status {}", response.status_code()),
+            Err(e) => e.to_string(),
+        };
+        console_error!(
+            "Upstream attempt {} failed ({}), retrying",
+            attempt + 1,
+            retryable
+        );
+        let backoff_ms = backoff_ms(u64::from(settings.backoff_ms), attempt);
+        Delay::from(Duration::from_millis(backoff_ms)).await;
+        attempt += 1;
+    }
+}
+
+// The timeout covers the wait for the response headers, not the body that streams after them
+async fn send_once(req: Request, timeout_ms: u32, mode: BodyMode) -> Result<Response> {
+    let fetch = Fetch::Request(encoding::prepare(req, mode)?);
+    if timeout_ms == 0 {
+        return Ok(encoding::received(fetch.send().await?, mode));
+    }
+    let controller = AbortController::default();
+    let signal = controller.signal();
+    let sending = pin!(fetch.send_with_signal(&signal));
+    let timeout = Delay::from(Duration::from_millis(u64::from(timeout_ms)));
+    match select(sending, timeout).await {
+        Either::Left((response, _)) => Ok(encoding::received(response?, mode)),
+        Either::Right(_) => {
+            controller.abort();
+            Err(Error::RustError(format!(
+                "no response within {} ms",
+                timeout_ms
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_only_transient_failures_of_idempotent_methods() {
+        assert!(is_idempotent(&Method::Get));
+        assert!(is_idempotent(&Method::Put));
+        assert!(!is_idempotent(&Method::Post));
+        assert!(!is_idempotent(&Method::Patch));
+
+        assert!(is_transient(None));
+        assert!(is_transient(Some(502)));
+        assert!(is_transient(Some(504)));
+        assert!(!is_transient(Some(500)));
+        assert!(!is_transient(Some(404)));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_ceiling() {
+        assert_eq!(backoff_ms(100, 0), 100);
+        assert_eq!(backoff_ms(100, 3), 800);
+        assert_eq!(backoff_ms(100, 60), MAX_RETRY_BACKOFF_MS);
+        assert_eq!(backoff_ms(100, 64), MAX_RETRY_BACKOFF_MS);
+        assert_eq!(backoff_ms(u64::MAX, 1), MAX_RETRY_BACKOFF_MS);
+        assert_eq!(backoff_ms(0, 100), 0);
+    }
+
+    #[test]
+    fn retries_posts_only_when_the_client_keyed_them() {
+        let settings = UpstreamSettings {
+            timeout_ms: 0,
+            max_retries: 2,
+            backoff_ms: 100,
+        };
+        assert_eq!(max_retries(&settings, &Method::Get, false), 2);
+        assert_eq!(max_retries(&settings, &Method::Post, true), 2);
+        assert_eq!(max_retries(&settings, &Method::Post, false), 0);
+    }
+}
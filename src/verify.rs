@@ -0,0 +1,487 @@
+use url::Url;
+use worker::*;
+
+use crate::access::{self, AccessJwtMode, AccessSettings};
+use crate::config::TokenRefresh;
+use crate::do_budget::DoBudget;
+use crate::encrypted_token;
+use crate::errors::Rejection;
+use crate::geo_policy;
+use crate::lookup_budget::{self, LookupBudget};
+use crate::pipeline::{self, Flow, Stage, ValidationContext};
+use crate::policy::FunctionPolicy;
+use crate::protected_params;
+use crate::rate_limit;
+use crate::reason::Reason;
+use crate::request_log::RequestLog;
+use crate::request_signing;
+use crate::revocation;
+use crate::shadow;
+use crate::tenant::{self, SignatureMode, TenantConfig};
+use crate::token::{
+    self, issue_hmac_token, parse_ed25519_public_key, parse_hmac_token, verify_ed25519_token,
+    verify_hmac_token, HmacToken, TimestampUnit,
+};
+use crate::turnstile;
+use crate::OaitTokens;
+
+#[derive(Clone, Debug, Default)]
+pub struct Verified {
+    // Fresh token minted for tokens past half their validity, when refresh is enabled
+    pub refreshed_token: Option<String>,
+    // `Set-Cookie` value for a session established by this request
+    pub session_cookie: Option<String>,
+    // Passed on a live session rather than a token check
+    pub resumed_session: bool,
+    // Passed on a continuation cookie from an earlier hop of a redirect flow
+    pub continued: bool,
+    // `Set-Cookie` value for a continuation minted by this request
+    pub continuation_cookie: Option<String>,
+    // Access token decrypted from an encrypted token, used instead of the plaintext oait part
+    pub access_token: Option<String>,
+    // Key id the token or request signature named
+    pub kid: Option<String>,
+    // `revocation::token_digest` of the token checked, kept by the sessions and continuations it
+    // opens so revoking the token ends them too
+    pub token_digest: Option<String>,
+    // The window the token was checked against, which refreshed tokens get too
+    pub validity_seconds: f64,
+}
+
+// What one signature check found
+#[derive(Default)]
+struct Signature {
+    valid: bool,
+    kid: Option<String>,
+    refreshed_token: Option<String>,
+    // Only encrypted tokens carry their access token inside the signed part
+    sealed_access_token: Option<String>,
+}
+
+// Everything the verification of one request reads, so each step takes the request once rather
+// than a dozen arguments
+pub struct Verifier<'a> {
+    pub env: &'a Env,
+    pub host: &'a str,
+    pub tenant: &'a TenantConfig,
+    pub client_ip: &'a str,
+    pub req: &'a Request,
+    // The body buffered by the body limit stage
+    pub body: &'a [u8],
+    pub url: &'a Url,
+    pub secret: &'a str,
+    pub policy: &'a FunctionPolicy,
+    pub access_settings: Option<&'a AccessSettings>,
+    pub do_budget: &'a DoBudget<'a>,
+    pub lookups: &'a LookupBudget,
+    pub log: &'a RequestLog,
+}
+
+impl<'a> Verifier<'a> {
+    fn jwt_replaces_oait(&self) -> bool {
+        self.access_settings
+            .is_some_and(|settings| settings.mode == AccessJwtMode::Replace)
+    }
+
+    // The revocation lookup and the protected parameters run alongside the other checks instead
+    // of after them, so they add no KV round trip of their own
+    pub async fn request(
+        &self,
+        parsed_tokens: &std::result::Result<OaitTokens, Rejection>,
+    ) -> std::result::Result<Verified, Rejection> {
+        let token_digest = match (parsed_tokens, self.jwt_replaces_oait()) {
+            (Ok(tokens), false) => Some(revocation::token_digest(
+                &tokens.cloudflare_token,
+                &crate::config::config(self.env),
+            )),
+            _ => None,
+        };
+        // The client IP and key id scopes apply whatever the token check is replaced by; only
+        // the token scope needs a cloudflare token that is checked
+        let kid = parsed_tokens
+            .as_ref()
+            .ok()
+            .and_then(|tokens| self.claimed_kid(tokens));
+        let (verified, revocation, params) = futures::future::join3(
+            self.token(parsed_tokens),
+            self.revocation(token_digest.as_deref(), kid.as_deref()),
+            self.params(),
+        )
+        .await;
+        let verified = verified?;
+        params?;
+        revocation?;
+        Ok(Verified {
+            token_digest,
+            ..verified
+        })
+    }
+
+    // A session or continuation was opened by a token check, so only what may have changed since
+    // is checked again: the rate limit, the Access JWT, and revocations of the client IP, of the
+    // token and of the key the session was opened with. Turnstile responses are single use and
+    // are not asked for again. Protected parameters belong to the request rather than the client,
+    // so they are always checked.
+    pub async fn resumed(
+        &self,
+        token_digest: Option<&str>,
+        kid: Option<&str>,
+    ) -> std::result::Result<(), Rejection> {
+        let stages: [&dyn Stage<_>; 2] = [&rate_limit::RateLimitCheck, &access::AccessJwtCheck];
+        let (checked, revocation, params) = futures::future::join3(
+            self.checks(&stages),
+            self.revocation(token_digest, kid),
+            self.params(),
+        )
+        .await;
+        checked?;
+        params?;
+        revocation
+    }
+
+    // Runs the validation stages in order; a stage that fails outright refuses the request
+    async fn checks(
+        &self,
+        stages: &[&dyn Stage<ValidationContext<'a>>],
+    ) -> std::result::Result<(), Rejection> {
+        let mut cx = ValidationContext {
+            req: self.req,
+            env: self.env,
+            url: self.url,
+            host: self.host,
+            client_ip: self.client_ip,
+            access_settings: self.access_settings,
+            do_budget: self.do_budget,
+        };
+        match pipeline::run(stages, &mut cx).await {
+            Ok(Flow::Reject(rejection)) => Err(rejection),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                console_error!("Validation check failed: {}", e);
+                Err(Rejection::new(
+                    500,
+                    Reason::VerificationUnavailable,
+                    "Token verification unavailable",
+                ))
+            }
+        }
+    }
+
+    async fn params(&self) -> std::result::Result<(), Rejection> {
+        let check = protected_params::ParamCheck {
+            host: self.host,
+            client_ip: self.client_ip,
+            validity_seconds: self.validity_seconds().0,
+            timestamp_unit: crate::timestamp_unit(self.env),
+            algorithms: crate::hmac_algorithms(self.env),
+            hash_encoding: crate::hash_encoding(self.env),
+        };
+        protected_params::verify(
+            self.env,
+            self.lookups,
+            &self.policy.protected_params,
+            self.url,
+            &check,
+        )
+        .await
+    }
+
+    fn validity_seconds(&self) -> (f64, String) {
+        crate::validity_seconds(self.env, self.policy, &geo_policy::client_signals(self.req))
+    }
+
+    // Without a `REVOCATIONS` binding nothing is revoked
+    async fn revocation(
+        &self,
+        token_digest: Option<&str>,
+        kid: Option<&str>,
+    ) -> std::result::Result<(), Rejection> {
+        let Ok(kv) = self.env.kv("REVOCATIONS") else {
+            return Ok(());
+        };
+        let config = crate::config::config(self.env);
+        let lookup = revocation::find_revocation(&kv, &config, token_digest, self.client_ip, kid);
+        // `Err` is a lookup that failed or ran out of time
+        let found = match self
+            .lookups
+            .within(lookup_budget::Dependency::Revocation, lookup)
+            .await
+        {
+            Some(Ok(scope)) => Ok(scope),
+            Some(Err(e)) => {
+                console_error!("Revocation lookup failed: {}", e);
+                Err(())
+            }
+            None => Err(()),
+        };
+
+        match found {
+            Ok(None) => Ok(()),
+            Ok(Some(scope)) => {
+                console_error!("Token revoked (scope={})", scope.name());
+                Err(Rejection::new(403, Reason::TokenRevoked, "Token revoked"))
+            }
+            Err(())
+                if self
+                    .lookups
+                    .fails_closed(lookup_budget::Dependency::Revocation) =>
+            {
+                Err(Rejection::new(
+                    503,
+                    Reason::VerificationUnavailable,
+                    "Token verification unavailable",
+                ))
+            }
+            // Fail open: a KV outage should not block every login
+            Err(()) => Ok(()),
+        }
+    }
+
+    // The key id a token claims, read ahead of verification so the revocation lookup need not wait
+    fn claimed_kid(&self, tokens: &OaitTokens) -> Option<String> {
+        match self.tenant.signature_mode {
+            SignatureMode::Hmac => {
+                parse_hmac_token(&tokens.cloudflare_token, crate::hmac_algorithms(self.env))
+                    .and_then(|token| token.kid.map(str::to_string))
+            }
+            SignatureMode::Encrypted => encrypted_token::parse(&tokens.cloudflare_token)
+                .and_then(|token| token.kid.map(str::to_string)),
+            SignatureMode::Request => self
+                .req
+                .headers()
+                .get("Authorization")
+                .ok()
+                .flatten()
+                .and_then(|value| request_signing::parse_authorization(&value))
+                .and_then(|header| header.kid),
+            SignatureMode::Ed25519 => None,
+        }
+    }
+
+    // Checks run in order: rate limit, Access JWT, Turnstile, oait format, then the token signature
+    async fn token(
+        &self,
+        parsed_tokens: &std::result::Result<OaitTokens, Rejection>,
+    ) -> std::result::Result<Verified, Rejection> {
+        let stages: [&dyn Stage<_>; 3] = [
+            &rate_limit::RateLimitCheck,
+            &access::AccessJwtCheck,
+            &turnstile::TurnstileCheck,
+        ];
+        self.checks(&stages).await?;
+
+        if self.jwt_replaces_oait() {
+            return parsed_tokens
+                .as_ref()
+                .map(|_| Verified::default())
+                .map_err(Clone::clone);
+        }
+
+        let tokens = parsed_tokens.as_ref().map_err(Clone::clone)?;
+        let (validity_seconds, validity_source) = self.validity_seconds();
+        self.log.line(format!(
+            "validity: window_seconds={} source={}",
+            validity_seconds, validity_source
+        ));
+
+        // Ed25519 tokens are never refreshed: the worker holds no private key to mint them
+        let signature = match self.tenant.signature_mode {
+            SignatureMode::Hmac => self.hmac(tokens, validity_seconds)?,
+            SignatureMode::Ed25519 => self.ed25519(tokens, validity_seconds).await?,
+            SignatureMode::Request => self.request_signature(validity_seconds),
+            SignatureMode::Encrypted => self.encrypted(tokens, validity_seconds)?,
+        };
+        if !signature.valid {
+            return Err(invalid_token());
+        }
+
+        Ok(Verified {
+            refreshed_token: signature.refreshed_token,
+            access_token: signature.sealed_access_token,
+            kid: signature.kid,
+            validity_seconds,
+            ..Verified::default()
+        })
+    }
+
+    fn hmac(
+        &self,
+        tokens: &OaitTokens,
+        validity_seconds: f64,
+    ) -> std::result::Result<Signature, Rejection> {
+        let env = self.env;
+        let Some(token) = parse_hmac_token(&tokens.cloudflare_token, crate::hmac_algorithms(env))
+        else {
+            return Err(invalid_token());
+        };
+        let audience = self.tenant.audience.as_deref().unwrap_or(self.host);
+        if !token.allows_audience(audience) {
+            console_error!("Token audience does not include {}", audience);
+            return Err(invalid_token());
+        }
+        let validity_seconds = match token.nonce {
+            Some(_) if !self.tenant.nonce_tokens => {
+                console_error!("Nonce tokens are not enabled for {}", self.host);
+                return Err(invalid_token());
+            }
+            Some(_) => crate::nonce_token_validity_seconds(env),
+            None => validity_seconds,
+        };
+        let Some(kid_secret) = crate::hmac_secret_for(env, self.secret, token.kid) else {
+            return Err(invalid_token());
+        };
+        let timestamp_unit = crate::timestamp_unit(env);
+        let truncated_len = crate::hmac_truncation(env, token.kid, token.algorithm);
+        let verifies = |secret: &str| {
+            verify_hmac_token(
+                self.client_ip,
+                &token,
+                secret,
+                validity_seconds,
+                timestamp_unit,
+                crate::hash_encoding(env),
+                truncated_len,
+            )
+        };
+        let valid = verifies(&kid_secret);
+        // Tried for readiness metrics during rotation; never changes the decision
+        if let Some(candidate) = shadow::shadow_secret(env, token.kid) {
+            shadow::record(env, self.host, token.kid, valid, verifies(&candidate));
+        }
+
+        let refreshed_token = valid
+            .then(|| {
+                self.refreshed(
+                    &token,
+                    &kid_secret,
+                    validity_seconds,
+                    timestamp_unit,
+                    truncated_len,
+                )
+            })
+            .flatten();
+        Ok(Signature {
+            valid,
+            kid: token.kid.map(str::to_string),
+            refreshed_token,
+            ..Signature::default()
+        })
+    }
+
+    // A valid HMAC token past half its window, minted again with the unit the issuer used.
+    // Signed links keep their own expiry and nonce tokens their short window, so neither is
+    // refreshed.
+    fn refreshed(
+        &self,
+        token: &HmacToken,
+        kid_secret: &str,
+        validity_seconds: f64,
+        timestamp_unit: TimestampUnit,
+        truncated_len: Option<usize>,
+    ) -> Option<String> {
+        let now = js_sys::Date::now() / 1000.0;
+        let issued_at = timestamp_unit.to_seconds(token.timestamp);
+        if token.lifetime.expires_at.is_some()
+            || token.nonce.is_some()
+            || crate::token_refresh_mode(self.env) == TokenRefresh::Off
+            || now - issued_at <= validity_seconds / 2.0
+        {
+            return None;
+        }
+        let lifetime = token::refreshed_lifetime(
+            token,
+            issued_at,
+            now,
+            validity_seconds,
+            crate::max_session_seconds(self.env),
+        )?;
+        Some(issue_hmac_token(
+            token.algorithm,
+            token.kid,
+            &token.audiences,
+            token.ip_bound.then_some(self.client_ip),
+            kid_secret,
+            timestamp_unit.in_unit_of(now, token.timestamp),
+            lifetime,
+            truncated_len,
+        ))
+    }
+
+    async fn ed25519(
+        &self,
+        tokens: &OaitTokens,
+        validity_seconds: f64,
+    ) -> std::result::Result<Signature, Rejection> {
+        let Some(public_key) =
+            tenant::ed25519_public_key(self.env, self.lookups, self.host, self.tenant)
+                .await
+                .and_then(|key| parse_ed25519_public_key(&key))
+        else {
+            console_error!("No valid Ed25519 public key configured for {}", self.host);
+            return Err(verification_unavailable());
+        };
+        Ok(Signature {
+            valid: verify_ed25519_token(
+                self.client_ip,
+                &tokens.cloudflare_token,
+                &public_key,
+                validity_seconds,
+                crate::timestamp_unit(self.env),
+            ),
+            ..Signature::default()
+        })
+    }
+
+    fn request_signature(&self, validity_seconds: f64) -> Signature {
+        let header = request_signing::verify(
+            self.req,
+            self.url,
+            self.body,
+            |kid| crate::hmac_secret_for(self.env, self.secret, kid),
+            validity_seconds,
+        );
+        Signature {
+            valid: header.is_some(),
+            kid: header.and_then(|header| header.kid),
+            ..Signature::default()
+        }
+    }
+
+    fn encrypted(
+        &self,
+        tokens: &OaitTokens,
+        validity_seconds: f64,
+    ) -> std::result::Result<Signature, Rejection> {
+        let Some(token) = encrypted_token::parse(&tokens.cloudflare_token) else {
+            return Err(invalid_token());
+        };
+        let Some(key) = crate::encryption_key_for(self.env, token.kid) else {
+            return Err(verification_unavailable());
+        };
+        let timestamp_unit = crate::timestamp_unit(self.env);
+        let payload = encrypted_token::open(&key, token.sealed).filter(|payload| {
+            payload.ip == self.client_ip
+                && token::is_fresh(payload.ts, timestamp_unit, validity_seconds)
+        });
+        Ok(Signature {
+            valid: payload.is_some(),
+            kid: token.kid.map(str::to_string),
+            sealed_access_token: payload
+                .map(|payload| payload.access_token)
+                .filter(|access_token| !access_token.is_empty()),
+            ..Signature::default()
+        })
+    }
+}
+
+fn invalid_token() -> Rejection {
+    Rejection::new(403, Reason::InvalidToken, "Invalid or expired token")
+}
+
+fn verification_unavailable() -> Rejection {
+    Rejection::new(
+        500,
+        Reason::VerificationUnavailable,
+        "Token verification unavailable",
+    )
+}
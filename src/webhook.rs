@@ -0,0 +1,174 @@
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::token::constant_time_compare;
+
+const DEFAULT_TOLERANCE_SECONDS: u32 = 300;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookProfile {
+    Github,
+    Stripe,
+}
+
+// Tenant `webhook` settings, e.g. { "profile": "stripe", "key_id": "stripe", "tolerance_seconds": 300 }.
+// The secret is `HMAC_SECRET_{KEY_ID}`, or `HMAC_SECRET` without a key id.
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookConfig {
+    pub profile: WebhookProfile,
+    pub key_id: Option<String>,
+    #[serde(default = "default_tolerance")]
+    pub tolerance_seconds: u32,
+}
+
+fn default_tolerance() -> u32 {
+    DEFAULT_TOLERANCE_SECONDS
+}
+
+pub trait WebhookVerifier {
+    fn signature_header(&self) -> &'static str;
+
+    // `now` and `tolerance_seconds` only matter for profiles whose signatures carry a timestamp
+    fn verify(
+        &self,
+        signature: &str,
+        body: &[u8],
+        secret: &str,
+        now: f64,
+        tolerance_seconds: f64,
+    ) -> bool;
+}
+
+// `X-Hub-Signature-256: sha256={hex HMAC-SHA256 of the body}`
+pub struct GithubVerifier;
+
+impl WebhookVerifier for GithubVerifier {
+    fn signature_header(&self) -> &'static str {
+        "X-Hub-Signature-256"
+    }
+
+    fn verify(
+        &self,
+        signature: &str,
+        body: &[u8],
+        secret: &str,
+        _now: f64,
+        _tolerance: f64,
+    ) -> bool {
+        signature
+            .strip_prefix("sha256=")
+            .is_some_and(|provided| matches_hex(secret, &[body], provided))
+    }
+}
+
+// `Stripe-Signature: t={timestamp},v1={hex HMAC-SHA256 of "{timestamp}.{body}"}`; any `v1` may match
+pub struct StripeVerifier;
+
+impl WebhookVerifier for StripeVerifier {
+    fn signature_header(&self) -> &'static str {
+        "Stripe-Signature"
+    }
+
+    fn verify(
+        &self,
+        signature: &str,
+        body: &[u8],
+        secret: &str,
+        now: f64,
+        tolerance_seconds: f64,
+    ) -> bool {
+        let mut timestamp = None;
+        let mut candidates = Vec::new();
+        for part in signature.split(',') {
+            match part.trim().split_once('=') {
+                Some(("t", value)) => timestamp = Some(value),
+                Some(("v1", value)) => candidates.push(value),
+                _ => {}
+            }
+        }
+
+        let Some(timestamp) = timestamp else {
+            return false;
+        };
+        match timestamp.parse::<f64>() {
+            Ok(signed_at) if (now - signed_at).abs() <= tolerance_seconds => {}
+            _ => return false,
+        }
+
+        let prefix = format!("{}.", timestamp);
+        candidates
+            .iter()
+            .any(|candidate| matches_hex(secret, &[prefix.as_bytes(), body], candidate))
+    }
+}
+
+pub fn verifier(profile: WebhookProfile) -> Box<dyn WebhookVerifier> {
+    match profile {
+        WebhookProfile::Github => Box::new(GithubVerifier),
+        WebhookProfile::Stripe => Box::new(StripeVerifier),
+    }
+}
+
+fn matches_hex(secret: &str, message: &[&[u8]], provided: &str) -> bool {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    for part in message {
+        mac.update(part);
+    }
+    let expected = mac.finalize().into_bytes();
+    // Undecodable signatures are rejected before the comparison
+    hex::decode(provided)
+        .ok()
+        .is_some_and(|provided| constant_time_compare(&expected, &provided))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex_hmac(secret: &str, message: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(message);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn github_signature_covers_the_raw_body() {
+        let body = br#"{"action":"opened"}"#;
+        let signature = format!("sha256={}", hex_hmac("secret", body));
+        let github = verifier(WebhookProfile::Github);
+        assert!(github.verify(&signature, body, "secret", 0.0, 0.0));
+        assert!(!github.verify(&signature, b"{}", "secret", 0.0, 0.0));
+        assert!(!github.verify(&signature, body, "other", 0.0, 0.0));
+        assert!(!github.verify(&signature[7..], body, "secret", 0.0, 0.0));
+    }
+
+    #[test]
+    fn stripe_signature_checks_timestamp_tolerance() {
+        let body = br#"{"type":"charge.succeeded"}"#;
+        let signed = hex_hmac("secret", &[b"1693123456.".as_slice(), body].concat());
+        let header = format!("t=1693123456,v1={},v0=ignored", signed);
+        let stripe = verifier(WebhookProfile::Stripe);
+        assert!(stripe.verify(&header, body, "secret", 1693123456.0 + 299.0, 300.0));
+        assert!(!stripe.verify(&header, body, "secret", 1693123456.0 + 301.0, 300.0));
+        assert!(!stripe.verify(&header, b"{}", "secret", 1693123456.0, 300.0));
+        assert!(!stripe.verify(
+            &format!("v1={}", signed),
+            body,
+            "secret",
+            1693123456.0,
+            300.0
+        ));
+    }
+
+    #[test]
+    fn stripe_accepts_any_v1_signature() {
+        let body = b"{}";
+        let signed = hex_hmac("secret", b"1693123456.{}");
+        let wrong = hex_hmac("rotated", b"1693123456.{}");
+        let header = format!("t=1693123456,v1={},v1={}", wrong, signed);
+        assert!(StripeVerifier.verify(&header, body, "secret", 1693123456.0, 300.0));
+    }
+}
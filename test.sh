@@ -11,6 +11,7 @@ readonly DEFAULT_HOST="reflector.cloudflareapp.cc"
 readonly DEFAULT_PORT=8787
 readonly DEFAULT_SECRET="default-secret"
 readonly TOKEN_VALIDITY_SECONDS=300
+readonly DEFAULT_MOCK_ORIGIN_PORT=8788
 
 # Colors
 readonly RED='\033[0;31m'
@@ -31,6 +32,8 @@ VERBOSE=false
 HOST="${HOST:-$DEFAULT_HOST}"
 PORT="${PORT:-$DEFAULT_PORT}"
 SECRET="${SECRET:-$DEFAULT_SECRET}"
+ORIGIN_TESTS=false
+MOCK_ORIGIN_PORT="${MOCK_ORIGIN_PORT:-$DEFAULT_MOCK_ORIGIN_PORT}"
 
 while [[ $# -gt 0 ]]; do
     case $1 in
@@ -50,6 +53,10 @@ while [[ $# -gt 0 ]]; do
             SECRET="$2"
             shift 2
             ;;
+        -o|--origin)
+            ORIGIN_TESTS=true
+            shift
+            ;;
         --help)
             echo "Usage: $0 [options]"
             echo "Options:"
@@ -57,6 +64,7 @@ while [[ $# -gt 0 ]]; do
             echo "  -h, --host HOST  Set host header (default: $DEFAULT_HOST)"
             echo "  -p, --port PORT  Set port (default: $DEFAULT_PORT)"
             echo "  -s, --secret KEY Set HMAC secret (default: $DEFAULT_SECRET)"
+            echo "  -o, --origin     Also run the tests against tests/mock-origin.mjs"
             exit 0
             ;;
         *)
@@ -92,8 +100,9 @@ log_test() {
 generate_hmac_token() {
     local client_ip="${1:-127.0.0.1}"
     local timestamp="${2:-$(date +%s%3N | sed 's/...$//')}.$(date +%3N)"
+    local algorithm="${3:-sha256}"
     local message="${client_ip}:${timestamp}"
-    local hash=$(echo -n "$message" | openssl dgst -sha256 -hmac "$SECRET" -binary | base64)
+    local hash=$(echo -n "$message" | openssl dgst "-${algorithm}" -hmac "$SECRET" -binary | base64 | tr -d '\n')
     echo "${timestamp}-${hash}"
 }
 
@@ -149,6 +158,41 @@ run_test() {
     fi
 }
 
+# Run test and check the status code plus a string in the response headers or body
+run_response_test() {
+    local test_name="$1"
+    local expected_code="$2"
+    local expected_text="$3"
+    local url="$4"
+    shift 4
+    local curl_args=("$@")
+
+    log_test "$test_name"
+    ((TESTS_RUN++))
+
+    local response
+    response=$(curl -s -D - -w "\n%{http_code}" "${curl_args[@]}" "$url" 2>&1)
+    local actual_code=$(echo "$response" | tail -n1)
+    if [[ "$VERBOSE" == true ]]; then
+        echo -e "${CYAN}Response:${NC}"
+        echo "$response" | head -n-1
+    fi
+
+    if [[ "$actual_code" != "$expected_code" ]]; then
+        log_error "Expected $expected_code, got $actual_code"
+        ((TESTS_FAILED++))
+        return 1
+    fi
+    if ! grep -qF -- "$expected_text" <<< "$response"; then
+        log_error "Response does not contain: $expected_text"
+        ((TESTS_FAILED++))
+        return 1
+    fi
+    log_success "Got $actual_code with $expected_text"
+    ((TESTS_PASSED++))
+    return 0
+}
+
 # Check if service is running
 check_service() {
     log_info "Checking if service is running on port $PORT..."
@@ -248,6 +292,68 @@ run_all_tests() {
         "${header_args[@]}" \
         -H "CF-Connecting-IP: 192.168.1.1" \
         -H "X-Forwarded-For: 192.168.1.1, 10.0.0.1"
+    
+    # Test 13: Valid token with explicit sha512 algorithm prefix
+    local sha512_token="sha512:$(generate_hmac_token "127.0.0.1" "" sha512)"
+    run_test "Valid sha512-prefixed token" "200" \
+        "${BASE_URL}/?function_id=APPS_LOGIN_DEFAULT&oait=forms_token++$(urlencode "$sha512_token")" \
+        "${header_args[@]}" \
+        -H "CF-Connecting-IP: 127.0.0.1"
+    
+    # Test 14: Valid token with hex-encoded hash
+    local hex_timestamp="$(date +%s).$(date +%3N)"
+    local hex_hash=$(echo -n "127.0.0.1:${hex_timestamp}" | openssl dgst -sha256 -hmac "$SECRET" -binary | od -An -tx1 | tr -d ' \n')
+    run_test "Valid token with hex hash" "200" \
+        "${BASE_URL}/?function_id=APPS_LOGIN_DEFAULT&oait=forms_token++${hex_timestamp}-${hex_hash}" \
+        "${header_args[@]}" \
+        -H "CF-Connecting-IP: 127.0.0.1"
+}
+
+# Needs `wrangler dev --env origin-tests`, which sends APPS_LOGIN_DEFAULT requests to the mock
+# origin. Its echo shows exactly what the worker forwarded.
+run_origin_tests() {
+    local header_args=(-H "host:${HOST}" -H "CF-Connecting-IP: 127.0.0.1")
+    local valid_token=$(urlencode "$(generate_hmac_token "127.0.0.1")")
+
+    echo -e "\n${BOLD}Mock origin tests${NC} (port ${MOCK_ORIGIN_PORT})"
+
+    # Test: Only the forms token reaches the origin
+    run_response_test "Forwards the forms token and strips the others" "200" \
+        '"url":"/login?oait=forms_token"' \
+        "${BASE_URL}/login?function_id=APPS_LOGIN_DEFAULT&oait=forms_token++${valid_token}++access_token_123" \
+        "${header_args[@]}"
+
+    # Test: Other parameters keep their order, repeats and empty values
+    run_response_test "Preserves repeated and empty query parameters" "200" \
+        '"url":"/login?id=1&id=2&q=&oait=forms_token"' \
+        "${BASE_URL}/login?id=1&function_id=APPS_LOGIN_DEFAULT&id=2&oait=forms_token++${valid_token}&q=" \
+        "${header_args[@]}"
+
+    # Test: The access token becomes the CF_Authorization cookie
+    run_response_test "Sets CF_Authorization from the access token" "200" \
+        "CF_Authorization=access_token_123; Path=/; HttpOnly; Secure; SameSite=Strict" \
+        "${BASE_URL}/login?function_id=APPS_LOGIN_DEFAULT&oait=forms_token++${valid_token}++access_token_123" \
+        "${header_args[@]}"
+
+    # Test: The origin's own cookies pass through beside it
+    run_response_test "Keeps the origin's cookies" "200" \
+        "origin_pref=dark" \
+        "${BASE_URL}/login?function_id=APPS_LOGIN_DEFAULT&oait=forms_token++${valid_token}++access_token_123" \
+        "${header_args[@]}"
+
+    # Test: POST bodies are forwarded unchanged
+    run_response_test "Forwards the request body" "200" \
+        '"body":"user=alice&remember=1"' \
+        "${BASE_URL}/login?function_id=APPS_LOGIN_DEFAULT&oait=forms_token++${valid_token}" \
+        "${header_args[@]}" \
+        -X POST -H "Content-Type: application/x-www-form-urlencoded" \
+        --data "user=alice&remember=1"
+
+    # Test: Rejected requests never reach the origin
+    run_response_test "Does not forward invalid tokens" "403" \
+        "Invalid or expired token" \
+        "${BASE_URL}/login?function_id=APPS_LOGIN_DEFAULT&oait=forms_token++invalid-hmac-token" \
+        "${header_args[@]}"
 }
 
 # ============================================================================
@@ -257,6 +363,14 @@ run_all_tests() {
 main() {
     check_service
     run_all_tests
+    if [[ "$ORIGIN_TESTS" == true ]]; then
+        if ! nc -z localhost "$MOCK_ORIGIN_PORT" &>/dev/null; then
+            log_error "Mock origin not running on port $MOCK_ORIGIN_PORT"
+            echo "Run: node tests/mock-origin.mjs"
+            exit 1
+        fi
+        run_origin_tests
+    fi
     
     # Print summary
     echo -e "\n${CYAN}════════════════════════════════════════════════════════════${NC}"
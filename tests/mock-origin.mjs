@@ -0,0 +1,28 @@
+// Mock origin for `./test.sh --origin`: answers every request with a JSON echo of what the
+// worker forwarded, and sets a cookie of its own so cookie merging can be checked.
+//
+//   node tests/mock-origin.mjs [port]
+
+import { createServer } from "node:http";
+
+const port = Number(process.argv[2] ?? process.env.MOCK_ORIGIN_PORT ?? 8788);
+
+createServer((req, res) => {
+  const chunks = [];
+  req.on("data", (chunk) => chunks.push(chunk));
+  req.on("end", () => {
+    const echo = {
+      method: req.method,
+      url: req.url,
+      headers: req.headers,
+      body: Buffer.concat(chunks).toString("utf8"),
+    };
+    res.writeHead(200, {
+      "Content-Type": "application/json",
+      "Set-Cookie": "origin_pref=dark; Path=/",
+    });
+    res.end(JSON.stringify(echo));
+  });
+}).listen(port, "127.0.0.1", () => {
+  console.log(`mock origin listening on http://127.0.0.1:${port}`);
+});